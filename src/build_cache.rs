@@ -0,0 +1,689 @@
+use std::{
+    io::{BufReader, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{prerequisite_hashes, variable_hashes, CACHE_DIRECTORY},
+    parse::{TargetGraph, TargetName},
+    sha256::{sha256, Sha256},
+};
+
+const BUILD_CACHE_DIRECTORY: &str = "build-cache";
+// In each local entry, next to its files.
+const INPUTS_FILE_NAME: &str = "inputs.json";
+const STATISTICS_FILE_NAME: &str = "build-cache-statistics";
+
+/// Sent as a bearer token to the `--cache-url`, if set.
+pub(crate) const TOKEN_ENVIRONMENT_VARIABLE: &str = "MAK_CACHE_TOKEN";
+
+/// What the key of an entry is a hash of, for one target of the group.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TargetInputs {
+    pub(crate) target: TargetName,
+    /// The expanded recipe (which includes the variables that the recipe uses).
+    pub(crate) scripts: Vec<String>,
+    /// The hashes of the contents of the prerequisites.
+    pub(crate) prerequisites: Vec<(String, String)>,
+    /// The hashes of the values of the variables from `# mak:env=…`.
+    pub(crate) variables: Vec<(String, String)>,
+}
+
+/// The inputs of the recipe of `group` for `--build-cache`. Should only be called once the prerequisites are up to
+/// date.
+pub(crate) fn cache_inputs(target_graph: &TargetGraph, group: &[TargetName]) -> Vec<TargetInputs> {
+    group
+        .iter()
+        .map(|member| {
+            let mut prerequisites: Vec<(String, String)> =
+                prerequisite_hashes(target_graph, member)
+                    .into_iter()
+                    .collect();
+            prerequisites.sort();
+            TargetInputs {
+                target: member.clone(),
+                scripts: target_graph
+                    .shell_invocations(member)
+                    .into_iter()
+                    .map(|invocation| invocation.script)
+                    .collect(),
+                prerequisites,
+                variables: variable_hashes(target_graph, member).into_iter().collect(),
+            }
+        })
+        .collect()
+}
+
+// Changed when what the key is a hash of changes, so that older entries aren't used.
+const KEY_VERSION: &[u8] = b"mak-build-cache-1";
+
+/// The SHA-256 hash of `inputs`, with every string and list preceded by its length.
+pub(crate) fn key_for_inputs(inputs: &[TargetInputs]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update_field(KEY_VERSION);
+    hasher.update(&(inputs.len() as u64).to_le_bytes());
+    for target_inputs in inputs {
+        hasher.update_field(target_inputs.target.0.as_bytes());
+        hasher.update(&(target_inputs.scripts.len() as u64).to_le_bytes());
+        for script in &target_inputs.scripts {
+            hasher.update_field(script.as_bytes());
+        }
+        for pairs in [&target_inputs.prerequisites, &target_inputs.variables] {
+            hasher.update(&(pairs.len() as u64).to_le_bytes());
+            for (name, hash) in pairs {
+                hasher.update_field(name.as_bytes());
+                hasher.update_field(hash.as_bytes());
+            }
+        }
+    }
+    hasher.finish()
+}
+
+/// The key of the outputs of `group`'s recipe for `--build-cache`: a hash of its `cache_inputs`.
+pub(crate) fn cache_key(target_graph: &TargetGraph, group: &[TargetName]) -> String {
+    key_for_inputs(&cache_inputs(target_graph, group))
+}
+
+/// Whether the targets of `group` can be stored in the build cache: they need to be files made by a recipe.
+pub(crate) fn is_cacheable(target_graph: &TargetGraph, group: &[TargetName]) -> bool {
+    group.iter().all(|member| {
+        target_graph.has_recipe(member)
+            && !target_graph.is_phony(member)
+            && !target_graph.recursive_make_targets.contains(member)
+            && !target_graph.submake_targets.contains_key(member)
+            && !target_graph.double_colon_rules.contains_key(member)
+    })
+}
+
+/// A target of a group, as it is stored in an entry: a file, or a tar archive of a `# mak:directory=true` target.
+pub(crate) struct Output {
+    path: String,
+    is_directory: bool,
+}
+
+impl Output {
+    // Where the file of the entry is, next to the target.
+    fn entry_file(&self) -> String {
+        match self.is_directory {
+            true => format!(
+                "{}.mak-archive-{}",
+                self.path.trim_end_matches('/'),
+                std::process::id()
+            ),
+            false => self.path.clone(),
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        match (self.is_directory, Path::new(&self.path)) {
+            (true, path) if !path.is_dir() => Err(format!("`{}` is not a directory", self.path)),
+            (false, path) if !path.is_file() => Err(format!("`{}` is not a file", self.path)),
+            _ => Ok(()),
+        }
+    }
+
+    fn archive(&self) -> Result<(), String> {
+        let mut command = Command::new("tar");
+        command
+            .arg("-cf")
+            .arg(self.entry_file())
+            .arg("-C")
+            .arg(&self.path)
+            .arg(".");
+        run(command).map_err(|error| format!("could not archive `{}`: {}", self.path, error))
+    }
+
+    // Replaces the directory with the contents of the archive. `-m` makes the files newer than their prerequisites.
+    fn extract(&self) -> Result<(), String> {
+        let _ = std::fs::remove_dir_all(&self.path);
+        std::fs::create_dir_all(&self.path).map_err(|error| error.to_string())?;
+        let mut command = Command::new("tar");
+        command
+            .arg("-xmf")
+            .arg(self.entry_file())
+            .arg("-C")
+            .arg(&self.path);
+        run(command).map_err(|error| format!("could not extract `{}`: {}", self.path, error))
+    }
+}
+
+/// The outputs of `group`, in order.
+pub(crate) fn outputs(target_graph: &TargetGraph, group: &[TargetName]) -> Vec<Output> {
+    group
+        .iter()
+        .map(|member| Output {
+            path: member.0.clone(),
+            is_directory: target_graph.directory_targets.contains(member),
+        })
+        .collect()
+}
+
+/// Somewhere to store the targets of recipes. An entry has a file for each target of a group, in order.
+pub(crate) trait CacheBackend: Send + Sync {
+    /// Copies the files of the entry for `key` to `paths`, if there is one. The restored files are newer than their
+    /// prerequisites, like freshly built ones.
+    fn restore(&self, key: &str, paths: &[String]) -> bool;
+
+    /// Stores the files at `paths` (which are regular files) as the entry for `key`.
+    fn store(&self, key: &str, paths: &[String]) -> Result<(), String>;
+}
+
+fn create_parent(path: &str) {
+    if let Some(parent) = Path::new(path).parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+}
+
+// Restores the files at `paths` from a remote cache, with `download(index, path)` downloading (and checking) the file
+// at `index` of the entry to `path`. They are downloaded next to `paths` first, so that a missing file doesn't leave some of them
+// changed.
+fn download_all(paths: &[String], download: impl Fn(usize, &str) -> Result<(), String>) -> bool {
+    let mut downloaded = vec![];
+    for (index, path) in paths.iter().enumerate() {
+        create_parent(path);
+        let partial_path = format!("{}.mak-download-{}", path, std::process::id());
+        let result = download(index, &partial_path);
+        downloaded.push(partial_path);
+        if result.is_err() {
+            for partial_path in &downloaded {
+                let _ = std::fs::remove_file(partial_path);
+            }
+            return false;
+        }
+    }
+    paths
+        .iter()
+        .zip(downloaded)
+        .all(|(path, partial_path)| std::fs::rename(partial_path, path).is_ok())
+}
+
+// The object of a remote entry with the SHA-256 hash of each of its files, one per line, in order. It is uploaded after
+// the files, so an entry without it is incomplete.
+const DIGESTS_OBJECT_NAME: &str = "digests";
+
+fn digests_path(key: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("mak-digests-{}-{}", std::process::id(), key))
+}
+
+fn file_sha256(path: &str) -> Result<String, String> {
+    std::fs::File::open(path)
+        .and_then(|file| sha256(BufReader::new(file)))
+        .map_err(|error| format!("could not read `{}`: {}", path, error))
+}
+
+// Stores the files at `paths` as the entry for `key` in a remote cache, with `upload(path, name)` uploading a file as
+// the object `name` of the entry: `0`, `1`, … for the files, and then `DIGESTS_OBJECT_NAME`.
+fn upload_entry(
+    key: &str,
+    paths: &[String],
+    upload: impl Fn(&str, &str) -> Result<(), String>,
+) -> Result<(), String> {
+    let mut digests = String::new();
+    for (index, path) in paths.iter().enumerate() {
+        digests.push_str(&file_sha256(path)?);
+        digests.push('\n');
+        upload(path, &index.to_string())
+            .map_err(|error| format!("could not upload `{}`: {}", path, error))?;
+    }
+    let digests_path = digests_path(key);
+    std::fs::write(&digests_path, digests).map_err(|error| error.to_string())?;
+    let result = upload(&digests_path.to_string_lossy(), DIGESTS_OBJECT_NAME)
+        .map_err(|error| format!("could not upload the digests: {}", error));
+    let _ = std::fs::remove_file(&digests_path);
+    result
+}
+
+// Restores the entry for `key` from a remote cache, with `download(name, path)` downloading the object `name` of the
+// entry (see `upload_entry`) to `path`. A file that doesn't match its digest (e.g. a truncated download) is a miss.
+fn download_entry(
+    key: &str,
+    paths: &[String],
+    download: impl Fn(&str, &str) -> Result<(), String>,
+) -> bool {
+    let digests_path = digests_path(key);
+    let digests = download(DIGESTS_OBJECT_NAME, &digests_path.to_string_lossy())
+        .ok()
+        .and_then(|()| std::fs::read_to_string(&digests_path).ok());
+    let _ = std::fs::remove_file(&digests_path);
+    let Some(digests) = digests else {
+        return false;
+    };
+    let digests: Vec<&str> = digests.lines().collect();
+    if digests.len() != paths.len() {
+        return false;
+    }
+    download_all(paths, |index, path| {
+        download(&index.to_string(), path)?;
+        match file_sha256(path)? == digests[index] {
+            true => Ok(()),
+            false => Err(format!("`{}` doesn't match its digest", path)),
+        }
+    })
+}
+
+// Runs `command`, with its stderr as the error if it fails.
+fn run(mut command: Command) -> Result<(), String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|error| format!("could not run `{}`: {}", program, error))?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(String::from_utf8_lossy(&output.stderr).trim().to_owned()),
+    }
+}
+
+/// The entries in `.mak/build-cache`.
+pub(crate) struct LocalCache {
+    directory: PathBuf,
+}
+
+impl LocalCache {
+    pub(crate) fn new() -> Self {
+        Self {
+            directory: Path::new(CACHE_DIRECTORY).join(BUILD_CACHE_DIRECTORY),
+        }
+    }
+
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.directory.join(key).is_dir()
+    }
+
+    // For `--cache-ls` and `--cache-inspect`. Entries that were stored by older versions of `mak` don't have them.
+    fn write_inputs(&self, key: &str, inputs: &[TargetInputs]) {
+        let path = self.directory.join(key).join(INPUTS_FILE_NAME);
+        if let (false, Ok(json)) = (path.exists(), serde_json::to_string_pretty(inputs)) {
+            let _ = std::fs::write(path, json);
+        }
+    }
+
+    /// The entries, most recently used first.
+    pub(crate) fn entries(&self) -> Vec<LocalEntry> {
+        let Ok(directory_entries) = std::fs::read_dir(&self.directory) else {
+            return vec![];
+        };
+        let mut entries: Vec<LocalEntry> = directory_entries
+            .map_while(Result::ok)
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| {
+                let key = entry.file_name().into_string().ok()?;
+                // Partially written entries.
+                if key.contains('.') {
+                    return None;
+                }
+                let files: Vec<std::fs::Metadata> = std::fs::read_dir(entry.path())
+                    .ok()?
+                    .map_while(Result::ok)
+                    .filter(|file| file.file_name() != INPUTS_FILE_NAME)
+                    .filter_map(|file| file.metadata().ok())
+                    .collect();
+                Some(LocalEntry {
+                    size: files.iter().map(std::fs::Metadata::len).sum(),
+                    last_used: entry.metadata().ok()?.modified().ok()?,
+                    inputs: self.inputs(&key),
+                    key,
+                })
+            })
+            .collect();
+        entries.sort_by(|a, b| b.last_used.cmp(&a.last_used));
+        entries
+    }
+
+    /// Deletes the entries that were last used more than `max_age` ago, and the least recently used ones that don't fit
+    /// in `max_size` with the ones used after them. Returns the deleted entries.
+    pub(crate) fn collect_garbage(&self, max_size: u64, max_age: Duration) -> Vec<LocalEntry> {
+        let now = SystemTime::now();
+        let mut total_size = 0;
+        let mut deleted = vec![];
+        let mut over_limit = false;
+        for entry in self.entries() {
+            total_size += entry.size;
+            over_limit |= total_size > max_size
+                || now
+                    .duration_since(entry.last_used)
+                    .is_ok_and(|age| age > max_age);
+            if over_limit && std::fs::remove_dir_all(self.directory.join(&entry.key)).is_ok() {
+                deleted.push(entry);
+            }
+        }
+        deleted
+    }
+
+    pub(crate) fn inputs(&self, key: &str) -> Option<Vec<TargetInputs>> {
+        let json = std::fs::read_to_string(self.directory.join(key).join(INPUTS_FILE_NAME)).ok()?;
+        serde_json::from_str(&json).ok()
+    }
+}
+
+/// An entry of the local build cache.
+pub(crate) struct LocalEntry {
+    pub(crate) key: String,
+    /// Of the files of the targets.
+    pub(crate) size: u64,
+    /// When it was stored or last restored from.
+    pub(crate) last_used: SystemTime,
+    pub(crate) inputs: Option<Vec<TargetInputs>>,
+}
+
+impl LocalEntry {
+    pub(crate) fn targets(&self) -> Vec<TargetName> {
+        self.inputs
+            .iter()
+            .flatten()
+            .map(|inputs| inputs.target.clone())
+            .collect()
+    }
+}
+
+impl CacheBackend for LocalCache {
+    fn restore(&self, key: &str, paths: &[String]) -> bool {
+        let directory = self.directory.join(key);
+        if !directory.is_dir() {
+            return false;
+        }
+        let restored = paths.iter().enumerate().all(|(index, path)| {
+            create_parent(path);
+            std::fs::copy(directory.join(index.to_string()), path).is_ok()
+        });
+        // For `collect_garbage`.
+        if restored {
+            let _ = std::fs::File::open(&directory)
+                .and_then(|directory| directory.set_modified(SystemTime::now()));
+        }
+        restored
+    }
+
+    fn store(&self, key: &str, paths: &[String]) -> Result<(), String> {
+        let directory = self.directory.join(key);
+        if directory.is_dir() {
+            return Ok(());
+        }
+        // Written next to the entry and then renamed, so that a partial entry is never used.
+        let partial_directory = directory.with_extension(format!("partial-{}", std::process::id()));
+        std::fs::create_dir_all(&partial_directory).map_err(|error| error.to_string())?;
+        let result = paths
+            .iter()
+            .enumerate()
+            .try_for_each(|(index, path)| {
+                std::fs::copy(path, partial_directory.join(index.to_string()))
+                    .map(|_| ())
+                    .map_err(|error| format!("could not copy `{}`: {}", path, error))
+            })
+            .and_then(|()| {
+                std::fs::rename(&partial_directory, &directory).map_err(|error| error.to_string())
+            });
+        if result.is_err() {
+            let _ = std::fs::remove_dir_all(&partial_directory);
+        }
+        result
+    }
+}
+
+/// A cache server that entries are read from with `GET <url>/<key>/<index>` (and `<url>/<key>/digests`, which the files
+/// are checked against) and written to with `PUT`, using `curl`.
+/// Any HTTP server that can store files works, e.g. nginx with WebDAV or an S3 bucket behind a proxy.
+pub(crate) struct HttpCache {
+    url: String,
+    token: Option<String>,
+}
+
+impl HttpCache {
+    pub(crate) fn new(url: &str) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_owned(),
+            token: std::env::var(TOKEN_ENVIRONMENT_VARIABLE)
+                .ok()
+                .filter(|token| !token.is_empty()),
+        }
+    }
+
+    // Runs `curl` with `args` for the object `name` of the entry for `key`. The token is passed on stdin, so that it
+    // doesn't show up in the list of processes.
+    fn curl(&self, key: &str, name: &str, args: &[&str]) -> Result<(), String> {
+        let mut command = Command::new("curl");
+        command
+            .args(["--silent", "--show-error", "--fail", "--location"])
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        if self.token.is_some() {
+            command.args(["--header", "@-"]);
+        }
+        command.arg(format!("{}/{}/{}", self.url, key, name));
+        let mut child = command
+            .spawn()
+            .map_err(|error| format!("could not run `curl`: {}", error))?;
+        if let (Some(token), Some(mut stdin)) = (&self.token, child.stdin.take()) {
+            let _ = writeln!(stdin, "Authorization: Bearer {}", token);
+        }
+        let output = child
+            .wait_with_output()
+            .map_err(|error| format!("could not run `curl`: {}", error))?;
+        match output.status.success() {
+            true => Ok(()),
+            false => Err(String::from_utf8_lossy(&output.stderr).trim().to_owned()),
+        }
+    }
+}
+
+impl CacheBackend for HttpCache {
+    fn restore(&self, key: &str, paths: &[String]) -> bool {
+        download_entry(key, paths, |name, path| {
+            self.curl(key, name, &["--output", path])
+        })
+    }
+
+    fn store(&self, key: &str, paths: &[String]) -> Result<(), String> {
+        upload_entry(key, paths, |path, name| {
+            // Without waiting for a `100 Continue`, which not all servers send.
+            self.curl(key, name, &["--header", "Expect:", "--upload-file", path])
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ObjectStore {
+    S3,
+    Gcs,
+}
+
+/// A bucket that entries are stored in as `<url>/<key>/<index>` objects (and `<url>/<key>/digests`, which the files are
+/// checked against): `s3://bucket/prefix` (using the `aws` CLI, which also works for S3-compatible stores with
+/// `AWS_ENDPOINT_URL`) or `gs://bucket/prefix` (using `gcloud`). The credentials come from the environment (e.g.
+/// `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`, or `CLOUDSDK_AUTH_ACCESS_TOKEN_FILE`) or the configuration of the
+/// CLI.
+pub(crate) struct ObjectStoreCache {
+    url: String,
+    object_store: ObjectStore,
+}
+
+impl ObjectStoreCache {
+    fn new(url: &str, object_store: ObjectStore) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_owned(),
+            object_store,
+        }
+    }
+
+    fn copy(&self, from: &str, to: &str) -> Result<(), String> {
+        let mut command = match self.object_store {
+            ObjectStore::S3 => {
+                let mut command = Command::new("aws");
+                command.args(["s3", "cp", "--only-show-errors"]);
+                command
+            }
+            ObjectStore::Gcs => {
+                let mut command = Command::new("gcloud");
+                command.args(["storage", "cp", "--quiet"]);
+                command
+            }
+        };
+        command.arg(from).arg(to).stdin(Stdio::null());
+        run(command)
+    }
+
+    fn object_url(&self, key: &str, name: &str) -> String {
+        format!("{}/{}/{}", self.url, key, name)
+    }
+}
+
+impl CacheBackend for ObjectStoreCache {
+    fn restore(&self, key: &str, paths: &[String]) -> bool {
+        download_entry(key, paths, |name, path| {
+            self.copy(&self.object_url(key, name), path)
+        })
+    }
+
+    fn store(&self, key: &str, paths: &[String]) -> Result<(), String> {
+        upload_entry(key, paths, |path, name| {
+            self.copy(path, &self.object_url(key, name))
+        })
+    }
+}
+
+// The backend for a `--cache-url`, by its scheme.
+fn remote_backend(cache_url: &str) -> Box<dyn CacheBackend> {
+    if cache_url.starts_with("s3://") {
+        Box::new(ObjectStoreCache::new(cache_url, ObjectStore::S3))
+    } else if cache_url.starts_with("gs://") {
+        Box::new(ObjectStoreCache::new(cache_url, ObjectStore::Gcs))
+    } else {
+        Box::new(HttpCache::new(cache_url))
+    }
+}
+
+/// How often the build cache had the targets of a recipe, over all builds (for `--cache-stats`).
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct Statistics {
+    pub(crate) local_hits: u64,
+    pub(crate) remote_hits: u64,
+    pub(crate) misses: u64,
+    pub(crate) stores: u64,
+}
+
+pub(crate) fn read_statistics() -> Statistics {
+    std::fs::read_to_string(Path::new(CACHE_DIRECTORY).join(STATISTICS_FILE_NAME))
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+/// The `--build-cache` and the `--cache-url` (if any), in the order they are checked.
+pub(crate) struct BuildCache {
+    local: LocalCache,
+    backends: Vec<Box<dyn CacheBackend>>,
+    // In the order of `Statistics`, for this build.
+    counts: [AtomicU64; 4],
+}
+
+impl BuildCache {
+    pub(crate) fn new(cache_url: Option<&str>) -> Self {
+        let mut backends: Vec<Box<dyn CacheBackend>> = vec![Box::new(LocalCache::new())];
+        if let Some(cache_url) = cache_url {
+            backends.push(remote_backend(cache_url));
+        }
+        Self {
+            local: LocalCache::new(),
+            backends,
+            counts: Default::default(),
+        }
+    }
+}
+
+impl BuildCache {
+    /// Adds the hits and misses of this build to the `read_statistics`.
+    pub(crate) fn write_statistics(&self) -> Result<(), String> {
+        let counts = self
+            .counts
+            .each_ref()
+            .map(|count| count.load(Ordering::SeqCst));
+        if counts.iter().all(|count| *count == 0) {
+            return Ok(());
+        }
+        let mut statistics = read_statistics();
+        statistics.local_hits += counts[0];
+        statistics.remote_hits += counts[1];
+        statistics.misses += counts[2];
+        statistics.stores += counts[3];
+        let json = serde_json::to_string(&statistics).map_err(|error| error.to_string())?;
+        std::fs::create_dir_all(CACHE_DIRECTORY).map_err(|error| error.to_string())?;
+        std::fs::write(Path::new(CACHE_DIRECTORY).join(STATISTICS_FILE_NAME), json)
+            .map_err(|error| error.to_string())
+    }
+
+    /// Runs `LocalCache::collect_garbage` if entries were added to `.mak/build-cache` during this build.
+    pub(crate) fn collect_garbage(&self, max_size: u64, max_age: Duration) -> Vec<LocalEntry> {
+        match self.counts[1].load(Ordering::SeqCst) + self.counts[3].load(Ordering::SeqCst) {
+            0 => vec![],
+            _ => self.local.collect_garbage(max_size, max_age),
+        }
+    }
+
+    /// Restores `outputs` from the entry for `key` (a hash of `inputs`), if one of the backends has it. An entry that
+    /// is only found in a later backend is also stored in the earlier ones.
+    pub(crate) fn restore(&self, key: &str, outputs: &[Output], inputs: &[TargetInputs]) -> bool {
+        let entry_files: Vec<String> = outputs.iter().map(Output::entry_file).collect();
+        let Some(index) = self
+            .backends
+            .iter()
+            .position(|backend| backend.restore(key, &entry_files))
+        else {
+            self.counts[2].fetch_add(1, Ordering::SeqCst);
+            return false;
+        };
+        self.counts[if index == 0 { 0 } else { 1 }].fetch_add(1, Ordering::SeqCst);
+        for backend in &self.backends[..index] {
+            let _ = backend.store(key, &entry_files);
+        }
+        self.local.write_inputs(key, inputs);
+        let mut restored = true;
+        for output in outputs.iter().filter(|output| output.is_directory) {
+            restored &= output.extract().is_ok();
+            let _ = std::fs::remove_file(output.entry_file());
+        }
+        restored
+    }
+
+    /// Stores `outputs` as the entry for `key` (a hash of `inputs`) in all of the backends.
+    pub(crate) fn store(
+        &self,
+        key: &str,
+        outputs: &[Output],
+        inputs: &[TargetInputs],
+    ) -> Result<(), String> {
+        outputs.iter().try_for_each(Output::check)?;
+        let directories: Vec<&Output> = outputs
+            .iter()
+            .filter(|output| output.is_directory)
+            .collect();
+        let result = directories
+            .iter()
+            .try_for_each(|output| output.archive())
+            .and_then(|()| {
+                let entry_files: Vec<String> = outputs.iter().map(Output::entry_file).collect();
+                let errors: Vec<String> = self
+                    .backends
+                    .iter()
+                    .filter_map(|backend| backend.store(key, &entry_files).err())
+                    .collect();
+                self.local.write_inputs(key, inputs);
+                self.counts[3].fetch_add(1, Ordering::SeqCst);
+                match errors.is_empty() {
+                    true => Ok(()),
+                    false => Err(errors.join("; ")),
+                }
+            });
+        for output in directories {
+            let _ = std::fs::remove_file(output.entry_file());
+        }
+        result
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::BufReader,
+    path::Path,
+    time::Duration,
+};
+
+use indexmap::{IndexMap, IndexSet};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    options::Symlinks,
+    parse::{directory_entries, file_metadata, GraphInternals, TargetGraph, TargetName},
+    sha256::{sha256, Sha256},
+};
+
+pub(crate) const CACHE_DIRECTORY: &str = ".mak";
+const CACHE_FILE_NAME: &str = "graph-cache";
+const DURATIONS_FILE_NAME: &str = "recipe-durations";
+const STATE_FILE_NAME: &str = "state";
+
+/// What was stored about a target the last time it was built (or found up to date), for changes that modification
+/// times don't show.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct TargetState {
+    /// For `--freshness hash`: the hash of each prerequisite file.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub(crate) prerequisites: IndexMap<String, String>,
+    /// For `# mak:env=CC,CFLAGS`: the hash of the value of each of the variables.
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub(crate) variables: IndexMap<String, String>,
+}
+
+pub(crate) type TargetStates = IndexMap<TargetName, TargetState>;
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry<G> {
+    version: String,
+    key: u64,
+    /// Every makefile that was read, with a hash of its contents.
+    makefiles: Vec<(String, u64)>,
+    /// A hash of the environment variables that the makefiles could refer to.
+    environment: u64,
+    graph: G,
+    internals: GraphInternals,
+}
+
+pub(crate) fn hash<T: Hash>(value: T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Hashes the arguments (and anything else) a parse depends on, apart from the makefiles and the environment.
+pub(crate) fn cache_key(inputs: &[String]) -> u64 {
+    hash(inputs)
+}
+
+// Since environment variables are variables in a makefile, any word in the makefiles could be one.
+fn environment_hash(texts: &[String]) -> u64 {
+    let mut words: Vec<&str> = texts
+        .iter()
+        .flat_map(|text| text.split(|c: char| !c.is_ascii_alphanumeric() && c != '_'))
+        .filter(|word| !word.is_empty())
+        .collect::<IndexSet<&str>>()
+        .into_iter()
+        .collect();
+    words.sort_unstable();
+    hash(
+        words
+            .iter()
+            .map(|word| (word, std::env::var_os(word)))
+            .collect::<Vec<_>>(),
+    )
+}
+
+fn cache_path() -> std::path::PathBuf {
+    Path::new(CACHE_DIRECTORY).join(CACHE_FILE_NAME)
+}
+
+/// Returns the cached graph if it was stored with the same `key` and none of its makefiles (or the environment
+/// variables they mention) have changed since.
+// TODO: notice makefiles that were missing (e.g. with `-include`) but exist now.
+pub(crate) fn read_cached_graph(key: u64) -> Option<TargetGraph> {
+    let json = std::fs::read_to_string(cache_path()).ok()?;
+    let entry: CacheEntry<TargetGraph> = serde_json::from_str(&json).ok()?;
+    if entry.version != env!("CARGO_PKG_VERSION") || entry.key != key {
+        return None;
+    }
+    let mut texts = vec![];
+    for (path, content_hash) in &entry.makefiles {
+        let text = std::fs::read_to_string(path).ok()?;
+        if hash(&text) != *content_hash {
+            return None;
+        }
+        texts.push(text);
+    }
+    if environment_hash(&texts) != entry.environment {
+        return None;
+    }
+    let mut target_graph = entry.graph;
+    target_graph.restore_internals(entry.internals);
+    Some(target_graph)
+}
+
+pub(crate) fn write_cached_graph(key: u64, target_graph: &TargetGraph) -> Result<(), String> {
+    // Without any files to check, the cache could never be invalidated.
+    if target_graph.makefiles.is_empty() {
+        return Err("no makefiles to check for changes".to_owned());
+    }
+    let mut makefiles = vec![];
+    let mut texts = vec![];
+    for path in &target_graph.makefiles {
+        let text = std::fs::read_to_string(path).map_err(|error| error.to_string())?;
+        makefiles.push((path.clone(), hash(&text)));
+        texts.push(text);
+    }
+    let entry = CacheEntry {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        key,
+        makefiles,
+        environment: environment_hash(&texts),
+        graph: target_graph,
+        internals: target_graph.internals(),
+    };
+    let json = serde_json::to_string(&entry).map_err(|error| error.to_string())?;
+    std::fs::create_dir_all(CACHE_DIRECTORY).map_err(|error| error.to_string())?;
+    std::fs::write(cache_path(), json).map_err(|error| error.to_string())
+}
+
+/// How long the recipe of each target took the last time it succeeded.
+pub(crate) fn read_recipe_durations() -> IndexMap<TargetName, Duration> {
+    std::fs::read_to_string(Path::new(CACHE_DIRECTORY).join(DURATIONS_FILE_NAME))
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+/// Updates the durations from `read_recipe_durations` with the ones from this run.
+pub(crate) fn write_recipe_durations(
+    durations: &IndexMap<TargetName, Duration>,
+) -> Result<(), String> {
+    let mut all_durations = read_recipe_durations();
+    all_durations.extend(durations.clone());
+    let json = serde_json::to_string(&all_durations).map_err(|error| error.to_string())?;
+    std::fs::create_dir_all(CACHE_DIRECTORY).map_err(|error| error.to_string())?;
+    std::fs::write(Path::new(CACHE_DIRECTORY).join(DURATIONS_FILE_NAME), json)
+        .map_err(|error| error.to_string())
+}
+
+// The SHA-256 hash of the contents of a file, or of the path that a symbolic link points to with
+// `--symlinks no-follow`.
+fn contents_hash(path: &Path, symlinks: Symlinks) -> Option<String> {
+    match file_metadata(path, symlinks).ok()? {
+        metadata if metadata.is_symlink() => {
+            let target = std::fs::read_link(path).ok()?;
+            sha256(target.as_os_str().as_encoded_bytes()).ok()
+        }
+        metadata if metadata.is_file() => {
+            sha256(BufReader::new(std::fs::File::open(path).ok()?)).ok()
+        }
+        _ => None,
+    }
+}
+
+/// The SHA-256 hash of a file's contents, or `None` if it can't be read. For a directory, the hash of the names and
+/// contents of everything in it.
+pub(crate) fn file_hash(path: &str, symlinks: Symlinks) -> Option<String> {
+    let directory = Path::new(path);
+    if !file_metadata(directory, symlinks).is_ok_and(|metadata| metadata.is_dir()) {
+        return contents_hash(directory, symlinks);
+    }
+    let mut hasher = Sha256::new();
+    for entry in directory_entries(directory, symlinks) {
+        let name = entry.strip_prefix(directory).unwrap_or(&entry);
+        hasher.update_field(name.as_os_str().as_encoded_bytes());
+        // Empty for a subdirectory or a file that can't be read, which no hash is.
+        hasher.update_field(
+            contents_hash(&entry, symlinks)
+                .unwrap_or_default()
+                .as_bytes(),
+        );
+    }
+    Some(hasher.finish())
+}
+
+/// The hashes of the prerequisite files of `target_name` (as they are now).
+pub(crate) fn prerequisite_hashes(
+    target_graph: &TargetGraph,
+    target_name: &TargetName,
+) -> IndexMap<String, String> {
+    target_graph
+        .edges
+        .get(target_name)
+        .into_iter()
+        .flatten()
+        .filter(|prerequisite| !target_graph.is_phony(prerequisite))
+        .filter_map(|prerequisite| {
+            let path = target_graph.resolve_path(&prerequisite.0)?;
+            // Other directories (e.g. `node_modules`) could take long to hash.
+            let is_directory = file_metadata(Path::new(&path), target_graph.symlinks)
+                .is_ok_and(|metadata| metadata.is_dir());
+            if is_directory && !target_graph.directory_targets.contains(prerequisite) {
+                return None;
+            }
+            let hash = file_hash(&path, target_graph.symlinks)?;
+            Some((path, hash))
+        })
+        .collect()
+}
+
+/// The hashes of the values of the variables that `target_name` declares with `# mak:env=…` (as they are now).
+pub(crate) fn variable_hashes(
+    target_graph: &TargetGraph,
+    target_name: &TargetName,
+) -> IndexMap<String, String> {
+    let variables = target_graph.variables_for_target(target_name);
+    target_graph
+        .environment_inputs
+        .get(target_name)
+        .into_iter()
+        .flatten()
+        .map(|name| {
+            (
+                name.clone(),
+                sha256(variables.expand(&format!("$({})", name)).as_bytes()).unwrap_or_default(),
+            )
+        })
+        .collect()
+}
+
+pub(crate) fn read_target_states() -> TargetStates {
+    std::fs::read_to_string(Path::new(CACHE_DIRECTORY).join(STATE_FILE_NAME))
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+/// Updates the states from `read_target_states` with the ones from this run.
+pub(crate) fn write_target_states(states: &TargetStates) -> Result<(), String> {
+    let mut all_states = read_target_states();
+    all_states.extend(states.clone());
+    let json = serde_json::to_string(&all_states).map_err(|error| error.to_string())?;
+    std::fs::create_dir_all(CACHE_DIRECTORY).map_err(|error| error.to_string())?;
+    std::fs::write(Path::new(CACHE_DIRECTORY).join(STATE_FILE_NAME), json)
+        .map_err(|error| error.to_string())
+}
@@ -0,0 +1,186 @@
+// For `--cache-stats`, `--cache-ls`, `--cache-inspect`, and `--cache-gc`: what is in the `--build-cache`, how often it
+// had the targets of recipes, and what the inputs of an entry were (e.g. to find out why two builds didn't share an
+// entry).
+
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    build_cache::{read_statistics, LocalCache, LocalEntry},
+    parse::TargetName,
+};
+
+// How many of the largest entries `--cache-stats` lists.
+const LARGEST_ENTRIES: usize = 5;
+
+fn human_size(size: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", size),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+fn human_age(stored: SystemTime) -> String {
+    let seconds = SystemTime::now()
+        .duration_since(stored)
+        .unwrap_or_default()
+        .as_secs();
+    match seconds {
+        0..=59 => format!("{}s ago", seconds),
+        60..=3599 => format!("{}m ago", seconds / 60),
+        3600..=86399 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
+
+fn rate(hits: u64, lookups: u64) -> String {
+    match lookups {
+        0 => "-".to_owned(),
+        _ => format!("{:.1}%", hits as f64 * 100.0 / lookups as f64),
+    }
+}
+
+fn describe_targets(entry: &LocalEntry) -> String {
+    match &entry.inputs {
+        Some(_) => entry
+            .targets()
+            .iter()
+            .map(TargetName::to_string)
+            .collect::<Vec<_>>()
+            .join(" "),
+        None => "(unknown targets)".to_owned(),
+    }
+}
+
+/// Prints the hit rates over all builds, and the number, total size, and largest of the local entries.
+pub(crate) fn print_statistics() {
+    let statistics = read_statistics();
+    let lookups = statistics.local_hits + statistics.remote_hits + statistics.misses;
+    // The `--cache-url` is only checked when `.mak/build-cache` doesn't have the entry.
+    let remote_lookups = statistics.remote_hits + statistics.misses;
+    println!("Lookups:     {}", lookups);
+    println!(
+        "Local hits:  {} ({})",
+        statistics.local_hits,
+        rate(statistics.local_hits, lookups)
+    );
+    println!(
+        "Remote hits: {} ({} of the local misses)",
+        statistics.remote_hits,
+        rate(statistics.remote_hits, remote_lookups)
+    );
+    println!(
+        "Misses:      {} ({})",
+        statistics.misses,
+        rate(statistics.misses, lookups)
+    );
+    println!("Stored:      {}", statistics.stores);
+    let mut entries = LocalCache::new().entries();
+    println!(
+        "Entries:     {} ({})",
+        entries.len(),
+        human_size(entries.iter().map(|entry| entry.size).sum())
+    );
+    if entries.is_empty() {
+        return;
+    }
+    entries.sort_by(|a, b| b.size.cmp(&a.size));
+    println!("Largest entries:");
+    for entry in entries.iter().take(LARGEST_ENTRIES) {
+        println!(
+            "  {}  {:>10}  {}",
+            entry.key,
+            human_size(entry.size),
+            describe_targets(entry)
+        );
+    }
+}
+
+/// Lists the local entries, most recently used first.
+pub(crate) fn print_entries() {
+    for entry in LocalCache::new().entries() {
+        println!(
+            "{}  {:>10}  {:>8}  {}",
+            entry.key,
+            human_size(entry.size),
+            human_age(entry.last_used),
+            describe_targets(&entry)
+        );
+    }
+}
+
+/// Deletes the entries that are over the limits (see `LocalCache::collect_garbage`) and prints them.
+pub(crate) fn collect_garbage(max_size: u64, max_age: Duration) {
+    let cache = LocalCache::new();
+    let deleted = cache.collect_garbage(max_size, max_age);
+    for entry in &deleted {
+        println!(
+            "Deleted: {}  {:>10}  {}",
+            entry.key,
+            human_size(entry.size),
+            describe_targets(entry)
+        );
+    }
+    let kept = cache.entries();
+    println!(
+        "Deleted {} entr{} ({}), kept {} ({})",
+        deleted.len(),
+        if deleted.len() == 1 { "y" } else { "ies" },
+        human_size(deleted.iter().map(|entry| entry.size).sum()),
+        kept.len(),
+        human_size(kept.iter().map(|entry| entry.size).sum())
+    );
+}
+
+/// Prints the inputs of the entry with the key `key_or_target`, or of the newest entry for the target
+/// `key_or_target`. Returns whether there is one.
+pub(crate) fn inspect(key_or_target: &str) -> bool {
+    let Some(entry) = LocalCache::new().entries().into_iter().find(|entry| {
+        entry.key == key_or_target
+            || entry
+                .targets()
+                .iter()
+                .any(|target_name| target_name.0 == key_or_target)
+    }) else {
+        eprintln!(
+            "No entry in the build cache with the key or for the target `{}`",
+            key_or_target
+        );
+        return false;
+    };
+    println!("Key:    {}", entry.key);
+    println!("Size:   {}", human_size(entry.size));
+    println!("Used:   {}", human_age(entry.last_used));
+    let Some(inputs) = &entry.inputs else {
+        println!("(The inputs were not recorded for this entry.)");
+        return true;
+    };
+    for target_inputs in inputs {
+        println!("Target: {}", target_inputs.target);
+        println!("  Recipe:");
+        for script in &target_inputs.scripts {
+            for line in script.lines() {
+                println!("    {}", line);
+            }
+        }
+        if !target_inputs.prerequisites.is_empty() {
+            println!("  Prerequisites (content hashes):");
+            for (prerequisite, hash) in &target_inputs.prerequisites {
+                println!("    {}  {}", hash, prerequisite);
+            }
+        }
+        if !target_inputs.variables.is_empty() {
+            println!("  Variables (value hashes):");
+            for (variable, hash) in &target_inputs.variables {
+                println!("    {}  {}", hash, variable);
+            }
+        }
+    }
+    true
+}
@@ -0,0 +1,225 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+// The `cpu.max` period, in microseconds.
+const CPU_PERIOD: u64 = 100_000;
+
+/// Parses a number of bytes with an optional binary unit, e.g. `512M` or `1.5G`.
+pub(crate) fn parse_memory_size(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let (number, unit) = match text.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(index) => text.split_at(index),
+        None => (text, ""),
+    };
+    let multiplier: u64 = match unit.to_ascii_uppercase().trim_end_matches("IB") {
+        "" | "B" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => {
+            return Err(format!(
+                "unknown unit `{}` (expected `K`, `M`, `G`, or `T`)",
+                unit
+            ))
+        }
+    };
+    match number.parse::<f64>() {
+        Ok(number) if number > 0.0 => Ok((number * multiplier as f64) as u64),
+        _ => Err(format!("invalid size `{}`", text)),
+    }
+}
+
+/// Parses a number of CPUs, e.g. `2` or `0.5`.
+pub(crate) fn parse_cpu_limit(text: &str) -> Result<f64, String> {
+    match text.parse::<f64>() {
+        Ok(cpus) if cpus > 0.0 && cpus.is_finite() => Ok(cpus),
+        _ => Err(format!("invalid number of CPUs `{}`", text)),
+    }
+}
+
+/// The limits for each recipe, from `--job-memory-limit` and `--job-cpu-limit`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct JobLimits {
+    pub(crate) memory: Option<u64>,
+    pub(crate) cpus: Option<f64>,
+}
+
+/// The cgroup (v2) that `mak` runs in, which the cgroups of the recipes are created in.
+pub(crate) struct JobCgroups {
+    directory: PathBuf,
+    limits: JobLimits,
+    next_id: AtomicU64,
+}
+
+/// The cgroup of a single recipe, which is removed when dropped.
+pub(crate) struct JobCgroup {
+    directory: PathBuf,
+}
+
+fn write(path: &Path, contents: &str) -> Result<(), String> {
+    std::fs::write(path, contents)
+        .map_err(|error| format!("could not write `{}`: {}", path.display(), error))
+}
+
+// The mount point of the cgroup v2 hierarchy, from `/proc/self/mountinfo`.
+fn cgroup2_mount_point() -> Option<PathBuf> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo.lines().find_map(|line| {
+        let (mount, filesystem) = line.split_once(" - ")?;
+        match filesystem.split_whitespace().next() {
+            Some("cgroup2") => mount.split_whitespace().nth(4).map(PathBuf::from),
+            _ => None,
+        }
+    })
+}
+
+impl JobCgroups {
+    /// Enables the controllers for the limits in the cgroup that `mak` runs in. Since a cgroup that has processes can't
+    /// pass controllers on, `mak` moves itself into a `mak` child cgroup first if needed, so this only works if `mak`
+    /// is the only process in its cgroup (e.g. with `systemd-run --user --scope -p Delegate=yes mak …`).
+    pub(crate) fn set_up(limits: JobLimits) -> Result<Arc<Self>, String> {
+        if !cfg!(target_os = "linux") {
+            return Err("per-job limits are only supported on Linux".to_owned());
+        }
+        let mount_point = cgroup2_mount_point().ok_or("cgroup v2 is not mounted")?;
+        let own_cgroup = std::fs::read_to_string("/proc/self/cgroup")
+            .ok()
+            .and_then(|cgroups| {
+                cgroups
+                    .lines()
+                    .find_map(|line| line.strip_prefix("0::").map(str::to_owned))
+            })
+            .ok_or("could not find the cgroup (v2) of `mak`")?;
+        let directory = mount_point.join(own_cgroup.trim_start_matches('/'));
+
+        let available =
+            std::fs::read_to_string(directory.join("cgroup.controllers")).unwrap_or_default();
+        let mut controllers = vec![];
+        if limits.memory.is_some() {
+            controllers.push("memory");
+        }
+        if limits.cpus.is_some() {
+            controllers.push("cpu");
+        }
+        for controller in &controllers {
+            if !available.split_whitespace().any(|name| name == *controller) {
+                return Err(format!(
+                    "the `{}` controller is not available in `{}`",
+                    controller,
+                    directory.display()
+                ));
+            }
+        }
+        let subtree_control = directory.join("cgroup.subtree_control");
+        let enable = controllers
+            .iter()
+            .map(|controller| format!("+{}", controller))
+            .collect::<Vec<String>>()
+            .join(" ");
+        if write(&subtree_control, &enable).is_err() {
+            let own_directory = directory.join("mak");
+            let _ = std::fs::create_dir(&own_directory);
+            write(
+                &own_directory.join("cgroup.procs"),
+                &std::process::id().to_string(),
+            )?;
+            write(&subtree_control, &enable).map_err(|error| {
+                format!(
+                    "{} (is `mak` the only process in its cgroup? You can use `systemd-run --user --scope -p Delegate=yes mak …`)",
+                    error
+                )
+            })?;
+        }
+        Ok(Arc::new(Self {
+            directory,
+            limits,
+            next_id: AtomicU64::new(0),
+        }))
+    }
+
+    /// Creates a cgroup with the limits, and makes `command` start in it.
+    pub(crate) fn create(&self, command: &mut Command) -> Result<JobCgroup, String> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let directory = self
+            .directory
+            .join(format!("job-{}-{}", std::process::id(), id));
+        std::fs::create_dir(&directory)
+            .map_err(|error| format!("could not create `{}`: {}", directory.display(), error))?;
+        let job_cgroup = JobCgroup { directory };
+        if let Some(memory) = self.limits.memory {
+            write(
+                &job_cgroup.directory.join("memory.max"),
+                &memory.to_string(),
+            )?;
+            // Without swap, a recipe over the limit is killed instead of slowing down everything else.
+            let _ = write(&job_cgroup.directory.join("memory.swap.max"), "0");
+            // Stops the whole recipe, rather than one of its processes that other ones may wait for.
+            let _ = write(&job_cgroup.directory.join("memory.oom.group"), "1");
+        }
+        if let Some(cpus) = self.limits.cpus {
+            let quota = ((cpus * CPU_PERIOD as f64) as u64).max(1000);
+            write(
+                &job_cgroup.directory.join("cpu.max"),
+                &format!("{} {}", quota, CPU_PERIOD),
+            )?;
+        }
+        job_cgroup.add(command)?;
+        Ok(job_cgroup)
+    }
+}
+
+impl JobCgroup {
+    #[cfg(unix)]
+    fn add(&self, command: &mut Command) -> Result<(), String> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let procs =
+            std::ffi::CString::new(self.directory.join("cgroup.procs").as_os_str().as_bytes())
+                .map_err(|_| format!("invalid cgroup path `{}`", self.directory.display()))?;
+        // Only async-signal-safe calls are allowed between `fork` and `exec`. Writing `0` moves the writing process.
+        unsafe {
+            std::os::unix::process::CommandExt::pre_exec(command, move || {
+                let fd = libc::open(procs.as_ptr(), libc::O_WRONLY);
+                if fd < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+                libc::close(fd);
+                match written {
+                    1 => Ok(()),
+                    _ => Err(std::io::Error::last_os_error()),
+                }
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn add(&self, _command: &mut Command) -> Result<(), String> {
+        Err("per-job limits are only supported on Linux".to_owned())
+    }
+
+    /// How often a process of the recipe was killed for going over the memory limit.
+    pub(crate) fn oom_kills(&self) -> u64 {
+        std::fs::read_to_string(self.directory.join("memory.events"))
+            .unwrap_or_default()
+            .lines()
+            .find_map(|line| line.strip_prefix("oom_kill "))
+            .and_then(|count| count.trim().parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for JobCgroup {
+    fn drop(&mut self) {
+        // Fails if the recipe left processes behind, which keeps them limited.
+        let _ = std::fs::remove_dir(&self.directory);
+    }
+}
@@ -0,0 +1,104 @@
+// For `--clean`: deletes the files that the rules make, so that a makefile doesn't need a `clean` rule that lists them
+// again (and falls behind when rules are added).
+
+use std::path::Path;
+
+use indexmap::IndexSet;
+
+use crate::parse::{TargetGraph, TargetName};
+
+// Whether `path` is one of the makefiles, which can have a rule to remake them.
+fn is_makefile(target_graph: &TargetGraph, path: &Path) -> bool {
+    let Ok(path) = path.canonicalize() else {
+        return false;
+    };
+    target_graph.makefiles.iter().any(|makefile| {
+        Path::new(makefile)
+            .canonicalize()
+            .is_ok_and(|makefile| makefile == path)
+    })
+}
+
+/// The existing files that the recipes of `target_names` and their prerequisites make (or of all targets, if
+/// `target_names` is empty): their targets, except phony and precious ones, and the makefiles.
+pub(crate) fn made_files(
+    target_graph: &TargetGraph,
+    target_names: &[TargetName],
+) -> Vec<TargetName> {
+    let targets: IndexSet<TargetName> = match target_names.is_empty() {
+        true => target_graph.edges.keys().cloned().collect(),
+        false => {
+            let mut needed: IndexSet<TargetName> = target_names.iter().cloned().collect();
+            let mut index = 0;
+            while let Some(target_name) = needed.get_index(index).cloned() {
+                needed.extend(target_graph.all_prerequisites(&target_name));
+                index += 1;
+            }
+            needed
+        }
+    };
+    targets
+        .into_iter()
+        .filter(|target_name| {
+            target_graph.has_recipe(target_name)
+                && !target_graph.is_phony(target_name)
+                && !target_graph.is_precious(target_name)
+                && std::fs::symlink_metadata(&target_name.0).is_ok()
+                && !is_makefile(target_graph, Path::new(&target_name.0))
+        })
+        .collect()
+}
+
+/// Deletes the `made_files` (or only lists them, for a dry run). Directories are deleted after the files, and only if
+/// they are empty, unless they are directory targets (`# mak:directory=true`). Returns whether nothing failed.
+pub(crate) fn clean(
+    target_graph: &TargetGraph,
+    target_names: &[TargetName],
+    dry_run: bool,
+) -> bool {
+    let (directories, files): (Vec<TargetName>, Vec<TargetName>) =
+        made_files(target_graph, target_names)
+            .into_iter()
+            .partition(|target_name| {
+                !target_graph.directory_targets.contains(target_name)
+                    && std::fs::symlink_metadata(&target_name.0)
+                        .is_ok_and(|metadata| metadata.is_dir())
+            });
+    if files.is_empty() && directories.is_empty() {
+        println!("Nothing to clean");
+    }
+    let mut success = true;
+    for file in &files {
+        if dry_run {
+            println!("Would delete: {}", file);
+            continue;
+        }
+        println!("Deleting: {}", file);
+        let result = match target_graph.directory_targets.contains(file) {
+            true => std::fs::remove_dir_all(&file.0),
+            false => std::fs::remove_file(&file.0),
+        };
+        if let Err(error) = result {
+            eprintln!("Could not delete `{}`: {}", file, error);
+            success = false;
+        }
+    }
+    for directory in &directories {
+        if dry_run {
+            println!("Would delete (if empty): {}", directory);
+            continue;
+        }
+        let is_empty =
+            std::fs::read_dir(&directory.0).is_ok_and(|mut entries| entries.next().is_none());
+        if !is_empty {
+            println!("Not deleting (not empty): {}", directory);
+            continue;
+        }
+        println!("Deleting: {}", directory);
+        if let Err(error) = std::fs::remove_dir(&directory.0) {
+            eprintln!("Could not delete `{}`: {}", directory, error);
+            success = false;
+        }
+    }
+    success
+}
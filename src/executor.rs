@@ -0,0 +1,384 @@
+use std::{
+    collections::{HashMap, HashSet},
+    process::Command,
+    time::SystemTime,
+};
+
+use crate::{
+    cache::{file_hash, variable_hashes, TargetStates},
+    parse::{TargetGraph, TargetName},
+};
+
+// Runs the shell invocations of a recipe one after the other. Each of them is run using the makefile's `SHELL`.
+const RUNNER_SHELL: &str = "/bin/sh";
+const DEFAULT_SHELL: &str = "/bin/sh";
+const DEFAULT_SHELL_FLAGS: &str = "-c";
+
+/// Quotes `word` for a POSIX shell, unless it only contains characters that don't need quoting.
+pub(crate) fn shell_quote(word: &str) -> String {
+    let is_plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-./=:@%+,".contains(c));
+    match is_plain {
+        true => word.to_owned(),
+        false => format!("'{}'", word.replace('\'', "'\\''")),
+    }
+}
+
+// The `SHELL` and `.SHELLFLAGS` (which can be set for specific targets), quoted to run a script by appending it.
+fn shell_command_prefix(target_graph: &TargetGraph, target_name: &TargetName) -> String {
+    let variables = target_graph.variables_for_target(target_name);
+    let shell = variables.expand("$(SHELL)");
+    let shell_flags = variables.expand("$(.SHELLFLAGS)");
+    let shell = match shell.trim() {
+        "" => DEFAULT_SHELL,
+        shell => shell,
+    };
+    let shell_flags = match shell_flags.trim() {
+        "" => DEFAULT_SHELL_FLAGS,
+        shell_flags => shell_flags,
+    };
+    shell
+        .split_whitespace()
+        .chain(shell_flags.split_whitespace())
+        .map(shell_quote)
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Runs the program and arguments of `command` in a container of `image` instead (using `docker`, `podman`, or a
+/// compatible `runtime`), with the current directory mounted at the same path. The recipe runs as the current user so
+/// that the files it writes are owned by them, and with the environment of the image.
+pub(crate) fn container_command(command: &Command, runtime: &str, image: &str) -> Command {
+    let current_dir = std::env::current_dir().expect("Could not get the current directory.");
+    let current_dir = current_dir.to_string_lossy();
+    let mut container_command = Command::new(runtime);
+    container_command
+        .args(["run", "--rm", "--init", "--interactive=false"])
+        .arg(format!("--volume={}:{}", current_dir, current_dir))
+        .arg(format!("--workdir={}", current_dir));
+    #[cfg(unix)]
+    match runtime.contains("podman") {
+        // Rootless `podman` maps the current user to root in the container, unless asked not to.
+        true => {
+            container_command.arg("--userns=keep-id");
+        }
+        false => {
+            container_command.arg(format!("--user={}:{}", unsafe { libc::getuid() }, unsafe {
+                libc::getgid()
+            }));
+        }
+    }
+    container_command
+        .arg(image)
+        .arg(command.get_program())
+        .args(command.get_args());
+    container_command
+}
+
+/// Overrides for whether targets are up to date, from `--always-make`, `--what-if`, `--assume-old`, and `--freshness`.
+#[derive(Debug, Default)]
+pub(crate) struct Freshness {
+    pub(crate) always_make: bool,
+    // Files that count as newer than all of their dependents.
+    pub(crate) assume_new: Vec<TargetName>,
+    // Files that are never rebuilt, and that don't make their dependents rebuild.
+    pub(crate) assume_old: Vec<TargetName>,
+    // With `--freshness hash`: a prerequisite that is newer than the target only counts as changed if its contents are
+    // different from the last time the target was built.
+    pub(crate) hash_prerequisites: bool,
+    // From `.mak/state`.
+    pub(crate) recorded_states: TargetStates,
+}
+
+impl Freshness {
+    fn recorded_hash(&self, target_name: &TargetName, path: &str) -> Option<&String> {
+        if !self.hash_prerequisites {
+            return None;
+        }
+        self.recorded_states
+            .get(target_name)?
+            .prerequisites
+            .get(path)
+    }
+
+    // Whether `path` still has the contents it had the last time `target_name` was built.
+    fn is_unchanged(
+        &self,
+        target_graph: &TargetGraph,
+        target_name: &TargetName,
+        path: &str,
+    ) -> bool {
+        self.recorded_hash(target_name, path)
+            .is_some_and(|recorded_hash| {
+                file_hash(path, target_graph.symlinks).as_ref() == Some(recorded_hash)
+            })
+    }
+}
+
+/// Whether the recipe of `target_name` needs to run, like `make` decides it: for phony targets, for targets whose file
+/// is missing, and for targets with a prerequisite that is phony, missing, or newer than the target.
+/// This is checked once the prerequisites are up to date.
+pub(crate) fn needs_rebuild(
+    target_graph: &TargetGraph,
+    target_name: &TargetName,
+    freshness: &Freshness,
+) -> bool {
+    changed_prerequisites(target_graph, target_name, freshness).is_some()
+}
+
+/// The variables from the `# mak:env=…` comment of `target_name` whose values changed since it was last built. Variables
+/// whose values were not stored yet don't count.
+pub(crate) fn changed_variables(
+    target_graph: &TargetGraph,
+    target_name: &TargetName,
+    freshness: &Freshness,
+) -> Vec<String> {
+    let Some(recorded_state) = freshness.recorded_states.get(target_name) else {
+        return vec![];
+    };
+    let variable_hashes = variable_hashes(target_graph, target_name);
+    variable_hashes
+        .iter()
+        .filter(|(name, hash)| {
+            recorded_state
+                .variables
+                .get(*name)
+                .is_some_and(|recorded_hash| recorded_hash != *hash)
+        })
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Like `needs_rebuild`, but returns the prerequisites that make the recipe run (which can be none, e.g. if the
+/// target's file is missing), or `None` if the target is up to date.
+pub(crate) fn changed_prerequisites(
+    target_graph: &TargetGraph,
+    target_name: &TargetName,
+    freshness: &Freshness,
+) -> Option<Vec<TargetName>> {
+    if freshness.assume_old.contains(target_name) {
+        return None;
+    }
+    let target_modified = target_graph
+        .resolve_path(&target_name.0)
+        .and_then(|path| target_graph.modified_time(target_name, &path));
+    let changed: Vec<TargetName> = target_graph
+        .edges
+        .get(target_name)
+        .into_iter()
+        .flatten()
+        .filter(|prerequisite| !freshness.assume_old.contains(prerequisite))
+        .filter(|prerequisite| {
+            freshness.assume_new.contains(prerequisite)
+                || target_graph.is_phony(prerequisite)
+                || target_graph
+                    .resolve_path(&prerequisite.0)
+                    .and_then(|path| Some((target_graph.modified_time(prerequisite, &path)?, path)))
+                    .is_none_or(|(prerequisite_modified, path)| {
+                        target_modified
+                            .is_some_and(|target_modified| prerequisite_modified > target_modified)
+                            && !freshness.is_unchanged(target_graph, target_name, &path)
+                    })
+        })
+        .cloned()
+        .collect();
+    let rebuild = target_graph.is_phony(target_name)
+        || freshness.always_make
+        || target_modified.is_none()
+        || !changed.is_empty()
+        || !changed_variables(target_graph, target_name, freshness).is_empty();
+    rebuild.then_some(changed)
+}
+
+/// For `--why`: whether the recipe of `target_name` needs to run (see `needs_rebuild`), with the reasons for that (or
+/// against it, e.g. a newer prerequisite whose contents didn't change).
+pub(crate) fn explain_rebuild(
+    target_graph: &TargetGraph,
+    target_name: &TargetName,
+    freshness: &Freshness,
+) -> (bool, Vec<String>) {
+    if freshness.assume_old.contains(target_name) {
+        return (
+            false,
+            vec!["it is assumed to be old (`--assume-old`)".to_owned()],
+        );
+    }
+    let mut reasons = vec![];
+    if target_graph.is_phony(target_name) {
+        reasons.push("it is phony".to_owned());
+    }
+    if freshness.always_make {
+        reasons.push("everything is rebuilt (`--always-make`)".to_owned());
+    }
+    let target_modified = target_graph
+        .resolve_path(&target_name.0)
+        .and_then(|path| target_graph.modified_time(target_name, &path));
+    if target_modified.is_none() && !target_graph.is_phony(target_name) {
+        reasons.push("its file doesn't exist".to_owned());
+    }
+    for name in changed_variables(target_graph, target_name, freshness) {
+        reasons.push(format!("`{}` changed since the last build", name));
+    }
+    for prerequisite in target_graph.edges.get(target_name).into_iter().flatten() {
+        if freshness.assume_old.contains(prerequisite) {
+            reasons.push(format!("`{}` is ignored (`--assume-old`)", prerequisite));
+            continue;
+        }
+        if freshness.assume_new.contains(prerequisite) {
+            reasons.push(format!(
+                "`{}` is assumed to be new (`--what-if`)",
+                prerequisite
+            ));
+            continue;
+        }
+        if target_graph.is_phony(prerequisite) {
+            reasons.push(format!("`{}` is phony", prerequisite));
+            continue;
+        }
+        let Some((prerequisite_modified, path)) = target_graph
+            .resolve_path(&prerequisite.0)
+            .and_then(|path| Some((target_graph.modified_time(prerequisite, &path)?, path)))
+        else {
+            reasons.push(format!("`{}` doesn't exist", prerequisite));
+            continue;
+        };
+        if !target_modified.is_some_and(|target_modified| prerequisite_modified > target_modified) {
+            continue;
+        }
+        reasons.push(
+            match (
+                freshness.recorded_hash(target_name, &path),
+                freshness.is_unchanged(target_graph, target_name, &path),
+            ) {
+                (_, true) => format!(
+                    "`{}` is newer, but its contents didn't change since the last build",
+                    prerequisite
+                ),
+                (Some(_), false) => format!(
+                    "the contents of `{}` changed since the last build",
+                    prerequisite
+                ),
+                (None, false) => format!("`{}` is newer than `{}`", prerequisite, target_name),
+            },
+        );
+    }
+    (needs_rebuild(target_graph, target_name, freshness), reasons)
+}
+
+/// Whether building `target_name` would run any recipe, like `make -q` (`--question`): its own recipe needs to run, or
+/// one of its prerequisites (or theirs) is out of date. Returns the target if there is no rule to make a missing one.
+pub(crate) fn is_out_of_date(
+    target_graph: &TargetGraph,
+    target_name: &TargetName,
+    freshness: &Freshness,
+    checked: &mut HashMap<TargetName, bool>,
+) -> Result<bool, TargetName> {
+    if let Some(out_of_date) = checked.get(target_name) {
+        return Ok(*out_of_date);
+    }
+    if freshness.assume_old.contains(target_name) {
+        return Ok(false);
+    }
+    if !target_graph.has_rule(target_name) && target_graph.resolve_path(&target_name.0).is_none() {
+        return Err(target_name.clone());
+    }
+    // Avoids infinite recursion for circular dependencies (which `make` drops).
+    checked.insert(target_name.clone(), false);
+    let mut out_of_date = false;
+    for prerequisite in target_graph.all_prerequisites(target_name) {
+        out_of_date |= is_out_of_date(target_graph, &prerequisite, freshness, checked)?;
+    }
+    out_of_date |=
+        target_graph.has_recipe(target_name) && needs_rebuild(target_graph, target_name, freshness);
+    checked.insert(target_name.clone(), out_of_date);
+    Ok(out_of_date)
+}
+
+/// Marks the out-of-date targets (from the prerequisites up to `target_name`) as up to date by updating their
+/// modification times (and creating missing ones) instead of running their recipes, like `make -t` (`--touch`).
+/// Returns the target if there is no rule to make a missing one.
+pub(crate) fn touch_out_of_date(
+    target_graph: &TargetGraph,
+    target_name: &TargetName,
+    freshness: &Freshness,
+    visited: &mut HashSet<TargetName>,
+) -> Result<(), TargetName> {
+    if !visited.insert(target_name.clone()) || freshness.assume_old.contains(target_name) {
+        return Ok(());
+    }
+    if !target_graph.has_rule(target_name) && target_graph.resolve_path(&target_name.0).is_none() {
+        return Err(target_name.clone());
+    }
+    for prerequisite in target_graph.all_prerequisites(target_name) {
+        touch_out_of_date(target_graph, &prerequisite, freshness, visited)?;
+    }
+    if target_graph.is_phony(target_name)
+        || !target_graph.has_recipe(target_name)
+        || !needs_rebuild(target_graph, target_name, freshness)
+    {
+        return Ok(());
+    }
+    let path = target_graph
+        .resolve_path(&target_name.0)
+        .unwrap_or_else(|| target_name.0.clone());
+    println!("touch {}", shell_quote(&path));
+    let touched = std::fs::File::options()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(error) = touched {
+        eprintln!("mak: *** Could not touch `{}`: {}", path, error);
+    }
+    Ok(())
+}
+
+/// A command that runs the recipe of `target_name` without `make`: each shell invocation is printed (unless it has an
+/// `@` prefix) and run in its own `$(SHELL) $(.SHELLFLAGS)`, and the first one that fails (without a `-` prefix) stops the recipe.
+/// Returns `None` if there is nothing to do. Like for `make`, this should only be called once the prerequisites are up
+/// to date, since the recipe's variables (e.g. `$?`) depend on them.
+pub(crate) fn recipe_command(
+    target_graph: &TargetGraph,
+    target_name: &TargetName,
+    freshness: &Freshness,
+) -> Option<Command> {
+    let mut lines = vec![];
+    if !target_graph.has_rule(target_name) && target_graph.resolve_path(&target_name.0).is_none() {
+        lines.push(format!(
+            "echo {} >&2; exit 2",
+            shell_quote(&format!(
+                "mak: *** No rule to make target '{}'.",
+                target_name
+            ))
+        ));
+    } else if !needs_rebuild(target_graph, target_name, freshness) {
+        return None;
+    }
+    let error_message = shell_quote(&format!("mak: *** [{}] Error", target_name));
+    let shell = shell_command_prefix(target_graph, target_name);
+    for invocation in target_graph.shell_invocations(target_name) {
+        let script = shell_quote(&invocation.script);
+        if !invocation.silent {
+            lines.push(format!("printf '%s\\n' {}", script));
+        }
+        lines.push(match invocation.ignore_errors {
+            true => format!("{} {} || true", shell, script),
+            false => format!(
+                "{} {} || {{ status=$?; echo {} $status >&2; exit $status; }}",
+                shell, script, error_message
+            ),
+        });
+    }
+    if lines.is_empty() {
+        return None;
+    }
+    let mut command = Command::new(RUNNER_SHELL);
+    command
+        .arg("-c")
+        .arg(lines.join("\n"))
+        .env_clear()
+        .envs(target_graph.recipe_environment(target_name));
+    Some(command)
+}
@@ -0,0 +1,311 @@
+// For `--trace-dependencies`: recipes run under `strace`, which logs the files that they open. A file in the project
+// that a recipe read but that is not among its prerequisites (directly or through other prerequisites) is an undeclared
+// dependency: the recipe doesn't run again when the file changes, and if another rule makes the file, a parallel build
+// can run the recipe before the file is ready.
+
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use indexmap::IndexSet;
+
+use crate::{
+    cache::hash,
+    parse::{TargetGraph, TargetName},
+};
+
+// The calls that open files, change the current directory, or start processes (which inherit it). The ones with a `?`
+// don't exist on every architecture.
+const TRACED_CALLS: &str =
+    "trace=?open,?openat,?openat2,?creat,execve,chdir,fchdir,?fork,?vfork,clone,?clone3";
+
+pub(crate) fn check_support() -> Result<(), String> {
+    if !cfg!(target_os = "linux") {
+        return Err("tracing file accesses is only supported on Linux".to_owned());
+    }
+    match Command::new("strace")
+        .arg("-V")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+    {
+        Ok(status) if status.success() => Ok(()),
+        _ => Err("`strace` is not installed".to_owned()),
+    }
+}
+
+// Resolves `.` and `..` without looking at the file system, since the file may not exist anymore.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+// `path` relative to `directory`, if it is in there.
+fn relative_path(path: &Path, directory: &Path) -> Option<String> {
+    let path = normalize(&directory.join(path));
+    let relative = path.strip_prefix(directory).ok()?;
+    Some(relative.to_string_lossy().into_owned())
+}
+
+// Undoes the escaping of a string argument from `strace`.
+fn unquote(argument: &str) -> Option<String> {
+    let argument = argument.strip_suffix("...").unwrap_or(argument);
+    let mut bytes = argument.strip_prefix('"')?.strip_suffix('"')?.bytes();
+    let mut unquoted = vec![];
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            unquoted.push(byte);
+            continue;
+        }
+        match bytes.next()? {
+            b'n' => unquoted.push(b'\n'),
+            b't' => unquoted.push(b'\t'),
+            digit @ b'0'..=b'7' => {
+                let mut value = u32::from(digit - b'0');
+                for _ in 0..2 {
+                    match bytes.clone().next() {
+                        Some(digit @ b'0'..=b'7') => {
+                            bytes.next();
+                            value = value * 8 + u32::from(digit - b'0');
+                        }
+                        _ => break,
+                    }
+                }
+                unquoted.push(value as u8);
+            }
+            byte => unquoted.push(byte),
+        }
+    }
+    Some(String::from_utf8_lossy(&unquoted).into_owned())
+}
+
+// The path that `-y` prints after a file descriptor, e.g. `3</tmp/file>`.
+fn descriptor_path(text: &str) -> Option<PathBuf> {
+    let (_, path) = text.split_once('<')?;
+    Some(PathBuf::from(path.strip_suffix('>')?))
+}
+
+// Splits a call like `openat(AT_FDCWD, "a, b", O_RDONLY) = 3</a, b>` into its name, arguments, and result.
+fn parse_call(call: &str) -> Option<(&str, Vec<&str>, &str)> {
+    let (name, rest) = call.split_once('(')?;
+    // `strace` aligns the results with spaces.
+    let (arguments, result) = rest.rsplit_once(" = ")?;
+    let arguments = arguments.trim_end().strip_suffix(')')?;
+    let mut split_arguments = vec![];
+    let (mut depth, mut in_string, mut escaped, mut start) = (0, false, false, 0);
+    for (index, c) in arguments.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '{' | '[' | '(' if !in_string => depth += 1,
+            '}' | ']' | ')' if !in_string => depth -= 1,
+            ',' if !in_string && depth == 0 => {
+                split_arguments.push(arguments[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    split_arguments.push(arguments[start..].trim());
+    Some((name, split_arguments, result.trim()))
+}
+
+#[derive(Default)]
+struct FileAccesses {
+    read: IndexSet<PathBuf>,
+    written: IndexSet<PathBuf>,
+}
+
+// The files that the processes in the output of `strace -f -y` opened, as absolute paths. Processes start in
+// `directory`.
+fn parse_trace(trace: &str, directory: &Path) -> FileAccesses {
+    let mut accesses = FileAccesses::default();
+    let mut current_directories: HashMap<String, PathBuf> = HashMap::new();
+    let mut unfinished_calls: HashMap<String, String> = HashMap::new();
+    for line in trace.lines() {
+        let Some((pid, call)) = line.split_once(' ') else {
+            continue;
+        };
+        let call = call.trim_start();
+        // A call that was interrupted by a call of another process.
+        if let Some((start, _)) = call.split_once(" <unfinished ...>") {
+            unfinished_calls.insert(pid.to_owned(), start.to_owned());
+            continue;
+        }
+        let call = match call.strip_prefix("<... ") {
+            Some(rest) => {
+                let (Some(start), Some((_, rest))) =
+                    (unfinished_calls.remove(pid), rest.split_once(" resumed>"))
+                else {
+                    continue;
+                };
+                format!("{}{}", start, rest)
+            }
+            None => call.to_owned(),
+        };
+        let Some((name, arguments, result)) = parse_call(&call) else {
+            continue;
+        };
+        if result.starts_with('-') || result.starts_with('?') {
+            continue;
+        }
+        let current_directory = current_directories
+            .get(pid)
+            .cloned()
+            .unwrap_or_else(|| directory.to_owned());
+        let (base, path, flags) = match (name, arguments.as_slice()) {
+            ("open", [path, flags, ..]) => (None, path, *flags),
+            ("openat" | "openat2", [base, path, flags, ..]) => (Some(*base), path, *flags),
+            ("creat", [path, ..]) => (None, path, "O_WRONLY|O_CREAT"),
+            ("execve", [path, ..]) => (None, path, "O_RDONLY"),
+            ("chdir", [path, ..]) => {
+                if let Some(path) = unquote(path) {
+                    current_directories
+                        .insert(pid.to_owned(), normalize(&current_directory.join(path)));
+                }
+                continue;
+            }
+            ("fchdir", [descriptor, ..]) => {
+                if let Some(path) = descriptor_path(descriptor) {
+                    current_directories.insert(pid.to_owned(), path);
+                }
+                continue;
+            }
+            ("fork" | "vfork" | "clone" | "clone3", _) => {
+                let child_pid: String = result.chars().take_while(char::is_ascii_digit).collect();
+                current_directories.insert(child_pid, current_directory);
+                continue;
+            }
+            _ => continue,
+        };
+        if flags.contains("O_DIRECTORY") || flags.contains("O_PATH") {
+            continue;
+        }
+        // `-y` prints the path of the file that was opened, otherwise it is relative to the base directory.
+        let path = descriptor_path(result).or_else(|| {
+            let path = PathBuf::from(unquote(path)?);
+            let base_directory = base.and_then(descriptor_path).unwrap_or(current_directory);
+            Some(normalize(&base_directory.join(path)))
+        });
+        let Some(path) = path else {
+            continue;
+        };
+        match ["O_WRONLY", "O_RDWR", "O_CREAT"]
+            .iter()
+            .any(|flag| flags.contains(flag))
+        {
+            true => accesses.written.insert(path),
+            false => accesses.read.insert(path),
+        };
+    }
+    accesses
+}
+
+/// The file that `strace` logs the file accesses of a recipe to. It is removed when this is dropped.
+pub(crate) struct Trace {
+    path: PathBuf,
+}
+
+impl Trace {
+    pub(crate) fn new(label: &str) -> Self {
+        Self {
+            path: std::env::temp_dir().join(format!(
+                "mak-trace-{}-{:016x}",
+                std::process::id(),
+                hash(label)
+            )),
+        }
+    }
+
+    /// Runs `command` under `strace`.
+    pub(crate) fn wrap(&self, command: &Command) -> Command {
+        let mut traced_command = Command::new("strace");
+        traced_command
+            .args(["-f", "-qq", "-y", "-s", "4096", "-e", TRACED_CALLS, "-o"])
+            .arg(&self.path)
+            .arg("--")
+            .arg(command.get_program())
+            .args(command.get_args());
+        for (key, value) in command.get_envs() {
+            match value {
+                Some(value) => traced_command.env(key, value),
+                None => traced_command.env_remove(key),
+            };
+        }
+        if let Some(current_dir) = command.get_current_dir() {
+            traced_command.current_dir(current_dir);
+        }
+        traced_command
+    }
+
+    /// The files in the current directory that the recipe read (but didn't write itself), relative to it.
+    pub(crate) fn read_files(&self) -> Vec<String> {
+        let (Ok(trace), Ok(directory)) = (std::fs::read(&self.path), std::env::current_dir())
+        else {
+            return vec![];
+        };
+        let accesses = parse_trace(&String::from_utf8_lossy(&trace), &directory);
+        accesses
+            .read
+            .iter()
+            .filter(|path| !accesses.written.contains(*path) && path.is_file())
+            .filter_map(|path| relative_path(path, &directory))
+            .filter(|path| !path.starts_with(".mak/"))
+            .collect()
+    }
+}
+
+impl Drop for Trace {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// The files of `read_files` that are not the makefiles, the targets of `group`, or (transitively) their
+/// prerequisites, with whether a rule makes them.
+pub(crate) fn undeclared_dependencies(
+    target_graph: &TargetGraph,
+    group: &[TargetName],
+    read_files: &[String],
+) -> Vec<(String, bool)> {
+    let Ok(directory) = std::env::current_dir() else {
+        return vec![];
+    };
+    let mut needed: IndexSet<TargetName> = group.iter().cloned().collect();
+    let mut index = 0;
+    while let Some(target_name) = needed.get_index(index).cloned() {
+        needed.extend(target_graph.all_prerequisites(&target_name));
+        index += 1;
+    }
+    let declared: IndexSet<String> = target_graph
+        .makefiles
+        .iter()
+        .cloned()
+        .chain(needed.iter().map(|target_name| {
+            target_graph
+                .resolve_path(&target_name.0)
+                .unwrap_or_else(|| target_name.0.clone())
+        }))
+        .filter_map(|path| relative_path(Path::new(&path), &directory))
+        .collect();
+    read_files
+        .iter()
+        .filter(|path| !declared.contains(*path))
+        .map(|path| {
+            let made_by_rule = target_graph.has_rule(&TargetName(path.clone()));
+            (path.clone(), made_by_rule)
+        })
+        .collect()
+}
@@ -0,0 +1,142 @@
+use crate::makefile_text::{is_directive, is_rule, line_groups, parse_assignment};
+
+// Splits a rule line into its targets, separator (`:`, `::`, or `&:`), and everything after the separator.
+fn split_rule(line: &str) -> Option<(&str, &str, &str)> {
+    // Skip colons in references like `$(SOURCES:.c=.o)`.
+    let mut depth = 0;
+    let index = line.char_indices().find_map(|(index, c)| {
+        match c {
+            '(' | '{' => depth += 1,
+            ')' | '}' => depth -= 1,
+            ':' if depth == 0 => return Some(index),
+            _ => {}
+        }
+        None
+    })?;
+    let (targets, rest) = line.split_at(index);
+    let (targets, separator, rest) = if let Some(targets) = targets.strip_suffix('&') {
+        (targets, "&:", &rest[1..])
+    } else if let Some(rest) = rest.strip_prefix("::") {
+        (targets, "::", rest)
+    } else {
+        (targets, ":", &rest[1..])
+    };
+    Some((targets.trim_end(), separator, rest.trim_start()))
+}
+
+// Puts the continuation lines of a rule's prerequisites below the first prerequisite.
+fn continue_aligned(output: &mut Vec<String>, first_line: String, continuation_lines: &[&str]) {
+    let column = match first_line.split_once(": ") {
+        Some((targets, _)) => targets.chars().count() + 2,
+        None => 0,
+    };
+    output.push(first_line);
+    for line in continuation_lines {
+        output.push(format!("{}{}", " ".repeat(column), line.trim_start()));
+    }
+}
+
+fn format_rule(group: &[&str], output: &mut Vec<String>) {
+    let Some((targets, separator, rest)) = split_rule(group[0]) else {
+        output.extend(group.iter().map(|line| line.to_string()));
+        return;
+    };
+    let joined = |words: Vec<&str>| -> String {
+        match words.iter().all(|word| word.is_empty()) {
+            true => format!("{}{}", targets, separator),
+            false => format!("{}{} {}", targets, separator, words.join(" ")),
+        }
+    };
+    if targets == ".PHONY" && separator == ":" && !group.iter().any(|line| line.contains('#')) {
+        let mut names: Vec<&str> = group
+            .iter()
+            .enumerate()
+            .flat_map(|(index, line)| match index {
+                0 => rest.trim_end_matches('\\').split_whitespace(),
+                _ => line.trim_end_matches('\\').split_whitespace(),
+            })
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        if group.len() == 1 || names.len() <= 1 {
+            output.push(joined(names));
+            return;
+        }
+        let last = names.len() - 1;
+        let lines: Vec<String> = names
+            .iter()
+            .enumerate()
+            .map(|(index, name)| match index {
+                _ if index == last => name.to_string(),
+                _ => format!("{} \\", name),
+            })
+            .collect();
+        let (first_line, continuation_lines) = lines.split_first().expect("names is not empty");
+        let continuation_lines: Vec<&str> = continuation_lines.iter().map(String::as_str).collect();
+        continue_aligned(output, joined(vec![first_line]), &continuation_lines);
+        return;
+    }
+    let first_line = joined(vec![rest]);
+    // An inline recipe (after `;`) continues as a recipe, so its indentation is kept.
+    if rest.contains(';') || rest == "\\" {
+        output.push(first_line);
+        output.extend(group[1..].iter().map(|line| line.to_string()));
+    } else {
+        continue_aligned(output, first_line, &group[1..]);
+    }
+}
+
+/// Formats makefile text: recipe lines are indented with a tab, rule separators are followed by a single space,
+/// continued prerequisite lists are aligned with the first prerequisite, and `.PHONY` prerequisites are sorted.
+/// Anything else (including recipes themselves) is kept as written.
+pub(crate) fn format_makefile(text: &str) -> String {
+    // With a custom `.RECIPEPREFIX`, recipe lines can't be told apart reliably.
+    let fix_indentation = !text.contains(".RECIPEPREFIX");
+    let mut output: Vec<String> = vec![];
+    let mut in_rule = false;
+    let mut in_define = false;
+    for group in line_groups(text) {
+        let first = group[0];
+        let trimmed = first.trim();
+        let unchanged = |output: &mut Vec<String>| {
+            output.extend(group.iter().map(|line| line.to_string()));
+        };
+        if in_define {
+            in_define = trimmed != "endef";
+            unchanged(&mut output);
+        } else if trimmed.is_empty() || trimmed.starts_with('#') || first.starts_with('\t') {
+            unchanged(&mut output);
+        } else if first.starts_with(' ') {
+            if fix_indentation
+                && in_rule
+                && !is_directive(trimmed)
+                && parse_assignment(trimmed).is_none()
+            {
+                output.push(format!("\t{}", first.trim_start()));
+                output.extend(group[1..].iter().map(|line| line.to_string()));
+            } else {
+                unchanged(&mut output);
+            }
+        } else if is_directive(trimmed) {
+            in_define = parse_assignment(trimmed).is_some_and(|assignment| assignment.is_define);
+            if in_define {
+                in_rule = false;
+            }
+            // Conditionals can appear inside a recipe.
+            unchanged(&mut output);
+        } else {
+            in_rule = is_rule(&group.join(" "));
+            // Targets with escaped colons are left alone.
+            if in_rule && !first.contains("\\:") {
+                format_rule(&group, &mut output);
+            } else {
+                unchanged(&mut output);
+            }
+        }
+    }
+    let mut formatted = output.join("\n");
+    if text.ends_with('\n') {
+        formatted.push('\n');
+    }
+    formatted
+}
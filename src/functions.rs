@@ -0,0 +1,417 @@
+use std::{
+    path::{Component, PathBuf},
+    process::{exit, Command, Stdio},
+};
+
+use crate::{
+    variables::{AutomaticVariables, Variables},
+    wildcard::expand_wildcard,
+};
+
+const FUNCTION_NAMES: &[&str] = &[
+    "abspath",
+    "addprefix",
+    "addsuffix",
+    "and",
+    "basename",
+    "call",
+    "dir",
+    "error",
+    "eval",
+    "filter",
+    "filter-out",
+    "findstring",
+    "firstword",
+    "flavor",
+    "foreach",
+    "if",
+    "info",
+    "join",
+    "lastword",
+    "notdir",
+    "or",
+    "origin",
+    "patsubst",
+    "realpath",
+    "shell",
+    "sort",
+    "strip",
+    "subst",
+    "suffix",
+    "value",
+    "warning",
+    "wildcard",
+    "word",
+    "wordlist",
+    "words",
+];
+
+/// Splits `patsubst %.c,%.o,$(SOURCES)` into the function name and its (unexpanded) arguments.
+pub(crate) fn split_function_call(body: &str) -> Option<(&str, &str)> {
+    let name_end = body.find([' ', '\t'])?;
+    let name = &body[..name_end];
+    FUNCTION_NAMES
+        .contains(&name)
+        .then(|| (name, body[name_end..].trim_start_matches([' ', '\t'])))
+}
+
+/// The name of a function that `mak` doesn't support (like `file` or `let`) if `body` looks like a call to one.
+pub(crate) fn unsupported_function(body: &str) -> Option<&str> {
+    let name = &body[..body.find([' ', '\t'])?];
+    (!name.is_empty()
+        && !FUNCTION_NAMES.contains(&name)
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+    .then_some(name)
+}
+
+// Splits at top-level commas, so that commas inside nested references stay part of their argument.
+// The last of `max_arguments` arguments keeps any remaining commas, like in `make`.
+fn split_arguments(arguments: &str, max_arguments: usize) -> Vec<&str> {
+    let mut split = vec![];
+    let mut nesting = 0;
+    let mut start = 0;
+    for (i, c) in arguments.char_indices() {
+        match c {
+            '(' | '{' => nesting += 1,
+            ')' | '}' => nesting -= 1,
+            ',' if nesting == 0 && split.len() + 1 < max_arguments => {
+                split.push(&arguments[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    split.push(&arguments[start..]);
+    split
+}
+
+pub(crate) fn match_pattern<'a>(pattern: &str, word: &'a str) -> Option<&'a str> {
+    match pattern.split_once('%') {
+        Some((prefix, suffix)) => {
+            if word.len() < prefix.len() + suffix.len() {
+                return None;
+            }
+            word.strip_prefix(prefix)?.strip_suffix(suffix)
+        }
+        None => (pattern == word).then_some(""),
+    }
+}
+
+pub(crate) fn patsubst(pattern: &str, replacement: &str, text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| match match_pattern(pattern, word) {
+            Some(stem) if pattern.contains('%') => replacement.replacen('%', stem, 1),
+            Some(_) => replacement.to_owned(),
+            None => word.to_owned(),
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn split_directory(word: &str) -> (&str, &str) {
+    match word.rfind('/') {
+        Some(slash_index) => word.split_at(slash_index + 1),
+        None => ("", word),
+    }
+}
+
+// The index of the `.` starting the suffix of the last path component, if any.
+fn suffix_index(word: &str) -> Option<usize> {
+    let (directory, file_name) = split_directory(word);
+    file_name
+        .rfind('.')
+        .map(|dot_index| directory.len() + dot_index)
+}
+
+fn absolute_path(word: &str) -> String {
+    let path = std::env::current_dir()
+        .map(|current_dir| current_dir.join(word))
+        .unwrap_or_else(|_| PathBuf::from(word));
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized.to_string_lossy().into_owned()
+}
+
+fn map_words(text: &str, f: impl Fn(&str) -> String) -> String {
+    text.split_whitespace()
+        .map(f)
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn parse_word_index(function_name: &str, text: &str) -> usize {
+    match text.trim().parse::<usize>() {
+        Ok(index) if index > 0 => index,
+        _ => {
+            eprintln!(
+                "*** non-numeric or zero first argument to '{}' function: '{}'.  Stop.",
+                function_name,
+                text.trim()
+            );
+            exit(2);
+        }
+    }
+}
+
+fn run_shell(command: &str) -> String {
+    let output = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .stderr(Stdio::inherit())
+        .output();
+    match output {
+        Ok(output) => String::from_utf8_lossy(&output.stdout)
+            .trim_end_matches('\n')
+            .replace('\n', " "),
+        Err(error) => {
+            eprintln!("Could not run `$(shell {})`: {}", command, error);
+            String::new()
+        }
+    }
+}
+
+impl Variables {
+    // Most functions take a fixed number of arguments, all expanded up front.
+    fn expand_arguments<const N: usize>(
+        &self,
+        arguments: &str,
+        automatic: Option<&AutomaticVariables>,
+        depth: usize,
+    ) -> [String; N] {
+        let split = split_arguments(arguments, N);
+        std::array::from_fn(|i| {
+            split
+                .get(i)
+                .map(|argument| self.expand_with_depth(argument, automatic, depth))
+                .unwrap_or_default()
+        })
+    }
+
+    pub(crate) fn call_function(
+        &self,
+        function_name: &str,
+        arguments: &str,
+        automatic: Option<&AutomaticVariables>,
+        depth: usize,
+    ) -> String {
+        let expand = |text: &str| self.expand_with_depth(text, automatic, depth);
+        match function_name {
+            "subst" => {
+                let [from, to, text] = self.expand_arguments(arguments, automatic, depth);
+                if from.is_empty() {
+                    text
+                } else {
+                    text.replace(&from, &to)
+                }
+            }
+            "patsubst" => {
+                let [pattern, replacement, text] =
+                    self.expand_arguments(arguments, automatic, depth);
+                patsubst(pattern.trim(), replacement.trim(), &text)
+            }
+            "strip" => expand(arguments)
+                .split_whitespace()
+                .collect::<Vec<&str>>()
+                .join(" "),
+            "findstring" => {
+                let [needle, text] = self.expand_arguments(arguments, automatic, depth);
+                if text.contains(&needle) {
+                    needle
+                } else {
+                    String::new()
+                }
+            }
+            "filter" | "filter-out" => {
+                let [patterns, text] = self.expand_arguments(arguments, automatic, depth);
+                let keep_matches = function_name == "filter";
+                text.split_whitespace()
+                    .filter(|word| {
+                        patterns
+                            .split_whitespace()
+                            .any(|pattern| match_pattern(pattern, word).is_some())
+                            == keep_matches
+                    })
+                    .collect::<Vec<&str>>()
+                    .join(" ")
+            }
+            "sort" => {
+                let text = expand(arguments);
+                let mut words: Vec<&str> = text.split_whitespace().collect();
+                words.sort();
+                words.dedup();
+                words.join(" ")
+            }
+            "word" => {
+                let [index, text] = self.expand_arguments(arguments, automatic, depth);
+                let index = parse_word_index(function_name, &index);
+                text.split_whitespace()
+                    .nth(index - 1)
+                    .unwrap_or_default()
+                    .to_owned()
+            }
+            "wordlist" => {
+                let [start, end, text] = self.expand_arguments(arguments, automatic, depth);
+                let start = parse_word_index(function_name, &start);
+                let end = end.trim().parse::<usize>().unwrap_or(0);
+                text.split_whitespace()
+                    .skip(start - 1)
+                    .take((end + 1).saturating_sub(start))
+                    .collect::<Vec<&str>>()
+                    .join(" ")
+            }
+            "words" => expand(arguments).split_whitespace().count().to_string(),
+            "firstword" => expand(arguments)
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_owned(),
+            "lastword" => expand(arguments)
+                .split_whitespace()
+                .last()
+                .unwrap_or_default()
+                .to_owned(),
+            "dir" => map_words(&expand(arguments), |word| match split_directory(word).0 {
+                "" => "./".to_owned(),
+                directory => directory.to_owned(),
+            }),
+            "notdir" => map_words(&expand(arguments), |word| {
+                split_directory(word).1.to_owned()
+            }),
+            "suffix" => map_words(&expand(arguments), |word| {
+                suffix_index(word)
+                    .map(|dot_index| word[dot_index..].to_owned())
+                    .unwrap_or_default()
+            }),
+            "basename" => map_words(&expand(arguments), |word| {
+                word[..suffix_index(word).unwrap_or(word.len())].to_owned()
+            }),
+            "addsuffix" | "addprefix" => {
+                let [affix, text] = self.expand_arguments(arguments, automatic, depth);
+                map_words(&text, |word| match function_name {
+                    "addsuffix" => format!("{}{}", word, affix),
+                    _ => format!("{}{}", affix, word),
+                })
+            }
+            "join" => {
+                let [a, b] = self.expand_arguments(arguments, automatic, depth);
+                let (a, b): (Vec<&str>, Vec<&str>) = (
+                    a.split_whitespace().collect(),
+                    b.split_whitespace().collect(),
+                );
+                (0..a.len().max(b.len()))
+                    .map(|i| format!("{}{}", a.get(i).unwrap_or(&""), b.get(i).unwrap_or(&"")))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            }
+            "wildcard" => expand(arguments)
+                .split_whitespace()
+                .flat_map(expand_wildcard)
+                .collect::<Vec<String>>()
+                .join(" "),
+            "realpath" => map_words(&expand(arguments), |word| {
+                std::fs::canonicalize(word)
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            }),
+            "abspath" => map_words(&expand(arguments), absolute_path),
+            // `if`, `or`, and `and` only expand the arguments they need.
+            "if" => {
+                let arguments = split_arguments(arguments, 3);
+                let condition = expand(arguments[0]);
+                match (
+                    condition.trim().is_empty(),
+                    arguments.get(1),
+                    arguments.get(2),
+                ) {
+                    (false, Some(then), _) => expand(then),
+                    (true, _, Some(otherwise)) => expand(otherwise),
+                    _ => String::new(),
+                }
+            }
+            "or" => split_arguments(arguments, usize::MAX)
+                .into_iter()
+                .map(expand)
+                .find(|value| !value.trim().is_empty())
+                .unwrap_or_default(),
+            "and" => {
+                let mut last = String::new();
+                for argument in split_arguments(arguments, usize::MAX) {
+                    last = expand(argument);
+                    if last.trim().is_empty() {
+                        return String::new();
+                    }
+                }
+                last
+            }
+            "foreach" => {
+                let arguments = split_arguments(arguments, 3);
+                let (Some(name), Some(list), Some(text)) =
+                    (arguments.first(), arguments.get(1), arguments.get(2))
+                else {
+                    return String::new();
+                };
+                let name = expand(name).trim().to_owned();
+                let mut scoped_variables = self.clone();
+                expand(list)
+                    .split_whitespace()
+                    .map(|word| {
+                        scoped_variables.define_simple(&name, word);
+                        scoped_variables.expand_with_depth(text, automatic, depth)
+                    })
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            }
+            "call" => {
+                let arguments: Vec<String> = split_arguments(arguments, usize::MAX)
+                    .into_iter()
+                    .map(expand)
+                    .collect();
+                let name = arguments[0].trim();
+                let Some(value) = self.raw_value(name) else {
+                    return String::new();
+                };
+                let mut scoped_variables = self.clone();
+                scoped_variables.define_simple("0", name);
+                for (i, argument) in arguments.iter().enumerate().skip(1) {
+                    scoped_variables.define_simple(&i.to_string(), argument);
+                }
+                scoped_variables.expand_with_depth(value, automatic, depth)
+            }
+            "origin" => self.origin(expand(arguments).trim(), automatic).to_owned(),
+            "flavor" => self.flavor(expand(arguments).trim()).to_owned(),
+            "eval" => {
+                self.push_evaluation(expand(arguments));
+                String::new()
+            }
+            "value" => self
+                .raw_value(expand(arguments).trim())
+                .unwrap_or_default()
+                .to_owned(),
+            "shell" => run_shell(&expand(arguments)),
+            "info" => {
+                println!("{}", expand(arguments));
+                String::new()
+            }
+            "warning" => {
+                eprintln!("warning: {}", expand(arguments));
+                String::new()
+            }
+            "error" => {
+                eprintln!("*** {}.  Stop.", expand(arguments));
+                exit(2);
+            }
+            _ => unreachable!("Internal error: unknown function `{}`", function_name),
+        }
+    }
+}
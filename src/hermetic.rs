@@ -0,0 +1,135 @@
+// For `--hermetic`: `mak` (and so the makefiles and recipes) only keeps a few environment variables, and `PATH` only
+// has the system directories and the declared tools. So builds don't depend on the shell profile of whoever runs them.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::cache::CACHE_DIRECTORY;
+
+// Kept in addition to `--keep-env`: where the user's configuration lives, what the terminal supports, the `make`
+// options and job server of a parent `make`, and the tokens for `--cache-url` and workers.
+const KEPT_VARIABLES: &[&str] = &[
+    "HOME",
+    "TERM",
+    "MAKEFLAGS",
+    "MAKELEVEL",
+    "GNUMAKEFLAGS",
+    crate::build_cache::TOKEN_ENVIRONMENT_VARIABLE,
+    crate::worker::TOKEN_ENVIRONMENT_VARIABLE,
+];
+
+const SYSTEM_PATH: &str = "/usr/bin:/bin";
+
+// The directory in `.mak` with a link to each declared tool.
+const TOOLS_DIRECTORY: &str = "tools";
+
+#[cfg(unix)]
+fn link_tool(path: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(path, link)
+}
+
+// Symbolic links need extra privileges on Windows, so the tool is copied.
+#[cfg(not(unix))]
+fn link_tool(path: &Path, link: &Path) -> std::io::Result<()> {
+    std::fs::copy(path, link).map(|_| ())
+}
+
+fn find_in_path(tool: &str) -> Option<PathBuf> {
+    if tool.contains('/') {
+        return Path::new(tool).canonicalize().ok();
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|directory| directory.join(tool))
+        .find(|path| path.is_file())
+}
+
+/// Removes the environment variables that are not kept, and replaces `PATH` with the system directories and the
+/// directory with links to `tools` (found in the current `PATH`, or paths). `make` is always a tool.
+pub(crate) fn isolate_environment(keep_env: &[String], tools: &[String]) -> Result<(), String> {
+    let tools_directory = std::env::current_dir()
+        .map_err(|error| format!("could not get the current directory: {}", error))?
+        .join(CACHE_DIRECTORY)
+        .join(TOOLS_DIRECTORY);
+    // Tools that are not declared anymore are removed.
+    let _ = std::fs::remove_dir_all(&tools_directory);
+    std::fs::create_dir_all(&tools_directory).map_err(|error| {
+        format!(
+            "could not create `{}`: {}",
+            tools_directory.display(),
+            error
+        )
+    })?;
+    for tool in std::iter::once("make").chain(tools.iter().map(String::as_str)) {
+        let Some(path) = find_in_path(tool) else {
+            return Err(format!("could not find the tool `{}` in `PATH`", tool));
+        };
+        // Named like it was passed, even if it is a link to a file with another name.
+        let Some(name) = Path::new(tool).file_name() else {
+            continue;
+        };
+        let link = tools_directory.join(name);
+        // A link that doesn't lead anywhere (e.g. to a tool that was moved) is replaced.
+        if link.symlink_metadata().is_ok() {
+            if link.exists() {
+                continue;
+            }
+            let _ = std::fs::remove_file(&link);
+        }
+        link_tool(&path, &link)
+            .map_err(|error| format!("could not link `{}`: {}", link.display(), error))?;
+    }
+    for (name, _) in std::env::vars_os() {
+        let kept = name.to_str().is_some_and(|name| {
+            KEPT_VARIABLES.contains(&name) || keep_env.iter().any(|kept| kept == name)
+        });
+        if !kept {
+            std::env::remove_var(name);
+        }
+    }
+    std::env::set_var(
+        "PATH",
+        format!("{}:{}", tools_directory.display(), SYSTEM_PATH),
+    );
+    Ok(())
+}
+
+/// A temporary directory for one recipe (its `TMPDIR`), which is removed when this is dropped.
+pub(crate) struct PrivateTempDirectory {
+    path: PathBuf,
+}
+
+impl PrivateTempDirectory {
+    /// Creates a new directory that only the current user can use. A directory that is already there (which someone
+    /// else could have made) is never used.
+    pub(crate) fn new() -> std::io::Result<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        loop {
+            let path = std::env::temp_dir().join(format!(
+                "mak-tmp-{}-{}",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::SeqCst)
+            ));
+            match builder.create(&path) {
+                Ok(()) => return Ok(Self { path }),
+                Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    pub(crate) fn set(&self, command: &mut Command) {
+        command.env("TMPDIR", &self.path);
+    }
+}
+
+impl Drop for PrivateTempDirectory {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use futures::channel::oneshot;
+
+/// The longest remaining chain of recipes that starts with the target (in milliseconds), then its `# mak:priority=…`.
+pub(crate) type Priority = (u64, i64);
+
+struct Waiting {
+    priority: Priority,
+    // Targets with the same priority get a slot in the order they asked for one.
+    order: u64,
+    sender: oneshot::Sender<JobSlot>,
+}
+
+impl PartialEq for Waiting {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiting {}
+
+impl PartialOrd for Waiting {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Waiting {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.order.cmp(&self.order))
+    }
+}
+
+#[derive(Default)]
+struct State {
+    available: usize,
+    waiting: BinaryHeap<Waiting>,
+    next_order: u64,
+    open_requested: bool,
+    opened: bool,
+    // The targets counted by `expect` that haven't asked for a slot yet.
+    expected: usize,
+}
+
+/// A limited number of slots for recipes to run in (`--jobs`). Unlike a semaphore, a freed slot goes to the waiting
+/// recipe with the highest priority.
+pub(crate) struct JobSlots {
+    num_slots: usize,
+    state: Mutex<State>,
+}
+
+/// Gives the slot to the next recipe when dropped.
+pub(crate) struct JobSlot {
+    job_slots: Arc<JobSlots>,
+}
+
+impl JobSlots {
+    /// The slots are only handed out after `open`.
+    pub(crate) fn new(num_slots: usize) -> Arc<Self> {
+        Arc::new(Self {
+            num_slots,
+            state: Mutex::default(),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Counts a target that is ready from the start, so that the slots are only opened once it has asked for one.
+    /// Returns whether it was counted (which is only before `open`), i.e. whether to pass `counted` to `acquire`.
+    pub(crate) fn expect(&self) -> bool {
+        let mut state = self.state();
+        if state.open_requested {
+            return false;
+        }
+        state.expected += 1;
+        true
+    }
+
+    /// For a target counted by `expect` that waits for something else (e.g. a resource) before it asks for a slot, so
+    /// that the slots don't wait for it.
+    pub(crate) fn arrive(self: &Arc<Self>) {
+        let mut state = self.state();
+        state.expected -= 1;
+        self.open_if_ready(state);
+    }
+
+    /// Hands out the slots once all of the targets counted by `expect` have asked for one (so that the first slots
+    /// also go to the ones with the highest priority).
+    pub(crate) fn open(self: &Arc<Self>) {
+        let mut state = self.state();
+        state.open_requested = true;
+        self.open_if_ready(state);
+    }
+
+    fn open_if_ready(self: &Arc<Self>, mut state: MutexGuard<'_, State>) {
+        if !state.open_requested || state.opened || state.expected > 0 {
+            return;
+        }
+        state.opened = true;
+        drop(state);
+        for _ in 0..self.num_slots {
+            self.release();
+        }
+    }
+
+    fn release(self: &Arc<Self>) {
+        let mut state = self.state();
+        let Some(waiting) = state.waiting.pop() else {
+            state.available += 1;
+            return;
+        };
+        drop(state);
+        // If the waiting task is gone, the slot is returned and dropped right away, which passes it on to the next one.
+        let _ = waiting.sender.send(JobSlot {
+            job_slots: self.clone(),
+        });
+    }
+
+    pub(crate) async fn acquire(self: &Arc<Self>, priority: Priority, counted: bool) -> JobSlot {
+        let receiver = {
+            let mut state = self.state();
+            if state.available > 0 {
+                state.available -= 1;
+                return JobSlot {
+                    job_slots: self.clone(),
+                };
+            }
+            let (sender, receiver) = oneshot::channel();
+            let order = state.next_order;
+            state.next_order += 1;
+            state.waiting.push(Waiting {
+                priority,
+                order,
+                sender,
+            });
+            if counted {
+                state.expected -= 1;
+                self.open_if_ready(state);
+            }
+            receiver
+        };
+        receiver
+            .await
+            .expect("Internal error: a job slot was dropped without being released.")
+    }
+}
+
+impl Drop for JobSlot {
+    fn drop(&mut self) {
+        self.job_slots.release();
+    }
+}
@@ -0,0 +1,162 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use async_std::task;
+
+/// A POSIX jobserver (like the one `make -j` creates) for the `make` processes that `mak` starts, so that the ones that
+/// run several jobs themselves (e.g. a recursive `$(MAKE) -C dir`) share the `--jobs` limit with `mak` instead of
+/// multiplying it. Each running recipe holds a token: the first one holds the implicit token (like for `make`), and
+/// the others take one from the pipe.
+pub(crate) struct Jobserver {
+    num_slots: usize,
+    read_fd: i32,
+    write_fd: i32,
+    implicit_token_in_use: AtomicBool,
+}
+
+/// Gives the token back when dropped.
+pub(crate) struct JobserverToken {
+    jobserver: Arc<Jobserver>,
+    implicit: bool,
+    // `None` if a token could not be read, so that the recipe runs anyway.
+    byte: Option<u8>,
+}
+
+impl Jobserver {
+    /// `None` if the pipe could not be created (or on other platforms).
+    #[cfg(unix)]
+    pub(crate) fn new(num_slots: usize) -> Option<Arc<Self>> {
+        let mut fds = [0; 2];
+        // Unlike the pipes that Rust creates, these are inherited by the processes that `mak` starts.
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            eprintln!(
+                "Could not create a jobserver for `make` subprocesses: {}",
+                std::io::Error::last_os_error()
+            );
+            return None;
+        }
+        let [read_fd, write_fd] = fds;
+        let tokens = vec![b'+'; num_slots.saturating_sub(1)];
+        unsafe {
+            libc::write(
+                write_fd,
+                tokens.as_ptr() as *const libc::c_void,
+                tokens.len(),
+            );
+        }
+        Some(Arc::new(Self {
+            num_slots,
+            read_fd,
+            write_fd,
+            implicit_token_in_use: AtomicBool::new(false),
+        }))
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn new(_num_slots: usize) -> Option<Arc<Self>> {
+        None
+    }
+
+    /// `make_flags` (the current `MAKEFLAGS`) with the jobserver instead of any `-j` flags. `--jobserver-fds` is for
+    /// `make` before 4.2.
+    pub(crate) fn make_flags(&self, make_flags: &str) -> String {
+        let (flags, overrides) = match make_flags.split_once(" -- ") {
+            Some((flags, overrides)) => (flags, Some(overrides)),
+            None => (make_flags, None),
+        };
+        let mut words: Vec<String> = flags
+            .split(' ')
+            .filter(|word| {
+                !word.is_empty()
+                    && !word.starts_with("-j")
+                    && !word.starts_with("--jobs")
+                    && !word.starts_with("--jobserver-")
+            })
+            .map(str::to_owned)
+            .collect();
+        words.push(format!("-j{}", self.num_slots));
+        words.push(format!(
+            "--jobserver-fds={},{}",
+            self.read_fd, self.write_fd
+        ));
+        words.push(format!(
+            "--jobserver-auth={},{}",
+            self.read_fd, self.write_fd
+        ));
+        if let Some(overrides) = overrides {
+            words.push("--".to_owned());
+            words.push(overrides.to_owned());
+        }
+        words.join(" ")
+    }
+
+    /// Waits until a token is free.
+    pub(crate) async fn acquire(self: &Arc<Self>) -> JobserverToken {
+        if !self.implicit_token_in_use.swap(true, Ordering::SeqCst) {
+            return JobserverToken {
+                jobserver: self.clone(),
+                implicit: true,
+                byte: None,
+            };
+        }
+        let jobserver = self.clone();
+        let byte = task::spawn_blocking(move || jobserver.read_token()).await;
+        JobserverToken {
+            jobserver: self.clone(),
+            implicit: false,
+            byte,
+        }
+    }
+
+    // `make` may make the pipe non-blocking, so this waits with `poll` when there is no token yet.
+    #[cfg(unix)]
+    fn read_token(&self) -> Option<u8> {
+        loop {
+            let mut byte = 0u8;
+            let result =
+                unsafe { libc::read(self.read_fd, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+            if result == 1 {
+                return Some(byte);
+            }
+            let error = std::io::Error::last_os_error();
+            match error.kind() {
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted => {
+                    let mut poll_fd = libc::pollfd {
+                        fd: self.read_fd,
+                        events: libc::POLLIN,
+                        revents: 0,
+                    };
+                    unsafe { libc::poll(&mut poll_fd, 1, -1) };
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn read_token(&self) -> Option<u8> {
+        None
+    }
+}
+
+impl Drop for JobserverToken {
+    fn drop(&mut self) {
+        if self.implicit {
+            self.jobserver
+                .implicit_token_in_use
+                .store(false, Ordering::SeqCst);
+        }
+        #[cfg(unix)]
+        if let Some(byte) = self.byte {
+            unsafe {
+                libc::write(
+                    self.jobserver.write_fd,
+                    &byte as *const u8 as *const libc::c_void,
+                    1,
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Mutex, PoisonError},
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{variable_hashes, CACHE_DIRECTORY},
+    parse::{TargetGraph, TargetName},
+    sha256::Sha256,
+};
+
+const JOURNAL_FILE_NAME: &str = "journal";
+
+/// A line of `.mak/journal`: a recipe that succeeded.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    target: TargetName,
+    fingerprint: String,
+}
+
+// Changed when what a fingerprint is a hash of changes, so that older journals aren't resumed.
+const FINGERPRINT_VERSION: &[u8] = b"mak-journal-1";
+
+/// The SHA-256 hash of the inputs of the recipe of `group`: the expanded recipe, the modification times and sizes of
+/// the prerequisites, and the values of the variables from `# mak:env=…`. Every string and list is preceded by its
+/// length. Cheaper than the `--build-cache` key, since it is computed for every recipe.
+pub(crate) fn fingerprint(target_graph: &TargetGraph, group: &[TargetName]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update_field(FINGERPRINT_VERSION);
+    hasher.update(&(group.len() as u64).to_le_bytes());
+    for member in group {
+        hasher.update_field(member.0.as_bytes());
+        let scripts: Vec<String> = target_graph
+            .shell_invocations(member)
+            .into_iter()
+            .map(|invocation| invocation.script)
+            .collect();
+        hasher.update(&(scripts.len() as u64).to_le_bytes());
+        for script in &scripts {
+            hasher.update_field(script.as_bytes());
+        }
+        let prerequisites: Vec<TargetName> = target_graph
+            .all_prerequisites(member)
+            .into_iter()
+            .filter(|prerequisite| !target_graph.is_phony(prerequisite))
+            .collect();
+        hasher.update(&(prerequisites.len() as u64).to_le_bytes());
+        for prerequisite in prerequisites {
+            hasher.update_field(prerequisite.0.as_bytes());
+            let file = target_graph
+                .resolve_path(&prerequisite.0)
+                .and_then(|path| std::fs::metadata(path).ok())
+                .and_then(|metadata| {
+                    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+                    Some((modified, metadata.len()))
+                });
+            // A missing file is a 0, and an existing one a 1 followed by its modification time and size.
+            match file {
+                Some((modified, size)) => {
+                    hasher.update(&[1]);
+                    hasher.update(&modified.as_secs().to_le_bytes());
+                    hasher.update(&modified.subsec_nanos().to_le_bytes());
+                    hasher.update(&size.to_le_bytes());
+                }
+                None => hasher.update(&[0]),
+            }
+        }
+        let variables = variable_hashes(target_graph, member);
+        hasher.update(&(variables.len() as u64).to_le_bytes());
+        for (name, hash) in &variables {
+            hasher.update_field(name.as_bytes());
+            hasher.update_field(hash.as_bytes());
+        }
+    }
+    hasher.finish()
+}
+
+/// The recipes that succeeded during the current build, in `.mak/journal`, so that `--resume` can skip them if the
+/// build is interrupted (or fails) before it is done. Each one is written right away, so that this also works after
+/// `mak` crashes or is killed.
+pub(crate) struct Journal {
+    // From the last build, with `--resume`.
+    finished_before: HashMap<TargetName, String>,
+    // `None` until the build starts, and if the journal can't be written.
+    file: Mutex<Option<File>>,
+    resume: bool,
+}
+
+impl Journal {
+    pub(crate) fn new(resume: bool) -> Self {
+        let finished_before = match resume {
+            true => std::fs::read_to_string(Path::new(CACHE_DIRECTORY).join(JOURNAL_FILE_NAME))
+                .unwrap_or_default()
+                .lines()
+                // The last line can be incomplete after a crash.
+                .filter_map(|line| serde_json::from_str::<Entry>(line).ok())
+                .map(|entry| (entry.target, entry.fingerprint))
+                .collect(),
+            false => HashMap::new(),
+        };
+        Self {
+            finished_before,
+            file: Mutex::new(None),
+            resume,
+        }
+    }
+
+    /// Opens the journal when the build starts. A new build starts a new journal, unless it continues the last one.
+    pub(crate) fn start(&self) {
+        let file = std::fs::create_dir_all(CACHE_DIRECTORY)
+            .and_then(|()| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(Path::new(CACHE_DIRECTORY).join(JOURNAL_FILE_NAME))
+            })
+            .and_then(|file| {
+                if !self.resume {
+                    file.set_len(0)?;
+                }
+                Ok(file)
+            });
+        *self.file.lock().unwrap_or_else(PoisonError::into_inner) = file.ok();
+    }
+
+    /// With `--resume`: whether the recipe of `group` already succeeded during the last build with the same
+    /// `fingerprint`, and its targets still exist.
+    pub(crate) fn finished_before(
+        &self,
+        target_graph: &TargetGraph,
+        group: &[TargetName],
+        fingerprint: &str,
+    ) -> bool {
+        self.finished_before
+            .get(&group[0])
+            .is_some_and(|finished| finished == fingerprint)
+            && group.iter().all(|member| {
+                target_graph.is_phony(member) || target_graph.resolve_path(&member.0).is_some()
+            })
+    }
+
+    /// Records that the recipe of `group` succeeded.
+    pub(crate) fn record(&self, group: &[TargetName], fingerprint: &str) {
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(file) = file.as_mut() else {
+            return;
+        };
+        let entry = Entry {
+            target: group[0].clone(),
+            fingerprint: fingerprint.to_owned(),
+        };
+        let mut line = serde_json::to_string(&entry).expect("Could not serialize a journal entry.");
+        line.push('\n');
+        // Written right away (the file isn't buffered), but not synced to the disk, which would slow down every build.
+        let _ = file.write_all(line.as_bytes());
+    }
+
+    /// Removes the journal once the build succeeded, since there is nothing left to resume.
+    pub(crate) fn remove(&self) {
+        let _ = std::fs::remove_file(Path::new(CACHE_DIRECTORY).join(JOURNAL_FILE_NAME));
+    }
+}
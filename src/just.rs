@@ -0,0 +1,106 @@
+use std::process::{exit, Command, Stdio};
+
+use serde_json::Value;
+
+use crate::parse::{TargetGraph, TargetName};
+
+const ERROR_COULD_NOT_DUMP_JUSTFILE: &str =
+    "Could not read recipes using `just --dump` (is `just` installed?)";
+
+fn just_args(justfile_path_str: &Option<String>) -> Vec<String> {
+    let mut args = vec![];
+    if let Some(justfile_path_str) = justfile_path_str {
+        args.push("--justfile".to_owned());
+        args.push(justfile_path_str.to_owned());
+    };
+    args
+}
+
+/// Runs a single recipe without its dependencies (which are scheduled separately).
+/// `just` accepts `VAR=value` overrides the same way as `make`.
+pub(crate) fn recipe_command(
+    justfile_path_str: &Option<String>,
+    variable_overrides: &[String],
+    target_name: &TargetName,
+) -> Command {
+    let mut command = Command::new("just");
+    command
+        .args(just_args(justfile_path_str))
+        .arg("--no-deps")
+        .args(variable_overrides)
+        .arg(&target_name.0);
+    command
+}
+
+// Recipe bodies are lists of lines, each made of text and `{{…}}` interpolation fragments.
+// Interpolations are only evaluated by `just`, so they are shown unevaluated.
+fn recipe_lines(recipe: &Value) -> Vec<String> {
+    let Some(lines) = recipe["body"].as_array() else {
+        return vec![];
+    };
+    lines
+        .iter()
+        .map(|line| {
+            line.as_array()
+                .into_iter()
+                .flatten()
+                .map(|fragment| match fragment.as_str() {
+                    Some(text) => text.to_owned(),
+                    None => "{{…}}".to_owned(),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Builds the graph from `just --dump --dump-format json`. Each recipe becomes a phony target.
+pub(crate) fn read_justfile(justfile_path_str: &Option<String>) -> TargetGraph {
+    let output = Command::new("just")
+        .args(just_args(justfile_path_str))
+        .args(["--dump", "--dump-format", "json"])
+        .stderr(Stdio::inherit())
+        .output()
+        .expect(ERROR_COULD_NOT_DUMP_JUSTFILE);
+    if !output.status.success() {
+        exit(output.status.code().unwrap_or(1));
+    }
+    let dump: Value = serde_json::from_slice(&output.stdout).expect(ERROR_COULD_NOT_DUMP_JUSTFILE);
+
+    let mut target_graph = TargetGraph::default();
+    let Some(recipes) = dump["recipes"].as_object() else {
+        return target_graph;
+    };
+    for (name, recipe) in recipes {
+        let target_name = TargetName(name.to_owned());
+        let dependencies = recipe["dependencies"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        // Dependencies after `&&` run after the recipe.
+        // TODO: schedule these once the graph can express them.
+        let priors = recipe["priors"]
+            .as_u64()
+            .map_or(dependencies.len(), |priors| priors as usize);
+        // TODO: pass dependency arguments.
+        let dependencies: Vec<TargetName> = dependencies
+            .iter()
+            .take(priors)
+            .filter_map(|dependency| dependency["recipe"].as_str())
+            .map(|dependency| TargetName(dependency.to_owned()))
+            .collect();
+        target_graph.edges.insert(target_name.clone(), dependencies);
+        target_graph
+            .recipes
+            .insert(target_name.clone(), recipe_lines(recipe));
+        if let Some(doc) = recipe["doc"].as_str() {
+            target_graph
+                .descriptions
+                .insert(target_name.clone(), doc.to_owned());
+        }
+        target_graph.phony.insert(target_name);
+    }
+    target_graph.default_goal = dump["first"]
+        .as_str()
+        .map(|first| TargetName(first.to_owned()));
+    target_graph
+}
@@ -0,0 +1,285 @@
+use indexmap::{IndexMap, IndexSet};
+use serde::Serialize;
+
+use crate::{
+    makefile_text::{
+        is_directive, is_rule, logical_lines, parse_assignment, strip_comment, Assignment,
+    },
+    parse::{TargetGraph, TargetName},
+};
+
+// Variables that `make` itself (or its built-in rules) reads, so they don't need to be referenced by the makefile.
+const IMPLICITLY_USED_VARIABLES: [&str; 33] = [
+    "SHELL",
+    "MAKESHELL",
+    "MAKEFLAGS",
+    "GNUMAKEFLAGS",
+    "MAKEFILES",
+    "MAKEOVERRIDES",
+    "VPATH",
+    "GPATH",
+    "SUFFIXES",
+    "AR",
+    "ARFLAGS",
+    "AS",
+    "ASFLAGS",
+    "CC",
+    "CFLAGS",
+    "CPP",
+    "CPPFLAGS",
+    "CXX",
+    "CXXFLAGS",
+    "FC",
+    "FFLAGS",
+    "LD",
+    "LDFLAGS",
+    "LDLIBS",
+    "LEX",
+    "LFLAGS",
+    "OUTPUT_OPTION",
+    "RM",
+    "TARGET_ARCH",
+    "YACC",
+    "YFLAGS",
+    "LOADLIBES",
+    "MAKEINFO",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum LintCheck {
+    /// A target has more than one recipe (only the last one is used).
+    DuplicateRecipe,
+    /// A target without a recipe or prerequisites (that isn't a file).
+    EmptyTarget,
+    /// A prerequisite that is neither a file nor a target.
+    MissingPrerequisite,
+    /// A variable that is assigned but never referenced.
+    UnusedVariable,
+    /// A recipe line that starts with spaces instead of a tab.
+    SpaceIndentedRecipe,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct LintProblem {
+    pub(crate) check: LintCheck,
+    /// The target, prerequisite, or variable that the problem is about.
+    pub(crate) name: String,
+    pub(crate) message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) line: Option<usize>,
+}
+
+impl LintProblem {
+    fn new(check: LintCheck, name: &str, message: String) -> Self {
+        Self {
+            check,
+            name: name.to_owned(),
+            message,
+            file: None,
+            line: None,
+        }
+    }
+}
+
+/// Checks the target graph (and, if `read_makefiles` is set, the text of its makefiles) for common mistakes.
+pub(crate) fn lint(target_graph: &TargetGraph, read_makefiles: bool) -> Vec<LintProblem> {
+    let mut problems = vec![];
+    for target_name in &target_graph.overridden_recipes {
+        problems.push(LintProblem::new(
+            LintCheck::DuplicateRecipe,
+            &target_name.0,
+            format!(
+                "`{}` has more than one recipe (only the last one is used)",
+                target_name
+            ),
+        ));
+    }
+
+    let prerequisites: IndexSet<&TargetName> = target_graph
+        .edges
+        .values()
+        .chain(target_graph.order_only_edges.values())
+        .flatten()
+        .collect();
+    let is_empty = |target_name: &TargetName| {
+        target_graph
+            .edges
+            .get(target_name)
+            .map_or(true, Vec::is_empty)
+            && !target_graph.order_only_edges.contains_key(target_name)
+            && !target_graph.has_recipe(target_name)
+            && target_graph.resolve_path(&target_name.0).is_none()
+    };
+    for target_name in target_graph.edges.keys() {
+        // Targets like `FORCE:` are commonly used as prerequisites to always rebuild other targets.
+        if is_empty(target_name)
+            && !prerequisites.contains(target_name)
+            && !target_graph
+                .prerequisites_without_rules
+                .contains(target_name)
+        {
+            problems.push(LintProblem::new(
+                LintCheck::EmptyTarget,
+                &target_name.0,
+                format!("`{}` has no recipe and no prerequisites", target_name),
+            ));
+        }
+    }
+    let mut reported = IndexSet::<&TargetName>::new();
+    for (target_name, dependencies) in target_graph
+        .edges
+        .iter()
+        .chain(&target_graph.order_only_edges)
+    {
+        for dependency in dependencies {
+            if target_graph
+                .prerequisites_without_rules
+                .contains(dependency)
+                && is_empty(dependency)
+                && !dependency.0.starts_with('.')
+                && reported.insert(dependency)
+            {
+                problems.push(LintProblem::new(
+                    LintCheck::MissingPrerequisite,
+                    &dependency.0,
+                    format!(
+                        "`{}` (a prerequisite of `{}`) is neither a file nor a target",
+                        dependency, target_name
+                    ),
+                ));
+            }
+        }
+    }
+
+    if read_makefiles {
+        let makefiles: Vec<(&String, String)> = target_graph
+            .makefiles
+            .iter()
+            .filter_map(|path| Some((path, std::fs::read_to_string(path).ok()?)))
+            .collect();
+        let uses_tab_prefix = target_graph
+            .variables
+            .raw_value(".RECIPEPREFIX")
+            .map_or(true, |prefix| prefix.is_empty() || prefix == "\t");
+        if uses_tab_prefix {
+            for (path, text) in &makefiles {
+                check_indentation(path, text, &mut problems);
+            }
+        }
+        check_unused_variables(target_graph, &makefiles, &mut problems);
+    }
+    problems
+}
+
+fn check_indentation(path: &str, text: &str, problems: &mut Vec<LintProblem>) {
+    let mut in_rule = false;
+    let mut in_define = false;
+    for (line_number, line) in logical_lines(text) {
+        let trimmed = line.trim();
+        if in_define {
+            in_define = trimmed != "endef";
+            continue;
+        }
+        if trimmed.is_empty() || trimmed.starts_with('#') || line.starts_with('\t') {
+            continue;
+        }
+        if line.starts_with(' ') {
+            if in_rule && !is_directive(trimmed) && parse_assignment(trimmed).is_none() {
+                problems.push(LintProblem {
+                    file: Some(path.to_owned()),
+                    line: Some(line_number),
+                    ..LintProblem::new(
+                        LintCheck::SpaceIndentedRecipe,
+                        trimmed,
+                        "Recipe line is indented with spaces instead of a tab".to_owned(),
+                    )
+                });
+            }
+            continue;
+        }
+        in_define = parse_assignment(trimmed).is_some_and(|assignment| assignment.is_define);
+        // Conditionals can appear inside a recipe.
+        if !is_directive(trimmed) || in_define {
+            in_rule = is_rule(&line);
+        }
+    }
+}
+
+fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !(c.is_alphanumeric() || "_.-".contains(c)))
+        .filter(|word| !word.is_empty())
+}
+
+// A variable is considered used if its name appears anywhere in the makefiles other than where it is assigned. This
+// errs on the side of not reporting anything.
+// TODO: variables that are only referenced using computed names (like `$($(TARGET)_FLAGS)`) are reported as unused.
+fn check_unused_variables(
+    target_graph: &TargetGraph,
+    makefiles: &[(&String, String)],
+    problems: &mut Vec<LintProblem>,
+) {
+    if target_graph.variables.exports_all() {
+        return;
+    }
+    // The first assignment of each variable.
+    let mut assignments: IndexMap<String, (String, usize)> = IndexMap::new();
+    let mut used = IndexSet::<String>::new();
+    for (path, text) in makefiles {
+        let mut in_define: Option<String> = None;
+        for (line_number, line) in logical_lines(text) {
+            if let Some(name) = &in_define {
+                if line.trim() == "endef" {
+                    in_define = None;
+                } else {
+                    used.extend(words(&line).filter(|word| word != name).map(str::to_owned));
+                }
+                continue;
+            }
+            let assignment = (!line.starts_with('\t'))
+                .then(|| parse_assignment(strip_comment(&line)))
+                .flatten();
+            match assignment {
+                Some(Assignment {
+                    name,
+                    value,
+                    is_define,
+                    is_export,
+                }) => {
+                    assignments
+                        .entry(name.to_owned())
+                        .or_insert_with(|| ((*path).clone(), line_number));
+                    if is_export {
+                        used.insert(name.to_owned());
+                    }
+                    if is_define {
+                        in_define = Some(name.to_owned());
+                    }
+                    used.extend(words(value).filter(|word| *word != name).map(str::to_owned));
+                }
+                None => used.extend(words(&line).map(str::to_owned)),
+            }
+        }
+    }
+    for (name, (path, line_number)) in assignments {
+        if used.contains(&name)
+            || name.starts_with('.')
+            || IMPLICITLY_USED_VARIABLES.contains(&name.as_str())
+            // Assigning an environment variable changes it for recipes.
+            || std::env::var_os(&name).is_some()
+        {
+            continue;
+        }
+        problems.push(LintProblem {
+            file: Some(path),
+            line: Some(line_number),
+            ..LintProblem::new(
+                LintCheck::UnusedVariable,
+                &name,
+                format!("`{}` is assigned but never used", name),
+            )
+        });
+    }
+}
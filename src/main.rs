@@ -1,72 +1,496 @@
-use async_std::task::{self, block_on, JoinHandle};
-use futures::{future::join_all, FutureExt};
-use indexmap::IndexMap;
-use indicatif::{MultiProgress, ProgressBar, ProgressFinish, ProgressStyle};
+use async_std::{
+    sync::Mutex,
+    task::{self, block_on, JoinHandle},
+};
+use console::{Color, Style};
+use futures::{
+    future::{join_all, select, Either},
+    FutureExt,
+};
+use indexmap::{IndexMap, IndexSet};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressFinish, ProgressStyle};
 mod options;
 use std::{
-    collections::HashMap,
-    io::{BufRead, BufReader},
-    path::Path,
-    process::{exit, Command, Stdio},
-    sync::{mpsc, Arc},
-    time::{Duration, Instant},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fmt::Display,
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::{exit, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use options::{get_options, MakArgs};
-use parse::TargetName;
+use build_cache::BuildCache;
+use cache::{TargetState, TargetStates};
+use cgroups::{JobCgroup, JobCgroups, JobLimits};
+use executor::{shell_quote, Freshness};
+use file_access::Trace;
+use hermetic::PrivateTempDirectory;
+use job_slots::{JobSlots, Priority};
+use jobserver::Jobserver;
+use journal::Journal;
+use manifest::{Outcome, Outcomes};
+use options::{get_options, Executor, Format, FreshnessCheck, MakArgs, OutputMode, ParserMode};
+use output_conflicts::WrittenOutputs;
+use output_order::{OutputOrder, Turn};
+use parse::{
+    escape_target_name, extend_unique, modified_time, parse_variable_override, ParseError,
+    TargetName, WAIT,
+};
+use processes::IoPriority;
+use remote::{WorkerPool, WorkerSlot};
+use resources::Resources;
+use sandbox::SandboxPolicy;
+use shuffle::Shuffle;
+use variables::VariableAssignment;
+use wildcard::matches_pattern;
+use worker::JobFile;
 
 use crate::parse::TargetGraph;
 
+mod build_cache;
+mod cache;
+mod cache_report;
+mod cgroups;
+mod clean;
+mod executor;
+mod file_access;
+mod fmt;
+mod functions;
+mod hermetic;
+mod job_slots;
+mod jobserver;
+mod journal;
+mod just;
+mod lint;
+mod makefile_text;
+mod manifest;
+mod ninja;
+mod output_conflicts;
+mod output_order;
+mod package_json;
 mod parse;
+mod processes;
+mod prune;
+mod recipe;
+mod recursive_make;
+mod remote;
+mod resources;
+mod sandbox;
+mod sha256;
+mod shuffle;
+mod stamp;
+mod taskfile;
+mod variables;
+mod where_target;
+mod why;
+mod wildcard;
+mod worker;
+mod yaml;
 
 const ERROR_COULD_NOT_LIST_TARGETS: &str =
     "Could not list targets using `make` (are you missing a Makefile?)";
 
-fn makefile_not_found(options: &MakArgs) {
+// Distinct from `make`'s own exit codes (1 for `-q` out of date, 2 for errors).
+const EXIT_CODE_PARSE_ERROR: i32 = 3;
+// Like `make`, when a recipe fails.
+const EXIT_CODE_BUILD_FAILED: i32 = 2;
+
+const DEFAULT_LOG_DIRECTORY: &str = ".mak/logs";
+
+// The names `make` looks for when no makefile is specified, in order.
+const DEFAULT_MAKEFILE_NAMES: [&str; 3] = ["GNUmakefile", "makefile", "Makefile"];
+
+fn find_default_makefile() -> Option<&'static str> {
+    DEFAULT_MAKEFILE_NAMES
+        .into_iter()
+        .find(|makefile_name| Path::new(makefile_name).exists())
+}
+
+fn makefile_not_found(options: &MakArgs) -> ! {
     if options.print_completion_targets {
         exit(0);
     }
-    eprintln!("No Makefile specified and no file found called `Makefile`");
+    eprintln!(
+        "No Makefile specified and no file found called {}",
+        DEFAULT_MAKEFILE_NAMES
+            .map(|makefile_name| format!("`{}`", makefile_name))
+            .join(", ")
+    );
     eprintln!("For more details, run: mak -h");
     exit(0);
 }
 
-fn main() {
-    let start_time = Instant::now();
-    let options = get_options();
+// `make` reports unreadable makefiles as `<including file>:<line>: <included file>: No such file or directory`, but still prints a (partial) database.
+fn check_listing_stderr(stderr: &str) {
+    let mut missing_include = false;
+    for line in stderr.lines() {
+        let mut parts = line.splitn(4, ": ");
+        if let (Some(location), Some(included_file), Some("No such file or directory"), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        {
+            eprintln!(
+                "Could not find included makefile `{}` (included from {})",
+                included_file, location
+            );
+            missing_include = true;
+        } else {
+            eprintln!("{}", line);
+        }
+    }
+    if missing_include {
+        exit(1);
+    }
+}
 
-    let mut args = vec!["-pRrq".to_owned()];
-    let makefile_path_str = options.makefile_path.as_ref().map(|p| {
-        p.to_str()
-            .expect("Could not convert Makefile path to a string.")
-            .to_owned()
-    });
-    if let Some(some_makefile_path_str) = &makefile_path_str {
-        let path = Path::new(&some_makefile_path_str);
-        if !path.exists() {
-            makefile_not_found(&options);
+fn print_target_list(target_graph: &TargetGraph, patterns: &[String], tags: &[String]) {
+    let mut target_names: Vec<&TargetName> = target_graph
+        .edges
+        .keys()
+        .filter(|target_name| target_graph.has_rule(target_name))
+        .filter(|target_name| tags.is_empty() || target_graph.has_tag(target_name, tags))
+        .filter(|target_name| {
+            patterns.is_empty()
+                || patterns
+                    .iter()
+                    .any(|pattern| matches_pattern(pattern, &target_name.0))
+        })
+        .collect();
+    target_names.sort_by(|a, b| a.0.cmp(&b.0));
+    let width = target_names
+        .iter()
+        .map(|target_name| target_name.0.chars().count())
+        .max()
+        .unwrap_or_default();
+    for target_name in target_names {
+        match target_graph.descriptions.get(target_name) {
+            Some(description) => println!("{:width$}  {}", target_name.0, description),
+            None => println!("{}", target_name),
+        }
+    }
+}
+
+// Formats the makefiles in place (or, with `--check`, reports the ones that aren't formatted) and exits.
+fn format_makefiles(options: &MakArgs, makefile_path_strs: &[String]) -> ! {
+    if options.format != Format::Make {
+        eprintln!("`--fmt` is only supported for `--format make`");
+        exit(1);
+    }
+    let makefile_path_strs = match makefile_path_strs.is_empty() {
+        true => match find_default_makefile() {
+            Some(default_makefile) => vec![default_makefile.to_owned()],
+            None => makefile_not_found(options),
+        },
+        false => makefile_path_strs.to_vec(),
+    };
+    let mut unformatted = false;
+    for makefile_path_str in makefile_path_strs {
+        let text = match std::fs::read_to_string(&makefile_path_str) {
+            Ok(text) => text,
+            Err(error) => {
+                eprintln!("Could not read `{}`: {}", makefile_path_str, error);
+                exit(1);
+            }
+        };
+        let formatted = fmt::format_makefile(&text);
+        if formatted == text {
+            continue;
+        }
+        if options.check {
+            let line_number = text
+                .lines()
+                .zip(formatted.lines())
+                .position(|(line, formatted_line)| line != formatted_line)
+                .unwrap_or_default()
+                + 1;
+            eprintln!("{}:{}: not formatted", makefile_path_str, line_number);
+            unformatted = true;
+        } else if let Err(error) = std::fs::write(&makefile_path_str, formatted) {
+            eprintln!("Could not write `{}`: {}", makefile_path_str, error);
+            exit(1);
+        } else if options.verbose {
+            eprintln!("Formatted: {}", makefile_path_str);
         }
-        args.append(&mut make_args(&makefile_path_str));
-    } else if !Path::new("makefile").exists() && !Path::new("Makefile").exists() {
-        makefile_not_found(&options);
     }
+    exit(if unformatted { 1 } else { 0 })
+}
 
+fn read_make_database(args: Vec<String>) -> Result<TargetGraph, ParseError> {
     let child = Command::new("make")
+        // `make -q` still runs recipe lines that use `$(MAKE)`, which would mix the databases of recursive makes into
+        // the output.
+        .arg("MAKE=:")
         .args(args)
         .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()
         .expect(ERROR_COULD_NOT_LIST_TARGETS);
     let output = child
         .wait_with_output()
         .expect(ERROR_COULD_NOT_LIST_TARGETS);
+    let stderr_str = String::from_utf8_lossy(&output.stderr);
+    check_listing_stderr(&stderr_str);
 
     let stdout_str = String::from_utf8(output.stdout).expect(ERROR_COULD_NOT_LIST_TARGETS);
-    let mut target_graph: TargetGraph =
-        TargetGraph::try_from(&stdout_str).expect("Could not parse targets");
-    target_graph.edges = IndexMap::from_iter(target_graph.edges.into_iter().filter(|edge| {
-        !edge.0 .0.starts_with('.') && makefile_path_str != Some(edge.0 .0.clone())
-    }));
+    let mut target_graph = TargetGraph::try_from(&stdout_str)?;
+    // The database only contains the last recipe of each target.
+    for line in stderr_str.lines() {
+        if let Some((_, target_name)) = line.split_once("warning: overriding recipe for target ") {
+            let target_name = target_name.trim_matches(['\'', '`']);
+            target_graph
+                .overridden_recipes
+                .insert(TargetName(target_name.to_owned()));
+        }
+    }
+    Ok(target_graph)
+}
+
+fn read_makefile(
+    options: &MakArgs,
+    makefile_path_strs: &[String],
+) -> Result<TargetGraph, ParseError> {
+    let mut args = vec!["-prq".to_owned()];
+    let main_makefile_path_strs = if makefile_path_strs.is_empty() {
+        // `make` does the same search itself, so there is no need to pass `-f`.
+        match find_default_makefile() {
+            Some(default_makefile) => vec![default_makefile.to_owned()],
+            None => makefile_not_found(options),
+        }
+    } else {
+        for makefile_path_str in makefile_path_strs {
+            if !Path::new(makefile_path_str).exists() {
+                makefile_not_found(options);
+            }
+        }
+        args.append(&mut make_args(makefile_path_strs, &[]));
+        makefile_path_strs.to_vec()
+    };
+    if options.verbose {
+        eprintln!("Using makefile: {}", main_makefile_path_strs.join(", "));
+    }
+
+    match options.parser {
+        ParserMode::MakeDb => {
+            args.extend(options.variable_overrides.iter().cloned());
+            read_make_database(args)
+        }
+        ParserMode::Native => {
+            let variable_overrides: Vec<VariableAssignment> = options
+                .variable_overrides
+                .iter()
+                .map(|variable_override| {
+                    parse_variable_override(variable_override)
+                        .expect("Internal error: variable override was not validated")
+                })
+                .collect();
+            TargetGraph::parse_makefile_files(
+                &main_makefile_path_strs,
+                &variable_overrides,
+                options.environment_overrides,
+            )
+        }
+    }
+}
+
+// Built-in variables for platform-specific rules, like `ifeq ($(MAK_OS),macos)`. These are passed to `make` (and both
+// parsers) through the environment, so the caller or the makefile can still override them.
+fn set_platform_variables() {
+    let mut platform_variables = vec![
+        ("MAK_OS", std::env::consts::OS),
+        ("MAK_ARCH", std::env::consts::ARCH),
+    ];
+    // Windows sets this itself, but makefiles commonly check for it using `ifeq ($(OS),Windows_NT)`.
+    if cfg!(windows) {
+        platform_variables.push(("OS", "Windows_NT"));
+    }
+    for (name, value) in platform_variables {
+        if std::env::var_os(name).is_none() {
+            std::env::set_var(name, value);
+        }
+    }
+}
+
+fn main() {
+    let start_time = Instant::now();
+    let options = get_options();
+    if let Some(worker_client) = &options.worker_client {
+        worker::run_client(&worker_client[0], &worker_client[1]);
+    }
+    if let Some(address) = &options.serve_worker {
+        worker::serve(address);
+    }
+    set_platform_variables();
+
+    let makefile_path_strs: Vec<String> = options
+        .makefile_paths
+        .iter()
+        .map(|p| {
+            p.to_str()
+                .expect("Could not convert Makefile path to a string.")
+                .to_owned()
+        })
+        .collect();
+    if makefile_path_strs.len() > 1 && options.format != Format::Make {
+        eprintln!("Multiple `--file` arguments are only supported for `--format make`");
+        exit(1);
+    }
+    // The path for the other formats.
+    let makefile_path_str = makefile_path_strs.first().cloned();
+    if options.fmt {
+        format_makefiles(&options, &makefile_path_strs);
+    }
+    if options.cache_stats {
+        cache_report::print_statistics();
+        exit(0);
+    }
+    if options.cache_ls {
+        cache_report::print_entries();
+        exit(0);
+    }
+    if let Some(key_or_target) = &options.cache_inspect {
+        let found = cache_report::inspect(key_or_target);
+        exit(if found { 0 } else { 1 });
+    }
+    if options.cache_gc {
+        cache_report::collect_garbage(options.cache_max_size, options.cache_max_age);
+        exit(0);
+    }
+    for variable_override in &options.variable_overrides {
+        if parse_variable_override(variable_override).is_none() {
+            eprintln!("Invalid variable assignment: {}", variable_override);
+            exit(1);
+        }
+    }
+    if !options.variable_overrides.is_empty()
+        && !matches!(options.format, Format::Make | Format::Just)
+    {
+        eprintln!(
+            "`VAR=value` arguments are only supported for `--format make` and `--format just`"
+        );
+        exit(1);
+    }
+    let cache_key = options.cache.then(|| {
+        let mut inputs = vec![
+            format!("{:?}", options.format),
+            format!("{:?}", options.parser),
+            format!("{:?}", options.environment_overrides),
+            std::env::current_dir()
+                .map(|current_dir| current_dir.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            // The default makefile could change.
+            find_default_makefile().unwrap_or_default().to_owned(),
+        ];
+        inputs.extend(makefile_path_strs.iter().cloned());
+        inputs.extend(options.variable_overrides.iter().cloned());
+        cache::cache_key(&inputs)
+    });
+    let freshness = Arc::new(Freshness {
+        always_make: options.always_make,
+        assume_new: options.what_if.iter().cloned().map(TargetName).collect(),
+        assume_old: options.assume_old.iter().cloned().map(TargetName).collect(),
+        hash_prerequisites: options.freshness == FreshnessCheck::Hash,
+        recorded_states: cache::read_target_states(),
+    });
+    let cached_target_graph = cache_key
+        .filter(|_| !options.always_make)
+        .and_then(cache::read_cached_graph);
+    if options.verbose && cached_target_graph.is_some() {
+        eprintln!("Using the cached graph");
+    }
+    let target_graph = match cached_target_graph {
+        Some(target_graph) => Ok(target_graph),
+        None => match options.format {
+            Format::Make => read_makefile(&options, &makefile_path_strs),
+            Format::Just => Ok(just::read_justfile(&makefile_path_str)),
+            Format::PackageJson => Ok(package_json::read_package_json(&makefile_path_str)),
+            Format::Taskfile => Ok(taskfile::read_taskfile(&makefile_path_str)),
+            Format::Ninja => Ok(ninja::read_ninja(&makefile_path_str)),
+        }
+        .inspect(|target_graph| {
+            if let Some(cache_key) = cache_key {
+                if let Err(error) = cache::write_cached_graph(cache_key, target_graph) {
+                    if options.verbose {
+                        eprintln!("Could not cache the graph: {}", error);
+                    }
+                }
+            }
+        }),
+    };
+    let mut target_graph = match target_graph {
+        Ok(target_graph) => target_graph,
+        Err(parse_error) => {
+            eprintln!("{}", parse_error);
+            exit(EXIT_CODE_PARSE_ERROR);
+        }
+    };
+    target_graph.symlinks = options.symlinks;
+    let makefiles = target_graph.makefiles.clone();
+    target_graph.edges = IndexMap::from_iter(
+        target_graph
+            .edges
+            .into_iter()
+            .filter(|edge| !edge.0 .0.starts_with('.') && !makefiles.contains(&edge.0 .0)),
+    );
+    if options.recursive_make && options.format == Format::Make {
+        let read_submakefile = |directory: &str| {
+            let mut args = vec![
+                "-prq".to_owned(),
+                "--no-print-directory".to_owned(),
+                "-C".to_owned(),
+                directory.to_owned(),
+            ];
+            args.extend(options.variable_overrides.iter().cloned());
+            read_make_database(args)
+        };
+        if let Err(parse_error) = recursive_make::splice_recursive_makes(
+            &mut target_graph,
+            options.verbose,
+            &read_submakefile,
+        ) {
+            eprintln!("{}", parse_error);
+            exit(EXIT_CODE_PARSE_ERROR);
+        }
+    }
 
+    if options.list {
+        print_target_list(&target_graph, &options.targets, &options.tags);
+        exit(0)
+    }
+
+    target_graph.instantiate_pattern_rules(
+        &options
+            .targets
+            .iter()
+            .chain(&options.where_target)
+            .map(|target_string| TargetName::normalized(target_string))
+            .collect::<Vec<TargetName>>(),
+    );
+
+    if options.lint {
+        let problems = lint::lint(&target_graph, options.format == Format::Make);
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&problems).expect("Could not print lint problems")
+        );
+        exit(if problems.is_empty() { 0 } else { 1 })
+    }
+    if let Some(where_target) = &options.where_target {
+        let target_name = TargetName::normalized(where_target);
+        if !target_graph.edges.contains_key(&target_name) {
+            eprintln!("Unknown target specified: {}", target_name);
+            exit(1)
+        }
+        println!(
+            "{}",
+            where_target::describe_target(&target_graph, &target_name)
+        );
+        exit(0)
+    }
     if options.print_graph {
         println!(
             "{}",
@@ -86,7 +510,46 @@ fn main() {
         exit(0)
     }
 
-    let target_names: Vec<TargetName> = if options.targets.is_empty() {
+    // Like for `make`, a target that is passed more than once is only built once.
+    let mut target_names: Vec<TargetName> = vec![];
+    extend_unique(
+        &mut target_names,
+        &options
+            .targets
+            .iter()
+            .map(|target_string| TargetName::normalized(target_string))
+            .collect::<Vec<TargetName>>(),
+    );
+    if !options.tags.is_empty() {
+        let tagged: Vec<TargetName> = target_graph
+            .edges
+            .keys()
+            .filter(|target_name| target_graph.has_tag(target_name, &options.tags))
+            .cloned()
+            .collect();
+        if tagged.is_empty() {
+            eprintln!("No targets have the tag: {}", options.tags.join(", "));
+            exit(1)
+        }
+        extend_unique(&mut target_names, &tagged);
+    }
+    if options.prune {
+        exit(
+            match prune::prune(&target_graph, options.dry_run, options.yes) {
+                true => 0,
+                false => 1,
+            },
+        )
+    }
+    if options.clean {
+        exit(
+            match clean::clean(&target_graph, &target_names, options.dry_run) {
+                true => 0,
+                false => 1,
+            },
+        )
+    }
+    if target_names.is_empty() {
         let default_target_name = match &target_graph.default_goal {
             Some(target_name) => target_name.clone(),
             None => {
@@ -94,35 +557,224 @@ fn main() {
                 exit(1)
             }
         };
-        vec![default_target_name]
-    } else {
-        options
-            .targets
-            .iter()
-            .map(|target_string| {
-                let target_name = TargetName(target_string.to_owned());
-                if !target_graph.edges.contains_key(&target_name) {
-                    eprintln!("Unknown target specified: {}", target_name);
-                    exit(1)
-                };
-                target_name
-            })
-            .collect()
-    };
+        target_names.push(default_target_name);
+    }
+    for target_name in &target_names {
+        if !target_graph.edges.contains_key(target_name) {
+            eprintln!("Unknown target specified: {}", target_name);
+            exit(1)
+        };
+    }
+    let mut skipped: IndexSet<TargetName> = target_graph
+        .edges
+        .keys()
+        .filter(|target_name| target_graph.has_tag(target_name, &options.skip_tags))
+        .cloned()
+        .collect();
+    if options.verbose {
+        for target_name in &skipped {
+            eprintln!("Skipping (tagged): {}", target_name);
+        }
+    }
+    for target_name in &freshness.assume_old {
+        if options.verbose {
+            eprintln!("Skipping (assumed old): {}", target_name);
+        }
+        skipped.insert(target_name.clone());
+    }
+    target_names.retain(|target_name| !skipped.contains(target_name));
+    if target_names.is_empty() {
+        eprintln!("All specified targets are skipped");
+        exit(0)
+    }
+
+    // Intermediate files are only deleted if this build creates them.
+    let intermediate_files: Vec<TargetName> = target_graph
+        .intermediate
+        .iter()
+        .filter(|target_name| {
+            target_graph.is_deletable_intermediate(target_name)
+                && modified_time(&target_name.0).is_none()
+        })
+        .cloned()
+        .collect();
+
+    if options.question {
+        if options.format != Format::Make {
+            eprintln!("`--question` is only supported for `--format make`");
+            exit(1);
+        }
+        let mut checked = HashMap::default();
+        for target_name in &target_names {
+            match executor::is_out_of_date(&target_graph, target_name, &freshness, &mut checked) {
+                Ok(false) => {}
+                Ok(true) => exit(1),
+                Err(missing) => {
+                    eprintln!("mak: *** No rule to make target '{}'.", missing);
+                    exit(EXIT_CODE_BUILD_FAILED)
+                }
+            }
+        }
+        exit(0)
+    }
+
+    if options.why {
+        if options.format != Format::Make {
+            eprintln!("`--why` is only supported for `--format make`");
+            exit(1);
+        }
+        why::explain(
+            &target_graph,
+            &target_names,
+            &freshness,
+            options.build_cache || options.cache_url.is_some(),
+        );
+        exit(0)
+    }
+
+    if options.touch {
+        if options.format != Format::Make {
+            eprintln!("`--touch` is only supported for `--format make`");
+            exit(1);
+        }
+        let mut visited = HashSet::default();
+        for target_name in &target_names {
+            if let Err(missing) =
+                executor::touch_out_of_date(&target_graph, target_name, &freshness, &mut visited)
+            {
+                eprintln!("mak: *** No rule to make target '{}'.", missing);
+                exit(EXIT_CODE_BUILD_FAILED)
+            }
+        }
+        exit(0)
+    }
 
     let multi_progress = Arc::new(MultiProgress::new());
+    // Progress bars show the order in which recipes finish.
+    if options.deterministic {
+        multi_progress.set_draw_target(ProgressDrawTarget::hidden());
+    }
+
+    let log_directory = match options.dry_run {
+        true => None,
+        false => {
+            let log_directory = options
+                .log_dir
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_LOG_DIRECTORY));
+            match std::fs::create_dir_all(&log_directory) {
+                Ok(()) => Some(log_directory),
+                Err(error) => {
+                    eprintln!(
+                        "Could not create the log directory `{}` (no logs will be written): {}",
+                        log_directory.display(),
+                        error
+                    );
+                    None
+                }
+            }
+        }
+    };
+
+    let limits = JobLimits {
+        memory: options.job_memory_limit,
+        cpus: options.job_cpu_limit,
+    };
+    let job_cgroups = match limits.memory.is_some() || limits.cpus.is_some() {
+        true if !options.dry_run => match JobCgroups::set_up(limits) {
+            Ok(job_cgroups) => Some(job_cgroups),
+            Err(error) => {
+                eprintln!("Could not set up the per-job limits: {}", error);
+                exit(1);
+            }
+        },
+        _ => None,
+    };
+
+    if options.sandbox && !options.dry_run {
+        if let Err(error) = sandbox::check_support() {
+            eprintln!("Could not sandbox recipes: {}", error);
+            exit(1);
+        }
+    }
+    if options.trace_dependencies && !options.dry_run {
+        if let Err(error) = file_access::check_support() {
+            eprintln!("Could not trace recipes: {}", error);
+            exit(1);
+        }
+    }
+
+    let worker_pool = options
+        .workers
+        .as_deref()
+        .map(|path| WorkerPool::new(remote::read_workers_file(path)));
+    let worker_slots = worker_pool
+        .as_ref()
+        .map(|worker_pool| worker_pool.total_slots())
+        .unwrap_or_default();
+
+    // Only `make` processes use it.
+    let jobserver = match (options.format, options.job_limit) {
+        (Format::Make, Some(job_limit)) if !options.dry_run => Jobserver::new(job_limit),
+        _ => None,
+    };
+    let jobserver_make_flags = jobserver
+        .as_ref()
+        .map(|jobserver| jobserver.make_flags(&std::env::var("MAKEFLAGS").unwrap_or_default()));
 
     let mut shared_make = SharedMake {
         multi_progress: multi_progress.clone(),
         futures: HashMap::default(),
-        target_graph,
-        makefile_path_str,
+        format: options.format,
+        executor: options.executor,
+        makefile_path_strs,
+        variable_overrides: options.variable_overrides.clone(),
+        delete_on_error: options.delete_on_error || target_graph.delete_on_error,
+        skipped,
+        keep_going: options.keep_going,
+        output_mode: options.output,
+        timeout: options.timeout,
+        retries: options.retries,
+        retry_delay: options.retry_delay,
+        nice: options.nice,
+        io_priority: options.ionice,
+        job_cgroups,
+        sandbox: options.sandbox,
+        trace_dependencies: options.trace_dependencies,
+        hermetic: options.hermetic,
+        build_cache: (options.build_cache || options.cache_url.is_some())
+            .then(|| Arc::new(BuildCache::new(options.cache_url.as_deref()))),
+        freshness,
+        container_runtime: options.container_runtime.clone(),
+        worker_pool,
+        jobserver,
+        jobserver_make_flags,
+        log_directory,
+        stopped: Arc::default(),
+        failed: Arc::default(),
+        not_built: Arc::default(),
+        attempts: Arc::default(),
+        resources: Resources::new(&options.resource),
+        job_slots: options
+            .job_limit
+            .map(|job_limit| JobSlots::new(job_limit + worker_slots)),
+        priorities: HashMap::default(),
+        shuffle: options.shuffle,
+        output_order: None,
+        recipe_durations: Arc::default(),
+        new_states: (options.freshness == FreshnessCheck::Hash
+            || !target_graph.environment_inputs.is_empty())
+        .then(Arc::default),
+        written_outputs: Arc::default(),
+        journal: Arc::new(Journal::new(options.resume)),
+        outcomes: options.manifest.is_some().then(Arc::default),
+        target_graph: Arc::new(target_graph),
     };
 
-    block_on(shared_make.make_targets(&target_names));
-    let num_main_targets = target_names.len();
-    let num_dependencies = shared_make.futures.len() - num_main_targets;
     if options.dry_run {
+        let num_targets = shared_make.dry_run(&target_names);
+        let num_main_targets = target_names.len();
+        let num_dependencies = num_targets - num_main_targets;
         println!(
             "Dry run found {} target{} and {} additional dependenc{} in {:?}",
             num_main_targets,
@@ -131,56 +783,852 @@ fn main() {
             if num_dependencies == 1 { "y" } else { "ies" },
             Instant::now() - start_time
         );
-    } else {
+        exit(0)
+    }
+
+    let output_conflicts =
+        output_conflicts::declared_conflicts(&shared_make.target_graph, &target_names);
+    for (path, writers) in &output_conflicts {
+        let writers: Vec<String> = writers
+            .iter()
+            .map(|writer| format!("`{}`", writer))
+            .collect();
+        eprintln!(
+            "Output conflict: `{}` is written by the recipes of {}",
+            path,
+            writers.join(" and ")
+        );
+    }
+    if !output_conflicts.is_empty() && !options.allow_output_conflicts {
+        eprintln!("These recipes race when they run in parallel. Pass `--allow-output-conflicts` to build anyway.");
+        exit(EXIT_CODE_BUILD_FAILED)
+    }
+
+    if let Some(shuffle) = options.shuffle {
         println!(
-            "Built {} target{} and {} additional dependenc{} in {:?}",
-            num_main_targets,
-            if num_main_targets == 1 { "" } else { "s" },
-            num_dependencies,
-            if num_dependencies == 1 { "y" } else { "ies" },
-            Instant::now() - start_time
+            "🔀 Shuffling the order of prerequisites (pass `{}` to use the same order again)",
+            shuffle.argument()
         );
     }
+    processes::handle_interrupts(multi_progress);
+    shared_make.journal.start();
+    block_on(shared_make.make_targets(&target_names, options.deterministic));
+    // Before intermediate files are deleted.
+    let changed_outputs = shared_make.written_outputs.changed_outputs();
+    for message in &changed_outputs {
+        eprintln!("Output conflict: {}", message);
+    }
+    let recipe_durations = shared_make
+        .recipe_durations
+        .lock()
+        .expect("Could not read how long recipes took.")
+        .clone();
+    if !recipe_durations.is_empty() {
+        if let Err(error) = cache::write_recipe_durations(&recipe_durations) {
+            if options.verbose {
+                eprintln!("Could not store how long recipes took: {}", error);
+            }
+        }
+    }
+    if let Some(build_cache) = &shared_make.build_cache {
+        if let Err(error) = build_cache.write_statistics() {
+            if options.verbose {
+                eprintln!("Could not store the build cache statistics: {}", error);
+            }
+        }
+        let deleted = build_cache.collect_garbage(options.cache_max_size, options.cache_max_age);
+        if options.verbose && !deleted.is_empty() {
+            eprintln!(
+                "Deleted {} least recently used entr{} from the build cache",
+                deleted.len(),
+                if deleted.len() == 1 { "y" } else { "ies" }
+            );
+        }
+    }
+    if let Some(new_states) = &shared_make.new_states {
+        let new_states = new_states
+            .lock()
+            .expect("Could not read the target states.");
+        if let Err(error) = cache::write_target_states(&new_states) {
+            eprintln!("Could not store the state of the targets: {}", error);
+        }
+    }
+    if let (Some(manifest_path), Some(outcomes)) = (&options.manifest, &shared_make.outcomes) {
+        if let Err(error) =
+            manifest::write_manifest(manifest_path, &shared_make.target_graph, outcomes)
+        {
+            eprintln!("Could not write `{}`: {}", manifest_path.display(), error);
+        }
+    }
+    for intermediate_file in intermediate_files {
+        if modified_time(&intermediate_file.0).is_some() {
+            eprintln!("Deleting intermediate file: {}", intermediate_file);
+            if let Err(error) = std::fs::remove_file(&intermediate_file.0) {
+                eprintln!("Could not delete `{}`: {}", intermediate_file, error);
+            }
+        }
+    }
+    let failed = shared_make
+        .failed
+        .lock()
+        .expect("Could not read the failed targets.")
+        .clone();
+    let attempts = shared_make
+        .attempts
+        .lock()
+        .expect("Could not read the number of attempts.")
+        .clone();
+    let retried: Vec<TargetName> = attempts
+        .keys()
+        .filter(|target_name| !failed.contains(target_name))
+        .cloned()
+        .collect();
+    if !retried.is_empty() {
+        println!(
+            "🔁 {} target{} only succeeded after retrying: {}",
+            retried.len(),
+            if retried.len() == 1 { "" } else { "s" },
+            target_list(&retried, &attempts)
+        );
+    }
+    if !failed.is_empty() {
+        let not_built = shared_make
+            .not_built
+            .lock()
+            .expect("Could not read the targets that were not built.")
+            .clone();
+        println!(
+            "❌ {} target{} failed: {}",
+            failed.len(),
+            if failed.len() == 1 { "" } else { "s" },
+            target_list(&failed, &attempts)
+        );
+        if !not_built.is_empty() {
+            println!(
+                "🚫 {} target{} not built because of the failure{}: {}",
+                not_built.len(),
+                if not_built.len() == 1 {
+                    " was"
+                } else {
+                    "s were"
+                },
+                if failed.len() == 1 { "" } else { "s" },
+                target_list(&not_built, &attempts)
+            );
+        }
+        exit(EXIT_CODE_BUILD_FAILED)
+    }
+    if !changed_outputs.is_empty() && !options.allow_output_conflicts {
+        exit(EXIT_CODE_BUILD_FAILED)
+    }
+    shared_make.journal.remove();
+    let num_main_targets = target_names.len();
+    let num_dependencies = shared_make
+        .futures
+        .keys()
+        .filter(|target_name| !shared_make.skipped.contains(*target_name))
+        .count()
+        - num_main_targets;
+    let duration = match options.deterministic {
+        true => String::new(),
+        false => format!(" in {:?}", Instant::now() - start_time),
+    };
+    println!(
+        "Built {} target{} and {} additional dependenc{}{}",
+        num_main_targets,
+        if num_main_targets == 1 { "" } else { "s" },
+        num_dependencies,
+        if num_dependencies == 1 { "y" } else { "ies" },
+        duration
+    );
+}
+
+// With the number of attempts for targets that were retried.
+fn target_list(target_names: &[TargetName], attempts: &IndexMap<TargetName, u32>) -> String {
+    target_names
+        .iter()
+        .map(|target_name| match attempts.get(target_name) {
+            Some(attempts) => format!("{} ({} attempts)", target_name, attempts),
+            None => target_name.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// Deletes the files that a failed recipe changed (`.DELETE_ON_ERROR`).
+fn delete_changed_files(files: &[String], original_modified_times: Vec<Option<SystemTime>>) {
+    for (file, original_modified_time) in files.iter().zip(original_modified_times) {
+        let modified_time = modified_time(file);
+        if modified_time.is_some() && modified_time != original_modified_time {
+            eprintln!("Deleting file: {}", file);
+            if let Err(error) = std::fs::remove_file(file) {
+                eprintln!("Could not delete `{}`: {}", file, error);
+            }
+        }
+    }
+}
+
+// Formats a command like it would be typed in a shell.
+fn command_line(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|word| shell_quote(&word.to_string_lossy()))
+        .collect::<Vec<String>>()
+        .join(" ")
 }
 
-type SharedFuture = futures::future::Shared<JoinHandle<()>>;
+// Resolves to whether the target is up to date (i.e. it was built or skipped using `--skip-tag`).
+type SharedFuture = futures::future::Shared<JoinHandle<bool>>;
+// Held while running a recipe in a `.NOTPARALLEL` subgraph.
+type SerialLock = Arc<Mutex<()>>;
 
 struct SharedMake {
     multi_progress: Arc<MultiProgress>,
     futures: HashMap<TargetName, SharedFuture>,
-    target_graph: TargetGraph,
-    makefile_path_str: Option<String>,
+    // Shared with the tasks that run recipes directly.
+    target_graph: Arc<TargetGraph>,
+    format: Format,
+    executor: Executor,
+    makefile_path_strs: Vec<String>,
+    variable_overrides: Vec<String>,
+    delete_on_error: bool,
+    // Targets with a `--skip-tag` tag, which are never built.
+    skipped: IndexSet<TargetName>,
+    keep_going: bool,
+    output_mode: OutputMode,
+    // From `--timeout`, for targets without a `# mak:timeout=…` comment.
+    timeout: Option<Duration>,
+    // From `--retries`, for targets without a `# mak:retries=…` comment.
+    retries: u32,
+    retry_delay: Option<Duration>,
+    // From `--nice` and `--ionice`, for targets without `# mak:nice=…`/`# mak:ionice=…` comments.
+    nice: Option<i32>,
+    io_priority: Option<IoPriority>,
+    // For `--job-memory-limit` and `--job-cpu-limit`.
+    job_cgroups: Option<Arc<JobCgroups>>,
+    sandbox: bool,
+    trace_dependencies: bool,
+    // With `--hermetic`, for the recipes' own `TMPDIR`.
+    hermetic: bool,
+    // From `--build-cache` and `--cache-url`.
+    build_cache: Option<Arc<BuildCache>>,
+    // From `--always-make`, `--what-if`, and `--assume-old`.
+    freshness: Arc<Freshness>,
+    container_runtime: String,
+    // For `--workers`.
+    worker_pool: Option<Arc<WorkerPool>>,
+    // The `MAKEFLAGS` that advertise the jobserver.
+    jobserver: Option<Arc<Jobserver>>,
+    jobserver_make_flags: Option<String>,
+    // `None` if the logs can't be written.
+    log_directory: Option<PathBuf>,
+    // Set once a recipe fails (without `--keep-going`), so that no more recipes are started.
+    stopped: Arc<AtomicBool>,
+    failed: Arc<std::sync::Mutex<Vec<TargetName>>>,
+    // Targets that were not built because a prerequisite failed (or, without `--keep-going`, anything did).
+    not_built: Arc<std::sync::Mutex<Vec<TargetName>>>,
+    // The number of times each retried target was run.
+    attempts: Arc<std::sync::Mutex<IndexMap<TargetName, u32>>>,
+    // `# mak:resources=…`, with the capacities from `--resource`.
+    resources: Arc<Resources>,
+    // `None` if there is no `--jobs` limit.
+    job_slots: Option<Arc<JobSlots>>,
+    // Which recipes get a job slot first: the ones on the longest chain of recipes (by how long they took last time)
+    // that still has to run before the goals are done.
+    priorities: HashMap<TargetName, u64>,
+    shuffle: Option<Shuffle>,
+    // With `--deterministic`.
+    output_order: Option<Arc<OutputOrder>>,
+    // How long each recipe that succeeded took, to prioritize them in the next run.
+    recipe_durations: Arc<std::sync::Mutex<IndexMap<TargetName, Duration>>>,
+    // With `--freshness hash` or `# mak:env=…` comments: the states of the targets that are up to date after this run.
+    new_states: Option<Arc<std::sync::Mutex<TargetStates>>>,
+    written_outputs: Arc<WrittenOutputs>,
+    // For `--resume`.
+    journal: Arc<Journal>,
+    // For `--manifest`.
+    outcomes: Option<Arc<Outcomes>>,
 }
 
 impl SharedMake {
-    async fn make_targets(&mut self, target_names: &[TargetName]) {
-        join_all(
-            target_names
+    // With `--executor direct`, recipes are run without `make`, except for the ones that `make` needs to run.
+    fn runs_directly(&self, target_name: &TargetName) -> bool {
+        // TODO: `::` rules.
+        self.format == Format::Make
+            && self.executor == Executor::Direct
+            && !self
+                .target_graph
+                .recursive_make_targets
+                .contains(target_name)
+            && !self.target_graph.submake_targets.contains_key(target_name)
+            && !self
+                .target_graph
+                .double_colon_rules
+                .contains_key(target_name)
+    }
+
+    // Whether `mak` can tell if `group` is up to date without starting `make` (like for `--executor direct`).
+    fn checks_freshness(&self, group: &[TargetName]) -> bool {
+        self.format == Format::Make
+            && self.executor == Executor::Make
+            && group.iter().all(|member| {
+                !self.target_graph.recursive_make_targets.contains(member)
+                    && !self.target_graph.submake_targets.contains_key(member)
+                    && !self.target_graph.double_colon_rules.contains_key(member)
+            })
+    }
+
+    fn is_cacheable(&self, group: &[TargetName]) -> bool {
+        self.format == Format::Make && build_cache::is_cacheable(&self.target_graph, group)
+    }
+
+    // Whether the recipe for `group` can run on a worker: it needs declared targets to copy back.
+    fn runs_remotely(&self, group: &[TargetName]) -> bool {
+        self.format == Format::Make
+            && group.iter().all(|member| {
+                self.target_graph.has_recipe(member)
+                    && !self.target_graph.images.contains_key(member)
+                    && !self.target_graph.is_phony(member)
+                    && !self.target_graph.local_only.contains(member)
+                    && !self.target_graph.recursive_make_targets.contains(member)
+                    && !self.target_graph.submake_targets.contains_key(member)
+                    && !self.target_graph.double_colon_rules.contains_key(member)
+            })
+    }
+
+    // The remaining chain of `target_name`: its own weight plus the longest remaining chain of the targets that depend on
+    // it, in milliseconds. Recipes that never ran count as taking a second.
+    fn critical_path_priority(
+        &self,
+        target_name: &TargetName,
+        dependents: &HashMap<TargetName, Vec<TargetName>>,
+        previous_durations: &IndexMap<TargetName, Duration>,
+        priorities: &mut HashMap<TargetName, u64>,
+    ) -> u64 {
+        if let Some(priority) = priorities.get(target_name) {
+            return *priority;
+        }
+        // Breaks cycles.
+        priorities.insert(target_name.clone(), 0);
+        let weight = match previous_durations.get(target_name) {
+            Some(duration) => duration.as_millis() as u64,
+            None if self.target_graph.has_recipe(target_name) => 1000,
+            None => 0,
+        };
+        let dependents_priority = dependents
+            .get(target_name)
+            .into_iter()
+            .flatten()
+            .map(|dependent| {
+                self.critical_path_priority(dependent, dependents, previous_durations, priorities)
+            })
+            .max()
+            .unwrap_or_default();
+        let priority = weight + dependents_priority;
+        priorities.insert(target_name.clone(), priority);
+        priority
+    }
+
+    fn critical_path_priorities(&self, target_names: &[TargetName]) -> HashMap<TargetName, u64> {
+        let mut dependents = HashMap::<TargetName, Vec<TargetName>>::new();
+        let mut queue: Vec<TargetName> = target_names.to_vec();
+        let mut seen: IndexSet<TargetName> = queue.iter().cloned().collect();
+        while let Some(target_name) = queue.pop() {
+            for dependency in self.group_and_dependencies(&target_name).1 {
+                dependents
+                    .entry(dependency.clone())
+                    .or_default()
+                    .push(target_name.clone());
+                if seen.insert(dependency.clone()) {
+                    queue.push(dependency);
+                }
+            }
+        }
+        let previous_durations = cache::read_recipe_durations();
+        let mut priorities = HashMap::new();
+        for target_name in &seen {
+            self.critical_path_priority(
+                target_name,
+                &dependents,
+                &previous_durations,
+                &mut priorities,
+            );
+        }
+        priorities
+    }
+
+    // The groups of targets in the order that `make -j1` would run them: each one after its prerequisites. Follows
+    // `make_target`.
+    fn sequential_order(
+        &self,
+        target_name: &TargetName,
+        visited: &mut IndexSet<TargetName>,
+        order: &mut Vec<Vec<TargetName>>,
+    ) {
+        if !visited.insert(target_name.clone()) || self.skipped.contains(target_name) {
+            return;
+        }
+        let (group, dependencies) = self.group_and_dependencies(target_name);
+        visited.extend(group.iter().cloned());
+        let wait_groups = self
+            .target_graph
+            .wait_groups
+            .get(target_name)
+            .cloned()
+            .unwrap_or_default();
+        for dependency in wait_groups.iter().flatten().chain(&dependencies) {
+            self.sequential_order(dependency, visited, order);
+        }
+        order.push(group);
+    }
+
+    async fn make_targets(&mut self, target_names: &[TargetName], deterministic: bool) {
+        if deterministic {
+            let mut visited = IndexSet::new();
+            let mut order = vec![];
+            for target_name in target_names {
+                self.sequential_order(target_name, &mut visited, &mut order);
+            }
+            self.output_order = Some(OutputOrder::new(&order));
+        }
+        if self.job_slots.is_some() && self.shuffle.is_none() && !deterministic {
+            self.priorities = self.critical_path_priorities(target_names);
+        }
+        let mut target_names = target_names.to_vec();
+        if let Some(shuffle) = self.shuffle {
+            shuffle.apply("", &mut target_names);
+        }
+        let serial_lock: Option<SerialLock> = self
+            .target_graph
+            .not_parallel
+            .as_ref()
+            .is_some_and(Vec::is_empty)
+            .then(SerialLock::default);
+        let join_handles: Vec<SharedFuture> = target_names
+            .iter()
+            .map(|target_name| self.make_target(target_name, 0, serial_lock.clone(), vec![]))
+            .collect();
+        if let Some(job_slots) = &self.job_slots {
+            job_slots.open();
+        }
+        join_all(join_handles).await;
+        if let Some(output_order) = &self.output_order {
+            output_order.flush();
+        }
+    }
+
+    // All members of a grouped target share one invocation (and future), so it waits for all of their prerequisites.
+    fn group_and_dependencies(
+        &self,
+        target_name: &TargetName,
+    ) -> (Vec<TargetName>, Vec<TargetName>) {
+        let group: Vec<TargetName> = match self.target_graph.target_group(target_name) {
+            Some(group) => group.to_vec(),
+            None => vec![target_name.clone()],
+        };
+        let mut dependencies: Vec<TargetName> = vec![];
+        for member in &group {
+            for dependency in self.target_graph.all_prerequisites(member) {
+                if !dependencies.contains(&dependency) && !group.contains(&dependency) {
+                    dependencies.push(dependency);
+                }
+            }
+        }
+        (group, dependencies)
+    }
+
+    // The wave of `target_name` in a dry run: 1 for targets without prerequisites, otherwise one more than the latest
+    // wave of its prerequisites (and at least `barrier_wave`, for prerequisites after a `.WAIT`). Like for
+    // `make_target`, the first time a target is reached determines its wave.
+    fn dry_run_wave(
+        &self,
+        target_name: &TargetName,
+        barrier_wave: usize,
+        waves: &mut IndexMap<TargetName, usize>,
+    ) -> usize {
+        if let Some(wave) = waves.get(target_name) {
+            return *wave;
+        }
+        if self.skipped.contains(target_name) {
+            return 0;
+        }
+        // Breaks cycles (which `make` drops).
+        waves.insert(target_name.clone(), 0);
+        let (group, dependencies) = self.group_and_dependencies(target_name);
+        let mut wave = barrier_wave.max(1);
+        let mut wait_barrier = 0;
+        for wait_group in self
+            .target_graph
+            .wait_groups
+            .get(target_name)
+            .cloned()
+            .unwrap_or_default()
+        {
+            let wait_group_wave = wait_group
                 .iter()
-                .map(|target_name| self.make_target(target_name, 0)),
-        )
-        .await;
+                .map(|dependency| self.dry_run_wave(dependency, wait_barrier + 1, waves))
+                .max()
+                .unwrap_or_default();
+            wait_barrier = wait_barrier.max(wait_group_wave);
+        }
+        for dependency in &dependencies {
+            wave = wave.max(self.dry_run_wave(dependency, 0, waves) + 1);
+        }
+        for member in group {
+            waves.insert(member, wave);
+        }
+        wave
     }
 
-    fn make_target(&mut self, target_name: &TargetName, depth: usize) -> SharedFuture {
+    /// Prints the commands that would be run for `target_names` and their prerequisites, without running anything. The
+    /// targets are grouped into waves, where each target only depends on targets in earlier waves (so the targets of a
+    /// wave can run at the same time). Returns the number of targets.
+    fn dry_run(&self, target_names: &[TargetName]) -> usize {
+        let mut waves = IndexMap::<TargetName, usize>::new();
+        for target_name in target_names {
+            self.dry_run_wave(target_name, 0, &mut waves);
+        }
+        let num_waves = waves.values().copied().max().unwrap_or_default();
+        let mut printed = IndexSet::<TargetName>::new();
+        // With `--what-if` and `--assume-old`, only the targets that would be rebuilt are listed.
+        let mut out_of_date = (self.format == Format::Make
+            && !(self.freshness.assume_new.is_empty() && self.freshness.assume_old.is_empty()))
+        .then(HashMap::default);
+        for wave in 1..=num_waves {
+            println!("Wave {}:", wave);
+            for target_name in waves
+                .iter()
+                .filter(|(_, target_wave)| **target_wave == wave)
+                .map(|(target_name, _)| target_name)
+            {
+                let (group, mut dependencies) = self.group_and_dependencies(target_name);
+                if !printed.insert(group[0].clone()) {
+                    continue;
+                }
+                if let Some(checked) = &mut out_of_date {
+                    let rebuilt = group.iter().any(|member| {
+                        executor::is_out_of_date(
+                            &self.target_graph,
+                            member,
+                            &self.freshness,
+                            checked,
+                        )
+                        .unwrap_or(true)
+                    });
+                    if !rebuilt {
+                        continue;
+                    }
+                }
+                // Files without a rule don't need anything to be run.
+                if self.format == Format::Make
+                    && dependencies.is_empty()
+                    && !group
+                        .iter()
+                        .any(|member| self.target_graph.has_recipe(member))
+                {
+                    continue;
+                }
+                if self.target_graph.wait_groups.contains_key(target_name) {
+                    dependencies.push(TargetName(WAIT.to_owned()));
+                }
+                let label = group
+                    .iter()
+                    .map(TargetName::to_string)
+                    .collect::<Vec<String>>()
+                    .join(" & ");
+                match self.runs_directly(target_name) {
+                    true => println!("  {}", label),
+                    false => {
+                        let command = individual_target_command(
+                            &self.target_graph,
+                            self.format,
+                            &dependencies,
+                            &self.makefile_path_strs,
+                            &self.variable_overrides,
+                            &self.freshness,
+                            target_name,
+                        );
+                        println!("  {}: {}", label, command_line(&command));
+                    }
+                }
+                if self.format != Format::Make {
+                    continue;
+                }
+                match self.target_graph.double_colon_rules.get(target_name) {
+                    // TODO: expand the recipes of `::` rules.
+                    Some(double_colon_rules) => {
+                        for recipe_line in double_colon_rules.iter().flat_map(|rule| &rule.recipe) {
+                            println!("      {}", recipe_line);
+                        }
+                    }
+                    None => {
+                        for invocation in self.target_graph.shell_invocations(target_name) {
+                            for script_line in invocation.script.lines() {
+                                println!("      {}", script_line);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        waves.len()
+    }
+
+    fn make_target(
+        &mut self,
+        target_name: &TargetName,
+        depth: usize,
+        serial_lock: Option<SerialLock>,
+        barrier: Vec<SharedFuture>,
+    ) -> SharedFuture {
         if let Some(sender) = self.futures.get(target_name) {
             // TODO: update depth if it decreased?
+            // TODO: serialize with the caller's `.NOTPARALLEL` subgraph or `.WAIT` barrier if this was scheduled outside of it.
             return sender.clone();
         }
+        // Dependents are built as if skipped targets were up to date.
+        if self.skipped.contains(target_name) {
+            let join_handle = task::spawn(async { true }).shared();
+            self.futures
+                .insert(target_name.clone(), join_handle.clone());
+            return join_handle;
+        }
 
-        let dependencies = self
+        assert!(
+            self.target_graph.edges.contains_key(target_name),
+            "Internal error: Unexpectedly missing a target"
+        );
+        let (group, mut dependencies) = self.group_and_dependencies(target_name);
+        let serial_lock = serial_lock.or_else(|| {
+            self.target_graph
+                .not_parallel
+                .as_ref()
+                .is_some_and(|not_parallel| not_parallel.contains(target_name))
+                .then(SerialLock::default)
+        });
+        // Each group of prerequisites separated by `.WAIT` starts once all the groups before it are done.
+        let mut wait_barrier: Vec<SharedFuture> = vec![];
+        let wait_groups = self
             .target_graph
-            .edges
+            .wait_groups
             .get(target_name)
-            .expect("Internal error: Unexpectedly missing a target")
-            .clone();
-        let dependency_handles: Vec<SharedFuture> = dependencies
+            .cloned()
+            .unwrap_or_default();
+        for mut wait_group in wait_groups {
+            if let Some(shuffle) = self.shuffle {
+                shuffle.apply(&target_name.0, &mut wait_group);
+            }
+            let wait_group_handles: Vec<SharedFuture> = wait_group
+                .iter()
+                .map(|dependency| {
+                    self.make_target(
+                        dependency,
+                        depth + 1,
+                        serial_lock.clone(),
+                        wait_barrier.clone(),
+                    )
+                })
+                .collect();
+            wait_barrier.extend(wait_group_handles);
+        }
+        // Only the order in which they are scheduled changes, not the `make` command.
+        let mut scheduled_dependencies = dependencies.clone();
+        if let Some(shuffle) = self.shuffle {
+            shuffle.apply(&target_name.0, &mut scheduled_dependencies);
+        }
+        let dependency_handles: Vec<SharedFuture> = scheduled_dependencies
             .iter()
-            .map(|target_name| (self.make_target(target_name, depth + 1)))
+            .map(|dependency| self.make_target(dependency, depth + 1, serial_lock.clone(), vec![]))
             .collect();
-        let makefile_path_str_owned = self.makefile_path_str.to_owned();
+        if self.target_graph.wait_groups.contains_key(target_name) {
+            // `make` before 4.4 treats `.WAIT` as a missing file.
+            dependencies.push(TargetName(WAIT.to_owned()));
+        }
+        let command = individual_target_command(
+            &self.target_graph,
+            self.format,
+            &dependencies,
+            &self.makefile_path_strs,
+            &self.variable_overrides,
+            &self.freshness,
+            target_name,
+        );
+        let direct_target_graph = self
+            .runs_directly(target_name)
+            .then(|| self.target_graph.clone());
+        // `make` is told that the prerequisites are up to date (with `-o`), which also makes it ignore whether they are
+        // newer than the target. So `mak` checks that itself, which also saves starting `make` for fresh targets.
+        let make_freshness = self.checks_freshness(&group).then(|| {
+            let target_graph = self.target_graph.clone();
+            let makefile_path_strs = self.makefile_path_strs.clone();
+            let variable_overrides = self.variable_overrides.clone();
+            let freshness = self.freshness.clone();
+            let dependencies = dependencies.clone();
+            let target_name = target_name.clone();
+            // `always_make` is set if a `# mak:env=…` variable changed (which `make` can't know about), or if the sandbox
+            // creates placeholders for the targets (which `make` would see as up to date).
+            let rebuild_command = move |changed: Vec<TargetName>, always_make: bool| {
+                let mut assume_new = freshness.assume_new.clone();
+                extend_unique(&mut assume_new, &changed);
+                individual_target_command(
+                    &target_graph,
+                    Format::Make,
+                    &dependencies,
+                    &makefile_path_strs,
+                    &variable_overrides,
+                    &Freshness {
+                        always_make: freshness.always_make || always_make,
+                        assume_new,
+                        assume_old: freshness.assume_old.clone(),
+                        ..Freshness::default()
+                    },
+                    &target_name,
+                )
+            };
+            (self.target_graph.clone(), group.clone(), rebuild_command)
+        });
         let target_name_owned = target_name.clone();
         let multi_progress_owned = self.multi_progress.clone();
+        let job_slots = self.job_slots.clone();
+        let resources = self.resources.clone();
+        let resource_names: Vec<String> = group
+            .iter()
+            .filter_map(|member| self.target_graph.resources.get(member))
+            .flatten()
+            .cloned()
+            .collect();
+        let priority: Priority = (
+            match (self.shuffle, &self.output_order) {
+                (Some(shuffle), _) => shuffle.priority(&target_name.0),
+                // The earlier in the order, the sooner.
+                (None, Some(output_order)) => {
+                    u64::MAX - output_order.position(target_name).unwrap_or_default() as u64
+                }
+                (None, None) => self
+                    .priorities
+                    .get(target_name)
+                    .copied()
+                    .unwrap_or_default(),
+            },
+            group
+                .iter()
+                .find_map(|member| self.target_graph.priorities.get(member).copied())
+                .unwrap_or_default(),
+        );
+        let recipe_durations = self.recipe_durations.clone();
+        let record_outcome = {
+            let outcomes = self.outcomes.clone();
+            let group = group.clone();
+            move |outcome: Outcome| {
+                if let Some(outcomes) = &outcomes {
+                    outcomes.record(&group, outcome);
+                }
+            }
+        };
+        let mut turn = self
+            .output_order
+            .as_ref()
+            .map(|output_order| output_order.turn(target_name));
+        let written_outputs = (self.written_outputs.clone(), self.target_graph.clone());
+        let journal = group
+            .iter()
+            .any(|member| self.target_graph.has_recipe(member))
+            .then(|| (self.journal.clone(), self.target_graph.clone()));
+        let stamp_target_graph = group
+            .iter()
+            .any(|member| self.target_graph.stamp_targets.contains(member))
+            .then(|| self.target_graph.clone());
+        let record_state = {
+            let new_states = self.new_states.clone();
+            let target_graph = self.target_graph.clone();
+            let group = group.clone();
+            let hash_prerequisites = self.freshness.hash_prerequisites;
+            move || {
+                let Some(new_states) = &new_states else {
+                    return;
+                };
+                let mut new_states = new_states
+                    .lock()
+                    .expect("Could not record the state of a target.");
+                for member in group.iter().filter(|member| !target_graph.is_phony(member)) {
+                    let target_state = TargetState {
+                        prerequisites: match hash_prerequisites {
+                            true => cache::prerequisite_hashes(&target_graph, member),
+                            false => IndexMap::new(),
+                        },
+                        variables: cache::variable_hashes(&target_graph, member),
+                    };
+                    if !target_state.prerequisites.is_empty() || !target_state.variables.is_empty()
+                    {
+                        new_states.insert(member.clone(), target_state);
+                    }
+                }
+            }
+        };
+        // Only when `mak` decides whether the recipe runs.
+        let sandbox_placeholders =
+            self.sandbox && (self.runs_directly(target_name) || self.checks_freshness(&group));
+        let command_setup = CommandSetup {
+            jobserver_make_flags: self.jobserver_make_flags.clone(),
+            hermetic: self.hermetic,
+            trace_target_graph: self.trace_dependencies.then(|| self.target_graph.clone()),
+            sandbox_target_graph: self.sandbox.then(|| self.target_graph.clone()),
+            sandbox_placeholders,
+            container: group
+                .iter()
+                .find_map(|member| self.target_graph.images.get(member).cloned())
+                .map(|image| (self.container_runtime.clone(), image)),
+            nice: group
+                .iter()
+                .find_map(|member| self.target_graph.nice.get(member).copied())
+                .or(self.nice),
+            io_priority: group
+                .iter()
+                .find_map(|member| self.target_graph.io_priorities.get(member).copied())
+                .or(self.io_priority),
+            job_cgroups: self.job_cgroups.clone(),
+        };
+        let build_cache = self
+            .build_cache
+            .clone()
+            .filter(|_| self.is_cacheable(&group))
+            .map(|build_cache| (build_cache, self.target_graph.clone()));
+        let sandbox_group = group.clone();
+        let jobserver = self.jobserver.clone();
+        let freshness = self.freshness.clone();
+        let remote = self
+            .worker_pool
+            .clone()
+            .filter(|_| self.runs_remotely(&group))
+            .map(|worker_pool| (worker_pool, self.target_graph.clone()));
+        let output_mode = self.output_mode;
+        let timeout = group
+            .iter()
+            .find_map(|member| self.target_graph.timeouts.get(member).copied())
+            .or(self.timeout);
+        let retries = group
+            .iter()
+            .find_map(|member| self.target_graph.retries.get(member).copied())
+            .unwrap_or(self.retries);
+        let retry_delay = self.retry_delay.unwrap_or_default();
+        let attempts = self.attempts.clone();
+        let log_path = self
+            .log_directory
+            .as_ref()
+            .map(|log_directory| log_directory.join(log_file_name(target_name)));
+        let stopped = self.stopped.clone();
+        let not_built = self.not_built.clone();
+        // Target files to delete if the recipe fails and changes them (`.DELETE_ON_ERROR`).
+        let deletable_files: Vec<String> = match self.delete_on_error {
+            true => group
+                .iter()
+                .filter(|member| {
+                    !self.target_graph.is_phony(member) && !self.target_graph.is_precious(member)
+                })
+                .map(|member| member.0.clone())
+                .collect(),
+            false => vec![],
+        };
 
         let progress_bar = ProgressBar::new(2);
         let progress_bar = multi_progress_owned.insert_from_back(0, progress_bar);
@@ -193,173 +1641,949 @@ impl SharedMake {
             0 => "🎯".to_owned(),
             depth => format!("{}{} ", "  ".repeat(depth), "↙"),
         };
-        progress_bar.set_prefix(format!("{}{}", indentation, target_name_owned));
+        let label = group
+            .iter()
+            .map(TargetName::to_string)
+            .collect::<Vec<String>>()
+            .join(" & ");
+        let prefix = format!("{}{}", indentation, label);
+        progress_bar.set_prefix(prefix.clone());
+        let recipe_report = RecipeReport {
+            target_name: target_name.clone(),
+            group: group.clone(),
+            label: label.clone(),
+            output_mode,
+            log_path: log_path.clone(),
+            sandboxed: self.sandbox,
+            keep_going: self.keep_going,
+            multi_progress: self.multi_progress.clone(),
+            failed: self.failed.clone(),
+            stopped: self.stopped.clone(),
+        };
         progress_bar.set_position(0);
+        // The job slots are opened once the targets that don't wait for anything have asked for one.
+        let mut counted_by_job_slots = barrier.is_empty()
+            && dependency_handles.is_empty()
+            && job_slots
+                .as_ref()
+                .is_some_and(|job_slots| job_slots.expect());
         let join_handle = task::spawn(async move {
-            join_all(dependency_handles).await;
+            // A failure before a `.WAIT` only affects the targets that depend on the failed target.
+            join_all(barrier).await;
+            let dependencies_up_to_date = join_all(dependency_handles)
+                .await
+                .into_iter()
+                .all(|up_to_date| up_to_date);
+            if let Some(job_slots) = job_slots.as_ref().filter(|_| {
+                counted_by_job_slots && (serial_lock.is_some() || !resource_names.is_empty())
+            }) {
+                job_slots.arrive();
+                counted_by_job_slots = false;
+            }
+            let _serial_guard = match &serial_lock {
+                Some(serial_lock) => Some(serial_lock.lock().await),
+                None => None,
+            };
+            // Taken before the job slot, so that waiting for a resource doesn't keep a slot from other recipes.
+            let _resource_slots = resources.acquire(&resource_names, priority).await;
+            let _job_slot = match &job_slots {
+                Some(job_slots) => Some(job_slots.acquire(priority, counted_by_job_slots).await),
+                None => None,
+            };
+            if !dependencies_up_to_date || stopped.load(Ordering::SeqCst) {
+                progress_bar.set_style(
+                    ProgressStyle::with_template("       🚫 {prefix}")
+                        .expect("Could not construct progress bar template."),
+                );
+                progress_bar.finish();
+                not_built
+                    .lock()
+                    .expect("Could not record a target that was not built.")
+                    .push(target_name_owned);
+                return false;
+            }
+            let fingerprint = journal.as_ref().map(|(journal, target_graph)| {
+                (journal, journal::fingerprint(target_graph, &sandbox_group))
+            });
+            if let (Some((journal, fingerprint)), Some((_, target_graph))) =
+                (&fingerprint, &journal)
+            {
+                if journal.finished_before(target_graph, &sandbox_group, fingerprint) {
+                    progress_bar.set_style(
+                        ProgressStyle::with_template("       ✅ {prefix} (done before, resumed)")
+                            .expect("Could not construct progress bar template."),
+                    );
+                    progress_bar.finish();
+                    record_outcome(Outcome::BuiltBefore);
+                    return true;
+                }
+            }
+            let stamp = stamp_target_graph
+                .as_ref()
+                .map(|target_graph| stamp::stamp(target_graph, &sandbox_group));
+            // With `-B`, the recipe still runs (and updates the stamp).
+            if !freshness.always_make
+                && stamp
+                    .as_ref()
+                    .is_some_and(|stamp| stamp::is_current(&sandbox_group, stamp))
+            {
+                progress_bar.set_style(
+                    ProgressStyle::with_template("       ✅ {prefix} (up to date, stamp)")
+                        .expect("Could not construct progress bar template."),
+                );
+                progress_bar.finish();
+                record_state();
+                record_outcome(Outcome::UpToDate);
+                return true;
+            }
+            let command = match &direct_target_graph {
+                Some(target_graph) => {
+                    match executor::recipe_command(target_graph, &target_name_owned, &freshness) {
+                        Some(command) => command,
+                        None => {
+                            progress_bar.set_style(
+                                ProgressStyle::with_template("       ✅ {prefix} (up to date)")
+                                    .expect("Could not construct progress bar template."),
+                            );
+                            progress_bar.finish();
+                            record_state();
+                            record_outcome(Outcome::UpToDate);
+                            return true;
+                        }
+                    }
+                }
+                None => match make_freshness {
+                    Some((target_graph, group, rebuild_command)) => {
+                        let mut changed: Option<Vec<TargetName>> = None;
+                        for member in &group {
+                            if let Some(member_changed) =
+                                executor::changed_prerequisites(&target_graph, member, &freshness)
+                            {
+                                extend_unique(
+                                    changed.get_or_insert_with(Vec::new),
+                                    &member_changed,
+                                );
+                            }
+                        }
+                        let Some(changed) = changed else {
+                            progress_bar.set_style(
+                                ProgressStyle::with_template("       ✅ {prefix} (up to date)")
+                                    .expect("Could not construct progress bar template."),
+                            );
+                            progress_bar.finish();
+                            record_state();
+                            record_outcome(Outcome::UpToDate);
+                            return true;
+                        };
+                        let changed_variables = group.iter().any(|member| {
+                            !executor::changed_variables(&target_graph, member, &freshness)
+                                .is_empty()
+                        });
+                        let has_missing_targets = group.iter().any(|member| {
+                            !target_graph.is_phony(member)
+                                && std::fs::symlink_metadata(&member.0).is_err()
+                        });
+                        rebuild_command(
+                            changed,
+                            changed_variables || (sandbox_placeholders && has_missing_targets),
+                        )
+                    }
+                    None => command,
+                },
+            };
+            let build_cache_key = build_cache.as_ref().map(|(build_cache, target_graph)| {
+                let inputs = build_cache::cache_inputs(target_graph, &sandbox_group);
+                (
+                    build_cache.clone(),
+                    build_cache::key_for_inputs(&inputs),
+                    Arc::new(build_cache::outputs(target_graph, &sandbox_group)),
+                    Arc::new(inputs),
+                )
+            });
+            if let Some((build_cache, key, outputs, inputs)) = &build_cache_key {
+                let (build_cache, key, outputs, inputs) = (
+                    build_cache.clone(),
+                    key.clone(),
+                    outputs.clone(),
+                    inputs.clone(),
+                );
+                if task::spawn_blocking(move || build_cache.restore(&key, &outputs, &inputs)).await
+                {
+                    progress_bar.set_style(
+                        ProgressStyle::with_template("       ✅ {prefix} (from the build cache)")
+                            .expect("Could not construct progress bar template."),
+                    );
+                    progress_bar.finish();
+                    record_state();
+                    record_outcome(Outcome::FromBuildCache);
+                    return true;
+                }
+            }
+
+            // Held until the recipe is done.
+            let worker_slot = remote.as_ref().and_then(|(worker_pool, target_graph)| {
+                Some((worker_pool.try_claim()?, target_graph))
+            });
+            let _jobserver_token = match (&jobserver, &worker_slot) {
+                (Some(jobserver), None) => Some(jobserver.acquire().await),
+                _ => None,
+            };
+            let (mut command, mut prepared_command) = command_setup.prepare(
+                command,
+                &sandbox_group,
+                &label,
+                worker_slot
+                    .as_ref()
+                    .map(|(worker_slot, target_graph)| (worker_slot, target_graph.as_ref())),
+                &multi_progress_owned,
+            );
+            if let Some((worker_slot, _)) = &worker_slot {
+                progress_bar.set_prefix(format!("{} (on {})", prefix, worker_slot.host()));
+            }
 
             progress_bar.reset_elapsed();
             progress_bar.set_position(1);
             progress_bar.set_style(
-                ProgressStyle::with_template("{elapsed:>06} {spinner}  {prefix:40} 🛠️ | {wide_msg}")
-                    .expect("Could not construct progress bar."),
+                ProgressStyle::with_template(
+                    "{elapsed:>06} {spinner}  {prefix:40} 🛠️ | {wide_msg}",
+                )
+                .expect("Could not construct progress bar."),
             );
             progress_bar.enable_steady_tick(Duration::from_millis(16));
 
-            let result = make_individual_target(
-                dependencies,
-                &makefile_path_str_owned,
-                &target_name_owned,
-                &progress_bar,
-            )
-            .await;
+            // Truncates the log from the last run.
+            let log_file = log_path
+                .as_deref()
+                .and_then(|log_path| match File::create(log_path) {
+                    Ok(log_file) => Some(Arc::new(std::sync::Mutex::new(log_file))),
+                    Err(error) => {
+                        eprintln!("Could not create `{}`: {}", log_path.display(), error);
+                        None
+                    }
+                });
+            let mut attempt = 1;
+            let recipe_started = SystemTime::now();
+            let result = loop {
+                let original_modified_times: Vec<_> = deletable_files
+                    .iter()
+                    .map(|file| modified_time(file))
+                    .collect();
+                let attempt_start_time = Instant::now();
+                let oom_kills = prepared_command
+                    .job_cgroup
+                    .as_ref()
+                    .map(JobCgroup::oom_kills);
+                let mut result = make_individual_target(
+                    &mut command,
+                    output_mode == OutputMode::Failures || turn.is_some(),
+                    timeout,
+                    log_file.clone(),
+                    &progress_bar,
+                    &multi_progress_owned,
+                    &label,
+                )
+                .await;
+                if let (IndividualTargetResult::Failure(failure_reason, _), Some(job_cgroup)) =
+                    (&mut result, &prepared_command.job_cgroup)
+                {
+                    if Some(job_cgroup.oom_kills()) > oom_kills {
+                        *failure_reason = FailureReason::OutOfMemory;
+                    }
+                }
+                let IndividualTargetResult::Failure(failure_reason, captured_output) = &result
+                else {
+                    recipe_durations
+                        .lock()
+                        .expect("Could not record how long a recipe took.")
+                        .insert(target_name_owned.clone(), attempt_start_time.elapsed());
+                    break result;
+                };
+                // Otherwise a partially written target could look up to date to the next attempt.
+                delete_changed_files(&deletable_files, original_modified_times);
+                if attempt > retries || stopped.load(Ordering::SeqCst) {
+                    break result;
+                }
+                let delay = retry_delay.saturating_mul(1 << (attempt - 1).min(16));
+                if output_mode == OutputMode::Stream {
+                    let (captured_output, label) = (captured_output.clone(), label.clone());
+                    report(&mut turn, &multi_progress_owned, move || {
+                        print_with_prefix(&captured_output, &label)
+                    });
+                }
+                let message = format!(
+                    "🔁 {} failed ({}), retrying in {:?} (attempt {} of {})",
+                    label,
+                    failure_reason,
+                    delay,
+                    attempt + 1,
+                    retries + 1
+                );
+                report(&mut turn, &multi_progress_owned, move || {
+                    eprintln!("{}", message)
+                });
+                attempt += 1;
+                attempts
+                    .lock()
+                    .expect("Could not record the number of attempts.")
+                    .insert(target_name_owned.clone(), attempt);
+                if let Some(log_file) = &log_file {
+                    let _ = writeln!(
+                        log_file.lock().expect("Could not write to a log file."),
+                        "--- mak: attempt {} of {} ---",
+                        attempt,
+                        retries + 1
+                    );
+                }
+                progress_bar.set_prefix(format!(
+                    "{} (attempt {}/{})",
+                    prefix,
+                    attempt,
+                    retries + 1
+                ));
+                task::sleep(delay).await;
+            };
+            drop(prepared_command.job_file.take());
 
             progress_bar.set_position(2);
             match result {
-                IndividualTargetResult::Success() => {
-                    progress_bar.set_style(
-                        ProgressStyle::with_template("{elapsed:>06} ✅ {prefix}")
-                            .expect("Could not construct progress bar template."),
+                IndividualTargetResult::Success(captured_output) => {
+                    recipe_report.succeeded(
+                        &mut turn,
+                        &progress_bar,
+                        captured_output,
+                        prepared_command.trace.as_ref(),
                     );
-                    progress_bar.finish();
+                    record_outcome(Outcome::Built);
+                    record_state();
+                    if let Some((journal, fingerprint)) = &fingerprint {
+                        journal.record(&sandbox_group, fingerprint);
+                    }
+                    if let Some(stamp) = &stamp {
+                        if let Err(error) = stamp::write_stamp(&sandbox_group, stamp) {
+                            multi_progress_owned.suspend(|| {
+                                eprintln!("Could not write the stamp of `{}`: {}", label, error)
+                            });
+                        }
+                    }
+                    let (written_outputs, target_graph) = &written_outputs;
+                    if sandbox_group
+                        .iter()
+                        .any(|member| target_graph.has_recipe(member))
+                    {
+                        written_outputs.record(
+                            &label,
+                            recipe_started,
+                            &output_conflicts::declared_outputs(target_graph, &sandbox_group),
+                        );
+                    }
+                    if let Some((build_cache, key, outputs, inputs)) = build_cache_key {
+                        if let Err(error) =
+                            task::spawn_blocking(move || build_cache.store(&key, &outputs, &inputs))
+                                .await
+                        {
+                            multi_progress_owned.suspend(|| {
+                                eprintln!(
+                                    "Could not store `{}` in the build cache: {}",
+                                    label, error
+                                )
+                            });
+                        }
+                    }
+                    true
                 }
-                IndividualTargetResult::Failure(output_lines) => {
-                    progress_bar.set_style(
-                        ProgressStyle::with_template("{elapsed:>06} ❌ {prefix}")
-                            .expect("Could not construct progress bar template."),
+                IndividualTargetResult::Failure(failure_reason, captured_output) => {
+                    recipe_report.failed(
+                        &mut turn,
+                        &progress_bar,
+                        failure_reason,
+                        captured_output,
+                        attempt,
                     );
+                    false
+                }
+            }
+        });
+        let join_handle = join_handle.shared();
+        for member in group {
+            self.futures.insert(member, join_handle.clone());
+        }
+        join_handle
+    }
+}
 
-                    println!("❌❌❌❌❌❌❌❌❌❌❌❌❌❌❌❌");
-                    println!("❌");
-                    println!("❌ Target failed:");
-                    println!("❌");
-                    println!("❌     {}", target_name_owned);
-                    println!("❌");
-                    println!("❌ ⬇ See below for output. ⬇");
-                    println!("❌");
-                    println!("❌❌❌❌❌❌❌❌❌❌❌❌❌❌❌❌");
-
-                    for output_line in output_lines {
-                        match output_line {
-                            OutputLine::Stdout(line) => println!("{}", line),
-                            OutputLine::Stderr(line) => eprintln!("{}", line),
-                        }
+// How a target's recipe is run, besides its command: with `--hermetic`, `--trace-dependencies`, `--sandbox`,
+// `--workers`, `# mak:image=…`, `--nice`/`--ionice`, and the per-job limits.
+struct CommandSetup {
+    jobserver_make_flags: Option<String>,
+    hermetic: bool,
+    trace_target_graph: Option<Arc<TargetGraph>>,
+    sandbox_target_graph: Option<Arc<TargetGraph>>,
+    sandbox_placeholders: bool,
+    // The container runtime and the image.
+    container: Option<(String, String)>,
+    nice: Option<i32>,
+    io_priority: Option<IoPriority>,
+    job_cgroups: Option<Arc<JobCgroups>>,
+}
+
+// What the command of a recipe needs until the recipe is done. Dropped in this order.
+struct PreparedCommand<'a> {
+    // Shared by the attempts, and removed once the target is done.
+    job_cgroup: Option<JobCgroup>,
+    // Each attempt reads it.
+    job_file: Option<JobFile>,
+    // Removes the placeholders that the recipe didn't write.
+    _sandbox_policy: Option<SandboxPolicy>,
+    trace: Option<(Trace, &'a TargetGraph)>,
+    _temp_directory: Option<PrivateTempDirectory>,
+}
+
+impl CommandSetup {
+    fn prepare(
+        &self,
+        mut command: Command,
+        group: &[TargetName],
+        label: &str,
+        worker_slot: Option<(&WorkerSlot, &TargetGraph)>,
+        multi_progress: &MultiProgress,
+    ) -> (Command, PreparedCommand<'_>) {
+        if let Some(make_flags) = &self.jobserver_make_flags {
+            command.env("MAKEFLAGS", make_flags);
+        }
+        let temp_directory =
+            self.hermetic
+                .then(PrivateTempDirectory::new)
+                .and_then(|temp_directory| match temp_directory {
+                    Ok(temp_directory) => Some(temp_directory),
+                    Err(error) => {
+                        multi_progress.suspend(|| {
+                            eprintln!("Could not create a `TMPDIR` for `{}`: {}", label, error)
+                        });
+                        None
                     }
+                });
+        if let Some(temp_directory) = &temp_directory {
+            temp_directory.set(&mut command);
+        }
+        let runs_locally = worker_slot.is_none() && self.container.is_none();
+        let trace = self
+            .trace_target_graph
+            .as_deref()
+            .filter(|_| runs_locally)
+            .map(|target_graph| (Trace::new(label), target_graph));
+        let sandbox_policy = self
+            .sandbox_target_graph
+            .as_ref()
+            .filter(|_| runs_locally)
+            .map(|target_graph| SandboxPolicy::new(target_graph, group, self.sandbox_placeholders));
+        let mut job_file = None;
+        if let Some((worker_slot, target_graph)) = worker_slot {
+            let outputs: Vec<String> = group.iter().map(|member| member.0.clone()).collect();
+            (command, job_file) = worker_slot.remote_command(
+                &command,
+                &remote::inputs(target_graph, group),
+                &outputs,
+            );
+        } else if let Some((runtime, image)) = &self.container {
+            command = executor::container_command(&command, runtime, image);
+        } else {
+            if let Some((trace, _)) = &trace {
+                command = trace.wrap(&command);
+            }
+            if let Some(sandbox_policy) = &sandbox_policy {
+                command = sandbox::sandbox(command, sandbox_policy);
+            }
+        }
+        processes::set_scheduling(&mut command, self.nice, self.io_priority);
+        let job_cgroup = self.job_cgroups.as_ref().and_then(|job_cgroups| {
+            job_cgroups
+                .create(&mut command)
+                .map_err(|error| {
+                    eprintln!("Running `{}` without the per-job limits: {}", label, error)
+                })
+                .ok()
+        });
+        (
+            command,
+            PreparedCommand {
+                job_cgroup,
+                job_file,
+                _sandbox_policy: sandbox_policy,
+                trace,
+                _temp_directory: temp_directory,
+            },
+        )
+    }
+}
+
+// Reports how a target's recipe went, once it's done.
+struct RecipeReport {
+    target_name: TargetName,
+    group: Vec<TargetName>,
+    label: String,
+    output_mode: OutputMode,
+    log_path: Option<PathBuf>,
+    sandboxed: bool,
+    keep_going: bool,
+    multi_progress: Arc<MultiProgress>,
+    failed: Arc<std::sync::Mutex<Vec<TargetName>>>,
+    stopped: Arc<AtomicBool>,
+}
 
-                    println!("❌❌❌❌❌❌❌❌❌❌❌❌❌❌❌❌");
-                    println!("❌");
-                    println!("❌ ⬆  See above for output. ⬆");
-                    println!("❌");
-                    println!("❌ Target failed:");
-                    println!("❌");
-                    println!("❌     {}", target_name_owned);
-                    println!("❌");
-                    println!("❌❌❌❌❌❌❌❌❌❌❌❌❌❌❌❌");
-
-                    exit(1)
+impl RecipeReport {
+    fn succeeded(
+        &self,
+        turn: &mut Option<Turn>,
+        progress_bar: &ProgressBar,
+        captured_output: Vec<OutputLine>,
+        trace: Option<&(Trace, &TargetGraph)>,
+    ) {
+        progress_bar.set_style(
+            ProgressStyle::with_template("{elapsed:>06} ✅ {prefix}")
+                .expect("Could not construct progress bar template."),
+        );
+        progress_bar.finish();
+        if self.output_mode == OutputMode::Stream {
+            let label = self.label.clone();
+            report(turn, &self.multi_progress, move || {
+                print_with_prefix(&captured_output, &label)
+            });
+        }
+        if let Some((trace, target_graph)) = trace {
+            let undeclared = file_access::undeclared_dependencies(
+                target_graph,
+                &self.group,
+                &trace.read_files(),
+            );
+            if !undeclared.is_empty() {
+                let files: Vec<String> = undeclared
+                    .iter()
+                    .map(|(path, made_by_rule)| match made_by_rule {
+                        true => format!("`{}` (made by a rule, so this can race)", path),
+                        false => format!("`{}`", path),
+                    })
+                    .collect();
+                let message = format!(
+                    "🔍 {} read files that are not among its prerequisites: {}",
+                    self.label,
+                    files.join(", ")
+                );
+                report(turn, &self.multi_progress, move || eprintln!("{}", message));
+            }
+        }
+    }
+
+    // Also stops the build without `--keep-going`.
+    fn failed(
+        &self,
+        turn: &mut Option<Turn>,
+        progress_bar: &ProgressBar,
+        failure_reason: FailureReason,
+        captured_output: Vec<OutputLine>,
+        attempt: u32,
+    ) {
+        let template = match failure_reason {
+            FailureReason::TimedOut(_) => "{elapsed:>06} ⏰ {prefix} (timed out)",
+            FailureReason::OutOfMemory => "{elapsed:>06} 💥 {prefix} (out of memory)",
+            FailureReason::ExitCode(_) => "{elapsed:>06} ❌ {prefix}",
+        };
+        progress_bar.set_style(
+            ProgressStyle::with_template(template)
+                .expect("Could not construct progress bar template."),
+        );
+
+        let target_name = self.target_name.clone();
+        let label = self.label.clone();
+        let log_path = self.log_path.clone();
+        let output_mode = self.output_mode;
+        let sandboxed = self.sandboxed;
+        report(turn, &self.multi_progress, move || {
+            // Only captured with `--deterministic`.
+            if output_mode == OutputMode::Stream {
+                print_with_prefix(&captured_output, &label);
+            }
+            if output_mode == OutputMode::Failures {
+                println!("❌❌❌❌❌❌❌❌❌❌❌❌❌❌❌❌");
+                println!("❌");
+                println!("❌ Target failed:");
+                println!("❌");
+                println!("❌     {}", target_name);
+                println!("❌");
+                println!("❌ {}", failure_reason);
+                if attempt > 1 {
+                    println!("❌ Attempts: {}", attempt);
                 }
+                println!("❌ ⬇ See below for output. ⬇");
+                println!("❌");
+                println!("❌❌❌❌❌❌❌❌❌❌❌❌❌❌❌❌");
+                for output_line in &captured_output {
+                    output_line.print();
+                }
+            }
+            println!("❌❌❌❌❌❌❌❌❌❌❌❌❌❌❌❌");
+            println!("❌");
+            match output_mode {
+                OutputMode::Stream => println!(
+                    "❌ ⬆  See above for output (starting with `[{}]`). ⬆",
+                    label
+                ),
+                OutputMode::Failures => println!("❌ ⬆  See above for output. ⬆"),
+            }
+            println!("❌");
+            println!("❌ Target failed:");
+            println!("❌");
+            println!("❌     {}", target_name);
+            println!("❌");
+            println!("❌ {}", failure_reason);
+            if attempt > 1 {
+                println!("❌ Attempts: {}", attempt);
+            }
+            if let Some(log_path) = &log_path {
+                println!("❌ Log: {}", log_path.display());
             }
+            if sandboxed {
+                println!("❌ Sandboxed: \"Permission denied\" errors can mean an undeclared prerequisite.");
+            }
+            println!("❌");
+            println!("❌❌❌❌❌❌❌❌❌❌❌❌❌❌❌❌");
         });
-        let join_handle = join_handle.shared();
-        self.futures
-            .insert(target_name.clone(), join_handle.clone());
-        join_handle
+
+        self.failed
+            .lock()
+            .expect("Could not record a failed target.")
+            .push(self.target_name.clone());
+        if !self.keep_going {
+            self.stopped.store(true, Ordering::SeqCst);
+        }
     }
 }
 
+#[derive(Clone)]
 enum OutputLine {
     Stdout(String),
     Stderr(String),
 }
 
+impl OutputLine {
+    fn print(&self) {
+        match self {
+            OutputLine::Stdout(line) => println!("{}", line),
+            OutputLine::Stderr(line) => eprintln!("{}", line),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum FailureReason {
+    // `None` if the process was killed by a signal.
+    ExitCode(Option<i32>),
+    TimedOut(Duration),
+    // A process of the recipe was killed for going over `--job-memory-limit`.
+    OutOfMemory,
+}
+
+impl Display for FailureReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FailureReason::ExitCode(Some(exit_code)) => write!(f, "Exit code: {}", exit_code),
+            FailureReason::ExitCode(None) => write!(f, "Killed by a signal"),
+            FailureReason::TimedOut(timeout) => write!(f, "Timed out after {:?}", timeout),
+            FailureReason::OutOfMemory => write!(f, "Killed for going over the memory limit"),
+        }
+    }
+}
+
 enum IndividualTargetResult {
-    Success(),
-    #[allow(clippy::type_complexity)]
-    Failure(mpsc::Receiver<OutputLine>),
+    // The output is only captured with `--output=failures` or `--deterministic`.
+    Success(Vec<OutputLine>),
+    // With the output for `--output failures`.
+    Failure(FailureReason, Vec<OutputLine>),
 }
 
-async fn make_individual_target(
-    dependencies: Vec<TargetName>,
-    makefile_path_str: &Option<String>,
+// Builds only `target_name`, assuming that its dependencies are already up to date.
+fn individual_target_command(
+    target_graph: &TargetGraph,
+    format: Format,
+    dependencies: &[TargetName],
+    makefile_path_strs: &[String],
+    variable_overrides: &[String],
+    freshness: &Freshness,
     target_name: &TargetName,
-    progress_bar: &ProgressBar,
-) -> IndividualTargetResult {
-    let mut args = make_args(makefile_path_str);
-    args.push(target_name.0.clone());
+) -> Command {
+    // Only makefiles can be passed several times.
+    let makefile_path_str = &makefile_path_strs.first().cloned();
+    match format {
+        // The recipe only ran a recursive `make`, whose targets are scheduled as dependencies instead.
+        Format::Make if target_graph.recursive_make_targets.contains(target_name) => {
+            Command::new("true")
+        }
+        Format::Make if target_graph.submake_targets.contains_key(target_name) => {
+            let submake_target = &target_graph.submake_targets[target_name];
+            let mut args = vec![
+                "--no-print-directory".to_owned(),
+                "-C".to_owned(),
+                submake_target.directory.clone(),
+            ];
+            args.extend(variable_overrides.iter().cloned());
+            args.push(submake_target.target_name.0.clone());
+            // Dependencies from the same directory are already up to date.
+            for dependency in dependencies {
+                if let Some(dependency) = target_graph
+                    .submake_targets
+                    .get(dependency)
+                    .filter(|dependency| dependency.directory == submake_target.directory)
+                {
+                    args.push("-o".to_owned());
+                    args.push(dependency.target_name.0.clone());
+                }
+            }
+            args.push("--".to_owned());
+
+            let mut command = Command::new("make");
+            command.args(args);
+            command
+        }
+        Format::Make => {
+            let mut args = make_args(makefile_path_strs, variable_overrides);
+            // Otherwise `make` deletes intermediate files as soon as one target that needs them is done, even if
+            // others still need them. They are deleted once the whole build is done instead.
+            let intermediate_files: Vec<String> = target_graph
+                .intermediate
+                .iter()
+                .filter(|target_name| target_graph.is_deletable_intermediate(target_name))
+                .map(escape_target_name)
+                .collect();
+            if !intermediate_files.is_empty() {
+                args.push(format!(
+                    "--eval=.SECONDARY: {}",
+                    intermediate_files.join(" ")
+                ));
+            }
+            args.push(target_name.0.clone());
+
+            for dependency in dependencies.iter().chain(&freshness.assume_old) {
+                args.push("-o".to_owned());
+                args.push(dependency.0.clone());
+            }
+            for file in &freshness.assume_new {
+                args.push("-W".to_owned());
+                args.push(file.0.clone());
+            }
+            // Only the target itself is rebuilt, since its prerequisites are passed with `-o`.
+            if freshness.always_make {
+                args.push("-B".to_owned());
+            }
+            args.push("--".to_owned());
 
-    for dependency in &dependencies {
-        args.push("-o".to_owned());
-        args.push(dependency.0.clone());
+            let mut command = Command::new("make");
+            command.args(args);
+            command
+        }
+        Format::Just => just::recipe_command(makefile_path_str, variable_overrides, target_name),
+        Format::PackageJson => {
+            package_json::script_command(target_graph, makefile_path_str, target_name)
+        }
+        Format::Taskfile => taskfile::task_command(target_graph, makefile_path_str, target_name),
+        Format::Ninja => ninja::build_command(target_graph, target_name),
     }
-    args.push("--".to_owned());
+}
 
-    let mut child = Command::new("make")
-        .args(args)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .expect("failed to execute process");
+// Colors to tell the output of different targets apart (red is left out, since it looks like an error).
+const PREFIX_COLORS: [Color; 5] = [
+    Color::Cyan,
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::Magenta,
+];
 
-    let (sender, receiver) = mpsc::channel::<OutputLine>();
+// Target names can contain `/`, which is escaped (along with `%`) to keep all logs in the same directory.
+fn log_file_name(target_name: &TargetName) -> String {
+    format!(
+        "{}.log",
+        target_name
+            .0
+            .replace('%', "%25")
+            .replace(['/', '\\'], "%2F")
+    )
+}
 
-    // TODO: deduplicate stderr and stdout implementations.
-    let sender_clone = sender.clone();
-    let stdout_reader = BufReader::new(
-        child
-            .stdout
-            .take()
-            .expect("Could not get stdout for a `make` invocation."),
-    );
-    let stdout_progress_bar_clone: ProgressBar = progress_bar.clone();
-    let stdout_join_handle = task::spawn(async move {
-        stdout_reader
-            .lines()
-            .map_while(Result::ok)
-            .for_each(move |line| {
-                if !line.trim().is_empty() {
-                    stdout_progress_bar_clone.set_message(line.clone())
-                };
-                // Ignore `send` failures, since those could be due to closing down the program from a target failure somewhere else.
-                let _ = sender_clone.send(OutputLine::Stdout(line));
-            });
-    });
+// `[target] `, in a color that is the same for the target in every run.
+fn output_prefix(label: &str, for_stderr: bool) -> String {
+    let mut hasher = DefaultHasher::new();
+    label.hash(&mut hasher);
+    let color = PREFIX_COLORS[(hasher.finish() % PREFIX_COLORS.len() as u64) as usize];
+    let style = match for_stderr {
+        true => Style::new().for_stderr(),
+        false => Style::new().for_stdout(),
+    };
+    format!("{} ", style.fg(color).apply_to(format!("[{}]", label)))
+}
 
-    let stderr_reader = BufReader::new(
-        child
-            .stderr
-            .take()
-            .expect("Could not get stdout for a `make` invocation."),
-    );
-    let stderr_progress_bar_clone: ProgressBar = progress_bar.clone();
-    let stderr_join_handle = task::spawn(async move {
-        stderr_reader
-            .lines()
-            .map_while(Result::ok)
-            .for_each(move |line| {
-                if !line.trim().is_empty() {
-                    stderr_progress_bar_clone.set_message(line.clone())
-                };
-                // Ignore `send` failures, since those could be due to closing down the program from a target failure somewhere else.
-                let _ = sender.send(OutputLine::Stderr(line));
-            })
-    });
-    if child
-        .wait()
-        .expect("Error while waiting for a `make` invocation to finish")
-        .success()
+// The captured output of a recipe, as `stream_lines` would have printed it.
+fn print_with_prefix(output_lines: &[OutputLine], label: &str) {
+    for output_line in output_lines {
+        match output_line {
+            OutputLine::Stdout(line) => println!("{}{}", output_prefix(label, false), line),
+            OutputLine::Stderr(line) => eprintln!("{}{}", output_prefix(label, true), line),
+        }
+    }
+}
+
+// Prints right away (above the progress bars), or with `--deterministic` once it is the target's turn.
+fn report(
+    turn: &mut Option<Turn>,
+    multi_progress: &MultiProgress,
+    report: impl FnOnce() + Send + 'static,
+) {
+    match turn {
+        Some(turn) => turn.defer(report),
+        None => multi_progress.suspend(report),
+    }
+}
+
+// Shows each line of a recipe's output as the message of the target's progress bar, and either adds it to `captured`
+// or prints it right away (above the progress bars and prefixed with the target, since recipes run at the same time).
+fn stream_lines(
+    reader: impl BufRead + Send + 'static,
+    output_line: fn(String) -> OutputLine,
+    captured: Option<Arc<std::sync::Mutex<Vec<OutputLine>>>>,
+    log_file: Option<Arc<std::sync::Mutex<File>>>,
+    progress_bar: &ProgressBar,
+    multi_progress: &Arc<MultiProgress>,
+    prefix: String,
+) -> JoinHandle<()> {
+    let progress_bar = progress_bar.clone();
+    let multi_progress = multi_progress.clone();
+    // Reading output blocks, so it doesn't run on the executor's threads (which would limit how many recipes run at the
+    // same time to the number of those threads).
+    task::spawn_blocking(move || {
+        for line in reader.lines().map_while(Result::ok) {
+            if !line.trim().is_empty() {
+                progress_bar.set_message(line.clone())
+            };
+            if let Some(log_file) = &log_file {
+                // A log that can't be written shouldn't fail the build.
+                let _ = writeln!(
+                    log_file.lock().expect("Could not write to a log file."),
+                    "{}",
+                    line
+                );
+            }
+            match &captured {
+                Some(captured) => captured
+                    .lock()
+                    .expect("Could not capture the output of a recipe.")
+                    .push(output_line(line)),
+                None => {
+                    multi_progress.suspend(|| output_line(format!("{}{}", prefix, line)).print())
+                }
+            }
+        }
+    })
+}
+
+// Stops the recipe (and anything it started), giving `make` a chance to delete partially written targets first.
+async fn stop_recipe(
+    pid: u32,
+    wait_handle: JoinHandle<std::io::Result<ExitStatus>>,
+) -> std::io::Result<ExitStatus> {
+    processes::stop_process_group(pid, false);
+    match select(
+        wait_handle,
+        Box::pin(task::sleep(processes::STOP_GRACE_PERIOD)),
+    )
+    .await
     {
-        IndividualTargetResult::Success()
-    } else {
-        join_all([stdout_join_handle, stderr_join_handle]).await;
-        IndividualTargetResult::Failure(receiver)
+        Either::Left((status, _)) => status,
+        Either::Right(((), wait_handle)) => {
+            processes::stop_process_group(pid, true);
+            wait_handle.await
+        }
     }
 }
 
-fn make_args(makefile_path_str: &Option<String>) -> Vec<String> {
+async fn make_individual_target(
+    command: &mut Command,
+    capture_output: bool,
+    timeout: Option<Duration>,
+    log_file: Option<Arc<std::sync::Mutex<File>>>,
+    progress_bar: &ProgressBar,
+    multi_progress: &Arc<MultiProgress>,
+    label: &str,
+) -> IndividualTargetResult {
+    // Both streams go into the same buffer, so that their lines stay in (roughly) the order they were printed in.
+    let captured = capture_output.then(Arc::default);
+    let mut child =
+        processes::spawn_recipe(command.stdout(Stdio::piped()).stderr(Stdio::piped()), label)
+            .expect("failed to execute process");
+
+    let stdout_join_handle = stream_lines(
+        BufReader::new(
+            child
+                .stdout
+                .take()
+                .expect("Could not get stdout for a `make` invocation."),
+        ),
+        OutputLine::Stdout,
+        captured.clone(),
+        log_file.clone(),
+        progress_bar,
+        multi_progress,
+        output_prefix(label, false),
+    );
+    let stderr_join_handle = stream_lines(
+        BufReader::new(
+            child
+                .stderr
+                .take()
+                .expect("Could not get stderr for a `make` invocation."),
+        ),
+        OutputLine::Stderr,
+        captured.clone(),
+        log_file.clone(),
+        progress_bar,
+        multi_progress,
+        output_prefix(label, true),
+    );
+    let pid = child.id();
+    let wait_handle = task::spawn_blocking(move || child.wait());
+    let (status, timed_out) = match timeout {
+        None => (wait_handle.await, None),
+        Some(timeout) => match select(wait_handle, Box::pin(task::sleep(timeout))).await {
+            Either::Left((status, _)) => (status, None),
+            Either::Right(((), wait_handle)) => {
+                (stop_recipe(pid, wait_handle).await, Some(timeout))
+            }
+        },
+    };
+    processes::recipe_finished(pid);
+    let status = status.expect("Error while waiting for a `make` invocation to finish");
+    // Prints all of the output before the result.
+    join_all([stdout_join_handle, stderr_join_handle]).await;
+    let captured_output = captured
+        .map(|captured| {
+            std::mem::take(
+                &mut *captured
+                    .lock()
+                    .expect("Could not read the output of a recipe."),
+            )
+        })
+        .unwrap_or_default();
+    let failure_reason = match (timed_out, status.success()) {
+        (Some(timeout), _) => FailureReason::TimedOut(timeout),
+        (None, true) => return IndividualTargetResult::Success(captured_output),
+        (None, false) => FailureReason::ExitCode(status.code()),
+    };
+    IndividualTargetResult::Failure(failure_reason, captured_output)
+}
+
+fn make_args(makefile_path_strs: &[String], variable_overrides: &[String]) -> Vec<String> {
     let mut args = vec![];
-    if let Some(makefile_path_str) = makefile_path_str {
+    for makefile_path_str in makefile_path_strs {
         args.push("-f".to_owned());
         args.push(makefile_path_str.to_owned());
-    };
+    }
+    args.extend(variable_overrides.iter().cloned());
     args
 }
@@ -0,0 +1,141 @@
+// Classifies the lines of makefile text as written (rather than evaluated), for checks and formatting.
+
+const ASSIGNMENT_OPERATORS: [&str; 7] = [":::=", "::=", ":=", "?=", "+=", "!=", "="];
+
+// A line continues on the next one if it ends with an odd number of backslashes (`\\` is an escaped backslash).
+pub(crate) fn continues(line: &str) -> bool {
+    let line = line.trim_end_matches('\r');
+    (line.len() - line.trim_end_matches('\\').len()) % 2 == 1
+}
+
+// Joins `\` continuation lines, keeping the number of the first line.
+pub(crate) fn logical_lines(text: &str) -> Vec<(usize, String)> {
+    let mut lines: Vec<(usize, String)> = vec![];
+    let mut continued = false;
+    for (index, line) in text.lines().enumerate() {
+        match lines.last_mut() {
+            Some((_, previous)) if continued => {
+                previous.push(' ');
+                previous.push_str(line.trim_start());
+            }
+            _ => lines.push((index + 1, line.to_owned())),
+        }
+        continued = continues(line);
+        if continued {
+            if let Some((_, last)) = lines.last_mut() {
+                last.pop();
+            }
+        }
+    }
+    lines
+}
+
+// Groups the physical lines of each logical line (lines ending in `\` continue on the next one).
+pub(crate) fn line_groups(text: &str) -> Vec<Vec<&str>> {
+    let mut groups: Vec<Vec<&str>> = vec![];
+    let mut continued = false;
+    for line in text.lines() {
+        match groups.last_mut() {
+            Some(group) if continued => group.push(line),
+            _ => groups.push(vec![line]),
+        }
+        continued = continues(line);
+    }
+    groups
+}
+
+pub(crate) fn strip_comment(line: &str) -> &str {
+    match line.find('#') {
+        Some(index) => &line[..index],
+        None => line,
+    }
+}
+
+pub(crate) struct Assignment<'a> {
+    pub(crate) name: &'a str,
+    // Empty for `define`, whose value is on the following lines.
+    pub(crate) value: &'a str,
+    pub(crate) is_define: bool,
+    pub(crate) is_export: bool,
+}
+
+// Parses an assignment like `override NAME := value` or `define NAME`.
+pub(crate) fn parse_assignment(line: &str) -> Option<Assignment> {
+    let mut line = line.trim();
+    let mut is_export = false;
+    while let Some(rest) = ["override ", "export ", "private "]
+        .iter()
+        .find_map(|modifier| line.strip_prefix(modifier))
+    {
+        is_export |= line.starts_with("export ");
+        line = rest.trim_start();
+    }
+    if let Some(rest) = line.strip_prefix("define ") {
+        return Some(Assignment {
+            name: rest.split_whitespace().next()?,
+            value: "",
+            is_define: true,
+            is_export,
+        });
+    }
+    let index = line.find(|c: char| c.is_whitespace() || "=:?+!".contains(c))?;
+    let (name, rest) = line.split_at(index);
+    let rest = rest.trim_start();
+    let operator = ASSIGNMENT_OPERATORS
+        .iter()
+        .find(|operator| rest.starts_with(**operator))?;
+    if name.is_empty() || name.contains('$') {
+        return None;
+    }
+    Some(Assignment {
+        name,
+        value: &rest[operator.len()..],
+        is_define: false,
+        is_export,
+    })
+}
+
+pub(crate) fn is_directive(line: &str) -> bool {
+    let word = line.split_whitespace().next().unwrap_or_default();
+    matches!(
+        word,
+        "ifeq"
+            | "ifneq"
+            | "ifdef"
+            | "ifndef"
+            | "else"
+            | "endif"
+            | "include"
+            | "-include"
+            | "sinclude"
+            | "define"
+            | "endef"
+            | "export"
+            | "unexport"
+            | "override"
+            | "undefine"
+            | "vpath"
+    )
+}
+
+pub(crate) fn is_rule(line: &str) -> bool {
+    !line.starts_with([' ', '\t'])
+        && !is_directive(line)
+        && parse_assignment(line).is_none()
+        && strip_comment(line).contains(':')
+}
+
+// Whether a rule line has a recipe after a `;` (outside of any `$(…)` reference), like `target: ; command`.
+pub(crate) fn has_inline_recipe(line: &str) -> bool {
+    let mut depth = 0;
+    is_rule(line)
+        && strip_comment(line).chars().any(|c| {
+            match c {
+                '(' | '{' => depth += 1,
+                ')' | '}' => depth -= 1,
+                ';' if depth == 0 => return true,
+                _ => {}
+            }
+            false
+        })
+}
@@ -0,0 +1,117 @@
+// For `--manifest`: a JSON file that lists the targets of the build with how they were brought up to date, and the
+// files they made with their sizes and SHA-256 hashes (e.g. for packaging, provenance attestations, or finding out why
+// the build cache missed).
+
+use std::{
+    collections::HashMap,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+};
+
+use serde::Serialize;
+
+use crate::{
+    parse::{directory_entries, file_metadata, TargetGraph, TargetName},
+    sha256::sha256,
+};
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Outcome {
+    Built,
+    UpToDate,
+    FromBuildCache,
+    // Skipped with `--resume`.
+    BuiltBefore,
+}
+
+#[derive(Serialize)]
+struct OutputFile {
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+#[derive(Serialize)]
+struct ManifestTarget {
+    target: TargetName,
+    outcome: Outcome,
+    outputs: Vec<OutputFile>,
+}
+
+#[derive(Serialize)]
+struct Manifest {
+    targets: Vec<ManifestTarget>,
+}
+
+/// How each target with a recipe was brought up to date during the build.
+#[derive(Default)]
+pub(crate) struct Outcomes {
+    outcomes: Mutex<HashMap<TargetName, Outcome>>,
+}
+
+impl Outcomes {
+    pub(crate) fn record(&self, group: &[TargetName], outcome: Outcome) {
+        let mut outcomes = self.outcomes.lock().unwrap_or_else(PoisonError::into_inner);
+        for member in group {
+            outcomes.insert(member.clone(), outcome);
+        }
+    }
+}
+
+fn output_file(path: &Path) -> Option<OutputFile> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let file = std::fs::File::open(path).ok()?;
+    Some(OutputFile {
+        path: path.to_string_lossy().into_owned(),
+        size: metadata.len(),
+        sha256: sha256(BufReader::new(file)).ok()?,
+    })
+}
+
+// The files of `target_name`: the target itself, or the files in it for a directory target.
+fn output_files(target_graph: &TargetGraph, target_name: &TargetName) -> Vec<OutputFile> {
+    if target_graph.is_phony(target_name) {
+        return vec![];
+    }
+    let path = PathBuf::from(&target_name.0);
+    match file_metadata(&path, target_graph.symlinks) {
+        Ok(metadata) if metadata.is_dir() => directory_entries(&path, target_graph.symlinks)
+            .iter()
+            .filter_map(|entry| output_file(entry))
+            .collect(),
+        Ok(_) => output_file(&path).into_iter().collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Writes the manifest for the targets with a recipe that have an outcome, in the order of the graph (so that it is the
+/// same for each build).
+pub(crate) fn write_manifest(
+    path: &Path,
+    target_graph: &TargetGraph,
+    outcomes: &Outcomes,
+) -> std::io::Result<()> {
+    let outcomes = outcomes
+        .outcomes
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    let targets = target_graph
+        .edges
+        .keys()
+        .filter(|target_name| target_graph.has_recipe(target_name))
+        .filter_map(|target_name| {
+            Some(ManifestTarget {
+                target: target_name.clone(),
+                outcome: *outcomes.get(target_name)?,
+                outputs: output_files(target_graph, target_name),
+            })
+        })
+        .collect();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let json = serde_json::to_string_pretty(&Manifest { targets })?;
+    std::fs::write(path, json + "\n")
+}
@@ -0,0 +1,430 @@
+use std::{
+    collections::HashMap,
+    process::{exit, Command},
+};
+
+use crate::parse::{TargetGraph, TargetName};
+
+const DEFAULT_NINJA_FILE_PATH: &str = "build.ninja";
+
+/// Runs the command of a build statement (stored as its recipe) with `sh`. Like `ninja`, paths are relative to the current directory.
+// TODO: skip outputs that are newer than their inputs (like `ninja` does).
+pub(crate) fn build_command(target_graph: &TargetGraph, target_name: &TargetName) -> Command {
+    let mut command = Command::new("/bin/sh");
+    command.arg("-c").arg(
+        target_graph
+            .recipes
+            .get(target_name)
+            .map(|recipe| recipe.join("\n"))
+            .unwrap_or_default(),
+    );
+    command
+}
+
+// Evaluates `$$`, `$ `, `$:`, `$name`, and `${name}`.
+fn evaluate(text: &str, lookup: &dyn Fn(&str) -> String) -> String {
+    let mut evaluated = String::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        if c != '$' {
+            evaluated.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some((_, '{')) => {
+                let mut name = String::new();
+                for (_, c) in chars.by_ref() {
+                    if c == '}' {
+                        break;
+                    }
+                    name.push(c);
+                }
+                evaluated.push_str(&lookup(&name));
+            }
+            Some((start, c)) if c.is_ascii_alphanumeric() || c == '_' || c == '-' => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, c)) = chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                evaluated.push_str(&lookup(&text[start..end]));
+            }
+            Some((_, c)) => evaluated.push(c),
+            None => {}
+        }
+    }
+    evaluated
+}
+
+// Like `ninja`, only quotes paths that need it.
+fn shell_escape(path: &str) -> String {
+    if !path.is_empty()
+        && path
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_+-./,=@%".contains(c))
+    {
+        return path.to_owned();
+    }
+    format!("'{}'", path.replace('\'', r"'\''"))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Path(String),
+    Colon,
+    Pipe,
+    DoublePipe,
+    PipeAt,
+}
+
+// Splits a `build` line into (unevaluated) paths and separators.
+fn tokenize_build_line(text: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut path = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let separator = match c {
+            '$' => {
+                path.push(c);
+                if let Some(c) = chars.next() {
+                    path.push(c);
+                }
+                continue;
+            }
+            ' ' | '\t' => None,
+            ':' => Some(Token::Colon),
+            '|' => Some(match chars.peek() {
+                Some('|') => {
+                    chars.next();
+                    Token::DoublePipe
+                }
+                Some('@') => {
+                    chars.next();
+                    Token::PipeAt
+                }
+                _ => Token::Pipe,
+            }),
+            c => {
+                path.push(c);
+                continue;
+            }
+        };
+        if !path.is_empty() {
+            tokens.push(Token::Path(std::mem::take(&mut path)));
+        }
+        tokens.extend(separator);
+    }
+    if !path.is_empty() {
+        tokens.push(Token::Path(path));
+    }
+    tokens
+}
+
+struct Build {
+    outputs: Vec<String>,
+    rule: String,
+    inputs: Vec<String>,
+    implicit_inputs: Vec<String>,
+    order_only_inputs: Vec<String>,
+    bindings: HashMap<String, String>,
+}
+
+#[derive(Default)]
+struct NinjaFile {
+    variables: HashMap<String, String>,
+    rules: HashMap<String, HashMap<String, String>>,
+    builds: Vec<Build>,
+    defaults: Vec<String>,
+}
+
+// Joins `$`-continued lines and drops comments.
+fn logical_lines(contents: &str) -> Vec<String> {
+    let mut lines = vec![];
+    let mut current: Option<String> = None;
+    for line in contents.lines() {
+        let line = match current.take() {
+            Some(previous) => previous + line.trim_start(),
+            None => {
+                if line.trim_start().starts_with('#') {
+                    continue;
+                }
+                line.to_owned()
+            }
+        };
+        let trailing_dollars = line.len() - line.trim_end_matches('$').len();
+        if trailing_dollars % 2 == 1 {
+            current = Some(line[..line.len() - 1].to_owned());
+        } else {
+            lines.push(line);
+        }
+    }
+    lines.extend(current);
+    lines
+}
+
+fn split_binding(line: &str) -> Option<(String, String)> {
+    let (name, value) = line.split_once('=')?;
+    Some((name.trim().to_owned(), value.trim_start().to_owned()))
+}
+
+fn exit_with_error(path: &str, line: &str, message: &str) -> ! {
+    eprintln!("Could not parse `{}`: {}: {}", path, message, line);
+    exit(1);
+}
+
+// `include` and `subninja` are both read into the same scope.
+// TODO: give `subninja` files their own scope for variables and rules.
+fn read_ninja_file(path: &str, ninja_file: &mut NinjaFile) {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) => {
+            eprintln!("Could not read `{}`: {}", path, error);
+            exit(1);
+        }
+    };
+    let lines = logical_lines(&contents);
+    let mut lines = lines.iter().peekable();
+    while let Some(line) = lines.next() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        // Evaluated in the file scope.
+        let evaluate_in_file = |text: &str| {
+            evaluate(text, &|name| {
+                ninja_file.variables.get(name).cloned().unwrap_or_default()
+            })
+        };
+        let (keyword, rest) = line.split_once([' ', '\t']).unwrap_or((line, ""));
+        let rest = rest.trim_start();
+        let mut bindings = HashMap::new();
+        if matches!(keyword, "rule" | "build" | "pool") {
+            while let Some(binding) = lines.next_if(|line| line.starts_with([' ', '\t'])) {
+                if binding.trim().is_empty() {
+                    continue;
+                }
+                let Some((name, value)) = split_binding(binding) else {
+                    exit_with_error(path, binding, "expected `name = value`");
+                };
+                bindings.insert(name, value);
+            }
+        }
+        match keyword {
+            "rule" => {
+                ninja_file.rules.insert(rest.trim().to_owned(), bindings);
+            }
+            "build" => {
+                // Build variables are evaluated once, in the file scope. Paths can refer to them.
+                let bindings: HashMap<String, String> = bindings
+                    .into_iter()
+                    .map(|(name, value)| {
+                        let value = evaluate_in_file(&value);
+                        (name, value)
+                    })
+                    .collect();
+                let evaluate_path = |text: &str| {
+                    evaluate(text, &|name| {
+                        bindings
+                            .get(name)
+                            .or_else(|| ninja_file.variables.get(name))
+                            .cloned()
+                            .unwrap_or_default()
+                    })
+                };
+                let tokens = tokenize_build_line(rest);
+                let Some(colon) = tokens.iter().position(|token| *token == Token::Colon) else {
+                    exit_with_error(path, line, "expected `:` in `build` statement");
+                };
+                let mut outputs = vec![];
+                for token in &tokens[..colon] {
+                    // Implicit outputs (after `|`) are also outputs of the build.
+                    if let Token::Path(output) = token {
+                        outputs.push(evaluate_path(output));
+                    }
+                }
+                let mut after_colon = tokens[colon + 1..].iter();
+                let Some(Token::Path(rule)) = after_colon.next() else {
+                    exit_with_error(path, line, "expected a rule name after `:`");
+                };
+                let (mut inputs, mut implicit_inputs, mut order_only_inputs) =
+                    (vec![], vec![], vec![]);
+                let mut section = &mut inputs;
+                for token in after_colon {
+                    match token {
+                        Token::Path(input) => section.push(evaluate_path(input)),
+                        Token::Pipe => section = &mut implicit_inputs,
+                        Token::DoublePipe => section = &mut order_only_inputs,
+                        // Validations do not need to be built first.
+                        // TODO: schedule validations.
+                        Token::PipeAt => break,
+                        Token::Colon => exit_with_error(path, line, "unexpected `:`"),
+                    }
+                }
+                ninja_file.builds.push(Build {
+                    outputs,
+                    rule: rule.to_owned(),
+                    inputs,
+                    implicit_inputs,
+                    order_only_inputs,
+                    bindings,
+                });
+            }
+            "default" => {
+                let defaults: Vec<String> = tokenize_build_line(rest)
+                    .into_iter()
+                    .filter_map(|token| match token {
+                        Token::Path(default) => Some(evaluate_in_file(&default)),
+                        _ => None,
+                    })
+                    .collect();
+                ninja_file.defaults.extend(defaults);
+            }
+            "include" | "subninja" => {
+                let included_path = evaluate_in_file(rest.trim());
+                read_ninja_file(&included_path, ninja_file);
+            }
+            // TODO: limit parallelism using pools.
+            "pool" => {}
+            _ => {
+                let Some((name, value)) = split_binding(line) else {
+                    exit_with_error(path, line, "unknown statement");
+                };
+                let value = evaluate_in_file(&value);
+                ninja_file.variables.insert(name, value);
+            }
+        }
+    }
+}
+
+// Looks up a variable for the command of `build`: `$in`/`$out`, then build variables, then rule variables
+// (which are evaluated in the scope of the build), then file variables.
+fn build_variable(
+    ninja_file: &NinjaFile,
+    build: &Build,
+    rule: &HashMap<String, String>,
+    name: &str,
+    depth: usize,
+) -> String {
+    let escaped = |paths: &[String], separator: &str| {
+        paths
+            .iter()
+            .map(|path| shell_escape(path))
+            .collect::<Vec<String>>()
+            .join(separator)
+    };
+    match name {
+        "in" => return escaped(&build.inputs, " "),
+        "in_newline" => return escaped(&build.inputs, "\n"),
+        "out" => return escaped(&build.outputs, " "),
+        _ => {}
+    }
+    if let Some(value) = build.bindings.get(name) {
+        return value.to_owned();
+    }
+    // Rule variables that refer to themselves are cut off instead of recursing forever.
+    if let Some(value) = rule.get(name).filter(|_| depth < 64) {
+        return evaluate(value, &|name| {
+            build_variable(ninja_file, build, rule, name, depth + 1)
+        });
+    }
+    ninja_file.variables.get(name).cloned().unwrap_or_default()
+}
+
+/// Builds the graph from the `build` statements of a `build.ninja`.
+/// Header dependencies (`depfile`/`deps`) and `restat` are not read.
+pub(crate) fn read_ninja(ninja_file_path_str: &Option<String>) -> TargetGraph {
+    let path = ninja_file_path_str
+        .as_deref()
+        .unwrap_or(DEFAULT_NINJA_FILE_PATH);
+    let mut ninja_file = NinjaFile::default();
+    read_ninja_file(path, &mut ninja_file);
+
+    let mut target_graph = TargetGraph::default();
+    let empty_rule = HashMap::new();
+    for build in &ninja_file.builds {
+        let is_phony = build.rule == "phony";
+        let rule = if is_phony {
+            &empty_rule
+        } else {
+            match ninja_file.rules.get(&build.rule) {
+                Some(rule) => rule,
+                None => {
+                    eprintln!(
+                        "Could not parse `{}`: unknown rule `{}` for `{}`",
+                        path,
+                        build.rule,
+                        build.outputs.join(" ")
+                    );
+                    exit(1);
+                }
+            }
+        };
+        let lookup = |name: &str| build_variable(&ninja_file, build, rule, name, 0);
+        let command = evaluate(rule.get("command").map_or("", String::as_str), &lookup);
+        let description = evaluate(rule.get("description").map_or("", String::as_str), &lookup);
+
+        let mut dependencies: Vec<TargetName> = vec![];
+        for input in build.inputs.iter().chain(&build.implicit_inputs) {
+            let input = TargetName(input.to_owned());
+            if !dependencies.contains(&input) {
+                dependencies.push(input);
+            }
+        }
+        let order_only_dependencies: Vec<TargetName> = build
+            .order_only_inputs
+            .iter()
+            .map(|input| TargetName(input.to_owned()))
+            .collect();
+        let outputs: Vec<TargetName> = build
+            .outputs
+            .iter()
+            .map(|output| TargetName(output.to_owned()))
+            .collect();
+        for output in &outputs {
+            target_graph
+                .edges
+                .insert(output.clone(), dependencies.clone());
+            if !order_only_dependencies.is_empty() {
+                target_graph
+                    .order_only_edges
+                    .insert(output.clone(), order_only_dependencies.clone());
+            }
+            if is_phony {
+                target_graph.phony.insert(output.clone());
+            } else {
+                target_graph
+                    .recipes
+                    .insert(output.clone(), vec![command.clone()]);
+            }
+            if !description.is_empty() {
+                target_graph
+                    .descriptions
+                    .insert(output.clone(), description.clone());
+            }
+        }
+        // All outputs of a build statement are made by a single command.
+        if outputs.len() > 1 {
+            target_graph.add_target_group(&outputs);
+        }
+    }
+    // Inputs that are not built are source files.
+    let inputs: Vec<TargetName> = target_graph
+        .edges
+        .values()
+        .chain(target_graph.order_only_edges.values())
+        .flatten()
+        .cloned()
+        .collect();
+    for input in inputs {
+        target_graph.edges.entry(input).or_default();
+    }
+    // TODO: build all `default` targets (and, without any, all targets that are not inputs of another build).
+    target_graph.default_goal = ninja_file
+        .defaults
+        .first()
+        .map(|default| TargetName(default.to_owned()));
+    target_graph.makefiles = vec![path.to_owned()];
+    target_graph
+}
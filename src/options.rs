@@ -1,31 +1,480 @@
-use clap::{CommandFactory, Parser};
+use clap::{CommandFactory, Parser, ValueEnum};
 use clap_complete::generator::generate;
 use clap_complete::{Generator, Shell};
+use std::ffi::OsString;
 use std::io::stdout;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::time::Duration;
+
+use crate::cgroups::{parse_cpu_limit, parse_memory_size};
+use crate::hermetic;
+use crate::parse::parse_duration;
+use crate::processes::{parse_io_priority, IoPriority};
+use crate::resources::parse_resource;
+use crate::shuffle::{parse_shuffle, Shuffle};
 
 /// Fast make
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 #[clap(name = "mak")]
 pub(crate) struct MakArgs {
-    /// Makefile path
+    /// Makefile path (or the justfile, `package.json`, Taskfile, or `build.ninja` path, for the other formats)
+    /// Like for `make`, this can be passed several times to read several makefiles (in order).
     #[clap(short = 'f', long = "file", alias = "makefile", verbatim_doc_comment)]
-    pub(crate) makefile_path: Option<PathBuf>,
+    pub(crate) makefile_paths: Vec<PathBuf>,
+
+    /// Where targets come from:
+    /// - `make`: a Makefile (run using `make`). `$(MAK_OS)` and `$(MAK_ARCH)` are set to the current platform, e.g. `linux`/`macos`/`windows` and `x86_64`/`aarch64`.
+    /// - `just`: a justfile (read using `just --dump` and run using `just`).
+    /// - `package-json`: the `scripts` of a `package.json` (run using `npm run`). `preX` runs before `X` and `postX` right after it.
+    /// - `taskfile`: the `tasks` of a go-task `Taskfile.yml` (`cmds` are run using `sh`, without evaluating templates).
+    /// - `ninja`: the `build` statements of a `build.ninja` (commands are run using `sh`).
+    #[clap(long, value_enum, default_value_t = Format::Make, verbatim_doc_comment)]
+    pub(crate) format: Format,
+
+    /// How to read the Makefile:
+    /// - `make-db`: evaluate it with `make` and parse its database (`make -prq`). Supports everything `make` does.
+    /// - `native`: parse the Makefile (and the ones it includes) directly, without running `make`.
+    #[clap(long, value_enum, default_value_t = ParserMode::MakeDb, verbatim_doc_comment)]
+    pub(crate) parser: ParserMode,
+
+    /// How to run the recipes of a Makefile:
+    /// - `make`: run `make` for each target that is out of date (telling it that the target's prerequisites are already
+    ///   up to date). Targets whose file is newer than their prerequisites are shown as up to date without starting `make`.
+    /// - `direct`: run the recipe lines using the shell, without starting `make` for each target. Like `make`, a recipe
+    ///   is only run if its target is out of date. `::` rules and recursive `make` targets are still run using `make`.
+    ///   With `--parser make-db`, `export` has no effect, since `make`'s database doesn't show what is exported.
+    #[clap(long, value_enum, default_value_t = Executor::Make, verbatim_doc_comment)]
+    pub(crate) executor: Executor,
+
+    /// How to decide whether a target is out of date:
+    /// - `mtime`: a prerequisite is newer than the target (like `make`).
+    /// - `hash`: a prerequisite is newer than the target, and its contents changed since the target was last built
+    ///   (stored in `.mak/state`). This way, `git checkout` or `touch` without changes doesn't cause rebuilds. Targets
+    ///   that were never built this way use `mtime`.
+    ///
+    /// Either way, a target with a comment like `# mak:env=CC,CFLAGS` is also out of date when the value of one of
+    /// those variables changed since it was last built.
+    #[clap(long, value_enum, default_value_t = FreshnessCheck::Mtime, verbatim_doc_comment)]
+    pub(crate) freshness: FreshnessCheck,
+
+    /// How to check symbolic links for whether targets are out of date (and for `--freshness hash` and the
+    /// `--build-cache`):
+    /// - `follow`: use the modification time and contents of the file that the link points to (like `make`).
+    /// - `no-follow`: use the modification time of the link itself, and the path that it points to instead of the
+    ///   contents.
+    ///
+    /// With `follow`, a link in a `# mak:directory=true` directory to a directory that was already checked (e.g. to a
+    /// parent directory) is not followed again.
+    #[clap(long, value_enum, default_value_t = Symlinks::Follow, verbatim_doc_comment)]
+    pub(crate) symlinks: Symlinks,
+
+    /// Before running a recipe, look for its targets in `.mak/build-cache`, under a key made from the targets, the
+    /// expanded recipe, the contents of the prerequisites, and the variables from `# mak:env=…`. If they are there
+    /// (because the recipe already ran with the same inputs, e.g. on another branch), they are copied from there
+    /// instead of running the recipe. Otherwise, they are stored there once the recipe succeeds. Only for
+    /// `--format make` targets with a recipe that are files (or directories, with `# mak:directory=true`).
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) build_cache: bool,
+
+    /// Also share the `--build-cache` with others through an HTTP server: entries are downloaded with
+    /// `GET URL/<key>/<index>` (for the targets of a recipe, in order) if they are not in `.mak/build-cache`, and
+    /// checked against the SHA-256 hashes in `URL/<key>/digests`; they are uploaded with `PUT` once a recipe succeeds
+    /// (the digests last). If `MAK_CACHE_TOKEN` is set, it is sent as a bearer token. Needs
+    /// `curl`. Implies `--build-cache`.
+    /// The URL can also be a bucket: `s3://bucket/prefix` (using the `aws` CLI, also for S3-compatible stores with
+    /// `AWS_ENDPOINT_URL`) or `gs://bucket/prefix` (using `gcloud`), with the credentials that they are configured
+    /// with (e.g. `AWS_ACCESS_KEY_ID`/`AWS_SECRET_ACCESS_KEY`, or `gcloud auth`).
+    #[clap(long, value_name = "URL", verbatim_doc_comment)]
+    pub(crate) cache_url: Option<String>,
+
+    /// Print how often the `--build-cache` had the targets of recipes (in `.mak/build-cache`, or at the `--cache-url`)
+    /// over all builds, and the number, total size, and largest of the entries in `.mak/build-cache` (instead of running
+    /// anything).
+    #[clap(long, group = "command-like", verbatim_doc_comment)]
+    pub(crate) cache_stats: bool,
+
+    /// List the entries in `.mak/build-cache`, most recently used first, with their keys, sizes, and targets (instead of running
+    /// anything).
+    #[clap(long, group = "command-like", verbatim_doc_comment)]
+    pub(crate) cache_ls: bool,
+
+    /// Print the inputs that the key of an entry in `.mak/build-cache` was made from: the expanded recipe, and the
+    /// hashes of the prerequisites and of the variables from `# mak:env=…` (instead of running anything). For a target,
+    /// this is its newest entry.
+    #[clap(
+        long,
+        group = "command-like",
+        value_name = "KEY|TARGET",
+        verbatim_doc_comment
+    )]
+    pub(crate) cache_inspect: Option<String>,
+
+    /// Delete the entries in `.mak/build-cache` that are over `--cache-max-age` or `--cache-max-size` (instead of
+    /// running anything). This also happens after each build that adds entries.
+    #[clap(long, group = "command-like", verbatim_doc_comment)]
+    pub(crate) cache_gc: bool,
+
+    /// The most that the entries in `.mak/build-cache` can take up, e.g. `500M` or `20G`. The least recently used
+    /// (stored or restored) entries are deleted first.
+    #[clap(long, value_name = "SIZE", value_parser = parse_memory_size, default_value = "10G", verbatim_doc_comment)]
+    pub(crate) cache_max_size: u64,
+
+    /// How long since an entry in `.mak/build-cache` was last used (stored or restored) until it is deleted, e.g. `12h`
+    /// or `7d`.
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration, default_value = "30d", verbatim_doc_comment)]
+    pub(crate) cache_max_age: Duration,
+
+    /// The shell to run recipes with, instead of the makefile's `SHELL` (the same as passing `SHELL=<SHELL>`).
+    #[clap(long, value_name = "SHELL", verbatim_doc_comment)]
+    pub(crate) shell: Option<String>,
+
+    /// What to do with the output of recipes:
+    /// - `stream`: print it as soon as it is printed, prefixed with the target (e.g. `[build] …`, in a color per target).
+    /// - `failures`: only print the output of targets that fail (e.g. to keep CI logs short).
+    #[clap(long, value_enum, default_value_t = OutputMode::Stream, verbatim_doc_comment)]
+    pub(crate) output: OutputMode,
+
+    /// Schedule the targets of makefiles in other directories that are run by recipes like `$(MAKE) -C subdir [targets]`
+    /// (instead of running each recursive `make` as a single step). Their targets are named `subdir/target`.
+    /// Recursive makefiles are always read using `make` (like `--parser make-db`).
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) recursive_make: bool,
+
+    /// If a recipe fails, delete its target file if the recipe changed it (as if the makefile used `.DELETE_ON_ERROR`),
+    /// so that a partially written file is not considered up to date later.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) delete_on_error: bool,
+
+    /// Stop a recipe (and everything it started) if it runs for longer than this, e.g. `90s`, `5m`, or `1h`. The target
+    /// then fails. Targets can set their own timeout with a comment on the line before their rule (or at the end of it),
+    /// e.g.:
+    ///
+    ///  # mak:timeout=30m
+    ///  test-integration: build
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration, verbatim_doc_comment)]
+    pub(crate) timeout: Option<Duration>,
+
+    /// How many times to run a failed recipe again (e.g. for downloads). Targets can set their own number of retries
+    /// with a comment like `# mak:retries=3` (placed like `mak:timeout`).
+    #[clap(long, value_name = "N", default_value_t = 0, verbatim_doc_comment)]
+    pub(crate) retries: u32,
+
+    /// How long to wait before the first retry. The delay doubles after each retry. Defaults to retrying right away.
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration, verbatim_doc_comment)]
+    pub(crate) retry_delay: Option<Duration>,
+
+    /// Run recipes with this niceness (like `nice -n`), e.g. `10` so that a big build doesn't slow down everything else.
+    /// Targets can set their own with a comment like `# mak:nice=19` (placed like `mak:tags`).
+    #[clap(
+        long,
+        value_name = "N",
+        allow_negative_numbers = true,
+        verbatim_doc_comment
+    )]
+    pub(crate) nice: Option<i32>,
+
+    /// Run recipes with this I/O scheduling class (like `ionice`, only on Linux): `idle`, `best-effort`, or `realtime`,
+    /// optionally with a level from 0 (highest) to 7, e.g. `best-effort:7`. Targets can set their own with a comment like
+    /// `# mak:ionice=idle`.
+    #[clap(long, value_name = "CLASS[:LEVEL]", value_parser = parse_io_priority, verbatim_doc_comment)]
+    pub(crate) ionice: Option<IoPriority>,
+
+    /// Run each recipe in a cgroup of its own with this memory limit (Linux with cgroup v2 only), e.g. `4G`, so that a
+    /// runaway recipe is killed instead of the machine running out of memory. `mak` needs to be able to create cgroups
+    /// in its own, e.g. with `systemd-run --user --scope -p Delegate=yes mak …`.
+    #[clap(long, value_name = "SIZE", value_parser = parse_memory_size, verbatim_doc_comment)]
+    pub(crate) job_memory_limit: Option<u64>,
+
+    /// Like `--job-memory-limit`, but limits each recipe to this many CPUs, e.g. `2` or `0.5`.
+    #[clap(long, value_name = "CPUS", value_parser = parse_cpu_limit)]
+    pub(crate) job_cpu_limit: Option<f64>,
+
+    /// How many recipes can use a resource at the same time, e.g. `db=2`. Targets declare the resources that they use
+    /// with a comment like `# mak:resources=db,port-8080` (placed like `mak:tags`), e.g. for tests that share a
+    /// database or a port. Resources that are not passed can only be used by one recipe at a time. Can be passed
+    /// several times.
+    #[clap(long, value_name = "NAME=CAPACITY", value_parser = parse_resource, verbatim_doc_comment)]
+    pub(crate) resource: Vec<(String, usize)>,
+
+    /// Run each recipe in a sandbox (Landlock on Linux, `sandbox-exec` on macOS) where it can only read the makefiles,
+    /// its prerequisites, and its targets from the current directory, so that an undeclared prerequisite fails the
+    /// build instead of making it flaky. Files outside of the current directory can still be read, and only the
+    /// temporary directory and `/dev` can be written. On Linux, targets that don't exist yet are created as empty files
+    /// first (and removed again if the recipe doesn't write them), and other files that the recipe creates in the
+    /// directories of the targets stay empty, so a recipe can't replace a target by renaming a temporary file onto it.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) sandbox: bool,
+
+    /// Run each recipe under `strace` (Linux only) to record which files it reads, and warn about files in the current
+    /// directory that it read but that are not among its prerequisites (directly or through other prerequisites).
+    /// These make parallel builds flaky if another rule makes them, and otherwise don't cause rebuilds when they
+    /// change. Recipes on `--workers` or in containers are not traced.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) trace_dependencies: bool,
 
-    /// Makefile target
+    /// Build even if the recipes of different targets write the same file, which is a race when they run in parallel.
+    /// Without this, `mak` fails before running anything if it finds such recipes (by their targets, and the files
+    /// that they redirect output to with `>`), and fails after the build if a target changed after its recipe
+    /// finished. With it, these are only warnings.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) allow_output_conflicts: bool,
+
+    /// Continue a build that was interrupted (or failed): skip the recipes that succeeded during the last build, if
+    /// their inputs (the expanded recipe, and the modification times of the prerequisites) didn't change since. Every
+    /// build records the recipes that succeeded in `.mak/journal`, which is removed once a build succeeds.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) resume: bool,
+
+    /// Also run recipes over SSH on the workers listed in this file, one per line as `HOST [SLOTS] [DIRECTORY]`, e.g.
+    /// `build1 8 /scratch/project` (`SLOTS` defaults to 1, `DIRECTORY` to `~/.cache/mak/remote/<current directory name>`).
+    /// A `HOST` like `mak://build2:7878` is a `mak --serve-worker` instead.
+    /// A ready target goes to a free worker first: its makefiles and prerequisites are copied there with `rsync`, and its
+    /// targets are copied back once the recipe succeeds. The workers need `rsync` and the tools that the recipes use.
+    /// Phony targets, recursive `make` targets, and targets with a `# mak:remote=false` comment always run locally.
+    /// `--jobs` is raised by the number of worker slots.
+    #[clap(long, value_name = "FILE", verbatim_doc_comment)]
+    pub(crate) workers: Option<PathBuf>,
+
+    /// Run recipes for coordinators (instead of building anything): listen on this address (e.g. `0.0.0.0:7878`), and
+    /// run the recipes that a `mak` with a `mak://HOST:PORT` line in its `--workers` file sends. Both need the same
+    /// secret in `MAK_WORKER_TOKEN`. Like for SSH workers, the recipes' prerequisites are sent along and their targets
+    /// sent back, and the worker needs the tools that the recipes use. Files are kept in `.mak/worker/`. Nothing is
+    /// encrypted (including the token), so outside of a trusted network, connect through an SSH tunnel (e.g.
+    /// `ssh -L 7878:localhost:7878 build1` and `mak://localhost:7878`) or a TLS proxy like `stunnel`.
+    #[clap(
+        long,
+        value_name = "ADDRESS",
+        group = "command-like",
+        verbatim_doc_comment
+    )]
+    pub(crate) serve_worker: Option<String>,
+
+    // Used by a coordinator to run a recipe on a `mak://` worker.
+    #[clap(long, hide = true, num_args = 2, value_names = ["ADDRESS", "JOB_FILE"])]
+    pub(crate) worker_client: Option<Vec<String>>,
+
+    /// The program that runs the recipes of targets with a `# mak:image=rust:1.79` comment (placed like `mak:tags`) in
+    /// a container of that image, with the current directory mounted at the same path: `docker`, `podman`, or anything
+    /// that accepts the same `run` arguments.
+    #[clap(
+        long,
+        value_name = "PROGRAM",
+        default_value = "docker",
+        verbatim_doc_comment
+    )]
+    pub(crate) container_runtime: String,
+
+    /// Keep building the targets that don't depend on a failed target, and list all failures at the end. Otherwise, no
+    /// new recipes are started once one fails (like for `make`, ones that are already running are allowed to finish).
+    #[clap(short = 'k', long, verbatim_doc_comment)]
+    pub(crate) keep_going: bool,
+
+    /// Set an environment variable (`KEY=value`) for `mak`, the makefiles, and all recipes, e.g. to switch toolchains
+    /// without editing the makefile. Can be passed several times. (Unlike for `make`, `-e` means
+    /// `--environment-overrides`.)
+    #[clap(long = "env", value_name = "KEY=VALUE", verbatim_doc_comment)]
+    pub(crate) environment_variables: Vec<String>,
+
+    /// Read environment variables (like for `--env`) from a file with `KEY=value` lines, e.g. `--env-file .env`. Empty
+    /// lines and lines starting with `#` are ignored, values can be quoted, and lines can start with `export `.
+    /// Variables passed using `--env` take precedence.
+    #[clap(long, value_name = "PATH", verbatim_doc_comment)]
+    pub(crate) env_file: Option<PathBuf>,
+
+    /// Let environment variables take precedence over assignments in the makefile (like `make -e`). Variables passed as
+    /// `VAR=value` and ones assigned using `override` still take precedence over the environment.
+    #[clap(short = 'e', long, verbatim_doc_comment)]
+    pub(crate) environment_overrides: bool,
+
+    /// Don't let the build depend on the environment it is started from: `mak`, the makefiles, and the recipes only
+    /// get `HOME`, `TERM`, the variables of a parent `make`, the variables of `--env` and `--env-file`, and the ones
+    /// passed to `--keep-env`. `PATH` only has `/usr/bin`, `/bin`, and links to the tools passed to `--tool` (and
+    /// `make`), and each recipe gets its own empty `TMPDIR`.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) hermetic: bool,
+
+    /// With `--hermetic`: keep this environment variable. Can be passed several times.
+    #[clap(long, value_name = "NAME", requires = "hermetic", verbatim_doc_comment)]
+    pub(crate) keep_env: Vec<String>,
+
+    /// With `--hermetic`: a tool that recipes can run, as a name (found in the current `PATH`) or a path, e.g.
+    /// `--tool cargo`. Can be passed several times.
+    #[clap(long, value_name = "TOOL", requires = "hermetic", verbatim_doc_comment)]
+    pub(crate) tool: Vec<String>,
+
+    /// Where to write the full output of each target's recipe, to `<DIR>/<target>.log` (replacing the log from the
+    /// last run). Defaults to `.mak/logs`.
+    #[clap(long, value_name = "DIR", verbatim_doc_comment)]
+    pub(crate) log_dir: Option<PathBuf>,
+
+    /// After the build, write a JSON manifest (to `.mak/manifest.json`, or `PATH`) with each target that has a recipe,
+    /// whether it was `built`, `up-to-date`, `from-build-cache`, or `built-before` (with `--resume`), and the files it
+    /// made (the files in it, for a directory target) with their sizes and SHA-256 hashes.
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = ".mak/manifest.json", value_name = "PATH", verbatim_doc_comment)]
+    pub(crate) manifest: Option<PathBuf>,
+
+    /// Reuse the graph from the last run (stored in `.mak/graph-cache`) if the makefiles, the environment variables they
+    /// mention, and the arguments are unchanged, instead of reading the makefiles again.
+    /// Note that changes in the output of `$(shell …)` or `$(wildcard …)` are not noticed.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) cache: bool,
+
+    /// How many recipes to run at the same time (defaults to the number of CPUs). Like for `make`, `-j` without a number
+    /// means no limit.
+    /// When more recipes could run than that, the ones on the longest chain of recipes that still have to run (by how
+    /// long they took last time) go first. Ties go to targets with a higher priority, set with a comment like
+    /// `# mak:priority=10` (placed like `mak:tags`).
+    #[clap(short = 'j', long, num_args = 0..=1, value_name = "N", verbatim_doc_comment)]
+    jobs: Option<Option<String>>,
+
+    /// Schedule the prerequisites of each target in a random order instead of the order they are listed in (like
+    /// `make --shuffle`), to find missing prerequisites that the build only gets away with because of that order. The
+    /// seed is printed, and passing it (`--shuffle=SEED`) gives the same order again. `--shuffle=reverse` reverses the
+    /// order instead. Either replaces the longest-chain-first order of `--jobs`.
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "random", value_name = "SEED", value_parser = parse_shuffle, verbatim_doc_comment)]
+    pub(crate) shuffle: Option<Shuffle>,
+
+    /// Print the same log for the same inputs, e.g. to compare builds or logs: recipes still run at the same time, but
+    /// the output of each one is held back until it is done and printed in a fixed order (the order in which they would
+    /// run one at a time), job slots go to the recipes in that order, and progress bars and times are not shown.
+    /// Recipes that fail without `--keep-going` can still stop the build at different points.
+    #[clap(long, conflicts_with = "shuffle", verbatim_doc_comment)]
+    pub(crate) deterministic: bool,
+
+    /// The maximum number of recipes to run at the same time, from `--jobs` (or `MAKEFLAGS`). `None` means no limit.
+    #[clap(skip)]
+    pub(crate) job_limit: Option<usize>,
+
+    /// Print extra information about what `mak` is doing (to stderr).
+    #[clap(short, long, verbatim_doc_comment)]
+    pub(crate) verbose: bool,
+
+    /// Build the targets with this tag (in addition to any targets passed), instead of the default goal.
+    /// Targets are tagged with a comment on the line before their rule (or at the end of it), e.g.:
+    ///
+    ///  # mak:tags=slow,network
+    ///  test-integration: build
+    ///
+    /// Can be passed several times.
+    #[clap(long = "tag", id = "TAG", verbatim_doc_comment)]
+    pub(crate) tags: Vec<String>,
+
+    /// Skip the targets with this tag, even when they are prerequisites of other targets (which are built without
+    /// them). Can be passed several times.
+    #[clap(long = "skip-tag", id = "SKIP_TAG", verbatim_doc_comment)]
+    pub(crate) skip_tags: Vec<String>,
+
+    /// Makefile targets to build, e.g. `mak build test lint` (or `VAR=value`, which overrides `VAR` like it does for
+    /// `make`). Prerequisites that several targets share are only built once.
     #[clap(verbatim_doc_comment)]
     pub(crate) targets: Vec<String>, // TODO: `Vec<TargetName>`
 
-    /// Show how commands would have been run, without actually running.
-    #[clap(long, group = "command-like", verbatim_doc_comment)]
+    /// The `VAR=value` arguments from `targets` (after any from `MAKEFLAGS`).
+    #[clap(skip)]
+    pub(crate) variable_overrides: Vec<String>,
+
+    /// The flags from `GNUMAKEFLAGS` and `MAKEFLAGS`, e.g. `["-k", "-j8"]`.
+    #[clap(skip)]
+    pub(crate) make_flags: Vec<String>,
+
+    /// Show the commands that would be run for each target, without running anything. Targets are listed in waves:
+    /// each target only depends on targets in earlier waves, so the targets in a wave can be run at the same time.
+    #[clap(short = 'n', long, group = "command-like", verbatim_doc_comment)]
     pub(crate) dry_run: bool,
 
+    /// Instead of running the recipes of out-of-date targets, update the modification times of their files (creating
+    /// missing ones), so that they count as up to date (like `make -t`). Only for `--format make`.
+    #[clap(short = 't', long, group = "command-like", verbatim_doc_comment)]
+    pub(crate) touch: bool,
+
+    /// Rebuild the targets and all of their prerequisites, even if they are up to date (like `make -B`). This also
+    /// ignores the cached graph (see `--cache`).
+    #[clap(short = 'B', long, verbatim_doc_comment)]
+    pub(crate) always_make: bool,
+
+    /// Pretend that FILE was just modified, so that everything that depends on it is rebuilt (like `make -W`). With
+    /// `--dry-run` (which then only lists the targets that would be rebuilt) or `--question`, this shows what a change
+    /// to FILE would rebuild without touching it. Can be passed several times.
+    #[clap(short = 'W', long, visible_aliases = ["new-file", "assume-new"], value_name = "FILE", verbatim_doc_comment)]
+    pub(crate) what_if: Vec<String>,
+
+    /// Never rebuild FILE, and don't rebuild anything because of it (like `make -o`), e.g. after changing a file in a
+    /// way that doesn't matter. Can be passed several times.
+    #[clap(
+        short = 'o',
+        long,
+        visible_alias = "old-file",
+        value_name = "FILE",
+        verbatim_doc_comment
+    )]
+    pub(crate) assume_old: Vec<String>,
+
+    /// Don't run anything, and exit with 0 if the targets are up to date or 1 if any recipe would run (like `make -q`).
+    /// Only for `--format make`.
+    #[clap(short = 'q', long, group = "command-like", verbatim_doc_comment)]
+    pub(crate) question: bool,
+
+    /// Don't run anything, but explain for the targets and their prerequisites whether they would be rebuilt, and why:
+    /// which prerequisite is newer (or changed, with `--freshness hash`), is missing, or is rebuilt first, and whether
+    /// the `--build-cache` has the targets. Only for `--format make`.
+    #[clap(long, group = "command-like", verbatim_doc_comment)]
+    pub(crate) why: bool,
+
+    /// Delete the files that the rules with recipes make (instead of running anything), for the targets passed and their
+    /// prerequisites, or for all targets. Phony and precious (`.PRECIOUS`) targets and the makefiles are kept, and
+    /// directories are only deleted if they are empty (except directory targets). With `--dry-run`, only lists them.
+    #[clap(long, conflicts_with_all = ["touch", "question", "why"], verbatim_doc_comment)]
+    pub(crate) clean: bool,
+
+    /// List the files in output directories that no rule makes (e.g. left over from a renamed target) and offer to
+    /// delete them (instead of running anything). Output directories are the ones with files that rules make (except
+    /// the current directory), unless they have files that no rule makes but that are prerequisites. With `--dry-run`,
+    /// only lists them. Exits with an error if stale files are left.
+    #[clap(long, conflicts_with_all = ["touch", "question", "why", "clean"], verbatim_doc_comment)]
+    pub(crate) prune: bool,
+
+    /// With `--prune`: delete the stale files without asking.
+    #[clap(long, requires = "prune", verbatim_doc_comment)]
+    pub(crate) yes: bool,
+
+    /// Check the Makefile for common problems (duplicate recipes, empty targets, missing prerequisites, unused variables,
+    /// and recipes indented with spaces) and print them as JSON (instead of running anything).
+    /// Exits with an error if there are any problems.
+    #[clap(long, group = "command-like", verbatim_doc_comment)]
+    pub(crate) lint: bool,
+
+    /// Format the Makefile in place (instead of running anything): recipe lines are indented with tabs, continued
+    /// prerequisite lists are aligned, and `.PHONY` prerequisites are sorted.
+    #[clap(long, group = "command-like", verbatim_doc_comment)]
+    pub(crate) fmt: bool,
+
+    /// With `--fmt`: print the makefiles that are not formatted (and exit with an error) instead of changing them.
+    #[clap(long, requires = "fmt", verbatim_doc_comment)]
+    pub(crate) check: bool,
+
     /// Print the dependency graph as JSON (instead of running anything).
     #[clap(long, group = "command-like", verbatim_doc_comment)]
     pub(crate) print_graph: bool,
 
+    /// Show where a target is defined (the file and line of its rules), the pattern rule it is instantiated from, its
+    /// prerequisites, and its recipe with variables expanded (instead of running anything).
+    #[clap(
+        long = "where",
+        group = "command-like",
+        value_name = "TARGET",
+        verbatim_doc_comment
+    )]
+    pub(crate) where_target: Option<String>,
+
+    /// List the targets defined by the Makefile, with descriptions from `target: ## description` comments (instead of running anything).
+    /// Any targets passed are used as shell-style patterns to filter the list, e.g.:
+    ///
+    ///  mak --list 'test*'
+    #[clap(long, group = "command-like", verbatim_doc_comment)]
+    pub(crate) list: bool,
+
     /// Print the the list of targets, one per line (instead of running anything).
     /// Does not return an error when `Makefile` is missing, to avoid unexpected issues with shell completions.
     #[clap(long, group = "command-like", verbatim_doc_comment)]
@@ -40,14 +489,307 @@ pub(crate) struct MakArgs {
     pub(crate) completions: Option<Shell>,
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Format {
+    Make,
+    Just,
+    PackageJson,
+    Taskfile,
+    Ninja,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ParserMode {
+    MakeDb,
+    Native,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Executor {
+    Make,
+    Direct,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FreshnessCheck {
+    Mtime,
+    Hash,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Symlinks {
+    #[default]
+    Follow,
+    NoFollow,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum OutputMode {
+    Stream,
+    Failures,
+}
+
+// Splits `KEY=value` (from `--env` or an env file), removing matching quotes around the value.
+fn parse_environment_variable(text: &str) -> Option<(String, String)> {
+    let text = text.trim();
+    let text = text.strip_prefix("export ").unwrap_or(text).trim_start();
+    let (key, value) = text.split_once('=')?;
+    let key = key.trim();
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+    let value = value.trim();
+    let value = ['"', '\'']
+        .into_iter()
+        .find_map(|quote| {
+            value
+                .strip_prefix(quote)
+                .and_then(|value| value.strip_suffix(quote))
+        })
+        .unwrap_or(value);
+    Some((key.to_owned(), value.to_owned()))
+}
+
+fn read_env_file(path: &Path) -> Vec<(String, String)> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(error) => {
+            eprintln!("Could not read `{}`: {}", path.display(), error);
+            exit(1);
+        }
+    };
+    let mut environment_variables = vec![];
+    for (index, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        match parse_environment_variable(line) {
+            Some(environment_variable) => environment_variables.push(environment_variable),
+            None => {
+                eprintln!(
+                    "{}:{}: expected `KEY=value`: {}",
+                    path.display(),
+                    index + 1,
+                    line
+                );
+                exit(1);
+            }
+        }
+    }
+    environment_variables
+}
+
+// Adds `-<letter>` to `MAKEFLAGS` in the environment, unless `make_flags` (from `MAKEFLAGS`) already has it.
+fn add_make_flag(make_flags: &[String], letter: char, long_flag: &str) {
+    if make_flags
+        .iter()
+        .any(|flag| *flag == format!("-{}", letter) || flag == long_flag)
+    {
+        return;
+    }
+    let make_flags = std::env::var("MAKEFLAGS").unwrap_or_default();
+    let make_flags = match make_flags.trim_start() {
+        "" => format!("-{}", letter),
+        // A first word without a `-` is a group of single-letter flags.
+        trimmed
+            if !trimmed.starts_with('-')
+                && !trimmed.split(' ').next().unwrap_or_default().contains('=') =>
+        {
+            format!("{}{}", letter, trimmed)
+        }
+        trimmed => format!("-{} {}", letter, trimmed),
+    };
+    std::env::set_var("MAKEFLAGS", make_flags);
+}
+
+// Splits `MAKEFLAGS` into words like `make` does: spaces are escaped with `\`, a first word without a `-` is a group
+// of single-letter flags (e.g. `ks` for `-k -s`), and everything after `--` is a variable assignment.
+fn parse_make_flags(make_flags: &str) -> (Vec<String>, Vec<String>) {
+    let mut words: Vec<String> = vec![];
+    let mut word = String::new();
+    let mut chars = make_flags.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => word.extend(chars.next()),
+            ' ' | '\t' => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    let (mut flags, mut variable_overrides) = (vec![], vec![]);
+    let mut words = words.into_iter().enumerate();
+    for (i, word) in words.by_ref() {
+        if word == "--" {
+            break;
+        }
+        if word.contains('=') && !word.starts_with('-') {
+            variable_overrides.push(word);
+        } else if i == 0 && !word.starts_with('-') {
+            flags.extend(word.chars().map(|flag| format!("-{}", flag)));
+        } else {
+            flags.push(word);
+        }
+    }
+    variable_overrides.extend(words.map(|(_, word)| word));
+    (flags, variable_overrides)
+}
+
 fn completions_for_shell(cmd: &mut clap::Command, generator: impl Generator) {
     generate(generator, cmd, "mak", &mut stdout());
 }
 
+// The flags that `MAKEFLAGS` can set, and whether they are set.
+fn flags_from_make_flags(args: &MakArgs) -> [(&'static str, bool); 6] {
+    [
+        ("--dry-run", args.dry_run),
+        ("--question", args.question),
+        ("--always-make", args.always_make),
+        ("--touch", args.touch),
+        ("--environment-overrides", args.environment_overrides),
+        ("--keep-going", args.keep_going),
+    ]
+}
+
 pub(crate) fn get_options() -> MakArgs {
     let mut command = MakArgs::command();
 
-    let args = MakArgs::parse();
+    let mut args = MakArgs::parse();
+    // Like `make`, `-j target` is `-j` (without a limit) followed by a target.
+    let mut jobs = args.jobs.take().map(|jobs| {
+        let jobs = jobs?;
+        match jobs.parse::<usize>() {
+            Ok(jobs) => Some(jobs),
+            Err(_) => {
+                args.targets.insert(0, jobs);
+                None
+            }
+        }
+    });
+    // Set before anything else reads the environment (including `MAKEFLAGS`), so that they apply everywhere.
+    let mut environment_variables = match &args.env_file {
+        Some(env_file) => read_env_file(env_file),
+        None => vec![],
+    };
+    for environment_variable in &args.environment_variables {
+        match parse_environment_variable(environment_variable) {
+            Some(environment_variable) => environment_variables.push(environment_variable),
+            None => {
+                eprintln!("Expected `KEY=value` for `--env`: {}", environment_variable);
+                exit(1);
+            }
+        }
+    }
+    if args.hermetic {
+        if let Err(error) = hermetic::isolate_environment(&args.keep_env, &args.tool) {
+            eprintln!("Could not set up the hermetic environment: {}", error);
+            exit(1);
+        }
+    }
+    for (key, value) in environment_variables {
+        std::env::set_var(key, value);
+    }
+    // Like `make`, any argument with a `=` is a variable assignment rather than a target.
+    (args.variable_overrides, args.targets) = args
+        .targets
+        .into_iter()
+        .partition(|target| target.contains('='));
+
+    // `make` subprocesses read these themselves (from the inherited environment), so only the flags that affect `mak`
+    // itself are applied here.
+    if args.format == Format::Make {
+        let mut variable_overrides = vec![];
+        for name in ["GNUMAKEFLAGS", "MAKEFLAGS"] {
+            if let Ok(value) = std::env::var(name) {
+                let (flags, overrides) = parse_make_flags(&value);
+                args.make_flags.extend(flags);
+                variable_overrides.extend(overrides);
+            }
+        }
+        // Assignments on the command line take precedence.
+        variable_overrides.append(&mut args.variable_overrides);
+        if let Some(shell) = &args.shell {
+            variable_overrides.push(format!("SHELL={}", shell));
+        }
+        args.variable_overrides = variable_overrides;
+        let set_before = flags_from_make_flags(&args);
+        for (index, flag) in args.make_flags.iter().enumerate() {
+            // `-j8`, `--jobs=8`, or `-j` (which may be followed by the number as a separate word).
+            let job_flag = match flag.as_str() {
+                "-j" | "--jobs" => Some(""),
+                flag => flag
+                    .strip_prefix("--jobs=")
+                    .or_else(|| flag.strip_prefix("-j")),
+            };
+            if let (Some(limit), None) = (job_flag, &jobs) {
+                let limit = match limit {
+                    "" => args
+                        .make_flags
+                        .get(index + 1)
+                        .map(String::as_str)
+                        .unwrap_or_default(),
+                    limit => limit,
+                };
+                jobs = Some(limit.parse().ok());
+            }
+            match flag.as_str() {
+                "-n" | "--just-print" | "--dry-run" | "--recon" => args.dry_run = true,
+                "-q" | "--question" => args.question = true,
+                "-B" | "--always-make" => args.always_make = true,
+                "-t" | "--touch" => args.touch = true,
+                "-e" | "--environment-overrides" => args.environment_overrides = true,
+                "-k" | "--keep-going" => args.keep_going = true,
+                _ => {}
+            }
+        }
+        if args.verbose && !args.make_flags.is_empty() {
+            eprintln!("Flags from `MAKEFLAGS`: {}", args.make_flags.join(" "));
+        }
+        // The flags that `MAKEFLAGS` added are checked like the ones on the command line, e.g. `-q` with `--dry-run`.
+        let added_flags: Vec<&str> = flags_from_make_flags(&args)
+            .into_iter()
+            .zip(set_before)
+            .filter(|((_, set), (_, set_before))| *set && !set_before)
+            .map(|((flag, _), _)| flag)
+            .collect();
+        if !added_flags.is_empty() {
+            let arguments = std::env::args_os()
+                .take(1)
+                .chain(added_flags.into_iter().map(OsString::from))
+                .chain(std::env::args_os().skip(1));
+            if let Err(error) = MakArgs::command().try_get_matches_from(arguments) {
+                error.exit();
+            }
+        }
+    }
+    args.job_limit = match jobs {
+        Some(jobs) => jobs.filter(|jobs| *jobs > 0),
+        None => Some(
+            std::thread::available_parallelism()
+                .map(usize::from)
+                .unwrap_or(1),
+        ),
+    };
+    if args.verbose {
+        match args.job_limit {
+            Some(job_limit) => eprintln!("Running up to {} recipes at the same time", job_limit),
+            None => eprintln!("Running any number of recipes at the same time"),
+        }
+    }
+
+    // Pass `-e` and `-B` on to every `make` subprocess the same way `make` does for its own submakes.
+    if args.environment_overrides {
+        add_make_flag(&args.make_flags, 'e', "--environment-overrides");
+    }
+    if args.always_make {
+        add_make_flag(&args.make_flags, 'B', "--always-make");
+    }
     if let Some(shell) = args.completions {
         completions_for_shell(&mut command, shell);
         // TODO: other shells?
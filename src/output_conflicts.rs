@@ -0,0 +1,221 @@
+// Recipes that write the same file race when they run in parallel, and the last one silently wins. These checks find
+// them before running anything (for the files that recipes write as far as their text shows) and after the build (for
+// targets that changed after their recipe finished).
+
+use std::{
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+use indexmap::{IndexMap, IndexSet};
+
+use crate::parse::{TargetGraph, TargetName};
+
+// File modification times come from a coarser clock than `SystemTime::now()`.
+const CLOCK_SLACK: Duration = Duration::from_millis(10);
+
+fn normalize(path: &str) -> String {
+    let mut path = path;
+    while let Some(rest) = path.strip_prefix("./") {
+        path = rest;
+    }
+    path.to_owned()
+}
+
+// Whether `text` runs `cd`, after which relative paths mean something else.
+fn changes_directory(text: &str) -> bool {
+    text.split(|c: char| c.is_whitespace() || ";&|()".contains(c))
+        .any(|word| word == "cd" || word == "pushd")
+}
+
+// The files that `script` redirects output to with `>` (or `>|` and `&>`), as far as they can be known without running
+// it: not after a `cd`, and not with shell variables, substitutions, or wildcards. `>>` doesn't count, since recipes
+// that append to the same file don't overwrite each other.
+fn redirect_targets(script: &str) -> Vec<String> {
+    let mut targets = vec![];
+    let mut quote = None;
+    let mut chars = script.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match (quote, c) {
+            (Some(quote_char), _) if c == quote_char => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '\\') => {
+                chars.next();
+            }
+            (None, '>') => {
+                if changes_directory(&script[..index]) {
+                    break;
+                }
+                match chars.peek().map(|(_, next)| *next) {
+                    // Appending, or duplicating a file descriptor (`2>&1`).
+                    Some('>' | '&') => {
+                        chars.next();
+                        continue;
+                    }
+                    Some('|') => {
+                        chars.next();
+                    }
+                    _ => {}
+                }
+                while chars
+                    .next_if(|(_, next)| *next == ' ' || *next == '\t')
+                    .is_some()
+                {}
+                let mut word = String::new();
+                while let Some((_, next)) =
+                    chars.next_if(|(_, next)| !next.is_whitespace() && !";&|<>()".contains(*next))
+                {
+                    if next != '\'' && next != '"' {
+                        word.push(next);
+                    }
+                }
+                if !word.is_empty()
+                    && !word.contains(['$', '`', '*', '?', '['])
+                    && !word.starts_with("/dev/")
+                {
+                    targets.push(normalize(&word));
+                }
+            }
+            _ => {}
+        }
+    }
+    targets
+}
+
+/// The files that the recipe of `group` writes: its targets (except phony ones), and the files that its recipe
+/// redirects output to.
+pub(crate) fn declared_outputs(target_graph: &TargetGraph, group: &[TargetName]) -> Vec<String> {
+    let mut outputs: Vec<String> = group
+        .iter()
+        .filter(|member| !target_graph.is_phony(member))
+        .map(|member| normalize(&member.0))
+        .collect();
+    for member in group {
+        for invocation in target_graph.shell_invocations(member) {
+            for path in redirect_targets(&invocation.script) {
+                if !outputs.contains(&path) {
+                    outputs.push(path);
+                }
+            }
+        }
+    }
+    outputs
+}
+
+/// The files that more than one of the recipes needed for `target_names` write, with (the first target of) each of
+/// those recipes. Recipes that only run `make` recursively don't count, since the files they write are unknown.
+pub(crate) fn declared_conflicts(
+    target_graph: &TargetGraph,
+    target_names: &[TargetName],
+) -> IndexMap<String, Vec<TargetName>> {
+    let mut visited: IndexSet<TargetName> = target_names.iter().cloned().collect();
+    let mut index = 0;
+    while let Some(target_name) = visited.get_index(index).cloned() {
+        visited.extend(target_graph.all_prerequisites(&target_name));
+        index += 1;
+    }
+    let mut groups: Vec<Vec<TargetName>> = vec![];
+    for target_name in &visited {
+        let group = target_graph
+            .target_group(target_name)
+            .map(<[TargetName]>::to_vec)
+            .unwrap_or_else(|| vec![target_name.clone()]);
+        if !groups.contains(&group)
+            && group.iter().any(|member| {
+                target_graph.has_recipe(member)
+                    && !target_graph.recursive_make_targets.contains(member)
+            })
+        {
+            groups.push(group);
+        }
+    }
+    let mut writers: IndexMap<String, Vec<TargetName>> = IndexMap::new();
+    for group in &groups {
+        for output in declared_outputs(target_graph, group) {
+            writers.entry(output).or_default().push(group[0].clone());
+        }
+    }
+    writers.retain(|_, writers| writers.len() > 1);
+    writers
+}
+
+struct RecipeRun {
+    label: String,
+    started: SystemTime,
+    finished: SystemTime,
+    outputs: Vec<(String, SystemTime)>,
+}
+
+/// The files that recipes wrote during the build, to find the ones that changed again after their recipe finished.
+#[derive(Default)]
+pub(crate) struct WrittenOutputs {
+    runs: Mutex<Vec<RecipeRun>>,
+}
+
+// Only files are checked, since a directory also changes when a file is added to it.
+fn file_modified_time(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .ok()
+        .filter(|metadata| metadata.is_file())?
+        .modified()
+        .ok()
+}
+
+impl WrittenOutputs {
+    /// Records that the recipe for `label` ran from `started` until now and wrote `outputs`.
+    pub(crate) fn record(&self, label: &str, started: SystemTime, outputs: &[String]) {
+        let run = RecipeRun {
+            label: label.to_owned(),
+            started,
+            finished: SystemTime::now(),
+            outputs: outputs
+                .iter()
+                .filter_map(|output| Some((output.clone(), file_modified_time(output)?)))
+                .collect(),
+        };
+        self.runs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(run);
+    }
+
+    /// A message for each file that changed after the recipe that wrote it finished, with the recipes that were
+    /// running when it changed.
+    pub(crate) fn changed_outputs(&self) -> Vec<String> {
+        let runs = self.runs.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut messages = vec![];
+        for run in runs.iter() {
+            for (output, recorded_modified) in &run.outputs {
+                let modified = file_modified_time(output);
+                if modified == Some(*recorded_modified) {
+                    continue;
+                }
+                let writers: Vec<String> = runs
+                    .iter()
+                    .filter(|other| {
+                        other.label != run.label
+                            && modified.is_some_and(|modified| {
+                                other.started <= modified + CLOCK_SLACK
+                                    && modified <= other.finished
+                            })
+                    })
+                    .map(|other| format!("`{}`", other.label))
+                    .collect();
+                messages.push(match writers.is_empty() {
+                    true => format!(
+                        "`{}` (written by `{}`) changed after its recipe finished",
+                        output, run.label
+                    ),
+                    false => format!(
+                        "`{}` (written by `{}`) changed again while the recipe of {} ran",
+                        output,
+                        run.label,
+                        writers.join(" or ")
+                    ),
+                });
+            }
+        }
+        messages
+    }
+}
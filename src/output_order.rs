@@ -0,0 +1,106 @@
+// For `--deterministic`: recipes still run at the same time, but what they print is held back and printed one target
+// at a time, in a fixed order (the order in which `make -j1` would run them). So two builds of the same inputs print
+// the same log, however long each recipe takes.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::parse::TargetName;
+
+type Report = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct State {
+    // The position whose output is printed next.
+    next: usize,
+    finished: BTreeMap<usize, Vec<Report>>,
+}
+
+pub(crate) struct OutputOrder {
+    positions: HashMap<TargetName, usize>,
+    state: Mutex<State>,
+}
+
+impl OutputOrder {
+    /// `order` has the groups of targets, each with the same position for all of their members.
+    pub(crate) fn new(order: &[Vec<TargetName>]) -> Arc<Self> {
+        let positions = order
+            .iter()
+            .enumerate()
+            .flat_map(|(position, group)| {
+                group.iter().map(move |member| (member.clone(), position))
+            })
+            .collect();
+        Arc::new(Self {
+            positions,
+            state: Mutex::default(),
+        })
+    }
+
+    pub(crate) fn position(&self, target_name: &TargetName) -> Option<usize> {
+        self.positions.get(target_name).copied()
+    }
+
+    /// Holds back what is printed for `target_name` until its turn.
+    pub(crate) fn turn(self: &Arc<Self>, target_name: &TargetName) -> Turn {
+        Turn {
+            output_order: self.clone(),
+            position: self.position(target_name),
+            reports: vec![],
+        }
+    }
+
+    fn finish(&self, position: usize, reports: Vec<Report>) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.finished.insert(position, reports);
+        // Printing while holding the lock keeps the output of different targets apart.
+        loop {
+            let next = state.next;
+            let Some(reports) = state.finished.remove(&next) else {
+                break;
+            };
+            for report in reports {
+                report();
+            }
+            state.next += 1;
+        }
+    }
+
+    /// Prints what is still held back (after a target that was never reached), in order.
+    pub(crate) fn flush(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        for (_, reports) in std::mem::take(&mut state.finished) {
+            for report in reports {
+                report();
+            }
+        }
+    }
+}
+
+/// What is printed for a target, which is printed once this is dropped (and all the targets before it are done).
+pub(crate) struct Turn {
+    output_order: Arc<OutputOrder>,
+    // `None` for targets that are not in the order, whose output is printed right away.
+    position: Option<usize>,
+    reports: Vec<Report>,
+}
+
+impl Turn {
+    pub(crate) fn defer(&mut self, report: impl FnOnce() + Send + 'static) {
+        match self.position {
+            Some(_) => self.reports.push(Box::new(report)),
+            None => report(),
+        }
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        if let Some(position) = self.position {
+            self.output_order
+                .finish(position, std::mem::take(&mut self.reports));
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    path::Path,
+    process::{exit, Command},
+};
+
+use serde_json::Value;
+
+use crate::parse::{TargetGraph, TargetName};
+
+const DEFAULT_PACKAGE_JSON_PATH: &str = "package.json";
+
+// `npm` runs scripts in the directory of the `package.json`.
+fn npm_args(package_json_path_str: &Option<String>) -> Vec<String> {
+    let mut args = vec![];
+    if let Some(package_json_path_str) = package_json_path_str {
+        if let Some(directory) = Path::new(package_json_path_str).parent() {
+            if !directory.as_os_str().is_empty() {
+                args.push("--prefix".to_owned());
+                args.push(directory.to_string_lossy().into_owned());
+            }
+        }
+    };
+    args
+}
+
+/// Runs a single script. `--ignore-scripts` keeps `npm` from running `preX` (which is scheduled as a dependency)
+/// and `postX` (which is run here, right after `X`).
+pub(crate) fn script_command(
+    target_graph: &TargetGraph,
+    package_json_path_str: &Option<String>,
+    target_name: &TargetName,
+) -> Command {
+    let post_script = TargetName(format!("post{}", target_name));
+    let mut command = Command::new("/bin/sh");
+    command
+        .arg("-c")
+        .arg(if target_graph.edges.contains_key(&post_script) {
+            r#"npm "$@" run --ignore-scripts -- "$MAK_SCRIPT" && npm "$@" run --ignore-scripts -- "post$MAK_SCRIPT""#
+        } else {
+            r#"npm "$@" run --ignore-scripts -- "$MAK_SCRIPT""#
+        })
+        .arg("sh")
+        .args(npm_args(package_json_path_str))
+        .env("MAK_SCRIPT", &target_name.0);
+    command
+}
+
+/// Builds the graph from the `scripts` of a `package.json`. Each script becomes a phony target that depends on its `pre` script.
+pub(crate) fn read_package_json(package_json_path_str: &Option<String>) -> TargetGraph {
+    let path = package_json_path_str
+        .as_deref()
+        .unwrap_or(DEFAULT_PACKAGE_JSON_PATH);
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) => {
+            eprintln!("Could not read `{}`: {}", path, error);
+            exit(1);
+        }
+    };
+    let package_json: Value = match serde_json::from_str(&contents) {
+        Ok(package_json) => package_json,
+        Err(error) => {
+            eprintln!("Could not parse `{}`: {}", path, error);
+            exit(1);
+        }
+    };
+
+    let mut target_graph = TargetGraph::default();
+    let Some(scripts) = package_json["scripts"].as_object() else {
+        return target_graph;
+    };
+    for (name, script) in scripts {
+        let target_name = TargetName(name.to_owned());
+        let pre_script = format!("pre{}", name);
+        let dependencies = if scripts.contains_key(&pre_script) {
+            vec![TargetName(pre_script)]
+        } else {
+            vec![]
+        };
+        target_graph.edges.insert(target_name.clone(), dependencies);
+        target_graph.recipes.insert(
+            target_name.clone(),
+            script.as_str().map(str::to_owned).into_iter().collect(),
+        );
+        target_graph.phony.insert(target_name);
+    }
+    target_graph.makefiles = vec![path.to_owned()];
+    target_graph
+}
@@ -1,16 +1,34 @@
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::exit,
+    time::{Duration, SystemTime},
+};
 
-use indexmap::IndexMap;
+use indexmap::{IndexMap, IndexSet};
+use indicatif::{ProgressBar, ProgressStyle};
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_till, take_until, take_while, take_while1},
-    combinator::{all_consuming, not, opt},
-    multi::{many0, separated_list0},
+    bytes::complete::{tag, take_till, take_while, take_while1},
+    combinator::{all_consuming, eof, not, opt, peek},
+    multi::{many0, separated_list0, separated_list1},
     IResult,
 };
 
-use serde::Serialize;
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize)]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    functions::match_pattern,
+    makefile_text::{continues, has_inline_recipe},
+    options::Symlinks,
+    processes::{parse_io_priority, IoPriority},
+    variables::{AssignmentOperator, AutomaticVariables, VariableAssignment, Variables},
+    wildcard::{expand_wildcard, has_wildcard},
+};
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
 pub(crate) struct TargetName(pub(crate) String);
 
 impl Display for TargetName {
@@ -19,18 +37,865 @@ impl Display for TargetName {
     }
 }
 
-#[derive(Debug, Default, Serialize)]
+impl TargetName {
+    /// Like `make`, drops any leading `./` so that e.g. `$(DIR)/foo` with `DIR = .` is the same target as `foo`.
+    pub(crate) fn normalized(name: &str) -> Self {
+        let mut stripped = name;
+        while let Some(rest) = stripped.strip_prefix("./") {
+            stripped = rest.trim_start_matches('/');
+        }
+        match stripped.is_empty() {
+            true => TargetName(name.to_owned()),
+            false => TargetName(stripped.to_owned()),
+        }
+    }
+}
+
+pub(crate) const WAIT: &str = ".WAIT";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub(crate) struct TargetGraph {
     pub(crate) edges: IndexMap<TargetName, Vec<TargetName>>,
     pub(crate) default_goal: Option<TargetName>,
+    /// The main makefile followed by every file it included, as listed in `MAKEFILE_LIST`.
+    pub(crate) makefiles: Vec<String>,
+    pub(crate) pattern_rules: Vec<PatternRule>,
+    /// Targets listed as prerequisites of `.PHONY`. These never correspond to files, so they are never up to date.
+    pub(crate) phony: IndexSet<TargetName>,
+    /// Prerequisites after a `|`. These must be built first, but never cause the target to be rebuilt.
+    pub(crate) order_only_edges: IndexMap<TargetName, Vec<TargetName>>,
+    /// The recipe lines of each target (except for `::` rules), as written (including `@`/`-`/`+` prefixes).
+    pub(crate) recipes: IndexMap<TargetName, Vec<String>>,
+    /// Each `::` rule of a target, in declaration order. `edges` holds the union of their prerequisites.
+    pub(crate) double_colon_rules: IndexMap<TargetName, Vec<DoubleColonRule>>,
+    /// `vpath` directives, in order. The `VPATH` variable is consulted after these.
+    pub(crate) search_paths: Vec<SearchPath>,
+    /// Target-specific (and pattern-specific) assignments like `release: CFLAGS += -O2`, in order.
+    pub(crate) target_variables: IndexMap<TargetName, Vec<VariableAssignment>>,
+    /// Sets of targets that are all produced by a single invocation of one recipe (`a b &: …`).
+    pub(crate) target_groups: Vec<Vec<TargetName>>,
+    /// Set if the makefile mentions `.NOTPARALLEL`. An empty list means no recipes may run in parallel, otherwise only the listed targets' prerequisites are built serially.
+    pub(crate) not_parallel: Option<Vec<TargetName>>,
+    /// Set by `.DELETE_ON_ERROR`: the target file of a failed recipe is deleted (if the recipe changed it).
+    pub(crate) delete_on_error: bool,
+    /// Prerequisites of `.PRECIOUS` (which may be `%` patterns). These are never deleted, even if their recipe fails.
+    pub(crate) precious: Vec<String>,
+    /// Prerequisites of `.SECONDARY`. These are intermediate files that are never deleted after the build.
+    /// An empty list (`.SECONDARY:` without prerequisites) makes every target secondary.
+    pub(crate) secondary: Option<Vec<TargetName>>,
+    /// Prerequisites of `.INTERMEDIATE`. These are deleted after the build (unless they existed before it).
+    // TODO: also treat files that are only made by chains of pattern rules as intermediate.
+    pub(crate) intermediate: Vec<TargetName>,
+    /// Set by `.ONESHELL`: all lines of a recipe are run in a single shell (rather than one shell per line).
+    pub(crate) one_shell: bool,
+    /// For targets with `.WAIT` in their prerequisites: the groups of prerequisites between each `.WAIT`, in order.
+    pub(crate) wait_groups: IndexMap<TargetName, Vec<Vec<TargetName>>>,
+    /// Descriptions from `target: ## description` comments.
+    pub(crate) descriptions: IndexMap<TargetName, String>,
+    /// Tags from `# mak:tags=a,b` comments (on the line before a rule or at the end of it).
+    pub(crate) tags: IndexMap<TargetName, Vec<String>>,
+    /// Timeouts from `# mak:timeout=30s` comments (placed like tags), which take precedence over `--timeout`.
+    pub(crate) timeouts: IndexMap<TargetName, Duration>,
+    /// Retries from `# mak:retries=3` comments, which take precedence over `--retries`.
+    pub(crate) retries: IndexMap<TargetName, u32>,
+    /// Priorities from `# mak:priority=10` comments, to decide which of the targets that are equally urgent otherwise
+    /// gets a job slot first (0 by default, higher first).
+    pub(crate) priorities: IndexMap<TargetName, i64>,
+    /// From `# mak:nice=10` comments, which take precedence over `--nice`.
+    pub(crate) nice: IndexMap<TargetName, i32>,
+    /// From `# mak:ionice=idle` comments, which take precedence over `--ionice`.
+    pub(crate) io_priorities: IndexMap<TargetName, IoPriority>,
+    /// From `# mak:remote=false` comments, for targets that are never run on `--workers`.
+    pub(crate) local_only: IndexSet<TargetName>,
+    /// From `# mak:directory=true` comments, for targets that are directories (e.g. `dist`): they count as modified
+    /// when a file in them is, are hashed by the names and contents of their files, and are stored in the
+    /// `--build-cache` as a whole.
+    pub(crate) directory_targets: IndexSet<TargetName>,
+    /// From `# mak:stamp=true` comments, for targets that don't make a file (e.g. `install-deps`): they are skipped when
+    /// their stamp in `.mak/stamps` shows that the recipe already succeeded with the same inputs.
+    pub(crate) stamp_targets: IndexSet<TargetName>,
+    /// From `# mak:image=rust:1.79` comments, for targets whose recipe runs in a container.
+    pub(crate) images: IndexMap<TargetName, String>,
+    /// From `# mak:resources=db,port-8080` comments, for targets whose recipes can't run at the same time as other
+    /// recipes that use the same resources.
+    pub(crate) resources: IndexMap<TargetName, Vec<String>>,
+    /// From `# mak:env=CC,CFLAGS` comments: variables (usually from the environment) whose values the target depends
+    /// on, so that it is rebuilt when they change.
+    pub(crate) environment_inputs: IndexMap<TargetName, Vec<String>>,
+    /// The `%` stem for targets whose prerequisites came from a pattern rule.
+    pub(crate) stems: IndexMap<TargetName, String>,
+    /// Targets spliced in from the makefiles of recursive `$(MAKE) -C <directory>` recipes (with `--recursive-make`).
+    pub(crate) submake_targets: IndexMap<TargetName, SubmakeTarget>,
+    /// Targets whose recipe only ran a recursive `make`. These depend on the spliced targets instead.
+    pub(crate) recursive_make_targets: IndexSet<TargetName>,
+    /// Targets with more than one recipe, where the last one replaces the others (which `make` warns about).
+    #[serde(skip)]
+    pub(crate) overridden_recipes: IndexSet<TargetName>,
+    /// Prerequisites that are not the target of any rule (like source files), which are listed in `edges` anyway.
+    #[serde(skip)]
+    pub(crate) prerequisites_without_rules: IndexSet<TargetName>,
+    /// Where the rules of each target are defined. `make`'s database only lists where the recipe is.
+    #[serde(skip)]
+    pub(crate) rule_locations: IndexMap<TargetName, Vec<Location>>,
+    /// The index (in `pattern_rules`) of the pattern rule that each target was instantiated from.
+    #[serde(skip)]
+    pub(crate) instantiated_from: IndexMap<TargetName, usize>,
+    #[serde(skip)]
+    pub(crate) variables: Variables,
+    #[serde(skip)]
+    first_target: Option<TargetName>,
+    #[serde(skip)]
+    not_targets: IndexSet<TargetName>,
+    /// Targets written with an escaped `\%`, which are files rather than patterns.
+    #[serde(skip)]
+    literal_percent_targets: IndexSet<TargetName>,
+    /// The known suffixes (`.SUFFIXES`), used to recognize old-style suffix rules.
+    #[serde(skip)]
+    suffixes: Vec<String>,
+    /// From `--symlinks`, for checking whether files changed.
+    #[serde(skip)]
+    pub(crate) symlinks: Symlinks,
+    /// Set by `.SECONDEXPANSION`.
+    #[serde(skip)]
+    second_expansion: bool,
+    #[serde(skip)]
+    pending_second_expansions: Vec<SecondExpansion>,
+}
+
+/// A line in a makefile.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Location {
+    pub(crate) file: String,
+    pub(crate) line: usize,
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+fn add_location(
+    rule_locations: &mut IndexMap<TargetName, Vec<Location>>,
+    target_name: &TargetName,
+    location: &Location,
+) {
+    let locations = rule_locations.entry(target_name.clone()).or_default();
+    if !locations.contains(location) {
+        locations.push(location.clone());
+    }
+}
+
+/// The parts of a parsed `TargetGraph` that `--print-graph` leaves out, which the parse cache stores separately.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct GraphInternals {
+    variables: Variables,
+    second_expansion: bool,
+    overridden_recipes: IndexSet<TargetName>,
+    prerequisites_without_rules: IndexSet<TargetName>,
+    rule_locations: IndexMap<TargetName, Vec<Location>>,
+}
+
+impl TargetGraph {
+    pub(crate) fn internals(&self) -> GraphInternals {
+        GraphInternals {
+            variables: self.variables.clone(),
+            second_expansion: self.second_expansion,
+            overridden_recipes: self.overridden_recipes.clone(),
+            prerequisites_without_rules: self.prerequisites_without_rules.clone(),
+            rule_locations: self.rule_locations.clone(),
+        }
+    }
+
+    pub(crate) fn restore_internals(&mut self, internals: GraphInternals) {
+        self.variables = internals.variables;
+        self.second_expansion = internals.second_expansion;
+        self.overridden_recipes = internals.overridden_recipes;
+        self.prerequisites_without_rules = internals.prerequisites_without_rules;
+        self.rule_locations = internals.rule_locations;
+    }
+}
+
+// Prerequisites of a rule for one target that still need their second expansion (`.SECONDEXPANSION`), which happens
+// once the whole makefile is read.
+#[derive(Debug)]
+struct SecondExpansion {
+    target_name: TargetName,
+    // For static pattern rules.
+    stem: Option<String>,
+    prerequisites: String,
+    order_only_prerequisites: String,
+}
+
+/// Where a spliced target comes from: `make -C <directory> <target_name>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SubmakeTarget {
+    pub(crate) directory: String,
+    pub(crate) target_name: TargetName,
+}
+
+pub(crate) fn extend_unique(target_names: &mut Vec<TargetName>, additional: &[TargetName]) {
+    for target_name in additional {
+        if !target_names.contains(target_name) {
+            target_names.push(target_name.clone());
+        }
+    }
+}
+
+pub(crate) fn modified_time(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// The metadata of the file at `path`, or of the symbolic link itself with `--symlinks no-follow`.
+pub(crate) fn file_metadata(path: &Path, symlinks: Symlinks) -> std::io::Result<std::fs::Metadata> {
+    match symlinks {
+        Symlinks::Follow => std::fs::metadata(path),
+        Symlinks::NoFollow => std::fs::symlink_metadata(path),
+    }
+}
+
+/// The files and directories in `directory`, recursively, sorted by path. Each directory is only listed once, so that
+/// symbolic links to a parent directory don't loop.
+pub(crate) fn directory_entries(directory: &Path, symlinks: Symlinks) -> Vec<PathBuf> {
+    let mut visited = IndexSet::new();
+    visited.extend(directory.canonicalize().ok());
+    let mut entries = vec![];
+    add_directory_entries(directory, symlinks, &mut visited, &mut entries);
+    entries
+}
+
+fn add_directory_entries(
+    directory: &Path,
+    symlinks: Symlinks,
+    visited: &mut IndexSet<PathBuf>,
+    entries: &mut Vec<PathBuf>,
+) {
+    let Ok(read_dir) = std::fs::read_dir(directory) else {
+        return;
+    };
+    let mut children: Vec<PathBuf> = read_dir.flatten().map(|entry| entry.path()).collect();
+    children.sort();
+    for path in children {
+        entries.push(path.clone());
+        let is_directory = file_metadata(&path, symlinks).is_ok_and(|metadata| metadata.is_dir());
+        if is_directory
+            && path
+                .canonicalize()
+                .is_ok_and(|canonical_path| visited.insert(canonical_path))
+        {
+            add_directory_entries(&path, symlinks, visited, entries);
+        }
+    }
+}
+
+// The newest modification time of the directory at `path` and everything in it, since changing a file doesn't change
+// the modification time of its directory.
+fn tree_modified_time(path: &str, symlinks: Symlinks) -> Option<SystemTime> {
+    let modified = file_metadata(Path::new(path), symlinks)
+        .and_then(|metadata| metadata.modified())
+        .ok()?;
+    Some(
+        directory_entries(Path::new(path), symlinks)
+            .iter()
+            .filter_map(|entry| {
+                file_metadata(entry, symlinks)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            })
+            .fold(modified, SystemTime::max),
+    )
+}
+
+/// A `vpath <pattern> <directories>` directive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SearchPath {
+    pub(crate) pattern: String,
+    pub(crate) directories: Vec<String>,
+}
+
+fn split_search_directories(text: &str) -> Vec<String> {
+    text.split([':', ' ', '\t'])
+        .filter(|directory| !directory.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// A rule like `%.o: %.c`, which applies to any target matching one of its target patterns.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct PatternRule {
+    pub(crate) targets: Vec<String>,
+    pub(crate) prerequisites: Vec<String>,
+    pub(crate) order_only_prerequisites: Vec<String>,
+    pub(crate) recipe: Vec<String>,
+}
+
+/// One of several independent `target:: prerequisites` rules for the same target.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DoubleColonRule {
+    pub(crate) prerequisites: Vec<TargetName>,
+    pub(crate) recipe: Vec<String>,
+}
+
+impl PatternRule {
+    // Follows `make`: if the pattern has no slash, directories are stripped
+    // from the target before matching and prepended to the stem afterwards.
+    pub(crate) fn match_stem(&self, target_name: &str) -> Option<String> {
+        self.targets.iter().find_map(|pattern| {
+            let (prefix, suffix) = pattern.split_once('%')?;
+            let (directory, file_name) = match (pattern.contains('/'), target_name.rfind('/')) {
+                (false, Some(slash_index)) => target_name.split_at(slash_index + 1),
+                _ => ("", target_name),
+            };
+            if file_name.len() < prefix.len() + suffix.len() {
+                return None;
+            }
+            let stem = file_name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            Some(format!("{}{}", directory, stem))
+        })
+    }
+
+    pub(crate) fn prerequisites_for_stem(prerequisites: &[String], stem: &str) -> Vec<TargetName> {
+        prerequisites
+            .iter()
+            .map(|prerequisite| TargetName(prerequisite.replacen('%', stem, 1)))
+            .collect()
+    }
+}
+
+impl TargetGraph {
+    /// The modification time of the file of `target_name` at `path` (from `resolve_path`). For `# mak:directory=true`
+    /// targets, the newest one in the directory.
+    pub(crate) fn modified_time(&self, target_name: &TargetName, path: &str) -> Option<SystemTime> {
+        match self.directory_targets.contains(target_name) {
+            true => tree_modified_time(path, self.symlinks),
+            false => file_metadata(Path::new(path), self.symlinks)
+                .and_then(|metadata| metadata.modified())
+                .ok(),
+        }
+    }
+
+    /// Finds a file through `vpath` directives and `VPATH` if it doesn't exist as named.
+    pub(crate) fn resolve_path(&self, file_name: &str) -> Option<String> {
+        if file_metadata(Path::new(file_name), self.symlinks).is_ok() {
+            return Some(file_name.to_owned());
+        }
+        if Path::new(file_name).is_absolute() {
+            return None;
+        }
+        let vpath_directories = self
+            .search_paths
+            .iter()
+            .filter(|search_path| match_pattern(&search_path.pattern, file_name).is_some())
+            .flat_map(|search_path| search_path.directories.iter().cloned());
+        let general_directories = split_search_directories(&self.variables.expand("$(VPATH)"));
+        vpath_directories
+            .chain(general_directories)
+            .map(|directory| format!("{}/{}", directory.trim_end_matches('/'), file_name))
+            .find(|candidate| Path::new(candidate).exists())
+    }
+
+    pub(crate) fn is_phony(&self, target_name: &TargetName) -> bool {
+        self.phony.contains(target_name)
+    }
+
+    // Expands `prerequisites` (which were already expanded once) again for `.SECONDEXPANSION`, with automatic variables
+    // for `target_name`. `$<`, `$^`, and `$+` refer to the prerequisites from the target's other rules.
+    fn second_expand(
+        &self,
+        prerequisites: &str,
+        target_name: &TargetName,
+        stem: &str,
+    ) -> Vec<String> {
+        let names = |target_names: Option<&Vec<TargetName>>| -> Vec<String> {
+            target_names
+                .into_iter()
+                .flatten()
+                .map(|target_name| target_name.0.clone())
+                .collect()
+        };
+        let automatic_variables = AutomaticVariables {
+            target: target_name.0.clone(),
+            prerequisites: names(self.edges.get(target_name)),
+            order_only_prerequisites: names(self.order_only_edges.get(target_name)),
+            newer_prerequisites: vec![],
+            stem: stem.to_owned(),
+        };
+        self.variables
+            .expand_for_target(prerequisites, &automatic_variables)
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    pub(crate) fn is_precious(&self, target_name: &TargetName) -> bool {
+        self.precious
+            .iter()
+            .any(|pattern| match_pattern(pattern, &target_name.0).is_some())
+    }
+
+    pub(crate) fn is_secondary(&self, target_name: &TargetName) -> bool {
+        self.secondary
+            .as_ref()
+            .is_some_and(|secondary| secondary.is_empty() || secondary.contains(target_name))
+    }
+
+    /// Whether `target_name` is an intermediate file that should be deleted once the build is done.
+    pub(crate) fn is_deletable_intermediate(&self, target_name: &TargetName) -> bool {
+        self.intermediate.contains(target_name)
+            && !self.is_secondary(target_name)
+            && !self.is_precious(target_name)
+            && !self.is_phony(target_name)
+    }
+
+    /// Normal prerequisites followed by order-only prerequisites. The target must wait for all of them.
+    pub(crate) fn all_prerequisites(&self, target_name: &TargetName) -> Vec<TargetName> {
+        let mut prerequisites = self.edges.get(target_name).cloned().unwrap_or_default();
+        if let Some(order_only_prerequisites) = self.order_only_edges.get(target_name) {
+            prerequisites.extend(order_only_prerequisites.iter().cloned());
+        }
+        prerequisites
+    }
+
+    /// The global variables with pattern-specific and then target-specific assignments for `target_name` applied.
+    pub(crate) fn variables_for_target(&self, target_name: &TargetName) -> Variables {
+        let mut variables = self.variables.clone();
+        let (pattern_assignments, target_assignments): (Vec<_>, Vec<_>) = self
+            .target_variables
+            .iter()
+            .partition(|(pattern, _)| pattern.0.contains('%'));
+        let matching_pattern_assignments =
+            pattern_assignments.into_iter().filter(|(pattern, _)| {
+                let pattern_rule = PatternRule {
+                    targets: vec![pattern.0.clone()],
+                    prerequisites: vec![],
+                    order_only_prerequisites: vec![],
+                    recipe: vec![],
+                };
+                pattern_rule.match_stem(&target_name.0).is_some()
+            });
+        let own_assignments = target_assignments
+            .into_iter()
+            .filter(|(name, _)| *name == target_name);
+        for (_, assignments) in matching_pattern_assignments.chain(own_assignments) {
+            for assignment in assignments {
+                variables.assign(&assignment.name, assignment.operator, &assignment.value);
+            }
+        }
+        variables
+    }
+
+    /// The environment to run the recipe of `target_name` with, following `export` and `unexport`.
+    pub(crate) fn recipe_environment(&self, target_name: &TargetName) -> Vec<(String, String)> {
+        self.variables_for_target(target_name).environment()
+    }
+
+    pub(crate) fn automatic_variables(&self, target_name: &TargetName) -> AutomaticVariables {
+        // Like `make`, automatic variables use the paths found through directory search.
+        let resolve = |prerequisite: &TargetName| {
+            self.resolve_path(&prerequisite.0)
+                .unwrap_or_else(|| prerequisite.0.clone())
+        };
+        let prerequisites: Vec<String> = self
+            .edges
+            .get(target_name)
+            .map(|prerequisites| prerequisites.iter().map(resolve).collect())
+            .unwrap_or_default();
+        // Without a target file (or for phony targets) every prerequisite counts as newer.
+        let target_modified = if self.is_phony(target_name) {
+            None
+        } else {
+            modified_time(&target_name.0)
+        };
+        let newer_prerequisites = prerequisites
+            .iter()
+            .filter(
+                |prerequisite| match (target_modified, modified_time(prerequisite)) {
+                    (Some(target_modified), Some(prerequisite_modified)) => {
+                        prerequisite_modified > target_modified
+                    }
+                    _ => true,
+                },
+            )
+            .cloned()
+            .collect();
+        AutomaticVariables {
+            target: target_name.0.clone(),
+            prerequisites,
+            order_only_prerequisites: self
+                .order_only_edges
+                .get(target_name)
+                .map(|prerequisites| prerequisites.iter().map(resolve).collect())
+                .unwrap_or_default(),
+            newer_prerequisites,
+            stem: self.stems.get(target_name).cloned().unwrap_or_default(),
+        }
+    }
+
+    // Translates old-style suffix rules into the equivalent pattern rules: `.c.o:` is `%.o: %.c`, `.c:` is `%: %.c`.
+    // Like `make`, a suffix rule with prerequisites is just an ordinary target.
+    fn translate_suffix_rules(&mut self) {
+        let suffix_rules: Vec<(TargetName, PatternRule)> = self
+            .edges
+            .iter()
+            .filter(|(target_name, dependencies)| {
+                target_name.0.starts_with('.')
+                    && dependencies.is_empty()
+                    && self.recipes.contains_key(*target_name)
+            })
+            .filter_map(|(target_name, _)| {
+                let (source, target) = self.split_suffix_rule(&target_name.0)?;
+                let pattern_rule = PatternRule {
+                    targets: vec![format!("%{}", target)],
+                    prerequisites: vec![format!("%{}", source)],
+                    order_only_prerequisites: vec![],
+                    recipe: self.recipes[target_name].clone(),
+                };
+                Some((target_name.clone(), pattern_rule))
+            })
+            .collect();
+        for (target_name, pattern_rule) in suffix_rules {
+            self.edges.shift_remove(&target_name);
+            self.recipes.shift_remove(&target_name);
+            // The `make` database already lists suffix rules as pattern rules.
+            if !self.pattern_rules.contains(&pattern_rule) {
+                self.pattern_rules.push(pattern_rule);
+            }
+        }
+    }
+
+    // Returns the source and target suffix (empty for a single-suffix rule).
+    fn split_suffix_rule<'a>(&self, name: &'a str) -> Option<(&'a str, &'a str)> {
+        for source in &self.suffixes {
+            if let Some(target) = name.strip_prefix(source.as_str()) {
+                if self.suffixes.iter().any(|suffix| suffix == target) {
+                    return Some((&name[..source.len()], target));
+                }
+            }
+        }
+        self.suffixes
+            .iter()
+            .any(|suffix| suffix == name)
+            .then_some((name, ""))
+    }
+
+    pub(crate) fn target_group(&self, target_name: &TargetName) -> Option<&[TargetName]> {
+        self.target_groups
+            .iter()
+            .find(|group| group.contains(target_name))
+            .map(Vec::as_slice)
+    }
+
+    pub(crate) fn add_target_group(&mut self, target_names: &[TargetName]) {
+        match self
+            .target_groups
+            .iter_mut()
+            .find(|group| target_names.iter().any(|t| group.contains(t)))
+        {
+            Some(group) => extend_unique(group, target_names),
+            None => {
+                let mut group = vec![];
+                extend_unique(&mut group, target_names);
+                self.target_groups.push(group);
+            }
+        }
+    }
+
+    fn is_pattern(&self, target_name: &TargetName) -> bool {
+        target_name.0.contains('%') && !self.literal_percent_targets.contains(target_name)
+    }
+
+    fn add_rule(
+        &mut self,
+        target_names: &[TargetName],
+        dependencies: Vec<TargetName>,
+        order_only_dependencies: Vec<TargetName>,
+        double_colon: bool,
+    ) {
+        // `.WAIT` is not a real prerequisite: it separates groups of prerequisites that must be built one after another.
+        let wait_groups: Vec<Vec<TargetName>> = dependencies
+            .split(|dependency| dependency.0 == WAIT)
+            .filter(|wait_group| !wait_group.is_empty())
+            .map(<[TargetName]>::to_vec)
+            .collect();
+        let dependencies = wait_groups.concat();
+        let order_only_dependencies: Vec<TargetName> = order_only_dependencies
+            .into_iter()
+            .filter(|dependency| dependency.0 != WAIT)
+            .collect();
+        let (pattern_targets, target_names): (Vec<TargetName>, Vec<TargetName>) = target_names
+            .iter()
+            .cloned()
+            .partition(|target_name| self.is_pattern(target_name));
+        if !pattern_targets.is_empty() {
+            self.pattern_rules.push(PatternRule {
+                targets: pattern_targets.into_iter().map(|t| t.0).collect(),
+                prerequisites: dependencies.iter().map(|d| d.0.clone()).collect(),
+                order_only_prerequisites: order_only_dependencies
+                    .iter()
+                    .map(|d| d.0.clone())
+                    .collect(),
+                recipe: vec![],
+            });
+        }
+        for target_name in target_names {
+            // Like `make`, the first target that starts with `.` can only be the default goal if it contains a `/`.
+            if self.first_target.is_none()
+                && (!target_name.0.starts_with('.') || target_name.0.contains('/'))
+            {
+                self.first_target = Some(target_name.clone());
+            }
+            if !order_only_dependencies.is_empty() {
+                extend_unique(
+                    self.order_only_edges
+                        .entry(target_name.clone())
+                        .or_default(),
+                    &order_only_dependencies,
+                );
+            }
+            if target_name.0 == ".PHONY" {
+                self.phony.extend(dependencies.iter().cloned());
+            }
+            if wait_groups.len() > 1 {
+                self.wait_groups
+                    .insert(target_name.clone(), wait_groups.clone());
+            }
+            if target_name.0 == ".SUFFIXES" {
+                // `.SUFFIXES:` without prerequisites clears the list.
+                if dependencies.is_empty() {
+                    self.suffixes.clear();
+                }
+                for dependency in &dependencies {
+                    if !self.suffixes.contains(&dependency.0) {
+                        self.suffixes.push(dependency.0.clone());
+                    }
+                }
+            }
+            if target_name.0 == ".PRECIOUS" {
+                for dependency in &dependencies {
+                    if !self.precious.contains(&dependency.0) {
+                        self.precious.push(dependency.0.clone());
+                    }
+                }
+            }
+            if target_name.0 == ".SECONDARY" {
+                extend_unique(self.secondary.get_or_insert_with(Vec::new), &dependencies);
+            }
+            if target_name.0 == ".INTERMEDIATE" {
+                extend_unique(&mut self.intermediate, &dependencies);
+            }
+            if target_name.0 == ".DELETE_ON_ERROR" {
+                self.delete_on_error = true;
+            }
+            if target_name.0 == ".SECONDEXPANSION" {
+                self.second_expansion = true;
+            }
+            if target_name.0 == ".ONESHELL" {
+                self.one_shell = true;
+            }
+            if target_name.0 == ".EXPORT_ALL_VARIABLES" {
+                self.variables.set_export_all(true);
+            }
+            if target_name.0 == ".NOTPARALLEL" {
+                extend_unique(
+                    self.not_parallel.get_or_insert_with(Vec::new),
+                    &dependencies,
+                );
+            }
+            if double_colon {
+                // Each `::` rule is independent, but the target can only be built once all of them can run.
+                extend_unique(
+                    self.edges.entry(target_name.clone()).or_default(),
+                    &dependencies,
+                );
+                self.double_colon_rules
+                    .entry(target_name)
+                    .or_default()
+                    .push(DoubleColonRule {
+                        prerequisites: dependencies.clone(),
+                        recipe: vec![],
+                    });
+            } else {
+                // Prerequisites from several rules for the same target are merged (only one of them may have a recipe).
+                extend_unique(self.edges.entry(target_name).or_default(), &dependencies);
+            }
+        }
+    }
+
+    pub(crate) fn has_recipe(&self, target_name: &TargetName) -> bool {
+        self.recipes.contains_key(target_name)
+            || self
+                .double_colon_rules
+                .get(target_name)
+                .is_some_and(|rules| rules.iter().any(|rule| !rule.recipe.is_empty()))
+    }
+
+    /// Adds edges for `target_names` (and, transitively, their prerequisites) using the first pattern rule
+    /// whose prerequisites all exist as files or known targets. Targets that have their own recipe are left alone.
+    pub(crate) fn instantiate_pattern_rules(&mut self, target_names: &[TargetName]) {
+        let mut pending: Vec<TargetName> = target_names.to_vec();
+        pending.extend(self.edges.keys().cloned());
+        let mut visited = IndexSet::<TargetName>::new();
+        while let Some(target_name) = pending.pop() {
+            // Like `make`, skip implicit rule search for phony targets.
+            if !visited.insert(target_name.clone())
+                || self.has_recipe(&target_name)
+                || self.is_phony(&target_name)
+            {
+                continue;
+            }
+            let Some((index, stem, prerequisites, order_only_prerequisites, recipe)) = self
+                .pattern_rules
+                .iter()
+                .enumerate()
+                .find_map(|(index, pattern_rule)| {
+                    // Like `make`, pattern rules without a recipe only cancel other rules.
+                    if pattern_rule.recipe.is_empty() {
+                        return None;
+                    }
+                    let stem = pattern_rule.match_stem(&target_name.0)?;
+                    // The second expansion happens before `%` is replaced.
+                    let second_expand = |prerequisites: &[String]| match self.second_expansion {
+                        true => self.second_expand(&prerequisites.join(" "), &target_name, &stem),
+                        false => prerequisites.to_vec(),
+                    };
+                    let prerequisites = PatternRule::prerequisites_for_stem(
+                        &second_expand(&pattern_rule.prerequisites),
+                        &stem,
+                    );
+                    let order_only_prerequisites = PatternRule::prerequisites_for_stem(
+                        &second_expand(&pattern_rule.order_only_prerequisites),
+                        &stem,
+                    );
+                    prerequisites
+                        .iter()
+                        .chain(&order_only_prerequisites)
+                        .all(|prerequisite| {
+                            self.edges.contains_key(prerequisite)
+                                || self.resolve_path(&prerequisite.0).is_some()
+                        })
+                        .then(|| {
+                            (
+                                index,
+                                stem,
+                                prerequisites,
+                                order_only_prerequisites,
+                                pattern_rule.recipe.clone(),
+                            )
+                        })
+                })
+            else {
+                continue;
+            };
+            self.stems.insert(target_name.clone(), stem);
+            self.recipes.insert(target_name.clone(), recipe);
+            self.instantiated_from.insert(target_name.clone(), index);
+            // Like `make`, the pattern rule's prerequisites come first (so `$<` is the first of them).
+            let edges = self.edges.entry(target_name.clone()).or_default();
+            let explicit_prerequisites = std::mem::replace(edges, prerequisites.clone());
+            extend_unique(edges, &explicit_prerequisites);
+            if !order_only_prerequisites.is_empty() {
+                extend_unique(
+                    self.order_only_edges.entry(target_name).or_default(),
+                    &order_only_prerequisites,
+                );
+            }
+            for prerequisite in prerequisites.into_iter().chain(order_only_prerequisites) {
+                self.edges.entry(prerequisite.clone()).or_default();
+                pending.push(prerequisite);
+            }
+        }
+    }
+}
+
+// A single logical line of the `make` database, before variable expansion.
+enum Line {
+    Rule {
+        targets: Vec<String>,
+        static_target_pattern: Option<String>,
+        dependencies: Vec<String>,
+        order_only_dependencies: Vec<String>,
+        separator: RuleSeparator,
+        inline_recipe: Option<String>,
+    },
+    Recipe(String),
+    Include {
+        optional: bool,
+        file_names: String,
+    },
+    Stem(String),
+    // `#  recipe to execute (from 'Makefile', line 9):` in `make`'s database.
+    RecipeLocation(Location),
+    AlsoMakes(Vec<String>),
+    NotATarget,
+    Assignment {
+        assignment: VariableAssignment,
+        is_override: bool,
+        is_export: bool,
+    },
+    // `export`/`unexport`, with the names to (un)export (or none, for all variables).
+    Export {
+        exported: bool,
+        names: Option<String>,
+    },
+    TargetAssignment {
+        targets: Vec<String>,
+        assignment: VariableAssignment,
+    },
+    Conditional(Conditional),
+    // `vpath`, `vpath <pattern>`, or `vpath <pattern> <directories>`.
+    Vpath {
+        pattern: Option<String>,
+        directories: Option<String>,
+    },
+    // A line with only variable references or function calls (like `$(eval …)`), whose expansion is read as makefile
+    // text.
+    Expansion(String),
+    Ignored,
+}
+
+enum Condition {
+    Equal { negate: bool, a: String, b: String },
+    Defined { negate: bool, name: String },
+}
+
+impl Condition {
+    fn evaluate(&self, variables: &Variables) -> bool {
+        match self {
+            Condition::Equal { negate, a, b } => {
+                (variables.expand(a).trim() == variables.expand(b).trim()) != *negate
+            }
+            Condition::Defined { negate, name } => {
+                variables.is_defined(variables.expand(name).trim()) != *negate
+            }
+        }
+    }
+}
+
+enum Conditional {
+    If(Condition),
+    Else(Option<Condition>),
+    Endif,
+}
+
+struct ConditionalFrame {
+    active: bool,
+    branch_taken: bool,
 }
 
 fn is_allowed_target_name_first_char(c: char) -> bool {
-    !is_makefile_whitespace(c) && c != '\n' && c != '\r' && c != ':'
+    !is_makefile_whitespace(c)
+        && c != '\n'
+        && c != '\r'
+        && c != ':'
+        && c != '|'
+        && c != '#'
+        && c != ';'
 }
 
 fn is_allowed_target_name_tail_char(c: char) -> bool {
-    !is_makefile_whitespace(c) && c != '\n' && c != '\r' && c != ':'
+    !is_makefile_whitespace(c) && c != '\n' && c != '\r' && c != ':' && c != ';'
 }
 
 fn is_makefile_whitespace(c: char) -> bool {
@@ -48,107 +913,1716 @@ fn parse_optional_comment(input: &str) -> IResult<&str, ()> {
     Ok((input, ()))
 }
 
-fn parse_target_name(input: &str) -> IResult<&str, TargetName> {
-    let (input, target_name_first_char) = take_while1(is_allowed_target_name_first_char)(input)?;
-    let (input, target_name_tail) = take_while(is_allowed_target_name_tail_char)(input)?;
-    let target_name = TargetName([target_name_first_char, target_name_tail].join(""));
-    Ok((input, target_name))
+// Recognizes a balanced `$(…)` or `${…}` reference, which may contain spaces and colons.
+fn parse_variable_reference(input: &str) -> IResult<&str, &str> {
+    let (rest, open) = alt((tag("$("), tag("${")))(input)?;
+    let (open, close) = if open == "$(" { ('(', ')') } else { ('{', '}') };
+    let mut nesting = 1;
+    for (i, c) in rest.char_indices() {
+        if c == open {
+            nesting += 1;
+        } else if c == close {
+            nesting -= 1;
+            if nesting == 0 {
+                let length = 2 + i + 1;
+                return Ok((&input[length..], &input[..length]));
+            }
+        }
+    }
+    Err(nom::Err::Error(nom::error::Error::new(
+        input,
+        nom::error::ErrorKind::TakeUntil,
+    )))
+}
+
+// A backslash-escaped character that would otherwise end the name, like `\ ` or `\:`. The backslash is kept until the
+// name is split into words after expansion (see `split_target_names`).
+fn parse_escaped_char(input: &str) -> IResult<&str, &str> {
+    let mut chars = input.chars();
+    match (chars.next(), chars.next()) {
+        (Some('\\'), Some(c)) if ESCAPABLE_TARGET_NAME_CHARS.contains(c) => {
+            let length = 1 + c.len_utf8();
+            Ok((&input[length..], &input[..length]))
+        }
+        _ => Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Escaped,
+        ))),
+    }
 }
 
-fn take_until_newline(input: &str) -> IResult<&str, ()> {
-    // TODO: allow end of file as well?
-    let (input, _) = take_until("\n")(input)?;
-    let (input, _) = tag("\n")(input)?;
-    Ok((input, ()))
+// On Windows, `make` treats the colon of a drive letter (like `C:/dir/file`) as part of the name.
+fn parse_drive_letter(input: &str) -> IResult<&str, &str> {
+    let bytes = input.as_bytes();
+    if cfg!(windows)
+        && bytes.len() >= 3
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && (bytes[2] == b'/' || bytes[2] == b'\\')
+    {
+        return Ok((&input[2..], &input[..2]));
+    }
+    Err(nom::Err::Error(nom::error::Error::new(
+        input,
+        nom::error::ErrorKind::Char,
+    )))
 }
 
-// Starts with optional whitespace
-fn parse_dependency(input: &str) -> IResult<&str, TargetName> {
-    let (input, _) = many0(alt((
-        tag(" "),
-        tag("\t"),
-        tag("|"),
-        tag("\\\n"),
-        tag("\\\r\n"),
+// Parses a target name as written (before variable expansion).
+fn parse_target_name(input: &str) -> IResult<&str, String> {
+    let (input, first) = alt((
+        parse_drive_letter,
+        // An escaped backslash doesn't escape what comes after it.
+        tag("\\\\"),
+        parse_escaped_char,
+        parse_variable_reference,
+        take_while1(|c| is_allowed_target_name_first_char(c) && c != '\\'),
+        tag("\\"),
+    ))(input)?;
+    let (input, tail) = many0(alt((
+        tag("\\\\"),
+        parse_escaped_char,
+        parse_variable_reference,
+        take_while1(|c| is_allowed_target_name_tail_char(c) && c != '$' && c != '\\'),
+        tag("$"),
+        tag("\\"),
     )))(input)?;
+    Ok((input, [first].into_iter().chain(tail).collect()))
+}
+
+// Starts with optional whitespace
+fn parse_dependency(input: &str) -> IResult<&str, String> {
+    let (input, _) = parse_dependency_separator(input)?;
     parse_target_name(input)
 }
 
-fn target_name_with_colon(input: &str) -> IResult<&str, TargetName> {
-    let (input, target_name) = parse_target_name(input)?;
+fn parse_dependency_separator(input: &str) -> IResult<&str, ()> {
+    let (input, _) = many0(alt((tag(" "), tag("\t"), tag("\\\n"), tag("\\\r\n"))))(input)?;
+    Ok((input, ()))
+}
+
+// The part after the second colon of `targets: target-pattern: prerequisite-patterns`.
+fn parse_static_pattern_prerequisites(input: &str) -> IResult<&str, Vec<String>> {
+    let (input, _) = parse_dependency_separator(input)?;
     let (input, _) = tag(":")(input)?;
-    Ok((input, target_name))
+    many0(parse_dependency)(input)
+}
+
+fn parse_order_only_dependencies(input: &str) -> IResult<&str, Vec<String>> {
+    let (input, _) = parse_dependency_separator(input)?;
+    let (input, _) = tag("|")(input)?;
+    many0(parse_dependency)(input)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RuleSeparator {
+    Colon,       // `:`
+    DoubleColon, // `::`
+    Grouped,     // `&:`
+}
+
+// Parses one or more whitespace-separated target names and the separator after them.
+fn target_names_with_colon(input: &str) -> IResult<&str, (Vec<String>, RuleSeparator)> {
+    let (input, mut target_names) =
+        separated_list1(take_while1(is_makefile_whitespace), parse_target_name)(input)?;
+    let (input, _) = take_while(is_makefile_whitespace)(input)?;
+    let (input, colon) = alt((tag("::"), tag(":")))(input)?;
+    let mut separator = match colon {
+        "::" => RuleSeparator::DoubleColon,
+        _ => RuleSeparator::Colon,
+    };
+    // `&` is a valid target name character, so `a b &:` parses `&` (or `b&`) as part of the target list.
+    if separator == RuleSeparator::Colon {
+        if let Some(last) = target_names.last_mut() {
+            if let Some(stripped) = last.strip_suffix('&') {
+                *last = stripped.to_owned();
+                if last.is_empty() {
+                    target_names.pop();
+                }
+                separator = RuleSeparator::Grouped;
+            }
+        }
+    }
+    if target_names.is_empty() {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::SeparatedList,
+        )));
+    }
+    Ok((input, (target_names, separator)))
+}
+
+fn parse_makefile_target(input: &str) -> IResult<&str, Line> {
+    let (input, (targets, separator)) = target_names_with_colon(input)?;
+
+    let (input, mut dependencies) = many0(parse_dependency)(input)?;
+    let (input, static_prerequisites) = opt(parse_static_pattern_prerequisites)(input)?;
+    let static_target_pattern = static_prerequisites.map(|static_prerequisites| {
+        std::mem::replace(&mut dependencies, static_prerequisites).join(" ")
+    });
+    let (input, order_only_dependencies) = opt(parse_order_only_dependencies)(input)?;
+
+    let (input, _) = take_while(is_makefile_whitespace)(input)?;
+    let (input, inline_recipe) = opt(parse_inline_recipe)(input)?;
+    let (input, _) = parse_optional_comment(input)?;
+
+    Ok((
+        input,
+        Line::Rule {
+            targets,
+            static_target_pattern,
+            dependencies,
+            order_only_dependencies: order_only_dependencies.unwrap_or_default(),
+            separator,
+            inline_recipe,
+        },
+    ))
+}
+
+// `target: prerequisites ; recipe`
+fn parse_inline_recipe(input: &str) -> IResult<&str, String> {
+    let (input, _) = tag(";")(input)?;
+    let (input, _) = take_while(is_makefile_whitespace)(input)?;
+    // Continuation lines (joined by `Statements`) are part of the recipe.
+    let mut length = 0;
+    for line in input.split_inclusive('\n') {
+        length += line.len();
+        if !continues(line.trim_end_matches('\n')) {
+            break;
+        }
+    }
+    let recipe_line = input[..length].trim_end_matches('\n');
+    Ok((
+        &input[recipe_line.len()..],
+        recipe_line.trim_end_matches('\r').to_owned(),
+    ))
+}
+
+fn is_allowed_variable_name_char(c: char) -> bool {
+    !is_makefile_whitespace(c) && c != '\n' && c != '\r' && c != ':' && c != '=' && c != '#'
+}
+
+fn parse_variable_assignment(input: &str) -> IResult<&str, VariableAssignment> {
+    let (input, mut name) = take_while1(is_allowed_variable_name_char)(input)?;
+    let (input, _) = take_while(is_makefile_whitespace)(input)?;
+    let (input, operator) = alt((tag("::="), tag(":="), tag("?="), tag("+="), tag("=")))(input)?;
+    let mut operator = operator;
+    // `FOO?=bar` and `FOO+=bar` (without spaces) leave the operator prefix in the name.
+    if operator == "=" {
+        if let Some(stripped) = name.strip_suffix('?') {
+            (name, operator) = (stripped, "?=");
+        } else if let Some(stripped) = name.strip_suffix('+') {
+            (name, operator) = (stripped, "+=");
+        }
+    }
+    let (input, _) = take_while(is_makefile_whitespace)(input)?;
+    let (input, value) = take_till(|c| c == '\n')(input)?;
+    Ok((
+        input,
+        VariableAssignment {
+            name: name.to_owned(),
+            operator: AssignmentOperator::from_token(operator)
+                .expect("Internal error: unexpected assignment operator"),
+            value: value.trim_end_matches('\r').to_owned(),
+        },
+    ))
+}
+
+// `[override|export] NAME op value`
+fn parse_assignment(input: &str) -> IResult<&str, Line> {
+    let (input, modifiers) = many0(|input| {
+        let (input, modifier) = alt((tag("override"), tag("export")))(input)?;
+        let (input, _) = take_while1(is_makefile_whitespace)(input)?;
+        Ok((input, modifier))
+    })(input)?;
+    let (input, assignment) = parse_variable_assignment(input)?;
+    Ok((
+        input,
+        Line::Assignment {
+            assignment,
+            is_override: modifiers.contains(&"override"),
+            is_export: modifiers.contains(&"export"),
+        },
+    ))
+}
+
+// `export [names]` or `unexport [names]` (without an assignment).
+fn parse_export(input: &str) -> IResult<&str, Line> {
+    let (input, directive) = alt((tag("export"), tag("unexport")))(input)?;
+    let (input, names) = alt((
+        |input| {
+            let (input, _) = take_while1(is_makefile_whitespace)(input)?;
+            take_till(|c| c == '\n' || c == '#')(input)
+        },
+        |input| {
+            let (input, _) = peek(alt((tag("\n"), tag("\r"), tag("#"), eof)))(input)?;
+            Ok((input, ""))
+        },
+    ))(input)?;
+    let (input, _) = take_till(|c| c == '\n')(input)?;
+    let names = names.trim_end_matches('\r').trim();
+    Ok((
+        input,
+        Line::Export {
+            exported: directive == "export",
+            names: (!names.is_empty()).then(|| names.to_owned()),
+        },
+    ))
 }
 
-fn parse_makefile_target(input: &str) -> IResult<&str, Option<TargetGraph>> {
-    let (input, target_name) = target_name_with_colon(input)?;
-    let mut target_graph = TargetGraph::default();
+/// Parses a `VAR=value` argument (as passed to `make` on the command line).
+pub(crate) fn parse_variable_override(text: &str) -> Option<VariableAssignment> {
+    all_consuming(parse_variable_assignment)(text)
+        .ok()
+        .map(|(_, assignment)| assignment)
+}
 
-    let (input, dependencies) = many0(parse_dependency)(input)?;
+// `targets: [override|export|private] NAME op value`, as opposed to a rule.
+fn parse_target_specific_assignment(input: &str) -> IResult<&str, Line> {
+    let (input, (targets, _)) = target_names_with_colon(input)?;
+    let (input, _) = take_while(is_makefile_whitespace)(input)?;
+    let (input, _) = many0(|input| {
+        // TODO: export target-specific variables marked `export`.
+        let (input, modifier) = alt((tag("override"), tag("export"), tag("private")))(input)?;
+        let (input, _) = take_while1(is_makefile_whitespace)(input)?;
+        Ok((input, modifier))
+    })(input)?;
+    let (input, assignment) = parse_variable_assignment(input)?;
+    Ok((
+        input,
+        Line::TargetAssignment {
+            targets,
+            assignment,
+        },
+    ))
+}
 
+// `include`, `-include`, or `sinclude` (the latter two ignore missing files).
+fn parse_include(input: &str) -> IResult<&str, Line> {
+    let (input, directive) = alt((tag("include"), tag("-include"), tag("sinclude")))(input)?;
+    let (input, _) = take_while1(is_makefile_whitespace)(input)?;
+    let (input, file_names) = take_till(|c| c == '\n' || c == '#')(input)?;
+    let (input, _) = take_till(|c| c == '\n')(input)?;
+    Ok((
+        input,
+        Line::Include {
+            optional: directive != "include",
+            file_names: file_names.trim_end_matches('\r').to_owned(),
+        },
+    ))
+}
+
+fn parse_recipe_line(input: &str) -> IResult<&str, Line> {
+    let (recipe_start, _) = tag("\t")(input)?;
+    let mut input = recipe_start;
+    // A recipe line continues past a trailing backslash, even if the next line does not start with a tab.
+    loop {
+        let (rest, line) = take_till(|c| c == '\n')(input)?;
+        input = rest;
+        if !line.trim_end_matches('\r').ends_with('\\') || input.is_empty() {
+            break;
+        }
+        input = &input[1..];
+    }
+    let recipe_line = &recipe_start[..recipe_start.len() - input.len()];
+    Ok((
+        input,
+        Line::Recipe(recipe_line.trim_end_matches('\r').to_owned()),
+    ))
+}
+
+fn parse_quoted(input: &str) -> IResult<&str, &str> {
+    let (input, quote) = alt((tag("\""), tag("'")))(input)?;
+    let (input, value) = take_till(|c: char| quote.starts_with(c) || c == '\n')(input)?;
+    let (input, _) = tag(quote)(input)?;
+    Ok((input, value))
+}
+
+// Parses the arguments of `ifeq`/`ifneq`, either `(a,b)` or `"a" "b"` (with either kind of quotes).
+fn parse_comparison_arguments(input: &str) -> IResult<&str, (String, String)> {
+    if let Some(rest) = input.strip_prefix('(') {
+        let mut nesting = 0;
+        let mut comma_index = None;
+        for (i, c) in rest.char_indices() {
+            match c {
+                '(' => nesting += 1,
+                ')' if nesting > 0 => nesting -= 1,
+                ')' => {
+                    if let Some(comma_index) = comma_index {
+                        return Ok((
+                            &rest[i + 1..],
+                            (
+                                rest[..comma_index].to_owned(),
+                                rest[comma_index + 1..i].to_owned(),
+                            ),
+                        ));
+                    }
+                    break;
+                }
+                ',' if nesting == 0 && comma_index.is_none() => comma_index = Some(i),
+                '\n' => break,
+                _ => {}
+            }
+        }
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Char,
+        )));
+    }
+    let (input, a) = parse_quoted(input)?;
+    let (input, _) = take_while1(is_makefile_whitespace)(input)?;
+    let (input, b) = parse_quoted(input)?;
+    Ok((input, (a.to_owned(), b.to_owned())))
+}
+
+fn parse_condition(input: &str) -> IResult<&str, Condition> {
+    let (input, keyword) = alt((tag("ifeq"), tag("ifneq"), tag("ifdef"), tag("ifndef")))(input)?;
+    let (input, _) = take_while1(is_makefile_whitespace)(input)?;
+    match keyword {
+        "ifeq" | "ifneq" => {
+            let (input, (a, b)) = parse_comparison_arguments(input)?;
+            let negate = keyword == "ifneq";
+            Ok((input, Condition::Equal { negate, a, b }))
+        }
+        _ => {
+            let (input, name) = take_till(|c| c == '\n' || c == '#')(input)?;
+            let negate = keyword == "ifndef";
+            let name = name.trim().to_owned();
+            Ok((input, Condition::Defined { negate, name }))
+        }
+    }
+}
+
+fn parse_conditional(input: &str) -> IResult<&str, Line> {
+    let (input, _) = take_while(|c| c == ' ')(input)?;
+    let (input, conditional) = alt((
+        |input| {
+            let (input, condition) = parse_condition(input)?;
+            Ok((input, Conditional::If(condition)))
+        },
+        |input| {
+            let (input, _) = tag("else")(input)?;
+            let (input, _) = take_while(is_makefile_whitespace)(input)?;
+            let (input, condition) = opt(parse_condition)(input)?;
+            Ok((input, Conditional::Else(condition)))
+        },
+        |input| {
+            let (input, _) = tag("endif")(input)?;
+            Ok((input, Conditional::Endif))
+        },
+    ))(input)?;
     let (input, _) = take_while(is_makefile_whitespace)(input)?;
     let (input, _) = parse_optional_comment(input)?;
+    Ok((input, Line::Conditional(conditional)))
+}
 
-    target_graph.edges.insert(target_name, dependencies);
-    Ok((input, Some(target_graph)))
+// Whether `line` only has `$(…)` or `${…}` references, separated by whitespace.
+fn is_only_references(line: &str) -> bool {
+    let mut chars = line.trim().chars().peekable();
+    if chars.peek().is_none() {
+        return false;
+    }
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let close = match (c, chars.next()) {
+            ('$', Some('(')) => ')',
+            ('$', Some('{')) => '}',
+            _ => return false,
+        };
+        let open = if close == ')' { '(' } else { '{' };
+        let mut nesting = 1;
+        while nesting > 0 {
+            match chars.next() {
+                Some(c) if c == open => nesting += 1,
+                Some(c) if c == close => nesting -= 1,
+                Some(_) => {}
+                None => return false,
+            }
+        }
+    }
+    true
+}
+
+fn parse_expansion_line(input: &str) -> IResult<&str, Line> {
+    let line_end = input.find('\n').unwrap_or(input.len());
+    let line = input[..line_end].trim_end_matches('\r');
+    if line.starts_with('\t') || !is_only_references(line) {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Tag,
+        )));
+    }
+    Ok((&input[line_end..], Line::Expansion(line.trim().to_owned())))
+}
+
+fn parse_vpath(input: &str) -> IResult<&str, Line> {
+    let (input, _) = tag("vpath")(input)?;
+    let (input, arguments) = take_till(|c| c == '\n' || c == '#')(input)?;
+    if !arguments.is_empty() && !arguments.starts_with([' ', '\t']) {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Tag,
+        )));
+    }
+    let mut arguments = arguments.trim().splitn(2, [' ', '\t']);
+    let pattern = arguments.next().filter(|pattern| !pattern.is_empty());
+    let directories = arguments.next();
+    Ok((
+        input,
+        Line::Vpath {
+            pattern: pattern.map(str::to_owned),
+            directories: directories.map(str::to_owned),
+        },
+    ))
+}
+
+// `make` marks files it only looked at (prerequisites, but also e.g. missing `-include`d makefiles) in its database.
+fn parse_not_a_target_comment(input: &str) -> IResult<&str, Line> {
+    let (input, _) = tag("# Not a target:")(input)?;
+    Ok((input, Line::NotATarget))
 }
 
-fn parse_default_goal(input: &str) -> IResult<&str, Option<TargetGraph>> {
-    let (input, _) = tag(".DEFAULT_GOAL := ")(input)?;
-    let (input, target_name) = parse_target_name(input)?;
-    let target_graph = TargetGraph {
-        edges: IndexMap::new(),
-        default_goal: Some(target_name),
+// `make` prints the other members of a grouped target (`a b &: …`) in its database.
+fn parse_also_makes_comment(input: &str) -> IResult<&str, Line> {
+    let (input, _) = tag("#  Also makes: ")(input)?;
+    let (input, target_names) = take_till(|c| c == '\n')(input)?;
+    Ok((
+        input,
+        Line::AlsoMakes(target_names.split_whitespace().map(str::to_owned).collect()),
+    ))
+}
+
+// `make` prints the stem of pattern-instantiated targets in its database.
+fn parse_stem_comment(input: &str) -> IResult<&str, Line> {
+    let (input, _) = tag("#  Implicit/static pattern stem: '")(input)?;
+    let (input, stem) = take_till(|c| c == '\'' || c == '\n')(input)?;
+    let (input, _) = tag("'")(input)?;
+    Ok((input, Line::Stem(stem.to_owned())))
+}
+
+fn parse_recipe_location_comment(input: &str) -> IResult<&str, Line> {
+    let (input, _) = tag("#  recipe to execute (from '")(input)?;
+    let (input, file) = take_till(|c| c == '\'' || c == '\n')(input)?;
+    let (input, _) = tag("', line ")(input)?;
+    let (input, line) = take_while1(|c: char| c.is_ascii_digit())(input)?;
+    let (input, _) = tag("):")(input)?;
+    let location = Location {
+        file: file.to_owned(),
+        line: line.parse().unwrap_or_default(),
     };
-    Ok((input, Some(target_graph)))
+    Ok((input, Line::RecipeLocation(location)))
+}
+
+fn is_directive_line(line: &str, directive: &str) -> bool {
+    line.trim_start()
+        .strip_prefix(directive)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with([' ', '\t', '#']))
 }
 
-fn parse_two_line_define(input: &str) -> IResult<&str, Option<TargetGraph>> {
-    let (input, _) = tag("define ")(input)?;
-    let (input, _) = take_until_newline(input)?;
-    let (input, _) = take_until_newline(input)?;
-    Ok((input, None))
+// `define NAME [op]` … `endef`, possibly nested. The body lines become the (multi-line) value.
+fn parse_define(input: &str) -> IResult<&str, Line> {
+    let (input, modifiers) = many0(|input| {
+        let (input, modifier) = alt((tag("override"), tag("export")))(input)?;
+        let (input, _) = take_while1(is_makefile_whitespace)(input)?;
+        Ok((input, modifier))
+    })(input)?;
+    let (input, _) = tag("define")(input)?;
+    let (input, _) = take_while1(is_makefile_whitespace)(input)?;
+    let (input, name) = take_while1(is_allowed_variable_name_char)(input)?;
+    let (input, _) = take_while(is_makefile_whitespace)(input)?;
+    let (input, operator) =
+        opt(alt((tag("::="), tag(":="), tag("?="), tag("+="), tag("="))))(input)?;
+    let (input, _) = take_while(is_makefile_whitespace)(input)?;
+    let (mut input, _) = alt((tag("\n"), tag("\r\n")))(input)?;
+
+    let mut body_lines: Vec<&str> = vec![];
+    let mut nesting = 0;
+    loop {
+        if input.is_empty() {
+            // Missing `endef`.
+            return Err(nom::Err::Error(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::TakeUntil,
+            )));
+        }
+        let (line, rest) = input.split_once('\n').unwrap_or((input, ""));
+        let line = line.trim_end_matches('\r');
+        if is_directive_line(line, "endef") {
+            if nesting == 0 {
+                input = &input[line.len()..];
+                break;
+            }
+            nesting -= 1;
+        } else if is_directive_line(line, "define") {
+            nesting += 1;
+        }
+        body_lines.push(line);
+        input = rest;
+    }
+    let (input, _) = take_till(|c| c == '\n')(input)?;
+    Ok((
+        input,
+        Line::Assignment {
+            assignment: VariableAssignment {
+                name: name.to_owned(),
+                operator: AssignmentOperator::from_token(operator.unwrap_or("="))
+                    .expect("Internal error: unexpected assignment operator"),
+                value: body_lines.join("\n"),
+            },
+            is_override: modifiers.contains(&"override"),
+            is_export: modifiers.contains(&"export"),
+        },
+    ))
 }
 
-fn parse_ignored_line(input: &str) -> IResult<&str, Option<TargetGraph>> {
-    not(target_name_with_colon)(input)?;
+fn parse_ignored_line(input: &str) -> IResult<&str, Line> {
+    not(target_names_with_colon)(input)?;
     let (input, _) = take_till(|c| c == '\n')(input)?;
-    Ok((input, None))
+    Ok((input, Line::Ignored))
+}
+
+const ESCAPABLE_TARGET_NAME_CHARS: &str = " \t:;#|%";
+
+/// Splits (expanded) text into target names like `make` does: `\ ` is a space within a name, and `\:`, `\;`, `\#`,
+/// and `\|` are the literal characters. `\%` is left as is, since whether it is literal depends on where the name is
+/// used.
+fn split_target_names(text: &str) -> Vec<TargetName> {
+    let mut names = vec![];
+    let mut name = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek().is_some_and(|next| {
+                *next != '%' && ESCAPABLE_TARGET_NAME_CHARS.contains(*next)
+            }) =>
+            {
+                name.extend(chars.next());
+            }
+            c if c.is_whitespace() => {
+                if !name.is_empty() {
+                    names.push(TargetName::normalized(&std::mem::take(&mut name)));
+                }
+            }
+            c => name.push(c),
+        }
+    }
+    if !name.is_empty() {
+        names.push(TargetName::normalized(&name));
+    }
+    names
+}
+
+// `make`'s database doesn't escape colons in names (like `c:d.txt: a:b`), so any colon other than the rule's separator is
+// escaped before parsing. The separator is the only one that is followed by whitespace (or another colon).
+fn escape_database_colons(statement: String) -> String {
+    if !statement.contains(':')
+        || statement.starts_with(['\t', '#'])
+        || statement.contains('=')
+        || ["define", "vpath", "export", "unexport", "override"]
+            .iter()
+            .any(|directive| statement.starts_with(directive))
+    {
+        return statement;
+    }
+    let chars: Vec<char> = statement.chars().collect();
+    let is_separator = |index: usize| match chars.get(index + 1) {
+        None | Some(' ' | '\t') => true,
+        Some(':') => matches!(chars.get(index + 2), None | Some(' ' | '\t')),
+        _ => false,
+    };
+    let mut escaped = String::with_capacity(statement.len());
+    let mut separator_end = None;
+    for (index, c) in chars.iter().enumerate() {
+        if *c == ':' && separator_end.map_or(true, |end| index >= end) {
+            if separator_end.is_none() && is_separator(index) {
+                separator_end = Some(match chars.get(index + 1) {
+                    Some(':') => index + 2,
+                    _ => index + 1,
+                });
+            } else {
+                escaped.push('\\');
+            }
+        }
+        escaped.push(*c);
+    }
+    escaped
+}
+
+/// Escapes a target name so that `make` reads it back as the same name, e.g. in `--eval=.SECONDARY: …`.
+pub(crate) fn escape_target_name(target_name: &TargetName) -> String {
+    let mut escaped = String::new();
+    for c in target_name.0.chars() {
+        match c {
+            '$' => escaped.push_str("$$"),
+            ' ' | '\t' | ':' | ';' | '#' | '|' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// An unescaped `%` makes a target a pattern.
+fn has_unescaped_percent(name: &str) -> bool {
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '%' => return true,
+            _ => {}
+        }
+    }
+    false
 }
 
-fn parse_makefile(input: &str) -> IResult<&str, TargetGraph> {
-    let mut main_target_graph = TargetGraph::default();
+/// Like `make`, replaces target and prerequisite names with wildcards (like `src/*.c`) by the files that match them. A
+/// wildcard that doesn't match any files is kept as is.
+fn expand_wildcard_target_names(target_names: Vec<TargetName>) -> Vec<TargetName> {
+    target_names
+        .into_iter()
+        .flat_map(|target_name| {
+            // Patterns are matched against targets instead, and the `\` of an escaped `\*` is kept until here.
+            if !has_wildcard(&target_name.0) || target_name.0.contains(['%', '\\']) {
+                return vec![target_name];
+            }
+            match expand_wildcard(&target_name.0) {
+                matches if matches.is_empty() => vec![target_name],
+                matches => matches
+                    .iter()
+                    .map(|path| TargetName::normalized(path))
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+fn expand_to_target_names(variables: &Variables, text: &str) -> Vec<TargetName> {
+    split_target_names(&variables.expand(text))
+}
 
+fn parse_lines(input: &str) -> IResult<&str, Vec<Line>> {
     // TODO: fail on something that looks like a target declaration without valid deps.
-    let (input, target_graphs) = separated_list0(
+    separated_list0(
         alt((tag("\n"), tag("\r\n"))),
         alt((
-            parse_two_line_define, // Takes priority due to similar syntax
+            parse_define, // Takes priority due to similar syntax
+            parse_conditional,
+            parse_vpath,
+            parse_expansion_line,
+            parse_assignment,
+            parse_export,
+            parse_include,
+            parse_target_specific_assignment, // Takes priority due to similar syntax
             parse_makefile_target,
-            parse_default_goal,
+            parse_recipe_line,
+            parse_stem_comment,
+            parse_recipe_location_comment,
+            parse_also_makes_comment,
+            parse_not_a_target_comment,
             parse_ignored_line,
         )),
-    )(input)?;
-    for target_graph in target_graphs.into_iter().flatten() {
-        main_target_graph.edges.extend(target_graph.edges);
-        if let Some(default_goal) = target_graph.default_goal {
-            main_target_graph.default_goal = Some(default_goal); // TODO: test against multiple default goals?
+    )(input)
+}
+
+// State that carries over from one line to the next (including into included makefiles).
+#[derive(Default)]
+struct Evaluation {
+    current_rule_targets: Vec<TargetName>,
+    // The rule that recipe lines belong to.
+    current_rule_double_colon: bool,
+    current_rule_has_recipe: bool,
+    current_pattern_rule: Option<usize>,
+    // Set by `# Not a target:` until the next rule.
+    not_a_target: bool,
+    // `make`'s database only contains the active branches, but makefile text can contain conditionals.
+    conditional_stack: Vec<ConditionalFrame>,
+    // Set when reading `make`'s database, where prerequisites have already been expanded once.
+    database: bool,
+    // The statement being evaluated, when reading makefiles directly.
+    location: Option<Location>,
+    // The statement with the `$(eval …)` whose text is being read.
+    eval_location: Option<Location>,
+}
+
+// Makefiles larger than this show a progress bar while they are parsed.
+const PARSE_PROGRESS_MIN_BYTES: u64 = 1 << 20;
+
+fn is_define_line(line: &str) -> bool {
+    let mut line = line.trim_start();
+    while let Some(rest) = ["override", "export"].iter().find_map(|modifier| {
+        line.strip_prefix(modifier)
+            .filter(|rest| rest.starts_with([' ', '\t']))
+    }) {
+        line = rest.trim_start();
+    }
+    is_directive_line(line, "define")
+}
+
+/// Reads makefile text one statement at a time, so that large makefiles don't have to be held in memory (several
+/// times) while parsing. A statement is a line with its `\` continuations joined (like `make` does before parsing),
+/// a recipe line (which keeps its continuations, since they are passed to the shell as written), or a whole `define`.
+struct Statements<R> {
+    reader: R,
+    // The number of the next line to read.
+    line_number: usize,
+    bytes_read: u64,
+}
+
+impl<R: BufRead> Statements<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line_number: 1,
+            bytes_read: 0,
+        }
+    }
+
+    fn read_line(&mut self) -> std::io::Result<Option<String>> {
+        let mut line = String::new();
+        let bytes_read = self.reader.read_line(&mut line)?;
+        if bytes_read == 0 {
+            return Ok(None);
+        }
+        self.bytes_read += bytes_read as u64;
+        self.line_number += 1;
+        if line.ends_with('\n') {
+            line.pop();
+        }
+        Ok(Some(line))
+    }
+
+    /// Returns the next statement and the number of its first line.
+    fn next_statement(&mut self) -> std::io::Result<Option<(usize, String)>> {
+        let first_line_number = self.line_number;
+        let Some(mut statement) = self.read_line()? else {
+            return Ok(None);
+        };
+        if statement.starts_with('\t') {
+            let mut line_continues = continues(&statement);
+            while line_continues {
+                let Some(line) = self.read_line()? else {
+                    break;
+                };
+                line_continues = continues(&line);
+                statement.push('\n');
+                statement.push_str(&line);
+            }
+        } else if is_define_line(&statement) {
+            let mut nesting = 0;
+            while let Some(line) = self.read_line()? {
+                statement.push('\n');
+                statement.push_str(&line);
+                let line = line.trim_end_matches('\r');
+                if is_directive_line(line, "endef") {
+                    if nesting == 0 {
+                        break;
+                    }
+                    nesting -= 1;
+                } else if is_define_line(line) {
+                    nesting += 1;
+                }
+            }
+        } else {
+            while continues(&statement) {
+                // Like recipe lines, an inline recipe (after `;`) keeps its continuations.
+                if has_inline_recipe(&statement) {
+                    let Some(line) = self.read_line()? else {
+                        break;
+                    };
+                    statement.push('\n');
+                    statement.push_str(&line);
+                    continue;
+                }
+                let joined_length = statement.trim_end_matches('\r').len() - 1;
+                statement.truncate(joined_length);
+                statement.truncate(statement.trim_end().len());
+                statement.push(' ');
+                let Some(line) = self.read_line()? else {
+                    break;
+                };
+                statement.push_str(line.trim_start());
+            }
+        }
+        Ok(Some((first_line_number, statement)))
+    }
+}
+
+fn parse_progress_bar(label: &str, total_bytes: u64) -> ProgressBar {
+    if total_bytes < PARSE_PROGRESS_MIN_BYTES {
+        return ProgressBar::hidden();
+    }
+    let progress_bar = ProgressBar::new(total_bytes).with_style(
+        ProgressStyle::with_template("Parsing {prefix} {wide_bar} {bytes}/{total_bytes}")
+            .expect("Could not construct progress bar template."),
+    );
+    progress_bar.set_prefix(label.to_owned());
+    progress_bar
+}
+
+// Finds rules with a trailing `## description` comment.
+fn target_descriptions(variables: &Variables, line: &str) -> Vec<(TargetName, String)> {
+    let Some((rule, description)) = line.split_once("##") else {
+        return vec![];
+    };
+    let Ok((rest, (target_names, _))) = target_names_with_colon(rule) else {
+        return vec![];
+    };
+    // Not an assignment like `FOO := ## …`.
+    if rest.starts_with('=') {
+        return vec![];
+    }
+    let description = description.split("# mak:").next().unwrap_or_default();
+    target_names
+        .iter()
+        .flat_map(|target_name| expand_to_target_names(variables, target_name))
+        .map(|target_name| (target_name, description.trim().to_owned()))
+        .collect()
+}
+
+// The value of `name` in a `# mak:name=value` comment.
+fn annotation_value<'a>(comment: &'a str, name: &str) -> Option<&'a str> {
+    let (_, value) = comment.split_once(&format!("mak:{}=", name))?;
+    Some(value.split_whitespace().next().unwrap_or_default())
+}
+
+// Parses the names of a `# mak:<name>=a,b` comment, like `# mak:tags=a,b`.
+fn parse_list_annotation(comment: &str, name: &str) -> Vec<String> {
+    let Some(names) = annotation_value(comment, name) else {
+        return vec![];
+    };
+    names
+        .split(',')
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Parses a duration like `30`/`30s`, `500ms`, `5m`, or `1.5h`.
+pub(crate) fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let (number, unit_seconds) = match text {
+        text if text.ends_with("ms") => (&text[..text.len() - 2], 0.001),
+        text if text.ends_with('s') => (&text[..text.len() - 1], 1.0),
+        text if text.ends_with('m') => (&text[..text.len() - 1], 60.0),
+        text if text.ends_with('h') => (&text[..text.len() - 1], 3600.0),
+        text if text.ends_with('d') => (&text[..text.len() - 1], 86400.0),
+        text => (text, 1.0),
+    };
+    match number.parse::<f64>() {
+        Ok(number) if number.is_finite() && number >= 0.0 => {
+            Ok(Duration::from_secs_f64(number * unit_seconds))
+        }
+        _ => Err(format!(
+            "invalid duration `{}` (expected e.g. `30s`, `500ms`, `5m`, `1h`, or `7d`)",
+            text
+        )),
+    }
+}
+
+// Parses the timeout of a `# mak:timeout=30s` comment.
+fn parse_timeout(comment: &str) -> Option<Duration> {
+    match parse_duration(annotation_value(comment, "timeout")?) {
+        Ok(timeout) => Some(timeout),
+        Err(error) => {
+            eprintln!("Ignoring `mak:timeout`: {}", error);
+            None
         }
     }
+}
 
-    Ok((input, main_target_graph))
+// Parses the number of a `# mak:<name>=<number>` comment, like `# mak:priority=10`.
+fn parse_number_annotation<T: std::str::FromStr>(comment: &str, name: &str) -> Option<T> {
+    let number = annotation_value(comment, name)?;
+    match number.parse() {
+        Ok(number) => Some(number),
+        Err(_) => {
+            eprintln!("Ignoring `mak:{}`: invalid number `{}`", name, number);
+            None
+        }
+    }
 }
 
-impl TryFrom<&String> for TargetGraph {
-    type Error = String;
+// Parses the I/O priority of a `# mak:ionice=idle` comment.
+fn parse_ionice(comment: &str) -> Option<IoPriority> {
+    match parse_io_priority(annotation_value(comment, "ionice")?) {
+        Ok(io_priority) => Some(io_priority),
+        Err(error) => {
+            eprintln!("Ignoring `mak:ionice`: {}", error);
+            None
+        }
+    }
+}
 
-    fn try_from(value: &String) -> Result<Self, Self::Error> {
-        match all_consuming(parse_makefile)(value) {
-            Ok((_, target_graph)) => Ok(target_graph),
-            Err(e) => {
-                eprintln!("Makefile parsing error: {:#?}", e);
-                Err("Invalid Makefile".into()) // TODO: pass on error
+// Finds the comments for a rule: the comment lines before it (collected in `pending_comments`) and the comment at the
+// end of the rule line.
+fn rule_comments(
+    variables: &Variables,
+    line: &str,
+    pending_comments: &mut Vec<String>,
+) -> Vec<(TargetName, Vec<String>)> {
+    if line.trim_start().starts_with('#') {
+        pending_comments.push(line.to_owned());
+        return vec![];
+    }
+    let mut comments = std::mem::take(pending_comments);
+    let (rule, comment) = line.split_once('#').unwrap_or((line, ""));
+    let Ok((rest, (target_names, _))) = target_names_with_colon(rule) else {
+        return vec![];
+    };
+    if rest.starts_with('=') {
+        return vec![];
+    }
+    comments.push(comment.to_owned());
+    target_names
+        .iter()
+        .flat_map(|target_name| expand_to_target_names(variables, target_name))
+        .map(|target_name| (target_name, comments.clone()))
+        .collect()
+}
+
+impl TargetGraph {
+    /// Parses makefiles directly (rather than `make`'s database), reading any makefiles they include. Like for `make -f a
+    /// -f b`, several `paths` are read in order as if they were a single makefile.
+    /// `variable_overrides` are `VAR=value` arguments, which take precedence over assignments in the makefile. So do
+    /// environment variables if `environment_overrides` is set (like `make -e`).
+    pub(crate) fn parse_makefile_files(
+        paths: &[String],
+        variable_overrides: &[VariableAssignment],
+        environment_overrides: bool,
+    ) -> Result<Self, ParseError> {
+        let mut target_graph = TargetGraph::default();
+        target_graph.variables.define_defaults();
+        target_graph
+            .variables
+            .set_environment_overrides(environment_overrides);
+        if let Ok(current_dir) = std::env::current_dir() {
+            target_graph
+                .variables
+                .define_simple("CURDIR", &current_dir.to_string_lossy());
+        }
+        target_graph.variables.set_strict_functions(true);
+        for assignment in variable_overrides {
+            target_graph.variables.assign_command_line(
+                &assignment.name,
+                assignment.operator,
+                &assignment.value,
+            );
+            // Like `make`, variables set on the command line are exported.
+            target_graph.variables.set_exported(&assignment.name, true);
+        }
+        let mut evaluation = Evaluation::default();
+        for path in paths {
+            target_graph.include_file(path, &mut evaluation)?;
+        }
+        target_graph.finish();
+        Ok(target_graph)
+    }
+
+    fn include_file(&mut self, path: &str, evaluation: &mut Evaluation) -> Result<(), ParseError> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(error) => {
+                eprintln!("Could not read makefile `{}`: {}", path, error);
+                exit(1);
+            }
+        };
+        let total_bytes = file.metadata().map_or(0, |metadata| metadata.len());
+        self.variables
+            .assign("MAKEFILE_LIST", AssignmentOperator::Append, path);
+        self.parse_statements(path, BufReader::new(file), total_bytes, evaluation)
+    }
+
+    // Parses and evaluates one statement at a time.
+    fn parse_statements(
+        &mut self,
+        file_name: &str,
+        reader: impl BufRead,
+        total_bytes: u64,
+        evaluation: &mut Evaluation,
+    ) -> Result<(), ParseError> {
+        let progress_bar = parse_progress_bar(file_name, total_bytes);
+        let mut statements = Statements::new(reader);
+        loop {
+            let (line_number, statement) = match statements.next_statement() {
+                Ok(Some(statement)) => statement,
+                Ok(None) => break,
+                Err(error) => {
+                    eprintln!("Could not read makefile `{}`: {}", file_name, error);
+                    exit(1);
+                }
+            };
+            let statement = match evaluation.database {
+                true => escape_database_colons(statement),
+                false => {
+                    evaluation.location =
+                        Some(evaluation.eval_location.clone().unwrap_or(Location {
+                            file: file_name.to_owned(),
+                            line: line_number,
+                        }));
+                    statement
+                }
+            };
+            let lines = match all_consuming(parse_lines)(&statement) {
+                Ok((_, lines)) => lines,
+                Err(nom::Err::Error(error) | nom::Err::Failure(error)) => {
+                    return Err(ParseError::new(file_name, &statement, error.input)
+                        .starting_at_line(line_number))
+                }
+                // Only produced by streaming parsers.
+                Err(nom::Err::Incomplete(_)) => {
+                    return Err(
+                        ParseError::new(file_name, &statement, "").starting_at_line(line_number)
+                    )
+                }
+            };
+            self.evaluate(lines, evaluation)?;
+            for text in self.variables.take_evaluations() {
+                let eval_location = evaluation.eval_location.clone();
+                evaluation.eval_location = eval_location.clone().or(evaluation.location.clone());
+                self.parse_statements(file_name, text.as_bytes(), 0, evaluation)?;
+                evaluation.eval_location = eval_location;
+            }
+            progress_bar.set_position(statements.bytes_read);
+        }
+        progress_bar.finish_and_clear();
+        Ok(())
+    }
+
+    // Lines are evaluated in order, so that each rule sees the variables defined before it.
+    fn evaluate(
+        &mut self,
+        lines: Vec<Line>,
+        evaluation: &mut Evaluation,
+    ) -> Result<(), ParseError> {
+        for line in lines {
+            let active = evaluation
+                .conditional_stack
+                .iter()
+                .all(|frame| frame.active);
+            if let Line::Conditional(conditional) = line {
+                match conditional {
+                    Conditional::If(condition) => {
+                        let taken = active && condition.evaluate(&self.variables);
+                        evaluation.conditional_stack.push(ConditionalFrame {
+                            active: taken,
+                            // An `if` nested in an inactive branch must not activate its `else`.
+                            branch_taken: taken || !active,
+                        });
+                    }
+                    Conditional::Else(condition) => {
+                        let Some(frame) = evaluation.conditional_stack.pop() else {
+                            eprintln!("Makefile parsing error: `else` without a matching `if`");
+                            continue;
+                        };
+                        let taken = !frame.branch_taken
+                            && condition
+                                .map_or(true, |condition| condition.evaluate(&self.variables));
+                        evaluation.conditional_stack.push(ConditionalFrame {
+                            active: taken,
+                            branch_taken: frame.branch_taken || taken,
+                        });
+                    }
+                    Conditional::Endif => {
+                        if evaluation.conditional_stack.pop().is_none() {
+                            eprintln!("Makefile parsing error: `endif` without a matching `if`");
+                        }
+                    }
+                }
+                continue;
+            }
+            if !active {
+                continue;
+            }
+            let variables = &self.variables;
+            match line {
+                Line::Rule {
+                    targets,
+                    static_target_pattern,
+                    dependencies,
+                    order_only_dependencies,
+                    separator,
+                    inline_recipe,
+                } => {
+                    let double_colon = separator == RuleSeparator::DoubleColon;
+                    evaluation.current_rule_double_colon = double_colon;
+                    evaluation.current_rule_has_recipe = false;
+                    evaluation.current_pattern_rule = None;
+                    // `make`'s database lists names that are already expanded (so a `$` is literal).
+                    // TODO: the database doesn't escape spaces in names, or `%` in names of files (rather than patterns).
+                    let database = evaluation.database;
+                    let expand_all = |texts: &[String]| -> Vec<TargetName> {
+                        texts
+                            .iter()
+                            .flat_map(|text| match database {
+                                true => split_target_names(text),
+                                false => expand_wildcard_target_names(expand_to_target_names(
+                                    variables, text,
+                                )),
+                            })
+                            .collect()
+                    };
+                    evaluation.current_rule_targets = expand_all(&targets);
+                    // A target with only escaped `%`s (like `100\%.txt`) is a file named with a literal `%`.
+                    for target_name in &mut evaluation.current_rule_targets {
+                        if target_name.0.contains("\\%") && !has_unescaped_percent(&target_name.0) {
+                            target_name.0 = target_name.0.replace("\\%", "%");
+                            self.literal_percent_targets.insert(target_name.clone());
+                        }
+                    }
+                    if let Some(location) = &evaluation.location {
+                        for target_name in &evaluation.current_rule_targets {
+                            if !self.is_pattern(target_name) {
+                                add_location(&mut self.rule_locations, target_name, location);
+                            }
+                        }
+                    }
+                    // With `.SECONDEXPANSION`, prerequisites that still contain `$` after the first expansion are
+                    // expanded again for each target.
+                    // TODO: `::` rules.
+                    let first_expansion = |texts: &[String]| match evaluation.database {
+                        true => texts.join(" "),
+                        false => variables.expand(&texts.join(" ")),
+                    };
+                    let second_expansion = (self.second_expansion && !double_colon)
+                        .then(|| {
+                            (
+                                first_expansion(&dependencies),
+                                first_expansion(&order_only_dependencies),
+                            )
+                        })
+                        .filter(|(dependencies, order_only_dependencies)| {
+                            dependencies.contains('$') || order_only_dependencies.contains('$')
+                        });
+                    let is_pattern_rule = static_target_pattern.is_none()
+                        && evaluation
+                            .current_rule_targets
+                            .iter()
+                            .any(|target_name| self.is_pattern(target_name));
+                    let (dependencies, order_only_dependencies) = match &second_expansion {
+                        // Pattern rules are expanded again when they are instantiated.
+                        Some((dependencies, order_only_dependencies)) if is_pattern_rule => {
+                            let words = |text: &str| -> Vec<TargetName> {
+                                text.split_whitespace()
+                                    .map(|word| TargetName(word.to_owned()))
+                                    .collect()
+                            };
+                            (words(dependencies), words(order_only_dependencies))
+                        }
+                        Some(_) => (vec![], vec![]),
+                        None => (
+                            expand_all(&dependencies),
+                            expand_all(&order_only_dependencies),
+                        ),
+                    };
+                    let second_expansion = second_expansion.filter(|_| !is_pattern_rule);
+                    if std::mem::take(&mut evaluation.not_a_target) {
+                        self.not_targets
+                            .extend(evaluation.current_rule_targets.iter().cloned());
+                    }
+                    let grouped = separator == RuleSeparator::Grouped;
+                    let Some(static_target_pattern) = static_target_pattern else {
+                        if let Some((prerequisites, order_only_prerequisites)) = &second_expansion {
+                            for target_name in &evaluation.current_rule_targets {
+                                self.pending_second_expansions.push(SecondExpansion {
+                                    target_name: target_name.clone(),
+                                    stem: None,
+                                    prerequisites: prerequisites.clone(),
+                                    order_only_prerequisites: order_only_prerequisites.clone(),
+                                });
+                            }
+                        }
+                        let pattern_rule_count = self.pattern_rules.len();
+                        self.add_rule(
+                            &evaluation.current_rule_targets,
+                            dependencies,
+                            order_only_dependencies,
+                            double_colon,
+                        );
+                        evaluation.current_pattern_rule = (self.pattern_rules.len()
+                            > pattern_rule_count)
+                            .then_some(pattern_rule_count);
+                        if grouped {
+                            self.add_target_group(&evaluation.current_rule_targets);
+                        }
+                        if let Some(inline_recipe) = inline_recipe {
+                            self.add_recipe_line(evaluation, inline_recipe);
+                        }
+                        continue;
+                    };
+                    // A static pattern rule (`targets: target-pattern: prerequisite-patterns`) is instantiated for each of its targets right away.
+                    let pattern_rule = PatternRule {
+                        targets: vec![variables.expand(&static_target_pattern).trim().to_owned()],
+                        prerequisites: dependencies.into_iter().map(|t| t.0).collect(),
+                        order_only_prerequisites: order_only_dependencies
+                            .into_iter()
+                            .map(|t| t.0)
+                            .collect(),
+                        recipe: vec![],
+                    };
+                    for target_name in evaluation.current_rule_targets.clone() {
+                        let Some(stem) = pattern_rule.match_stem(&target_name.0) else {
+                            eprintln!(
+                                "Target `{}` does not match the static target pattern `{}`",
+                                target_name, pattern_rule.targets[0]
+                            );
+                            continue;
+                        };
+                        self.add_rule(
+                            &[target_name.clone()],
+                            PatternRule::prerequisites_for_stem(&pattern_rule.prerequisites, &stem),
+                            PatternRule::prerequisites_for_stem(
+                                &pattern_rule.order_only_prerequisites,
+                                &stem,
+                            ),
+                            double_colon,
+                        );
+                        if let Some((prerequisites, order_only_prerequisites)) = &second_expansion {
+                            self.pending_second_expansions.push(SecondExpansion {
+                                target_name: target_name.clone(),
+                                stem: Some(stem.clone()),
+                                prerequisites: prerequisites.clone(),
+                                order_only_prerequisites: order_only_prerequisites.clone(),
+                            });
+                        }
+                        self.stems.insert(target_name, stem);
+                    }
+                    if let Some(inline_recipe) = inline_recipe {
+                        self.add_recipe_line(evaluation, inline_recipe);
+                    }
+                }
+                Line::NotATarget => evaluation.not_a_target = true,
+                Line::AlsoMakes(also_makes) => {
+                    let mut group = evaluation.current_rule_targets.clone();
+                    group.extend(also_makes.into_iter().map(TargetName));
+                    self.add_target_group(&group);
+                }
+                Line::Stem(stem) => {
+                    for target_name in &evaluation.current_rule_targets {
+                        self.stems.insert(target_name.clone(), stem.clone());
+                    }
+                }
+                Line::RecipeLocation(location) => {
+                    for target_name in &evaluation.current_rule_targets {
+                        add_location(&mut self.rule_locations, target_name, &location);
+                    }
+                }
+                Line::Recipe(recipe_line) => self.add_recipe_line(evaluation, recipe_line),
+                Line::Assignment {
+                    assignment:
+                        VariableAssignment {
+                            name,
+                            operator,
+                            value,
+                        },
+                    is_override,
+                    is_export,
+                } => {
+                    let name = variables.expand(&name);
+                    let name = name.trim();
+                    if is_override {
+                        self.variables.assign_override(name, operator, &value);
+                    } else {
+                        self.variables.assign(name, operator, &value);
+                    }
+                    if is_export {
+                        self.variables.set_exported(name, true);
+                    }
+                }
+                Line::Export { exported, names } => match names {
+                    Some(names) => {
+                        for name in variables.expand(&names).split_whitespace() {
+                            self.variables.set_exported(name, exported);
+                        }
+                    }
+                    None => self.variables.set_export_all(exported),
+                },
+                Line::TargetAssignment {
+                    targets,
+                    mut assignment,
+                } => {
+                    assignment.name = variables.expand(&assignment.name).trim().to_owned();
+                    for target_name in targets
+                        .iter()
+                        .flat_map(|target| expand_to_target_names(variables, target))
+                    {
+                        self.target_variables
+                            .entry(target_name)
+                            .or_default()
+                            .push(assignment.clone());
+                    }
+                }
+                Line::Vpath {
+                    pattern,
+                    directories,
+                } => {
+                    let pattern = pattern.map(|pattern| variables.expand(&pattern));
+                    match (pattern, directories) {
+                        (Some(pattern), Some(directories)) => {
+                            let directories =
+                                split_search_directories(&variables.expand(&directories));
+                            self.search_paths.push(SearchPath {
+                                pattern,
+                                directories,
+                            });
+                        }
+                        (Some(pattern), None) => self
+                            .search_paths
+                            .retain(|search_path| search_path.pattern != pattern),
+                        (None, _) => self.search_paths.clear(),
+                    }
+                }
+                Line::Include {
+                    optional,
+                    file_names,
+                } => {
+                    for file_name in variables
+                        .expand(&file_names)
+                        .split_whitespace()
+                        .map(str::to_owned)
+                        .collect::<Vec<String>>()
+                    {
+                        if Path::new(&file_name).exists() {
+                            self.include_file(&file_name, evaluation)?;
+                        } else if !optional {
+                            // TODO: remake missing makefiles like `make` does?
+                            eprintln!("Could not find included makefile `{}`", file_name);
+                            exit(1);
+                        }
+                    }
+                }
+                // Like `make`, the expansion can also be a rule or an assignment.
+                Line::Expansion(text) if !evaluation.database => {
+                    let expanded = variables.expand(&text);
+                    if !expanded.trim().is_empty() {
+                        variables.push_evaluation(expanded);
+                    }
+                }
+                Line::Expansion(_) => {}
+                Line::Conditional(_) | Line::Ignored => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn add_recipe_line(&mut self, evaluation: &mut Evaluation, recipe_line: String) {
+        if let Some(pattern_rule_index) = evaluation.current_pattern_rule {
+            self.pattern_rules[pattern_rule_index]
+                .recipe
+                .push(recipe_line.clone());
+        }
+        for target_name in &evaluation.current_rule_targets {
+            // Pattern targets are only stored in `pattern_rules`.
+            if self.is_pattern(target_name) {
+                continue;
+            }
+            if evaluation.current_rule_double_colon {
+                if let Some(double_colon_rule) = self
+                    .double_colon_rules
+                    .get_mut(target_name)
+                    .and_then(|double_colon_rules| double_colon_rules.last_mut())
+                {
+                    double_colon_rule.recipe.push(recipe_line.clone());
+                }
+                continue;
+            }
+            let recipe = self.recipes.entry(target_name.clone()).or_default();
+            // Like in `make`, a later recipe for the same target replaces the earlier one.
+            if !evaluation.current_rule_has_recipe && !recipe.is_empty() {
+                recipe.clear();
+                self.overridden_recipes.insert(target_name.clone());
+            }
+            recipe.push(recipe_line.clone());
+        }
+        evaluation.current_rule_has_recipe = true;
+    }
+
+    fn finish(&mut self) {
+        for second_expansion in std::mem::take(&mut self.pending_second_expansions) {
+            let SecondExpansion {
+                target_name,
+                stem,
+                prerequisites,
+                order_only_prerequisites,
+            } = second_expansion;
+            // Like for pattern rules, `%` is replaced after the second expansion.
+            let expand = |prerequisites: &str| {
+                let expanded =
+                    self.second_expand(prerequisites, &target_name, stem.as_deref().unwrap_or(""));
+                match &stem {
+                    Some(stem) => PatternRule::prerequisites_for_stem(&expanded, stem),
+                    None => expanded.into_iter().map(TargetName).collect(),
+                }
+            };
+            let prerequisites = expand(&prerequisites);
+            let order_only_prerequisites = expand(&order_only_prerequisites);
+            self.add_rule(
+                &[target_name],
+                prerequisites,
+                order_only_prerequisites,
+                false,
+            );
+        }
+        self.translate_suffix_rules();
+        // Drop files that `make` looked at without them being a prerequisite, like missing `-include`d makefiles.
+        let prerequisites: IndexSet<TargetName> = self
+            .edges
+            .values()
+            .chain(self.order_only_edges.values())
+            .flatten()
+            .cloned()
+            .collect();
+        for not_target in std::mem::take(&mut self.not_targets) {
+            if prerequisites.contains(&not_target) {
+                self.prerequisites_without_rules.insert(not_target);
+            } else {
+                self.edges.shift_remove(&not_target);
+            }
+        }
+        // Like the `make` database, list prerequisites that have no rule (e.g. source files) as targets without prerequisites.
+        let prerequisites: Vec<TargetName> = self
+            .edges
+            .values()
+            .chain(self.order_only_edges.values())
+            .flatten()
+            .cloned()
+            .collect();
+        for prerequisite in prerequisites {
+            if !self.edges.contains_key(&prerequisite) {
+                self.edges.insert(prerequisite.clone(), vec![]);
+                self.prerequisites_without_rules.insert(prerequisite);
+            }
+        }
+        // TODO: test against multiple default goals?
+        let variables = &self.variables;
+        self.default_goal = expand_to_target_names(variables, "$(.DEFAULT_GOAL)")
+            .into_iter()
+            .next()
+            .or_else(|| self.first_target.clone());
+        self.makefiles = variables
+            .expand("$(MAKEFILE_LIST)")
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect();
+        // The `make` database does not contain comments, so these are read from the makefiles themselves.
+        for makefile in self.makefiles.clone() {
+            self.read_rule_comments(&makefile);
+        }
+    }
+
+    pub(crate) fn has_tag(&self, target_name: &TargetName, tags: &[String]) -> bool {
+        self.tags
+            .get(target_name)
+            .is_some_and(|target_tags| target_tags.iter().any(|tag| tags.contains(tag)))
+    }
+
+    fn read_rule_comments(&mut self, path: &str) {
+        let Ok(file) = File::open(path) else {
+            return;
+        };
+        let mut statements = Statements::new(BufReader::new(file));
+        let mut pending_comments = vec![];
+        while let Ok(Some((_, statement))) = statements.next_statement() {
+            if statement.starts_with('\t') {
+                continue;
+            }
+            if is_define_line(&statement) {
+                pending_comments.clear();
+                continue;
+            }
+            for (target_name, description) in target_descriptions(&self.variables, &statement) {
+                self.descriptions.insert(target_name, description);
+            }
+            for (target_name, comments) in
+                rule_comments(&self.variables, &statement, &mut pending_comments)
+            {
+                for comment in &comments {
+                    for tag in parse_list_annotation(comment, "tags") {
+                        let target_tags = self.tags.entry(target_name.clone()).or_default();
+                        if !target_tags.contains(&tag) {
+                            target_tags.push(tag);
+                        }
+                    }
+                    for resource in parse_list_annotation(comment, "resources") {
+                        let resources = self.resources.entry(target_name.clone()).or_default();
+                        if !resources.contains(&resource) {
+                            resources.push(resource);
+                        }
+                    }
+                    for name in parse_list_annotation(comment, "env") {
+                        let names = self
+                            .environment_inputs
+                            .entry(target_name.clone())
+                            .or_default();
+                        if !names.contains(&name) {
+                            names.push(name);
+                        }
+                    }
+                    if let Some(timeout) = parse_timeout(comment) {
+                        self.timeouts.insert(target_name.clone(), timeout);
+                    }
+                    if let Some(retries) = parse_number_annotation(comment, "retries") {
+                        self.retries.insert(target_name.clone(), retries);
+                    }
+                    if let Some(priority) = parse_number_annotation(comment, "priority") {
+                        self.priorities.insert(target_name.clone(), priority);
+                    }
+                    if let Some(nice) = parse_number_annotation(comment, "nice") {
+                        self.nice.insert(target_name.clone(), nice);
+                    }
+                    if let Some(io_priority) = parse_ionice(comment) {
+                        self.io_priorities.insert(target_name.clone(), io_priority);
+                    }
+                    if let Some(image) = annotation_value(comment, "image") {
+                        self.images.insert(target_name.clone(), image.to_owned());
+                    }
+                    match annotation_value(comment, "directory") {
+                        None | Some("false") => {}
+                        Some("true") => {
+                            self.directory_targets.insert(target_name.clone());
+                        }
+                        Some(value) => eprintln!(
+                            "Ignoring `mak:directory`: expected `true` or `false`, not `{}`",
+                            value
+                        ),
+                    }
+                    match annotation_value(comment, "stamp") {
+                        None | Some("false") => {}
+                        Some("true") => {
+                            self.stamp_targets.insert(target_name.clone());
+                        }
+                        Some(value) => eprintln!(
+                            "Ignoring `mak:stamp`: expected `true` or `false`, not `{}`",
+                            value
+                        ),
+                    }
+                    match annotation_value(comment, "remote") {
+                        None | Some("true") => {}
+                        Some("false") => {
+                            self.local_only.insert(target_name.clone());
+                        }
+                        Some(value) => eprintln!(
+                            "Ignoring `mak:remote`: expected `true` or `false`, not `{}`",
+                            value
+                        ),
+                    }
+                }
             }
         }
     }
+
+    /// Whether the makefile has a rule for `target_name` (as opposed to it only being a prerequisite).
+    pub(crate) fn has_rule(&self, target_name: &TargetName) -> bool {
+        self.has_recipe(target_name)
+            || self
+                .edges
+                .get(target_name)
+                .is_some_and(|edges| !edges.is_empty())
+            || self.is_phony(target_name)
+            || self.descriptions.contains_key(target_name)
+    }
+}
+
+/// A location in the parsed text that could not be parsed, with enough context to point the user at it.
+#[derive(Debug)]
+pub(crate) struct ParseError {
+    pub(crate) file: String,
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+    pub(crate) text: String,
+    pub(crate) hint: String,
+}
+
+impl ParseError {
+    // For errors in text that starts at `line_number` of the file.
+    fn starting_at_line(mut self, line_number: usize) -> Self {
+        self.line += line_number - 1;
+        self
+    }
+
+    fn new(file: &str, source: &str, remaining: &str) -> Self {
+        let offset = source.len() - remaining.len();
+        let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let text = source[line_start..]
+            .split('\n')
+            .next()
+            .unwrap_or_default()
+            .trim_end_matches('\r')
+            .to_owned();
+        Self {
+            file: file.to_owned(),
+            line: source[..line_start].matches('\n').count() + 1,
+            column: source[line_start..offset].chars().count() + 1,
+            hint: parse_error_hint(&text).to_owned(),
+            text,
+        }
+    }
+}
+
+fn parse_error_hint(text: &str) -> &'static str {
+    let trimmed = text.trim_start();
+    if text.starts_with('\t') {
+        "recipe lines (starting with a tab) must follow a rule"
+    } else if trimmed.starts_with("define") {
+        "`define` must be followed by a variable name and closed by `endef`"
+    } else if trimmed.contains("$(") && trimmed.matches('(').count() != trimmed.matches(')').count()
+        || trimmed.contains("${") && trimmed.matches('{').count() != trimmed.matches('}').count()
+    {
+        "check for an unterminated variable reference"
+    } else if trimmed.contains(':') {
+        "expected a rule like `target: prerequisites` or `target: pattern: prerequisites`"
+    } else {
+        "expected a rule, a variable assignment, or a directive"
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+        writeln!(f, "error: could not parse makefile")?;
+        writeln!(
+            f,
+            "{}--> {}:{}:{}",
+            gutter, self.file, self.line, self.column
+        )?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.text)?;
+        writeln!(f, "{} | {}^", gutter, " ".repeat(self.column - 1))?;
+        write!(f, "{} = hint: {}", gutter, self.hint)
+    }
+}
+
+// The `make` database does not map its lines back to the original makefiles.
+const MAKE_DATABASE_FILE: &str = "<make database> (see `make -prq`)";
+
+impl TryFrom<&String> for TargetGraph {
+    type Error = ParseError;
+
+    /// Parses `make`'s database (`make -prq`).
+    fn try_from(value: &String) -> Result<Self, Self::Error> {
+        let mut target_graph = TargetGraph {
+            // The database lists rules in no particular order, so this has to be known up front.
+            second_expansion: value
+                .lines()
+                .any(|line| line.starts_with(".SECONDEXPANSION:")),
+            ..Default::default()
+        };
+        let mut evaluation = Evaluation {
+            database: true,
+            ..Default::default()
+        };
+        target_graph.parse_statements(
+            MAKE_DATABASE_FILE,
+            value.as_bytes(),
+            value.len() as u64,
+            &mut evaluation,
+        )?;
+        target_graph.finish();
+        Ok(target_graph)
+    }
 }
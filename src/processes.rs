@@ -0,0 +1,216 @@
+use std::{
+    process::{exit, Child, Command},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use indicatif::{MultiProgress, ProgressDrawTarget};
+use serde::{Deserialize, Serialize};
+
+// Like shells, for a process that was stopped by `SIGINT`.
+const EXIT_CODE_INTERRUPTED: i32 = 130;
+
+/// How long a recipe that is stopped (because it timed out or `mak` was interrupted) gets to exit before it is killed.
+pub(crate) const STOP_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+// The process ID (which is also the process group ID) and label of each recipe that is running.
+static RUNNING_RECIPES: Mutex<Vec<(u32, String)>> = Mutex::new(vec![]);
+
+fn running_recipes() -> std::sync::MutexGuard<'static, Vec<(u32, String)>> {
+    RUNNING_RECIPES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Starts a recipe in a process group of its own, so that everything it starts can be stopped when it times out or
+/// `mak` is interrupted.
+pub(crate) fn spawn_recipe(command: &mut Command, label: &str) -> std::io::Result<Child> {
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(command, 0);
+    // Holding the lock while spawning means that the interrupt handler can't miss a recipe.
+    let mut running_recipes = running_recipes();
+    let child = command.spawn()?;
+    running_recipes.push((child.id(), label.to_owned()));
+    Ok(child)
+}
+
+/// Called once the recipe started by `spawn_recipe` has exited.
+pub(crate) fn recipe_finished(pid: u32) {
+    running_recipes().retain(|(running_pid, _)| *running_pid != pid);
+}
+
+/// An I/O scheduling class and level, like for `ionice`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct IoPriority {
+    // 1 for realtime, 2 for best-effort, and 3 for idle.
+    class: u8,
+    // From 0 (highest) to 7 (lowest). Ignored for the idle class.
+    level: u8,
+}
+
+/// Parses `idle`, `best-effort`, or `realtime`, optionally followed by a level, e.g. `best-effort:7`.
+pub(crate) fn parse_io_priority(text: &str) -> Result<IoPriority, String> {
+    let (class, level) = match text.split_once(':') {
+        Some((class, level)) => (class, Some(level)),
+        None => (text, None),
+    };
+    let class = match class {
+        "realtime" => 1,
+        "best-effort" => 2,
+        "idle" => 3,
+        class => {
+            return Err(format!(
+                "unknown I/O scheduling class `{}` (expected `idle`, `best-effort`, or `realtime`)",
+                class
+            ))
+        }
+    };
+    let level = match level.map(str::parse::<u8>) {
+        None => 4,
+        Some(Ok(level)) if level <= 7 => level,
+        Some(_) => return Err("the level must be from 0 to 7".to_owned()),
+    };
+    Ok(IoPriority { class, level })
+}
+
+/// Makes the recipe's processes run with the given niceness and I/O priority. Like for `nice` and `ionice`, these
+/// are only a best effort: if they can't be set (e.g. a negative niceness without permission), the recipe runs
+/// anyway.
+pub(crate) fn set_scheduling(
+    command: &mut Command,
+    nice: Option<i32>,
+    io_priority: Option<IoPriority>,
+) {
+    #[cfg(unix)]
+    unsafe {
+        std::os::unix::process::CommandExt::pre_exec(command, move || {
+            if let Some(nice) = nice {
+                // The type of the first argument differs between platforms.
+                libc::setpriority(libc::PRIO_PROCESS as _, 0, nice);
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(io_priority) = io_priority {
+                const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+                const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+                let level = match io_priority.class {
+                    3 => 0,
+                    _ => io_priority.level as libc::c_int,
+                };
+                libc::syscall(
+                    libc::SYS_ioprio_set,
+                    IOPRIO_WHO_PROCESS,
+                    0,
+                    ((io_priority.class as libc::c_int) << IOPRIO_CLASS_SHIFT) | level,
+                );
+            }
+            #[cfg(not(target_os = "linux"))]
+            let _ = io_priority;
+            Ok(())
+        });
+    }
+    // TODO: scheduling on other platforms.
+    #[cfg(not(unix))]
+    let _ = (command, nice, io_priority);
+}
+
+/// Sends `SIGTERM` (or `SIGKILL` if `force` is set) to the process group of `pid`.
+#[cfg(unix)]
+pub(crate) fn stop_process_group(pid: u32, force: bool) {
+    let signal = match force {
+        true => libc::SIGKILL,
+        false => libc::SIGTERM,
+    };
+    // The process group has the same ID as the process that started it.
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), signal);
+    }
+}
+
+// Whether any process of the process group of `pid` is still running.
+#[cfg(unix)]
+fn process_group_exists(pid: u32) -> bool {
+    unsafe { libc::kill(-(pid as libc::pid_t), 0) == 0 }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn stop_process_group(pid: u32, _force: bool) {
+    let _ = Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &pid.to_string()])
+        .status();
+}
+
+#[cfg(unix)]
+static INTERRUPT_PIPE_WRITE_FD: std::sync::atomic::AtomicI32 =
+    std::sync::atomic::AtomicI32::new(-1);
+
+// Only async-signal-safe calls are allowed here, so the actual handling happens on the thread reading the pipe.
+#[cfg(unix)]
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    let fd = INTERRUPT_PIPE_WRITE_FD.load(std::sync::atomic::Ordering::SeqCst);
+    unsafe {
+        libc::write(fd, [0u8].as_ptr() as *const libc::c_void, 1);
+    }
+}
+
+/// On `SIGINT` (Ctrl-C) or `SIGTERM`: stops the progress bars, stops all running recipes (which gives `make` the
+/// chance to delete partially written targets), lists them, and exits with 130. Recipes that are still running after
+/// the `STOP_GRACE_PERIOD` are killed.
+#[cfg(unix)]
+pub(crate) fn handle_interrupts(multi_progress: Arc<MultiProgress>) {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        eprintln!(
+            "Could not set up handling Ctrl-C: {}",
+            std::io::Error::last_os_error()
+        );
+        return;
+    }
+    let [read_fd, write_fd] = fds;
+    INTERRUPT_PIPE_WRITE_FD.store(write_fd, std::sync::atomic::Ordering::SeqCst);
+    unsafe {
+        libc::signal(libc::SIGINT, on_interrupt as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, on_interrupt as libc::sighandler_t);
+    }
+    std::thread::spawn(move || {
+        let mut byte = 0u8;
+        if unsafe { libc::read(read_fd, &mut byte as *mut u8 as *mut libc::c_void, 1) } != 1 {
+            return;
+        }
+        // Keeps any more recipes from starting.
+        let running_recipes = running_recipes();
+        let _ = multi_progress.clear();
+        multi_progress.set_draw_target(ProgressDrawTarget::hidden());
+        eprintln!("🛑 Interrupted.");
+        if !running_recipes.is_empty() {
+            eprintln!(
+                "🛑 Stopping {} running recipe{}: {}",
+                running_recipes.len(),
+                if running_recipes.len() == 1 { "" } else { "s" },
+                running_recipes
+                    .iter()
+                    .map(|(_, label)| label.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            );
+        }
+        for (pid, _) in running_recipes.iter() {
+            stop_process_group(*pid, false);
+        }
+        let deadline = std::time::Instant::now() + STOP_GRACE_PERIOD;
+        while std::time::Instant::now() < deadline
+            && running_recipes
+                .iter()
+                .any(|(pid, _)| process_group_exists(*pid))
+        {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        for (pid, _) in running_recipes.iter() {
+            stop_process_group(*pid, true);
+        }
+        exit(EXIT_CODE_INTERRUPTED)
+    });
+}
+
+// TODO: stop recipes on Ctrl-C on other platforms.
+#[cfg(not(unix))]
+pub(crate) fn handle_interrupts(_multi_progress: Arc<MultiProgress>) {}
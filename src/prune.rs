@@ -0,0 +1,199 @@
+// For `--prune`: finds the files in output directories that no rule makes anymore (e.g. left over from a renamed
+// target), so that they don't end up in packages or hide a missing rule.
+
+use std::{
+    collections::HashSet,
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::parse::{PatternRule, TargetGraph, TargetName};
+
+fn normalize(path: &str) -> PathBuf {
+    Path::new(path)
+        .components()
+        .filter(|component| !matches!(component, std::path::Component::CurDir))
+        .collect()
+}
+
+// The directories that recipes write to: the ones that have files made by rules (or are made by rules themselves),
+// except the top-level directory and directories with files that no rule makes. Directories inside other ones are
+// left out, since those are searched anyway.
+fn output_directories(
+    made: &HashSet<PathBuf>,
+    sources: &HashSet<PathBuf>,
+    directory_targets: &HashSet<PathBuf>,
+) -> Vec<PathBuf> {
+    let mut directories: Vec<PathBuf> = vec![];
+    for path in made {
+        let candidates = [
+            path.parent().map(Path::to_path_buf),
+            path.is_dir().then(|| path.clone()),
+        ];
+        for directory in candidates.into_iter().flatten() {
+            if directory.as_os_str().is_empty()
+                || directory.is_absolute()
+                || directory.starts_with(".mak")
+                || directories.contains(&directory)
+            {
+                continue;
+            }
+            directories.push(directory);
+        }
+    }
+    directories.retain(|directory| {
+        !directory_targets.contains(directory)
+            && !sources.iter().any(|source| source.starts_with(directory))
+    });
+    let mut outermost: Vec<PathBuf> = directories
+        .iter()
+        .filter(|directory| {
+            !directories
+                .iter()
+                .any(|other| other != *directory && directory.starts_with(other))
+        })
+        .cloned()
+        .collect();
+    outermost.sort();
+    outermost
+}
+
+// Whether a pattern rule makes `path` from prerequisites that exist or that rules make, like `make` would. Rules that
+// match any file (like the built-in `%: %.o`) don't count.
+fn made_by_pattern_rule(target_graph: &TargetGraph, path: &Path, made: &HashSet<PathBuf>) -> bool {
+    let path = path.to_string_lossy();
+    target_graph
+        .pattern_rules
+        .iter()
+        .filter(|pattern_rule| {
+            !pattern_rule.recipe.is_empty()
+                && !pattern_rule.targets.iter().any(|target| target == "%")
+        })
+        .any(|pattern_rule| {
+            let Some(stem) = pattern_rule.match_stem(&path) else {
+                return false;
+            };
+            PatternRule::prerequisites_for_stem(&pattern_rule.prerequisites, &stem)
+                .iter()
+                .all(|prerequisite| {
+                    Path::new(&prerequisite.0).exists()
+                        || made.contains(&normalize(&prerequisite.0))
+                })
+        })
+}
+
+fn find_stale_files(
+    target_graph: &TargetGraph,
+    directory: &Path,
+    made: &HashSet<PathBuf>,
+    directory_targets: &HashSet<PathBuf>,
+    stale: &mut Vec<PathBuf>,
+) {
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return;
+    };
+    let mut entries: Vec<PathBuf> = entries
+        .map_while(Result::ok)
+        .map(|entry| entry.path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        let path = normalize(&path.to_string_lossy());
+        // The files in a directory target are made by its recipe.
+        if directory_targets.contains(&path) {
+            continue;
+        }
+        let is_directory = std::fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir());
+        match is_directory {
+            true => find_stale_files(target_graph, &path, made, directory_targets, stale),
+            false if !made.contains(&path) && !made_by_pattern_rule(target_graph, &path, made) => {
+                stale.push(path)
+            }
+            false => {}
+        }
+    }
+}
+
+/// The files in output directories that are not targets of rules with recipes (or of pattern rules).
+pub(crate) fn stale_files(target_graph: &TargetGraph) -> Vec<PathBuf> {
+    let (made, sources): (Vec<&TargetName>, Vec<&TargetName>) = target_graph
+        .edges
+        .keys()
+        .filter(|target_name| !target_graph.is_phony(target_name))
+        .partition(|target_name| target_graph.has_recipe(target_name));
+    let made: HashSet<PathBuf> = made
+        .iter()
+        .map(|target_name| normalize(&target_name.0))
+        .collect();
+    let sources: HashSet<PathBuf> = sources
+        .iter()
+        .map(|target_name| normalize(&target_name.0))
+        .chain(
+            target_graph
+                .makefiles
+                .iter()
+                .map(|makefile| normalize(makefile)),
+        )
+        .filter(|path| !made.contains(path))
+        .collect();
+    let directory_targets: HashSet<PathBuf> = target_graph
+        .directory_targets
+        .iter()
+        .map(|target_name| normalize(&target_name.0))
+        .collect();
+    let mut stale = vec![];
+    for directory in output_directories(&made, &sources, &directory_targets) {
+        find_stale_files(
+            target_graph,
+            &directory,
+            &made,
+            &directory_targets,
+            &mut stale,
+        );
+    }
+    stale
+}
+
+/// Lists the `stale_files`, and deletes them if `yes` or if the user agrees (when asked on a terminal). Returns whether
+/// no stale files are left.
+pub(crate) fn prune(target_graph: &TargetGraph, dry_run: bool, yes: bool) -> bool {
+    let stale = stale_files(target_graph);
+    if stale.is_empty() {
+        println!("No stale files");
+        return true;
+    }
+    println!(
+        "{} file{} in output directories that no rule makes:",
+        stale.len(),
+        if stale.len() == 1 { "" } else { "s" }
+    );
+    for path in &stale {
+        println!("  {}", path.display());
+    }
+    if dry_run {
+        return false;
+    }
+    let delete = yes || {
+        if !std::io::stdin().is_terminal() {
+            println!("Pass `--yes` to delete them.");
+            return false;
+        }
+        print!("Delete them? [y/N] ");
+        let _ = std::io::stdout().flush();
+        let mut answer = String::new();
+        let _ = std::io::stdin().read_line(&mut answer);
+        matches!(answer.trim(), "y" | "Y" | "yes")
+    };
+    if !delete {
+        return false;
+    }
+    let mut success = true;
+    for path in &stale {
+        println!("Deleting: {}", path.display());
+        if let Err(error) = std::fs::remove_file(path) {
+            eprintln!("Could not delete `{}`: {}", path.display(), error);
+            success = false;
+        }
+    }
+    success
+}
@@ -0,0 +1,85 @@
+use crate::parse::{TargetGraph, TargetName};
+
+// Stands in for a `\`-newline while a recipe line is expanded, so that it isn't split there like the newlines of a
+// `define`. (A private use character, which makefiles don't contain.)
+const CONTINUATION: char = '\u{E000}';
+
+// Like `make`, a `\`-newline stays in the script (for the shell to handle), but the tab that starts the next line is
+// removed.
+fn protect_continuations(recipe_line: &str) -> String {
+    recipe_line
+        .replace("\\\n\t", "\\\n")
+        .replace("\\\n", &CONTINUATION.to_string())
+}
+
+/// A single shell invocation of (part of) a recipe, with the expanded script and its `@`/`-`/`+` prefixes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ShellInvocation {
+    pub(crate) script: String,
+    /// `@`: don't echo the script.
+    pub(crate) silent: bool,
+    /// `-`: ignore a non-zero exit code.
+    pub(crate) ignore_errors: bool,
+    /// `+`: run even when only showing what would be run.
+    pub(crate) always_run: bool,
+}
+
+impl ShellInvocation {
+    // Strips (and records) the prefixes of a recipe line. These may be separated by whitespace.
+    fn apply_prefixes<'a>(&mut self, line: &'a str) -> &'a str {
+        let mut line = line;
+        loop {
+            line = line.trim_start_matches([' ', '\t']);
+            match line.chars().next() {
+                Some('@') => self.silent = true,
+                Some('-') => self.ignore_errors = true,
+                Some('+') => self.always_run = true,
+                _ => return line,
+            }
+            line = &line[1..];
+        }
+    }
+}
+
+impl TargetGraph {
+    /// The shell invocations that run the recipe of `target_name`, with variables expanded: one per recipe line or,
+    /// with `.ONESHELL`, a single one for the whole recipe. In that case only the prefixes of the first line count,
+    /// and the prefixes of the other lines are removed (like `make` does for POSIX shells).
+    pub(crate) fn shell_invocations(&self, target_name: &TargetName) -> Vec<ShellInvocation> {
+        let Some(recipe) = self.recipes.get(target_name) else {
+            return vec![];
+        };
+        // TODO: `::` rules.
+        let variables = self.variables_for_target(target_name);
+        let automatic_variables = self.automatic_variables(target_name);
+
+        let mut invocations: Vec<ShellInvocation> = vec![];
+        for recipe_line in recipe {
+            let mut line_prefixes = ShellInvocation::default();
+            let recipe_line = line_prefixes.apply_prefixes(recipe_line);
+            // A line can expand to several lines (e.g. a `define`), each of which is a separate recipe line.
+            let expanded = variables
+                .expand_for_target(&protect_continuations(recipe_line), &automatic_variables);
+            for expanded_line in expanded.lines() {
+                let mut invocation = line_prefixes.clone();
+                let expanded_line = invocation
+                    .apply_prefixes(expanded_line)
+                    .replace(CONTINUATION, "\\\n");
+                let expanded_line = expanded_line.as_str();
+                match invocations.last_mut() {
+                    Some(one_shell) if self.one_shell => {
+                        one_shell.script.push('\n');
+                        one_shell.script.push_str(expanded_line);
+                    }
+                    _ => {
+                        invocation.script = expanded_line.to_owned();
+                        invocations.push(invocation);
+                    }
+                }
+            }
+        }
+        // Like `make`, empty lines don't start a shell.
+        invocations.retain(|invocation| !invocation.script.trim().is_empty());
+        invocations
+    }
+}
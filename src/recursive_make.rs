@@ -0,0 +1,247 @@
+use std::path::Path;
+
+use indexmap::IndexMap;
+
+use crate::parse::{extend_unique, ParseError, SubmakeTarget, TargetGraph, TargetName};
+
+// Guards against makefiles that (indirectly) run `make` in their own directory.
+const MAX_RECURSION_DEPTH: usize = 16;
+
+// Flags that do not change what a recursive `make` builds.
+const IGNORED_FLAGS: [&str; 5] = [
+    "-s",
+    "--silent",
+    "-w",
+    "--print-directory",
+    "--no-print-directory",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+struct RecursiveMake {
+    directory: String,
+    // Empty for the default goal.
+    targets: Vec<String>,
+}
+
+// Recognizes a recipe line like `$(MAKE) -C directory [targets]` (or `cd directory && $(MAKE) [targets]`).
+// Anything else (loops, variables in arguments, extra flags) is kept as an opaque recipe.
+fn parse_recursive_make(recipe_line: &str, target_name: &TargetName) -> Option<RecursiveMake> {
+    let line = recipe_line.trim_start_matches(['@', '-', '+', ' ', '\t']);
+    let (mut directory, line) = match line.split_once("&&") {
+        Some((cd, line)) => {
+            let mut words = cd.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some("cd"), Some(directory), None) => (Some(directory.to_owned()), line),
+                _ => return None,
+            }
+        }
+        None => (None, line),
+    };
+    let mut words = line.split_whitespace();
+    if !matches!(words.next(), Some("$(MAKE)" | "${MAKE}" | "make")) {
+        return None;
+    }
+    let mut targets = vec![];
+    while let Some(word) = words.next() {
+        let word = match word {
+            "$@" => target_name.0.as_str(),
+            word => word,
+        };
+        if word.contains(['$', '=', ';', '|', '&', '>', '<', '`']) {
+            return None;
+        }
+        if IGNORED_FLAGS.contains(&word) {
+            continue;
+        }
+        let sub_directory = if word == "-C" || word == "--directory" {
+            words.next()?.to_owned()
+        } else if let Some(sub_directory) = word
+            .strip_prefix("--directory=")
+            .or_else(|| word.strip_prefix("-C"))
+        {
+            sub_directory.to_owned()
+        } else if word.starts_with('-') {
+            return None;
+        } else {
+            targets.push(word.to_owned());
+            continue;
+        };
+        directory = Some(match directory {
+            Some(directory) => Path::new(&directory)
+                .join(sub_directory)
+                .to_string_lossy()
+                .into_owned(),
+            None => sub_directory,
+        });
+    }
+    let directory = directory?;
+    if directory.contains('$') {
+        return None;
+    }
+    Some(RecursiveMake {
+        directory: directory
+            .trim_start_matches("./")
+            .trim_end_matches('/')
+            .to_owned(),
+        targets,
+    })
+}
+
+fn prefixed(directory: &str, target_name: &TargetName) -> TargetName {
+    TargetName(format!("{}/{}", directory, target_name))
+}
+
+/// Replaces the recipes of targets that only run `$(MAKE) -C <directory> [targets]` with the targets of the makefile
+/// in that directory (read using `read_makefile`), prefixed with `<directory>/`. This allows the targets of the
+/// sub-makefile to be scheduled (in parallel) alongside everything else.
+pub(crate) fn splice_recursive_makes(
+    target_graph: &mut TargetGraph,
+    verbose: bool,
+    read_makefile: &dyn Fn(&str) -> Result<TargetGraph, ParseError>,
+) -> Result<(), ParseError> {
+    splice_recursive_makes_with_depth(target_graph, verbose, read_makefile, 0)
+}
+
+fn splice_recursive_makes_with_depth(
+    target_graph: &mut TargetGraph,
+    verbose: bool,
+    read_makefile: &dyn Fn(&str) -> Result<TargetGraph, ParseError>,
+    depth: usize,
+) -> Result<(), ParseError> {
+    if depth >= MAX_RECURSION_DEPTH {
+        return Ok(());
+    }
+    let recursive_makes: Vec<(TargetName, RecursiveMake)> = target_graph
+        .recipes
+        .iter()
+        .filter_map(|(target_name, recipe)| match recipe.as_slice() {
+            // TODO: handle recipes with several lines (e.g. a loop over directories).
+            [recipe_line] => parse_recursive_make(recipe_line, target_name)
+                .map(|recursive_make| (target_name.clone(), recursive_make)),
+            _ => None,
+        })
+        .collect();
+
+    // Each directory is only read (and spliced in) once, even if several targets run `make` in it.
+    let mut default_goals: IndexMap<String, Option<TargetName>> = IndexMap::new();
+    for (target_name, recursive_make) in recursive_makes {
+        let directory = recursive_make.directory;
+        if !default_goals.contains_key(&directory) {
+            if verbose {
+                eprintln!("Reading recursive makefile in: {}", directory);
+            }
+            let mut sub_graph = read_makefile(&directory)?;
+            splice_recursive_makes_with_depth(&mut sub_graph, verbose, read_makefile, depth + 1)?;
+            let sub_targets: Vec<TargetName> = sub_graph
+                .edges
+                .keys()
+                .filter(|sub_target| {
+                    !sub_target.0.starts_with('.') && !sub_graph.makefiles.contains(&sub_target.0)
+                })
+                .cloned()
+                .collect();
+            // Targets that the parent makefile defines itself would be ambiguous.
+            if sub_targets.iter().any(|sub_target| {
+                let spliced = prefixed(&directory, sub_target);
+                target_graph.edges.contains_key(&spliced)
+                    && !target_graph.submake_targets.contains_key(&spliced)
+            }) {
+                if verbose {
+                    eprintln!("Not splicing {} (conflicting target names)", directory);
+                }
+                default_goals.insert(directory.clone(), None);
+                continue;
+            }
+            let default_goal = sub_graph
+                .default_goal
+                .as_ref()
+                .map(|default_goal| prefixed(&directory, default_goal));
+            splice(target_graph, &directory, sub_graph, &sub_targets);
+            default_goals.insert(directory.clone(), default_goal);
+        }
+        let goals: Vec<TargetName> = if recursive_make.targets.is_empty() {
+            match default_goals.get(&directory).cloned().flatten() {
+                Some(default_goal) => vec![default_goal],
+                None => continue,
+            }
+        } else {
+            recursive_make
+                .targets
+                .iter()
+                .map(|goal| prefixed(&directory, &TargetName(goal.to_owned())))
+                .collect()
+        };
+        if !goals
+            .iter()
+            .all(|goal| target_graph.submake_targets.contains_key(goal))
+        {
+            continue;
+        }
+        extend_unique(
+            target_graph.edges.entry(target_name.clone()).or_default(),
+            &goals,
+        );
+        target_graph.recursive_make_targets.insert(target_name);
+    }
+    Ok(())
+}
+
+fn splice(
+    target_graph: &mut TargetGraph,
+    directory: &str,
+    sub_graph: TargetGraph,
+    sub_targets: &[TargetName],
+) {
+    let spliced: Vec<TargetName> = sub_targets
+        .iter()
+        .map(|sub_target| prefixed(directory, sub_target))
+        .collect();
+    let prefix_all = |target_names: &[TargetName]| -> Vec<TargetName> {
+        target_names
+            .iter()
+            .map(|target_name| prefixed(directory, target_name))
+            .collect()
+    };
+    for (sub_target, spliced_target) in sub_targets.iter().zip(&spliced) {
+        target_graph.edges.insert(
+            spliced_target.clone(),
+            prefix_all(&sub_graph.edges[sub_target]),
+        );
+        if let Some(order_only) = sub_graph.order_only_edges.get(sub_target) {
+            target_graph
+                .order_only_edges
+                .insert(spliced_target.clone(), prefix_all(order_only));
+        }
+        if let Some(recipe) = sub_graph.recipes.get(sub_target) {
+            target_graph
+                .recipes
+                .insert(spliced_target.clone(), recipe.clone());
+        }
+        if sub_graph.phony.contains(sub_target) {
+            target_graph.phony.insert(spliced_target.clone());
+        }
+        if sub_graph.recursive_make_targets.contains(sub_target) {
+            target_graph
+                .recursive_make_targets
+                .insert(spliced_target.clone());
+        }
+        // Targets from deeper recursive makes already know their directory (relative to `directory`).
+        let submake_target = match sub_graph.submake_targets.get(sub_target) {
+            Some(submake_target) => SubmakeTarget {
+                directory: format!("{}/{}", directory, submake_target.directory),
+                target_name: submake_target.target_name.clone(),
+            },
+            None => SubmakeTarget {
+                directory: directory.to_owned(),
+                target_name: sub_target.clone(),
+            },
+        };
+        target_graph
+            .submake_targets
+            .insert(spliced_target.clone(), submake_target);
+    }
+    for group in &sub_graph.target_groups {
+        let group = prefix_all(group);
+        target_graph.add_target_group(&group);
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    path::{Component, Path},
+    process::{exit, Command},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::{
+    executor::shell_quote,
+    parse::{TargetGraph, TargetName},
+    worker::{Job, JobFile},
+};
+
+// Relative to the home directory on the worker.
+const DEFAULT_REMOTE_DIRECTORY: &str = ".cache/mak/remote";
+
+// Workers that run `mak --serve-worker` instead of being used over SSH.
+const MAK_WORKER_PREFIX: &str = "mak://";
+
+/// A host that recipes can run on, from a `--workers` file.
+#[derive(Debug)]
+pub(crate) struct Worker {
+    // Anything that `ssh` and `rsync` accept, e.g. `user@build1` or a `Host` from `~/.ssh/config`, or `mak://HOST:PORT`.
+    host: String,
+    slots: usize,
+    // The copy of the current directory on the worker.
+    directory: String,
+}
+
+/// Reads a workers file, which has one worker per line: `HOST [SLOTS] [DIRECTORY]`, e.g. `build1 8 /scratch/project`.
+/// `SLOTS` (the number of recipes that can run on it at the same time) defaults to 1, and `DIRECTORY` to
+/// `~/.cache/mak/remote/<name of the current directory>`. `DIRECTORY` is not used for `mak://HOST:PORT` workers.
+pub(crate) fn read_workers_file(path: &Path) -> Vec<Worker> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(error) => {
+            eprintln!("Could not read `{}`: {}", path.display(), error);
+            exit(1);
+        }
+    };
+    let project_name = project_name();
+    let mut workers = vec![];
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (host, slots, directory) = match words[..] {
+            [] => continue,
+            [host] => (host, Some("1"), None),
+            [host, slots] => (host, Some(slots), None),
+            [host, slots, directory] => (host, Some(slots), Some(directory)),
+            _ => (words[0], None, None),
+        };
+        let Some(slots) = slots
+            .and_then(|slots| slots.parse::<usize>().ok())
+            .filter(|slots| *slots > 0)
+        else {
+            eprintln!(
+                "{}:{}: expected `HOST [SLOTS] [DIRECTORY]`: {}",
+                path.display(),
+                index + 1,
+                line.trim()
+            );
+            exit(1);
+        };
+        workers.push(Worker {
+            host: host.to_owned(),
+            slots,
+            directory: directory
+                .map(str::to_owned)
+                .unwrap_or_else(|| format!("{}/{}", DEFAULT_REMOTE_DIRECTORY, project_name)),
+        });
+    }
+    workers
+}
+
+// The name of the current directory.
+fn project_name() -> String {
+    std::env::current_dir()
+        .ok()
+        .and_then(|current_dir| {
+            current_dir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+        .unwrap_or_default()
+}
+
+/// The workers and how many of their slots are in use.
+pub(crate) struct WorkerPool {
+    workers: Vec<Worker>,
+    busy: Mutex<Vec<usize>>,
+}
+
+/// A slot on a worker, which is given back when dropped.
+pub(crate) struct WorkerSlot {
+    worker_pool: Arc<WorkerPool>,
+    index: usize,
+}
+
+// Only relative paths inside of the current directory are copied. Anything else (e.g. system headers) needs to exist
+// on the workers already.
+fn is_project_path(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// The files to copy to a worker for `group`: the makefiles and the prerequisites. Should only be called once the
+/// prerequisites are up to date.
+pub(crate) fn inputs(target_graph: &TargetGraph, group: &[TargetName]) -> Vec<String> {
+    let mut inputs = target_graph.makefiles.clone();
+    for member in group {
+        for prerequisite in target_graph.all_prerequisites(member) {
+            if target_graph.is_phony(&prerequisite) {
+                continue;
+            }
+            if let Some(path) = target_graph.resolve_path(&prerequisite.0) {
+                if !inputs.contains(&path) {
+                    inputs.push(path);
+                }
+            }
+        }
+    }
+    inputs
+}
+
+impl WorkerPool {
+    pub(crate) fn new(workers: Vec<Worker>) -> Arc<Self> {
+        Arc::new(Self {
+            busy: Mutex::new(vec![0; workers.len()]),
+            workers,
+        })
+    }
+
+    pub(crate) fn total_slots(&self) -> usize {
+        self.workers.iter().map(|worker| worker.slots).sum()
+    }
+
+    /// A free slot on the least busy worker, if there is one.
+    pub(crate) fn try_claim(self: &Arc<Self>) -> Option<WorkerSlot> {
+        let mut busy = self.busy.lock().unwrap_or_else(PoisonError::into_inner);
+        let index = (0..self.workers.len())
+            .filter(|index| busy[*index] < self.workers[*index].slots)
+            .min_by_key(|index| busy[*index] * 1000 / self.workers[*index].slots)?;
+        busy[index] += 1;
+        Some(WorkerSlot {
+            worker_pool: self.clone(),
+            index,
+        })
+    }
+}
+
+impl Drop for WorkerSlot {
+    fn drop(&mut self) {
+        self.worker_pool
+            .busy
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)[self.index] -= 1;
+    }
+}
+
+impl WorkerSlot {
+    pub(crate) fn host(&self) -> &str {
+        &self.worker_pool.workers[self.index].host
+    }
+
+    /// A command that copies `inputs` to the worker, runs `command` there (in the copy of the current directory), and
+    /// copies `outputs` back if it succeeded. Only the program and arguments of `command` are used, so the recipe runs
+    /// with the environment of the worker. For a `mak://` worker, the command reads the job from a file, which needs to
+    /// be kept until the recipe is done.
+    pub(crate) fn remote_command(
+        &self,
+        command: &Command,
+        inputs: &[String],
+        outputs: &[String],
+    ) -> (Command, Option<JobFile>) {
+        let worker = &self.worker_pool.workers[self.index];
+        if let Some(address) = worker.host.strip_prefix(MAK_WORKER_PREFIX) {
+            let job = Job {
+                project: project_name(),
+                command: std::iter::once(command.get_program())
+                    .chain(command.get_args())
+                    .map(|word| word.to_string_lossy().into_owned())
+                    .collect(),
+                inputs: inputs
+                    .iter()
+                    .filter(|input| is_project_path(input))
+                    .cloned()
+                    .collect(),
+                outputs: outputs
+                    .iter()
+                    .filter(|output| is_project_path(output))
+                    .cloned()
+                    .collect(),
+            };
+            // A file, since a large job could be too long for an argument.
+            let job_file = JobFile::new(&job).unwrap_or_else(|error| {
+                eprintln!("Could not write the job for `{}`: {}", self.host(), error);
+                exit(1)
+            });
+            let mut remote_command = Command::new(
+                std::env::current_exe().expect("Could not find the `mak` executable."),
+            );
+            remote_command
+                .arg("--worker-client")
+                .arg(address)
+                .arg(job_file.path());
+            return (remote_command, Some(job_file));
+        }
+        let host = shell_quote(&worker.host);
+        let directory = shell_quote(&worker.directory);
+        let remote_command_line = std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|word| shell_quote(&word.to_string_lossy()))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let mut lines = vec![
+            "set -e".to_owned(),
+            format!(
+                "ssh {} {}",
+                host,
+                shell_quote(&format!("mkdir -p {}", directory))
+            ),
+        ];
+        let inputs: Vec<String> = inputs
+            .iter()
+            .filter(|input| is_project_path(input))
+            .map(|input| shell_quote(input))
+            .collect();
+        if !inputs.is_empty() {
+            // `--relative` keeps the directories of the files. `--protect-args` keeps the remote shell from splitting
+            // the remote paths.
+            lines.push(format!(
+                "rsync --archive --relative --protect-args -- {} {}:{}/",
+                inputs.join(" "),
+                host,
+                directory
+            ));
+        }
+        lines.push(format!(
+            "ssh {} {}",
+            host,
+            shell_quote(&format!("cd {} && {}", directory, remote_command_line))
+        ));
+        for output in outputs.iter().filter(|output| is_project_path(output)) {
+            lines.push(format!(
+                "rsync --archive --relative --protect-args -- {}:{} .",
+                host,
+                shell_quote(&format!("{}/./{}", worker.directory, output))
+            ));
+        }
+        let mut remote_command = Command::new("/bin/sh");
+        remote_command.arg("-c").arg(lines.join("\n"));
+        (remote_command, None)
+    }
+}
@@ -0,0 +1,58 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::job_slots::{JobSlot, JobSlots, Priority};
+
+/// Parses a `NAME=CAPACITY` resource from `--resource`, e.g. `db=2`.
+pub(crate) fn parse_resource(text: &str) -> Result<(String, usize), String> {
+    let Some((name, capacity)) = text.split_once('=') else {
+        return Err(format!("expected `NAME=CAPACITY`, not `{}`", text));
+    };
+    match capacity.parse::<usize>() {
+        Ok(capacity) if capacity > 0 && !name.is_empty() => Ok((name.to_owned(), capacity)),
+        _ => Err(format!("invalid resource `{}`", text)),
+    }
+}
+
+/// Named resources that only a limited number of recipes (1 unless set with `--resource`) can use at the same time,
+/// for targets with a comment like `# mak:resources=db,port-8080`.
+pub(crate) struct Resources {
+    capacities: HashMap<String, usize>,
+    slots: Mutex<HashMap<String, Arc<JobSlots>>>,
+}
+
+impl Resources {
+    pub(crate) fn new(capacities: &[(String, usize)]) -> Arc<Self> {
+        Arc::new(Self {
+            capacities: capacities.iter().cloned().collect(),
+            slots: Default::default(),
+        })
+    }
+
+    fn slots(&self, name: &str) -> Arc<JobSlots> {
+        let mut slots = self.slots.lock().unwrap_or_else(PoisonError::into_inner);
+        slots
+            .entry(name.to_owned())
+            .or_insert_with(|| {
+                let job_slots = JobSlots::new(self.capacities.get(name).copied().unwrap_or(1));
+                job_slots.open();
+                job_slots
+            })
+            .clone()
+    }
+
+    /// Waits until all of `names` are free. They are always taken in the same order, so that two recipes can't each
+    /// hold a resource that the other one is waiting for.
+    pub(crate) async fn acquire(&self, names: &[String], priority: Priority) -> Vec<JobSlot> {
+        let mut names = names.to_vec();
+        names.sort();
+        names.dedup();
+        let mut held = vec![];
+        for name in names {
+            held.push(self.slots(&name).acquire(priority, false).await);
+        }
+        held
+    }
+}
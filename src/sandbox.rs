@@ -0,0 +1,402 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    time::SystemTime,
+};
+
+use crate::parse::{TargetGraph, TargetName};
+
+/// What a sandboxed recipe may access in the project (the current directory): it can read the makefiles, its
+/// prerequisites, and its targets, and write its targets. Everything outside of the project can be read, but only
+/// the temporary directory and `/dev` can be written.
+pub(crate) struct SandboxPolicy {
+    project: PathBuf,
+    readable: Vec<PathBuf>,
+    writable: Vec<PathBuf>,
+    // Created for targets that didn't exist yet, and removed when this is dropped if the recipe didn't write them.
+    placeholders: Vec<PathBuf>,
+}
+
+// Landlock can only allow writing to files that exist, so a target that doesn't exist yet is created (with its
+// directories) as an empty file or directory that is older than everything else.
+fn create_placeholder(path: &Path, is_directory: bool) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match is_directory {
+        true => std::fs::create_dir(path)?,
+        false => drop(std::fs::File::create_new(path)?),
+    }
+    std::fs::File::open(path)?.set_modified(SystemTime::UNIX_EPOCH)
+}
+
+fn is_untouched_placeholder(path: &Path) -> bool {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return false;
+    };
+    let is_empty = match metadata.is_dir() {
+        true => std::fs::read_dir(path).is_ok_and(|mut entries| entries.next().is_none()),
+        false => metadata.len() == 0,
+    };
+    is_empty
+        && metadata
+            .modified()
+            .is_ok_and(|modified| modified == SystemTime::UNIX_EPOCH)
+}
+
+impl SandboxPolicy {
+    /// Should only be called once the prerequisites are up to date, since only files that exist can be allowed. With
+    /// `create_placeholders`, the targets that don't exist yet are created first (which `make` would see as up to date,
+    /// unless it is run with `-B`).
+    pub(crate) fn new(
+        target_graph: &TargetGraph,
+        group: &[TargetName],
+        create_placeholders: bool,
+    ) -> Self {
+        let project = std::env::current_dir()
+            .expect("Could not get the current directory.")
+            .canonicalize()
+            .expect("Could not resolve the current directory.");
+        let mut readable: Vec<PathBuf> = target_graph
+            .makefiles
+            .iter()
+            .map(|makefile| project.join(makefile))
+            .collect();
+        for member in group {
+            for prerequisite in target_graph.all_prerequisites(member) {
+                if target_graph.is_phony(&prerequisite) {
+                    continue;
+                }
+                if let Some(path) = target_graph.resolve_path(&prerequisite.0) {
+                    readable.push(project.join(path));
+                }
+            }
+        }
+        let mut placeholders = vec![];
+        let mut writable = vec![];
+        for member in group.iter().filter(|member| !target_graph.is_phony(member)) {
+            let path = project.join(&member.0);
+            if create_placeholders
+                && std::fs::symlink_metadata(&path).is_err()
+                && create_placeholder(&path, target_graph.directory_targets.contains(member))
+                    .is_ok()
+            {
+                placeholders.push(path.clone());
+            }
+            writable.push(path);
+        }
+        Self {
+            project,
+            readable,
+            writable,
+            placeholders,
+        }
+    }
+}
+
+impl Drop for SandboxPolicy {
+    fn drop(&mut self) {
+        for placeholder in &self.placeholders {
+            if is_untouched_placeholder(placeholder) {
+                let _ = match placeholder.is_dir() {
+                    true => std::fs::remove_dir(placeholder),
+                    false => std::fs::remove_file(placeholder),
+                };
+            }
+        }
+    }
+}
+
+// Each directory under `root` (which contains `inner`) that isn't on the way to `inner`, so that access can be allowed
+// to all of `root` except for `inner`.
+fn everything_except(root: &Path, inner: &Path) -> Vec<PathBuf> {
+    let mut siblings = vec![];
+    let Ok(relative) = inner.strip_prefix(root) else {
+        return vec![root.to_owned()];
+    };
+    let mut directory = root.to_owned();
+    for component in relative.components() {
+        if let Ok(entries) = std::fs::read_dir(&directory) {
+            for entry in entries.flatten() {
+                if entry.file_name() != component.as_os_str() {
+                    siblings.push(entry.path());
+                }
+            }
+        }
+        directory.push(component);
+    }
+    siblings
+}
+
+// The directories that recipes can always write to (like `/dev/null` and temporary files).
+fn scratch_directories() -> Vec<PathBuf> {
+    let temp_dir = std::env::temp_dir();
+    vec![
+        temp_dir.canonicalize().unwrap_or(temp_dir),
+        PathBuf::from("/dev"),
+    ]
+}
+
+// The closest directory of `path` (or its parents) that exists, which new files and directories can be created in.
+fn existing_parent(path: &Path) -> Option<&Path> {
+    path.ancestors().skip(1).find(|ancestor| ancestor.is_dir())
+}
+
+#[cfg(target_os = "linux")]
+mod landlock {
+    use std::{
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+        path::Path,
+    };
+
+    pub(super) const EXECUTE: u64 = 1 << 0;
+    pub(super) const WRITE_FILE: u64 = 1 << 1;
+    pub(super) const READ_FILE: u64 = 1 << 2;
+    pub(super) const READ_DIR: u64 = 1 << 3;
+    // Removing, creating, and renaming (ABI 2) files and directories.
+    pub(super) const CHANGE_DIR: u64 = 0x1ff0 | REFER;
+    pub(super) const MAKE_DIR: u64 = 1 << 7;
+    pub(super) const MAKE_REG: u64 = 1 << 8;
+    const REFER: u64 = 1 << 13;
+    pub(super) const TRUNCATE: u64 = 1 << 14;
+    const FILE_ACCESS: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE;
+    pub(super) const ALL: u64 = FILE_ACCESS | READ_DIR | CHANGE_DIR;
+
+    const CREATE_RULESET_VERSION: u32 = 1 << 0;
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// The access rights that the running kernel supports, or `None` if Landlock is not available.
+    pub(super) fn supported_access() -> Option<u64> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        match abi {
+            ..=0 => None,
+            1 => Some(ALL & !REFER & !TRUNCATE),
+            2 => Some(ALL & !TRUNCATE),
+            _ => Some(ALL),
+        }
+    }
+
+    /// A path that access is allowed to (including everything under it, for a directory).
+    pub(super) struct Rule {
+        fd: OwnedFd,
+        allowed_access: u64,
+    }
+
+    impl Rule {
+        pub(super) fn new(path: &Path, allowed_access: u64, supported: u64) -> Option<Self> {
+            use std::os::unix::ffi::OsStrExt;
+
+            let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+            let fd = unsafe { libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+            if fd < 0 {
+                return None;
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            let is_dir = std::fs::metadata(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+                .is_ok_and(|metadata| metadata.is_dir());
+            let allowed_access = match is_dir {
+                true => allowed_access & supported,
+                false => allowed_access & supported & FILE_ACCESS,
+            };
+            Some(Self { fd, allowed_access })
+        }
+    }
+
+    // Only makes syscalls, since it runs between `fork` and `exec`.
+    pub(super) fn restrict_self(rules: &[Rule], supported: u64) -> std::io::Result<()> {
+        let attr = RulesetAttr {
+            handled_access_fs: supported,
+        };
+        let ruleset_fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if ruleset_fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let ruleset_fd = ruleset_fd as libc::c_int;
+        for rule in rules {
+            if rule.allowed_access == 0 {
+                continue;
+            }
+            let path_beneath = PathBeneathAttr {
+                allowed_access: rule.allowed_access,
+                parent_fd: rule.fd.as_raw_fd(),
+            };
+            if unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset_fd,
+                    RULE_PATH_BENEATH,
+                    &path_beneath as *const PathBeneathAttr,
+                    0,
+                )
+            } != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0
+            || unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset_fd, 0) } != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+        unsafe { libc::close(ruleset_fd) };
+        Ok(())
+    }
+}
+
+/// Whether recipes can be sandboxed on this system.
+pub(crate) fn check_support() -> Result<(), String> {
+    #[cfg(target_os = "linux")]
+    return match landlock::supported_access() {
+        Some(_) => Ok(()),
+        None => Err(
+            "Landlock is not available (it needs Linux 5.13 or later with Landlock enabled)"
+                .to_owned(),
+        ),
+    };
+    #[cfg(target_os = "macos")]
+    return match Path::new("/usr/bin/sandbox-exec").exists() {
+        true => Ok(()),
+        false => Err("`/usr/bin/sandbox-exec` was not found".to_owned()),
+    };
+    #[allow(unreachable_code)]
+    Err("sandboxing is only supported on Linux and macOS".to_owned())
+}
+
+/// Makes `command` run with only the access that `policy` allows, using Landlock.
+#[cfg(target_os = "linux")]
+pub(crate) fn sandbox(mut command: Command, policy: &SandboxPolicy) -> Command {
+    use landlock::*;
+
+    let Some(supported) = supported_access() else {
+        return command;
+    };
+    let read = READ_FILE | READ_DIR | EXECUTE;
+    let mut rules = vec![];
+    let mut allow = |path: &Path, allowed_access: u64| {
+        if let Some(rule) = Rule::new(path, allowed_access, supported) {
+            rules.push(rule);
+        }
+    };
+    for path in everything_except(Path::new("/"), &policy.project) {
+        allow(&path, read);
+    }
+    // Listing directories is fine, only reading files is limited.
+    allow(&policy.project, READ_DIR);
+    for path in &policy.readable {
+        allow(path, read);
+    }
+    for scratch_directory in scratch_directories() {
+        for path in everything_except(&scratch_directory, &policy.project) {
+            allow(&path, ALL);
+        }
+    }
+    for path in &policy.writable {
+        allow(path, ALL);
+        // Landlock can only allow creating files in a whole directory. The files in it other than the targets still can't
+        // be written or deleted, so files that the recipe creates there stay empty.
+        if let Some(parent) = existing_parent(path) {
+            allow(parent, MAKE_REG | MAKE_DIR);
+        }
+    }
+    unsafe {
+        std::os::unix::process::CommandExt::pre_exec(&mut command, move || {
+            restrict_self(&rules, supported)
+        });
+    }
+    command
+}
+
+// Quotes a string for a sandbox profile.
+#[cfg(target_os = "macos")]
+fn profile_string(path: &Path) -> String {
+    format!(
+        "\"{}\"",
+        path.to_string_lossy()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+    )
+}
+
+/// Makes `command` run with only the access that `policy` allows, using `sandbox-exec`.
+#[cfg(target_os = "macos")]
+pub(crate) fn sandbox(command: Command, policy: &SandboxPolicy) -> Command {
+    let mut profile = vec![
+        "(version 1)".to_owned(),
+        "(allow default)".to_owned(),
+        format!(
+            "(deny file-read-data (subpath {}))",
+            profile_string(&policy.project)
+        ),
+        // Later rules take precedence.
+        "(deny file-write* (subpath \"/\"))".to_owned(),
+    ];
+    for path in &policy.readable {
+        profile.push(format!(
+            "(allow file-read-data (subpath {}))",
+            profile_string(path)
+        ));
+    }
+    for path in scratch_directories().iter().chain(&policy.writable) {
+        profile.push(format!(
+            "(allow file-read-data file-write* (subpath {}))",
+            profile_string(path)
+        ));
+    }
+    for path in &policy.writable {
+        for ancestor in path.ancestors().skip(1) {
+            if !ancestor.starts_with(&policy.project) || ancestor == policy.project {
+                break;
+            }
+            profile.push(format!(
+                "(allow file-write-create (literal {}))",
+                profile_string(ancestor)
+            ));
+        }
+    }
+    let mut sandboxed = Command::new("/usr/bin/sandbox-exec");
+    sandboxed
+        .arg("-p")
+        .arg(profile.join("\n"))
+        .arg(command.get_program())
+        .args(command.get_args());
+    for (key, value) in command.get_envs() {
+        match value {
+            Some(value) => sandboxed.env(key, value),
+            None => sandboxed.env_remove(key),
+        };
+    }
+    if let Some(current_dir) = command.get_current_dir() {
+        sandboxed.current_dir(current_dir);
+    }
+    sandboxed
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn sandbox(command: Command, _policy: &SandboxPolicy) -> Command {
+    command
+}
@@ -0,0 +1,77 @@
+// For `--shuffle` (like `make --shuffle` since 4.4): the prerequisites of each target are scheduled in a different
+// order than they are listed in, so that missing prerequisites which a build only gets away with because of the order
+// show up as failures (and can be reproduced with the same seed).
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cache::hash;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Shuffle {
+    Reverse,
+    Seed(u64),
+}
+
+pub(crate) fn parse_shuffle(text: &str) -> Result<Shuffle, String> {
+    match text {
+        "reverse" => Ok(Shuffle::Reverse),
+        "random" => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            // Short enough to type.
+            Ok(Shuffle::Seed(
+                hash((now, std::process::id())) % 1_000_000_000,
+            ))
+        }
+        seed => seed.parse().map(Shuffle::Seed).map_err(|_| {
+            format!(
+                "invalid shuffle mode `{}` (expected `random`, `reverse`, or a seed)",
+                seed
+            )
+        }),
+    }
+}
+
+// SplitMix64.
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut value = *state;
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
+
+impl Shuffle {
+    /// Reorders the prerequisites of `key` (or the goals, for an empty `key`). The order only depends on the seed and
+    /// `key`, not on the order in which targets are reached.
+    pub(crate) fn apply<T>(self, key: &str, items: &mut [T]) {
+        match self {
+            Shuffle::Reverse => items.reverse(),
+            Shuffle::Seed(seed) => {
+                let mut state = hash((seed, key));
+                for index in (1..items.len()).rev() {
+                    let other = next_random(&mut state) % (index as u64 + 1);
+                    items.swap(index, other as usize);
+                }
+            }
+        }
+    }
+
+    /// The priority of `key` for a job slot, in place of the critical path (which would undo the shuffle). Without a
+    /// seed, targets get a slot in the order they asked for one.
+    pub(crate) fn priority(self, key: &str) -> u64 {
+        match self {
+            Shuffle::Reverse => 0,
+            Shuffle::Seed(seed) => next_random(&mut hash((seed, key, "priority"))),
+        }
+    }
+
+    /// The argument that gives the same order again.
+    pub(crate) fn argument(self) -> String {
+        match self {
+            Shuffle::Reverse => "--shuffle=reverse".to_owned(),
+            Shuffle::Seed(seed) => format!("--shuffle={}", seed),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+// For `# mak:stamp=true`: targets that don't make a file (e.g. `install-deps`) would run on every build, so a stamp in
+// `.mak/stamps` records the inputs that their recipe last succeeded with.
+
+use std::path::{Path, PathBuf};
+
+use crate::{
+    cache::{prerequisite_hashes, variable_hashes, CACHE_DIRECTORY},
+    parse::{TargetGraph, TargetName},
+    sha256::Sha256,
+};
+
+const STAMPS_DIRECTORY: &str = "stamps";
+
+// Changed when what a stamp is a hash of changes, so that older stamps aren't current.
+const STAMP_VERSION: &[u8] = b"mak-stamp-1";
+
+fn stamp_path(target_name: &TargetName) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.update(target_name.0.as_bytes());
+    Path::new(CACHE_DIRECTORY)
+        .join(STAMPS_DIRECTORY)
+        .join(hasher.finish())
+}
+
+fn read_stamp(target_name: &TargetName) -> Option<String> {
+    let text = std::fs::read_to_string(stamp_path(target_name)).ok()?;
+    Some(text.trim().to_owned())
+}
+
+/// The SHA-256 hash of the inputs of the recipe of `group`: the expanded recipe, the contents of the prerequisites
+/// (and the stamps of prerequisites that have them), and the values of the variables from `# mak:env=…`. Every string
+/// and list is preceded by its length. Should only be called once the prerequisites are up to date.
+pub(crate) fn stamp(target_graph: &TargetGraph, group: &[TargetName]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update_field(STAMP_VERSION);
+    hasher.update(&(group.len() as u64).to_le_bytes());
+    for member in group {
+        hasher.update_field(member.0.as_bytes());
+        let scripts: Vec<String> = target_graph
+            .shell_invocations(member)
+            .into_iter()
+            .map(|invocation| invocation.script)
+            .collect();
+        hasher.update(&(scripts.len() as u64).to_le_bytes());
+        for script in &scripts {
+            hasher.update_field(script.as_bytes());
+        }
+        // Without a stamp, the hash is empty (which no stamp is).
+        let stamped_prerequisites: Vec<(String, String)> = target_graph
+            .all_prerequisites(member)
+            .into_iter()
+            .filter(|prerequisite| target_graph.stamp_targets.contains(prerequisite))
+            .map(|prerequisite| {
+                let stamp = read_stamp(&prerequisite).unwrap_or_default();
+                (prerequisite.0, stamp)
+            })
+            .collect();
+        for pairs in [
+            prerequisite_hashes(target_graph, member)
+                .into_iter()
+                .collect(),
+            stamped_prerequisites,
+            variable_hashes(target_graph, member)
+                .into_iter()
+                .collect::<Vec<_>>(),
+        ] {
+            hasher.update(&(pairs.len() as u64).to_le_bytes());
+            for (name, hash) in pairs {
+                hasher.update_field(name.as_bytes());
+                hasher.update_field(hash.as_bytes());
+            }
+        }
+    }
+    hasher.finish()
+}
+
+/// Whether the recipe of `group` last succeeded with the inputs of `stamp`.
+pub(crate) fn is_current(group: &[TargetName], stamp: &str) -> bool {
+    read_stamp(&group[0]).is_some_and(|current| current == stamp)
+}
+
+/// Records that the recipe of `group` succeeded with the inputs of `stamp`.
+pub(crate) fn write_stamp(group: &[TargetName], stamp: &str) -> std::io::Result<()> {
+    let path = stamp_path(&group[0]);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, format!("{}\n", stamp))
+}
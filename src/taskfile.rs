@@ -0,0 +1,138 @@
+use std::{
+    path::Path,
+    process::{exit, Command},
+};
+
+use serde_json::Value;
+
+use crate::{
+    parse::{TargetGraph, TargetName},
+    yaml::parse_yaml,
+};
+
+// The names `task` looks for, in order.
+const DEFAULT_TASKFILE_NAMES: [&str; 4] = [
+    "Taskfile.yml",
+    "taskfile.yml",
+    "Taskfile.yaml",
+    "taskfile.yaml",
+];
+
+fn taskfile_path(taskfile_path_str: &Option<String>) -> String {
+    if let Some(taskfile_path_str) = taskfile_path_str {
+        return taskfile_path_str.to_owned();
+    }
+    match DEFAULT_TASKFILE_NAMES
+        .into_iter()
+        .find(|taskfile_name| Path::new(taskfile_name).exists())
+    {
+        Some(taskfile_name) => taskfile_name.to_owned(),
+        None => {
+            eprintln!(
+                "No Taskfile specified and no file found called {}",
+                DEFAULT_TASKFILE_NAMES
+                    .map(|taskfile_name| format!("`{}`", taskfile_name))
+                    .join(", ")
+            );
+            exit(1);
+        }
+    }
+}
+
+/// Runs the `cmds` of a task (stored as its recipe) with `sh`, from the directory of the Taskfile.
+pub(crate) fn task_command(
+    target_graph: &TargetGraph,
+    taskfile_path_str: &Option<String>,
+    target_name: &TargetName,
+) -> Command {
+    let mut script = vec!["set -e".to_owned()];
+    script.extend(
+        target_graph
+            .recipes
+            .get(target_name)
+            .cloned()
+            .unwrap_or_default(),
+    );
+    let mut command = Command::new("/bin/sh");
+    command.arg("-c").arg(script.join("\n"));
+    if let Some(directory) = Path::new(&taskfile_path(taskfile_path_str)).parent() {
+        if !directory.as_os_str().is_empty() {
+            command.current_dir(directory);
+        }
+    }
+    command
+}
+
+// Each entry of `deps` or `cmds` is either a string or a mapping like `{task: name}` or `{cmd: …}`.
+fn task_reference(entry: &Value) -> Option<&str> {
+    entry.as_str().or_else(|| entry["task"].as_str())
+}
+
+fn command_line(entry: &Value) -> Option<String> {
+    if let Some(cmd) = entry.as_str().or_else(|| entry["cmd"].as_str()) {
+        return Some(cmd.trim_end_matches('\n').to_owned());
+    }
+    // TODO: schedule tasks called from `cmds` instead of running `task`.
+    entry["task"].as_str().map(|task| format!("task {}", task))
+}
+
+/// Builds the graph from the `tasks` of a go-task `Taskfile.yml`.
+/// Templates (`{{.VAR}}`), `vars`, `env`, and `includes` are not evaluated.
+pub(crate) fn read_taskfile(taskfile_path_str: &Option<String>) -> TargetGraph {
+    let path = taskfile_path(taskfile_path_str);
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(error) => {
+            eprintln!("Could not read `{}`: {}", path, error);
+            exit(1);
+        }
+    };
+    let taskfile = match parse_yaml(&contents) {
+        Ok(taskfile) => taskfile,
+        Err(error) => {
+            eprintln!("Could not parse `{}`: {}", path, error);
+            exit(1);
+        }
+    };
+
+    let mut target_graph = TargetGraph::default();
+    if let Some(tasks) = taskfile["tasks"].as_object() {
+        for (name, task) in tasks {
+            let target_name = TargetName(name.to_owned());
+            // A task can also be just a command or a list of commands.
+            let (dependencies, commands) = match task {
+                Value::String(_) => (&Value::Null, std::slice::from_ref(task)),
+                Value::Array(commands) => (&Value::Null, commands.as_slice()),
+                task => (
+                    &task["deps"],
+                    task["cmds"].as_array().map_or(&[][..], Vec::as_slice),
+                ),
+            };
+            let dependencies: Vec<TargetName> = dependencies
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(task_reference)
+                .map(|dependency| TargetName(dependency.to_owned()))
+                .collect();
+            let mut recipe: Vec<String> = commands.iter().filter_map(command_line).collect();
+            if let Some(dir) = task["dir"].as_str() {
+                recipe.insert(0, format!("cd '{}'", dir.replace('\'', r"'\''")));
+            }
+            target_graph.edges.insert(target_name.clone(), dependencies);
+            target_graph.recipes.insert(target_name.clone(), recipe);
+            if let Some(desc) = task["desc"].as_str().or_else(|| task["summary"].as_str()) {
+                target_graph
+                    .descriptions
+                    .insert(target_name.clone(), desc.to_owned());
+            }
+            target_graph.phony.insert(target_name);
+        }
+    }
+    let default_task = TargetName("default".to_owned());
+    if target_graph.edges.contains_key(&default_task) {
+        target_graph.default_goal = Some(default_task);
+    }
+    target_graph.makefiles = vec![path];
+    target_graph
+}
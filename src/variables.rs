@@ -0,0 +1,567 @@
+use std::sync::{Arc, Mutex, PoisonError};
+
+use indexmap::{IndexMap, IndexSet};
+use serde::{Deserialize, Serialize};
+
+use crate::functions::{patsubst, split_function_call, unsupported_function};
+
+// Guards against self-referential recursive variables (e.g. `FOO = $(FOO) bar`).
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+// `make`'s built-in variables (without `-R`) that are most commonly used, which have the lowest precedence.
+const DEFAULT_VARIABLES: [(&str, &str); 32] = [
+    ("SHELL", "/bin/sh"),
+    (".SHELLFLAGS", "-c"),
+    ("MAKE_COMMAND", "make"),
+    ("MAKE", "$(MAKE_COMMAND)"),
+    ("AR", "ar"),
+    ("ARFLAGS", "rv"),
+    ("AS", "as"),
+    ("CC", "cc"),
+    ("CXX", "g++"),
+    ("CPP", "$(CC) -E"),
+    ("FC", "f77"),
+    ("LD", "ld"),
+    ("LEX", "lex"),
+    ("YACC", "yacc"),
+    ("OBJC", "cc"),
+    ("PC", "pc"),
+    ("CO", "co"),
+    ("GET", "get"),
+    ("MAKEINFO", "makeinfo"),
+    ("TEX", "tex"),
+    ("RM", "rm -f"),
+    ("OUTPUT_OPTION", "-o $@"),
+    ("COMPILE.c", "$(CC) $(CFLAGS) $(CPPFLAGS) $(TARGET_ARCH) -c"),
+    (
+        "COMPILE.cc",
+        "$(CXX) $(CXXFLAGS) $(CPPFLAGS) $(TARGET_ARCH) -c",
+    ),
+    ("COMPILE.cpp", "$(COMPILE.cc)"),
+    ("COMPILE.s", "$(AS) $(ASFLAGS) $(TARGET_MACH)"),
+    (
+        "LINK.c",
+        "$(CC) $(CFLAGS) $(CPPFLAGS) $(LDFLAGS) $(TARGET_ARCH)",
+    ),
+    (
+        "LINK.cc",
+        "$(CXX) $(CXXFLAGS) $(CPPFLAGS) $(LDFLAGS) $(TARGET_ARCH)",
+    ),
+    ("LINK.cpp", "$(LINK.cc)"),
+    ("LINK.o", "$(CC) $(LDFLAGS) $(TARGET_ARCH)"),
+    ("LEX.l", "$(LEX) $(LFLAGS) -t"),
+    ("YACC.y", "$(YACC) $(YFLAGS)"),
+];
+
+// Variables that `make` never takes from the environment.
+const NOT_FROM_ENVIRONMENT: [&str; 1] = ["SHELL"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum AssignmentOperator {
+    #[serde(rename = "=")]
+    Recursive,
+    #[serde(rename = ":=")]
+    Simple, // Also `::=`
+    #[serde(rename = "?=")]
+    Conditional,
+    #[serde(rename = "+=")]
+    Append,
+}
+
+impl AssignmentOperator {
+    pub(crate) fn from_token(token: &str) -> Option<Self> {
+        match token {
+            "=" => Some(Self::Recursive),
+            ":=" | "::=" => Some(Self::Simple),
+            "?=" => Some(Self::Conditional),
+            "+=" => Some(Self::Append),
+            _ => None,
+        }
+    }
+}
+
+/// A single `NAME op value` line, with the value as written.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct VariableAssignment {
+    pub(crate) name: String,
+    pub(crate) operator: AssignmentOperator,
+    pub(crate) value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Flavor {
+    Recursive,
+    Simple,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Variable {
+    pub(crate) flavor: Flavor,
+    pub(crate) value: String,
+}
+
+/// The values of `make`'s automatic variables for a single target.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct AutomaticVariables {
+    pub(crate) target: String,
+    pub(crate) prerequisites: Vec<String>,
+    pub(crate) order_only_prerequisites: Vec<String>,
+    pub(crate) newer_prerequisites: Vec<String>,
+    pub(crate) stem: String,
+}
+
+impl AutomaticVariables {
+    fn get(&self, name: &str) -> Option<String> {
+        let (base, modifier) = match name.len() {
+            1 => (name, None),
+            2 if name.ends_with('D') || name.ends_with('F') => (&name[..1], name.chars().nth(1)),
+            _ => return None,
+        };
+        let words: Vec<String> = match base {
+            "@" => vec![self.target.clone()],
+            "<" => self.prerequisites.first().cloned().into_iter().collect(),
+            "^" => {
+                let mut unique: Vec<String> = vec![];
+                for prerequisite in &self.prerequisites {
+                    if !unique.contains(prerequisite) {
+                        unique.push(prerequisite.clone());
+                    }
+                }
+                unique
+            }
+            "+" => self.prerequisites.clone(),
+            "|" => self.order_only_prerequisites.clone(),
+            "?" => self.newer_prerequisites.clone(),
+            "*" => vec![self.stem.clone()],
+            _ => return None,
+        };
+        let words = words.iter().map(|word| {
+            let (directory, file_name) = match word.rfind('/') {
+                Some(slash_index) => (&word[..slash_index], &word[slash_index + 1..]),
+                None => (".", word.as_str()),
+            };
+            match modifier {
+                Some('D') => directory.to_owned(),
+                Some('F') => file_name.to_owned(),
+                _ => word.clone(),
+            }
+        });
+        Some(words.collect::<Vec<String>>().join(" "))
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Variables {
+    table: IndexMap<String, Variable>,
+    // Set on the command line (or with `override`), so ordinary assignments are ignored.
+    overridden: IndexSet<String>,
+    // `export NAME` (`true`) or `unexport NAME` (`false`).
+    exports: IndexMap<String, bool>,
+    // `export` on its own (or `.EXPORT_ALL_VARIABLES`).
+    export_all: bool,
+    // Set by `-e`: variables from the environment take precedence over assignments in the makefile.
+    environment_overrides: bool,
+    // Built-in variables, which are only used if neither the makefile nor the environment defines them.
+    defaults: IndexMap<String, Variable>,
+    // Set on the command line (for `$(origin …)`).
+    command_line: IndexSet<String>,
+    // The text of `$(eval …)` calls, which the parser reads as makefile text after the statement that expanded them.
+    // Shared with the copies that `$(call …)` and `$(foreach …)` expand with.
+    #[serde(skip)]
+    pending_evaluations: Arc<Mutex<Vec<String>>>,
+    // Set when reading makefiles directly, where calls to functions that `mak` doesn't support stop (instead of
+    // expanding to nothing).
+    #[serde(skip)]
+    strict_functions: bool,
+}
+
+impl Variables {
+    /// Assigns a variable from the makefile, following `make`'s precedence: command line (and `override`), then the
+    /// environment with `-e`, then the makefile, then the environment, then built-in defaults.
+    pub(crate) fn assign(&mut self, name: &str, operator: AssignmentOperator, value: &str) {
+        if self.overridden.contains(name)
+            || (self.environment_overrides && self.environment_value(name).is_some())
+        {
+            return;
+        }
+        self.assign_unchecked(name, operator, value);
+    }
+
+    pub(crate) fn set_environment_overrides(&mut self, environment_overrides: bool) {
+        self.environment_overrides = environment_overrides;
+    }
+
+    /// Defines `make`'s built-in variables (like `CC`), as if `make` was run without `-R`.
+    pub(crate) fn define_defaults(&mut self) {
+        for (name, value) in DEFAULT_VARIABLES {
+            let variable = Variable {
+                flavor: Flavor::Recursive,
+                value: value.to_owned(),
+            };
+            self.defaults.insert(name.to_owned(), variable);
+        }
+    }
+
+    fn environment_value(&self, name: &str) -> Option<String> {
+        if NOT_FROM_ENVIRONMENT.contains(&name) {
+            return None;
+        }
+        std::env::var(name).ok()
+    }
+
+    // The value of a variable that the makefile hasn't assigned (yet), from the environment or the defaults.
+    fn inherited(&self, name: &str) -> Option<Variable> {
+        match self.environment_value(name) {
+            Some(value) => Some(Variable {
+                flavor: Flavor::Simple,
+                value,
+            }),
+            None => self.defaults.get(name).cloned(),
+        }
+    }
+
+    /// Assigns a variable from the command line (`make VAR=value`) or an `override` directive.
+    pub(crate) fn assign_override(
+        &mut self,
+        name: &str,
+        operator: AssignmentOperator,
+        value: &str,
+    ) {
+        self.overridden.insert(name.to_owned());
+        self.assign_unchecked(name, operator, value);
+    }
+
+    /// Assigns a variable from the command line (`make VAR=value`).
+    pub(crate) fn assign_command_line(
+        &mut self,
+        name: &str,
+        operator: AssignmentOperator,
+        value: &str,
+    ) {
+        self.command_line.insert(name.to_owned());
+        self.assign_override(name, operator, value);
+    }
+
+    pub(crate) fn set_strict_functions(&mut self, strict_functions: bool) {
+        self.strict_functions = strict_functions;
+    }
+
+    pub(crate) fn push_evaluation(&self, text: String) {
+        self.pending_evaluations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(text);
+    }
+
+    /// The text of the `$(eval …)` calls since the last time this was called, in order.
+    pub(crate) fn take_evaluations(&self) -> Vec<String> {
+        std::mem::take(
+            &mut *self
+                .pending_evaluations
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        )
+    }
+
+    /// Where a variable was defined, like `$(origin …)` returns it.
+    pub(crate) fn origin(
+        &self,
+        name: &str,
+        automatic: Option<&AutomaticVariables>,
+    ) -> &'static str {
+        if automatic.is_some_and(|automatic| automatic.get(name).is_some()) {
+            return "automatic";
+        }
+        if self.command_line.contains(name) {
+            return "command line";
+        }
+        if self.overridden.contains(name) {
+            return "override";
+        }
+        if self.environment_overrides && self.environment_value(name).is_some() {
+            return "environment override";
+        }
+        if self.table.contains_key(name) {
+            return "file";
+        }
+        if self.environment_value(name).is_some() {
+            return "environment";
+        }
+        match self.defaults.contains_key(name) {
+            true => "default",
+            false => "undefined",
+        }
+    }
+
+    /// How a variable is expanded, like `$(flavor …)` returns it.
+    pub(crate) fn flavor(&self, name: &str) -> &'static str {
+        match self.table.get(name) {
+            Some(Variable {
+                flavor: Flavor::Simple,
+                ..
+            }) => "simple",
+            Some(_) => "recursive",
+            // Like in `make`, variables from the environment are recursive.
+            None if self.inherited(name).is_some() => "recursive",
+            None => "undefined",
+        }
+    }
+
+    fn assign_unchecked(&mut self, name: &str, operator: AssignmentOperator, value: &str) {
+        let variable = match operator {
+            AssignmentOperator::Recursive => Variable {
+                flavor: Flavor::Recursive,
+                value: value.to_owned(),
+            },
+            AssignmentOperator::Simple => Variable {
+                flavor: Flavor::Simple,
+                value: self.expand(value),
+            },
+            AssignmentOperator::Conditional => {
+                // Defined in the environment (or by default) also counts, so e.g. `CC ?= gcc` keeps `cc`.
+                if self.table.contains_key(name) || self.inherited(name).is_some() {
+                    return;
+                }
+                Variable {
+                    flavor: Flavor::Recursive,
+                    value: value.to_owned(),
+                }
+            }
+            AssignmentOperator::Append => match self
+                .table
+                .get(name)
+                .cloned()
+                .or_else(|| self.inherited(name))
+            {
+                Some(existing) => {
+                    // Simple variables expand the appended text immediately, recursive ones keep it raw.
+                    let appended = match existing.flavor {
+                        Flavor::Simple => self.expand(value),
+                        Flavor::Recursive => value.to_owned(),
+                    };
+                    Variable {
+                        flavor: existing.flavor,
+                        value: join_with_space(&existing.value, &appended),
+                    }
+                }
+                None => Variable {
+                    flavor: Flavor::Recursive,
+                    value: value.to_owned(),
+                },
+            },
+        };
+        self.table.insert(name.to_owned(), variable);
+    }
+
+    /// Sets a simple variable to `value` verbatim (without expanding it).
+    pub(crate) fn define_simple(&mut self, name: &str, value: &str) {
+        let variable = Variable {
+            flavor: Flavor::Simple,
+            value: value.to_owned(),
+        };
+        self.table.insert(name.to_owned(), variable);
+    }
+
+    pub(crate) fn raw_value(&self, name: &str) -> Option<&str> {
+        self.table.get(name).map(|variable| variable.value.as_str())
+    }
+
+    /// Expands `$(VAR)`, `${VAR}`, `$V`, and `$$` references. Undefined variables expand to the empty string, like in `make`.
+    pub(crate) fn expand(&self, text: &str) -> String {
+        self.expand_with_depth(text, None, 0)
+    }
+
+    /// Like `expand`, but also resolves automatic variables (`$@`, `$<`, `$^`, …) for a specific target.
+    pub(crate) fn expand_for_target(&self, text: &str, automatic: &AutomaticVariables) -> String {
+        self.expand_with_depth(text, Some(automatic), 0)
+    }
+
+    pub(crate) fn expand_with_depth(
+        &self,
+        text: &str,
+        automatic: Option<&AutomaticVariables>,
+        depth: usize,
+    ) -> String {
+        if depth > MAX_EXPANSION_DEPTH {
+            eprintln!(
+                "Variable expansion is too deeply nested (recursive variable?): {}",
+                text
+            );
+            return String::new();
+        }
+        let mut output = String::with_capacity(text.len());
+        let mut chars = text.char_indices();
+        while let Some((_, c)) = chars.next() {
+            if c != '$' {
+                output.push(c);
+                continue;
+            }
+            match chars.next() {
+                None => output.push('$'),
+                Some((_, '$')) => output.push('$'),
+                Some((start, open @ ('(' | '{'))) => {
+                    let close = if open == '(' { ')' } else { '}' };
+                    let body_start = start + 1;
+                    let mut nesting = 1;
+                    let mut body_end = None;
+                    for (i, c) in chars.by_ref() {
+                        if c == open {
+                            nesting += 1;
+                        } else if c == close {
+                            nesting -= 1;
+                            if nesting == 0 {
+                                body_end = Some(i);
+                                break;
+                            }
+                        }
+                    }
+                    match body_end {
+                        Some(body_end) => output.push_str(&self.expand_body(
+                            &text[body_start..body_end],
+                            automatic,
+                            depth,
+                        )),
+                        // Unterminated reference: keep it verbatim rather than silently dropping text.
+                        None => output.push_str(&text[start - 1..]),
+                    }
+                }
+                Some((_, name)) => {
+                    output.push_str(&self.expand_reference(&name.to_string(), automatic, depth))
+                }
+            }
+        }
+        output
+    }
+
+    // The text between `$(` and `)`: a function call, a substitution reference (`$(VAR:.c=.o)`), or a variable name.
+    fn expand_body(
+        &self,
+        body: &str,
+        automatic: Option<&AutomaticVariables>,
+        depth: usize,
+    ) -> String {
+        if let Some((function_name, arguments)) = split_function_call(body) {
+            return self.call_function(function_name, arguments, automatic, depth + 1);
+        }
+        if let Some(function_name) = unsupported_function(body).filter(|_| self.strict_functions) {
+            eprintln!(
+                "*** unsupported function '{}' (pass `--parser make-db` to read the makefile with `make`).  Stop.",
+                function_name
+            );
+            std::process::exit(2);
+        }
+        let name = self.expand_with_depth(body, automatic, depth + 1);
+        if let Some((name, substitution)) = name.split_once(':') {
+            if let Some((from, to)) = substitution.split_once('=') {
+                let (from, to) = if from.contains('%') {
+                    (from.to_owned(), to.to_owned())
+                } else {
+                    (format!("%{}", from), format!("%{}", to))
+                };
+                let value = self.expand_reference(name, automatic, depth);
+                return patsubst(&from, &to, &value);
+            }
+        }
+        self.expand_reference(&name, automatic, depth)
+    }
+
+    pub(crate) fn expand_reference(
+        &self,
+        name: &str,
+        automatic: Option<&AutomaticVariables>,
+        depth: usize,
+    ) -> String {
+        if let Some(value) = automatic.and_then(|automatic| automatic.get(name)) {
+            return value;
+        }
+        match self.table.get(name) {
+            Some(Variable {
+                flavor: Flavor::Recursive,
+                value,
+            }) => self.expand_with_depth(value, automatic, depth + 1),
+            Some(Variable {
+                flavor: Flavor::Simple,
+                value,
+            }) => value.clone(),
+            // Environment variables (and defaults) are visible as `make` variables unless the makefile defines them.
+            None => match self.inherited(name) {
+                Some(Variable {
+                    flavor: Flavor::Recursive,
+                    value,
+                }) => self.expand_with_depth(&value, automatic, depth + 1),
+                Some(Variable { value, .. }) => value,
+                None => String::new(),
+            },
+        }
+    }
+
+    pub(crate) fn set_exported(&mut self, name: &str, exported: bool) {
+        self.exports.insert(name.to_owned(), exported);
+    }
+
+    pub(crate) fn exports_all(&self) -> bool {
+        self.export_all
+    }
+
+    pub(crate) fn set_export_all(&mut self, export_all: bool) {
+        self.export_all = export_all;
+    }
+
+    /// The environment for recipes, like `make` constructs it: variables from the environment are passed on unless
+    /// they are unexported, and others only if they are exported.
+    pub(crate) fn environment(&self) -> Vec<(String, String)> {
+        let mut environment: Vec<(String, String)> = vec![];
+        for (name, value) in std::env::vars() {
+            if self.exports.get(&name) == Some(&false) {
+                continue;
+            }
+            // A makefile assignment replaces the value from the environment.
+            let value = match self.table.contains_key(&name) {
+                true => self.expand_reference(&name, None, 0),
+                false => value,
+            };
+            environment.push((name, value));
+        }
+        for name in self.table.keys() {
+            if std::env::var_os(name).is_some() {
+                continue;
+            }
+            let exported = match self.exports.get(name) {
+                Some(exported) => *exported,
+                // `export` on its own only applies to names that the shell can use.
+                None => {
+                    self.export_all
+                        && !name.is_empty()
+                        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                }
+            };
+            if exported {
+                environment.push((name.to_owned(), self.expand_reference(name, None, 0)));
+            }
+        }
+        environment
+    }
+
+    /// Whether `name` has a non-empty value (without expanding it), as tested by `ifdef`.
+    pub(crate) fn is_defined(&self, name: &str) -> bool {
+        match self
+            .table
+            .get(name)
+            .cloned()
+            .or_else(|| self.inherited(name))
+        {
+            Some(variable) => !variable.value.is_empty(),
+            None => false,
+        }
+    }
+}
+
+fn join_with_space(a: &str, b: &str) -> String {
+    if a.is_empty() {
+        b.to_owned()
+    } else if b.is_empty() {
+        a.to_owned()
+    } else {
+        format!("{} {}", a, b)
+    }
+}
@@ -0,0 +1,86 @@
+use crate::parse::{TargetGraph, TargetName};
+
+fn names(target_names: &[TargetName]) -> String {
+    if target_names.is_empty() {
+        return "(none)".to_owned();
+    }
+    target_names
+        .iter()
+        .map(TargetName::to_string)
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Describes where `target_name` is defined and how it is built: the locations of its rules, the pattern rule it was
+/// instantiated from, its prerequisites, and its recipe (with variables expanded).
+pub(crate) fn describe_target(target_graph: &TargetGraph, target_name: &TargetName) -> String {
+    let mut lines = vec![target_name.to_string()];
+    match target_graph.rule_locations.get(target_name) {
+        Some(locations) => {
+            let locations: Vec<String> = locations.iter().map(ToString::to_string).collect();
+            lines.push(format!("  Defined at: {}", locations.join(", ")));
+        }
+        None if target_graph.instantiated_from.contains_key(target_name) => {}
+        None => match target_graph.resolve_path(&target_name.0) {
+            Some(path) => lines.push(format!("  Defined at: (no rule, a file at `{}`)", path)),
+            None => lines.push("  Defined at: (unknown)".to_owned()),
+        },
+    }
+    if let Some(pattern_rule) = target_graph
+        .instantiated_from
+        .get(target_name)
+        .and_then(|index| target_graph.pattern_rules.get(*index))
+    {
+        lines.push(format!(
+            "  Pattern rule: {}: {}",
+            pattern_rule.targets.join(" "),
+            pattern_rule.prerequisites.join(" ")
+        ));
+    }
+    if let Some(stem) = target_graph.stems.get(target_name) {
+        lines.push(format!("  Stem: {}", stem));
+    }
+    if target_graph.is_phony(target_name) {
+        lines.push("  Phony: yes".to_owned());
+    }
+    let prerequisites = target_graph
+        .edges
+        .get(target_name)
+        .cloned()
+        .unwrap_or_default();
+    lines.push(format!("  Prerequisites: {}", names(&prerequisites)));
+    if let Some(order_only_prerequisites) = target_graph.order_only_edges.get(target_name) {
+        lines.push(format!(
+            "  Order-only prerequisites: {}",
+            names(order_only_prerequisites)
+        ));
+    }
+    match target_graph.double_colon_rules.get(target_name) {
+        // TODO: expand the recipes of `::` rules.
+        Some(double_colon_rules) => {
+            for (index, double_colon_rule) in double_colon_rules.iter().enumerate() {
+                lines.push(format!(
+                    "  Rule {} (`::`): {}",
+                    index + 1,
+                    names(&double_colon_rule.prerequisites)
+                ));
+                for recipe_line in &double_colon_rule.recipe {
+                    lines.push(format!("    {}", recipe_line));
+                }
+            }
+        }
+        None => {
+            let invocations = target_graph.shell_invocations(target_name);
+            match invocations.is_empty() {
+                true => lines.push("  Recipe: (none)".to_owned()),
+                false => lines.push("  Recipe:".to_owned()),
+            }
+            for invocation in invocations {
+                for script_line in invocation.script.lines() {
+                    lines.push(format!("    {}", script_line));
+                }
+            }
+        }
+    }
+    lines.join("\n")
+}
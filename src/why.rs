@@ -0,0 +1,119 @@
+use indexmap::IndexMap;
+
+use crate::{
+    build_cache::{cache_key, is_cacheable, LocalCache},
+    executor::{explain_rebuild, Freshness},
+    parse::{TargetGraph, TargetName},
+    stamp,
+};
+
+/// Prints whether each of `target_names` and their prerequisites would be rebuilt, and why (`--why`). Prerequisites
+/// come first. Files without a rule are only listed if they are missing.
+pub(crate) fn explain(
+    target_graph: &TargetGraph,
+    target_names: &[TargetName],
+    freshness: &Freshness,
+    build_cache: bool,
+) {
+    let build_cache = build_cache.then(LocalCache::new);
+    let mut rebuilt = IndexMap::new();
+    for target_name in target_names {
+        explain_target(
+            target_graph,
+            target_name,
+            freshness,
+            build_cache.as_ref(),
+            &mut rebuilt,
+        );
+    }
+}
+
+// Returns whether `target_name` is rebuilt (or, without a recipe, counts as updated because a prerequisite is).
+fn explain_target(
+    target_graph: &TargetGraph,
+    target_name: &TargetName,
+    freshness: &Freshness,
+    build_cache: Option<&LocalCache>,
+    rebuilt: &mut IndexMap<TargetName, bool>,
+) -> bool {
+    if let Some(is_rebuilt) = rebuilt.get(target_name) {
+        return *is_rebuilt;
+    }
+    // Avoids infinite recursion for circular dependencies (which `make` drops).
+    rebuilt.insert(target_name.clone(), false);
+    for prerequisite in target_graph.all_prerequisites(target_name) {
+        explain_target(target_graph, &prerequisite, freshness, build_cache, rebuilt);
+    }
+    if !target_graph.has_rule(target_name) {
+        if target_graph.resolve_path(&target_name.0).is_none() {
+            println!("{}: missing, and there is no rule to make it", target_name);
+        }
+        return false;
+    }
+
+    let (needs_rebuild, mut reasons) = explain_rebuild(target_graph, target_name, freshness);
+    // Order-only prerequisites don't make it rebuild.
+    let rebuilt_prerequisites: Vec<&TargetName> = target_graph
+        .edges
+        .get(target_name)
+        .into_iter()
+        .flatten()
+        .filter(|prerequisite| rebuilt.get(*prerequisite).copied().unwrap_or_default())
+        .collect();
+    for prerequisite in &rebuilt_prerequisites {
+        reasons.push(format!("`{}` is rebuilt first", prerequisite));
+    }
+    let mut is_rebuilt = needs_rebuild || !rebuilt_prerequisites.is_empty();
+    let group = target_graph
+        .target_group(target_name)
+        .map(<[TargetName]>::to_vec)
+        .unwrap_or_else(|| vec![target_name.clone()]);
+    // The stamp depends on the contents of the prerequisites, which are only known once they are rebuilt (except for
+    // phony ones, which it doesn't include).
+    let stamped = is_rebuilt
+        && !freshness.always_make
+        && group
+            .iter()
+            .any(|member| target_graph.stamp_targets.contains(member))
+        && rebuilt_prerequisites.iter().all(|prerequisite| {
+            target_graph.is_phony(prerequisite)
+                && !target_graph.stamp_targets.contains(*prerequisite)
+        });
+    if stamped && stamp::is_current(&group, &stamp::stamp(target_graph, &group)) {
+        reasons = vec![
+            "its stamp shows that the recipe already succeeded with the same inputs".to_owned(),
+        ];
+        is_rebuilt = false;
+    }
+    let has_recipe = target_graph.has_recipe(target_name);
+    println!(
+        "{}: {}",
+        target_name,
+        match (has_recipe, is_rebuilt) {
+            (false, _) => "nothing to run (no recipe)",
+            (true, true) => "rebuilds",
+            (true, false) => "up to date",
+        }
+    );
+    for reason in &reasons {
+        println!("  - {}", reason);
+    }
+
+    if let Some(build_cache) =
+        build_cache.filter(|_| is_rebuilt && is_cacheable(target_graph, &group))
+    {
+        // The key depends on the contents of the prerequisites, which are only known once they are rebuilt.
+        match rebuilt_prerequisites.is_empty() {
+            true => {
+                let key = cache_key(target_graph, &group);
+                match build_cache.contains(&key) {
+                    true => println!("  - the build cache has its targets (key {}), so they are restored instead", key),
+                    false => println!("  - the local build cache doesn't have its targets (key {}) yet", key),
+                }
+            }
+            false => println!("  - whether the build cache has its targets depends on the prerequisites that are rebuilt"),
+        }
+    }
+    rebuilt.insert(target_name.clone(), is_rebuilt);
+    is_rebuilt
+}
@@ -0,0 +1,107 @@
+use std::path::Path;
+
+pub(crate) fn has_wildcard(text: &str) -> bool {
+    text.contains(['*', '?', '['])
+}
+
+// Matches a single path component against a shell-style pattern (`*`, `?`, `[abc]`, `[a-z]`, `[!abc]`).
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| matches(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && matches(&pattern[1..], &name[1..]),
+        Some('[') => {
+            let Some(c) = name.first() else {
+                return false;
+            };
+            // A `]` right after the `[` is part of the class, so the search for the closing `]` starts after it.
+            let Some(close_offset) = pattern.iter().skip(2).position(|c| *c == ']') else {
+                return pattern.first() == name.first() && matches(&pattern[1..], &name[1..]);
+            };
+            let class = &pattern[1..close_offset + 2];
+            let (negate, class) = match class.first() {
+                Some('!' | '^') => (true, &class[1..]),
+                _ => (false, class),
+            };
+            let mut in_class = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == '-' {
+                    in_class |= class[i] <= *c && *c <= class[i + 2];
+                    i += 3;
+                } else {
+                    in_class |= class[i] == *c;
+                    i += 1;
+                }
+            }
+            in_class != negate && matches(&pattern[close_offset + 3..], &name[1..])
+        }
+        Some(p) => name.first() == Some(p) && matches(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Matches a whole name (`/` is not special) against a shell-style pattern.
+pub(crate) fn matches_pattern(pattern: &str, name: &str) -> bool {
+    matches(
+        &pattern.chars().collect::<Vec<char>>(),
+        &name.chars().collect::<Vec<char>>(),
+    )
+}
+
+/// Expands a glob like `src/*.c` against the filesystem, like `$(wildcard …)`.
+/// Returns sorted matches, or the pattern itself if it has no wildcards and names an existing file.
+pub(crate) fn expand_wildcard(pattern: &str) -> Vec<String> {
+    if !has_wildcard(pattern) {
+        return if Path::new(pattern).exists() {
+            vec![pattern.to_owned()]
+        } else {
+            vec![]
+        };
+    }
+    let (mut candidates, components) = match pattern.strip_prefix('/') {
+        Some(relative) => (vec!["/".to_owned()], relative),
+        None => (vec![String::new()], pattern),
+    };
+    for component in components
+        .split('/')
+        .filter(|component| !component.is_empty())
+    {
+        let mut next_candidates = vec![];
+        for candidate in candidates {
+            if !has_wildcard(component) {
+                next_candidates.push(format!("{}{}/", candidate, component));
+                continue;
+            }
+            let directory = if candidate.is_empty() {
+                "."
+            } else {
+                &candidate
+            };
+            let Ok(entries) = std::fs::read_dir(directory) else {
+                continue;
+            };
+            let pattern: Vec<char> = component.chars().collect();
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                // Like the shell, only match hidden files if the pattern asks for them.
+                if name.starts_with('.') && !component.starts_with('.') {
+                    continue;
+                }
+                if matches(&pattern, &name.chars().collect::<Vec<char>>()) {
+                    next_candidates.push(format!("{}{}/", candidate, name));
+                }
+            }
+        }
+        candidates = next_candidates;
+    }
+    let mut matches: Vec<String> = candidates
+        .into_iter()
+        .map(|candidate| candidate.trim_end_matches('/').to_owned())
+        .filter(|candidate| Path::new(candidate).exists())
+        .collect();
+    if pattern.ends_with('/') {
+        matches = matches.into_iter().map(|m| format!("{}/", m)).collect();
+    }
+    matches.sort();
+    matches
+}
@@ -0,0 +1,485 @@
+// A `mak` worker (`--serve-worker`) runs recipes for a coordinator, i.e. a `mak` invocation with `mak://HOST:PORT`
+// workers in its `--workers` file. The coordinator runs a `mak --worker-client` process for each recipe, which sends
+// the recipe as a line of JSON followed by the contents of its inputs, prints the output lines that the worker sends
+// back, writes the outputs (which follow the last line), and exits with the recipe's exit code. This way, timeouts,
+// retries, progress, and failures work like for local recipes. Nothing is encrypted (not even the token), so the
+// connection needs to go through an SSH tunnel or a TLS proxy unless the network is trusted.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+    process::{exit, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{processes, sha256::Sha256};
+
+/// The secret that the coordinator needs to send, so that not just anyone who can connect can run commands.
+pub(crate) const TOKEN_ENVIRONMENT_VARIABLE: &str = "MAK_WORKER_TOKEN";
+
+// Relative to the directory that the worker was started in.
+const WORK_DIRECTORY: &str = ".mak/worker";
+
+// The longest first line of a connection, which is read before the token is checked.
+const MAX_REQUEST_SIZE: u64 = 4 * 1024 * 1024;
+
+/// What the `mak --worker-client` process gets from the coordinator (in the file that its second argument is).
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Job {
+    // The name of the coordinator's directory, so that each project gets a directory of its own on the worker.
+    pub(crate) project: String,
+    pub(crate) command: Vec<String>,
+    pub(crate) inputs: Vec<String>,
+    pub(crate) outputs: Vec<String>,
+}
+
+/// A file with a `Job`, which is removed when this is dropped (once the recipe is done, since each attempt reads it).
+pub(crate) struct JobFile {
+    path: PathBuf,
+}
+
+impl JobFile {
+    pub(crate) fn new(job: &Job) -> Result<Self, String> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "mak-job-{}-{}.json",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let json = serde_json::to_string(job).expect("Could not serialize a job.");
+        std::fs::write(&path, json).map_err(|error| error.to_string())?;
+        Ok(Self { path })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for JobFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// A file whose contents follow the line that it is in.
+#[derive(Serialize, Deserialize)]
+struct FileHeader {
+    path: String,
+    size: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Request {
+    token: String,
+    project: String,
+    command: Vec<String>,
+    inputs: Vec<FileHeader>,
+    outputs: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Response {
+    // Once the token is checked, so that the inputs are only sent to a worker that reads them.
+    Accepted,
+    Stdout(String),
+    Stderr(String),
+    // `exit_code` is `None` if the recipe was killed by a signal (or could not be started).
+    Finished {
+        exit_code: Option<i32>,
+        outputs: Vec<FileHeader>,
+    },
+}
+
+// Files are only read and written inside of `directory` (which needs to exist): `path` can't have `..` in it, or lead
+// out of `directory` through a symbolic link (e.g. one that a recipe made). The parts of `path` that don't exist yet
+// can be created with `create_dir_all`.
+fn project_file(directory: &Path, path: &str) -> Option<PathBuf> {
+    let is_relative = Path::new(path)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !is_relative || path.is_empty() {
+        return None;
+    }
+    let directory = directory.canonicalize().ok()?;
+    let path = directory.join(path);
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.symlink_metadata().is_ok())?;
+    let missing = path.strip_prefix(existing).ok()?;
+    let canonical = existing.canonicalize().ok()?;
+    if !canonical.starts_with(&directory) {
+        return None;
+    }
+    match missing.as_os_str().is_empty() {
+        true => Some(canonical),
+        false => Some(canonical.join(missing)),
+    }
+}
+
+// Copies `size` bytes from `reader` to the file at `path`.
+fn receive_file(reader: &mut impl Read, path: &Path, size: u64) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|error| error.to_string())?;
+    }
+    let mut file = File::create(path).map_err(|error| error.to_string())?;
+    let received =
+        std::io::copy(&mut reader.take(size), &mut file).map_err(|error| error.to_string())?;
+    match received == size {
+        true => Ok(()),
+        false => Err(format!(
+            "the connection was closed while receiving `{}`",
+            path.display()
+        )),
+    }
+}
+
+// Opens the file at `path` to send it (if it is a regular file).
+fn open_file(path: &Path) -> Option<(File, u64)> {
+    let file = File::open(path).ok()?;
+    let metadata = file.metadata().ok()?;
+    metadata.is_file().then_some((file, metadata.len()))
+}
+
+// Copies `size` bytes of `file` to `writer`. A file that got shorter breaks the connection, since the other side can't
+// tell where the next file starts.
+fn send_file(writer: &mut impl Write, file: File, size: u64, path: &str) -> std::io::Result<()> {
+    let sent = std::io::copy(&mut file.take(size), writer)?;
+    match sent == size {
+        true => Ok(()),
+        false => Err(std::io::Error::other(format!(
+            "`{}` changed while it was sent",
+            path
+        ))),
+    }
+}
+
+// The hashes of the tokens are compared without stopping at the first difference, so that how long the comparison
+// takes doesn't tell how much of a guessed token is right.
+fn tokens_match(token: &str, expected: &str) -> bool {
+    let digest = |token: &str| {
+        let mut hasher = Sha256::new();
+        hasher.update(token.as_bytes());
+        hasher.finish()
+    };
+    digest(token)
+        .bytes()
+        .zip(digest(expected).bytes())
+        .fold(0, |difference, (byte, expected_byte)| {
+            difference | (byte ^ expected_byte)
+        })
+        == 0
+}
+
+fn token() -> String {
+    match std::env::var(TOKEN_ENVIRONMENT_VARIABLE) {
+        Ok(token) if !token.is_empty() => token,
+        _ => {
+            eprintln!(
+                "`{}` needs to be set (to the same secret for the coordinator and its workers).",
+                TOKEN_ENVIRONMENT_VARIABLE
+            );
+            exit(1);
+        }
+    }
+}
+
+fn write_line(writer: &mut impl Write, value: &impl Serialize) -> std::io::Result<()> {
+    let mut line = serde_json::to_string(value).expect("Could not serialize a message.");
+    line.push('\n');
+    writer.write_all(line.as_bytes())
+}
+
+fn send(stream: &Mutex<TcpStream>, response: &Response) -> std::io::Result<()> {
+    write_line(
+        &mut *stream.lock().unwrap_or_else(PoisonError::into_inner),
+        response,
+    )
+}
+
+// Sends the last line, followed by the `outputs` (which are files inside of `directory`) that exist.
+fn send_finished(
+    stream: &Mutex<TcpStream>,
+    exit_code: Option<i32>,
+    directory: &Path,
+    outputs: &[String],
+) -> std::io::Result<()> {
+    let files: Vec<(&String, File, u64)> = outputs
+        .iter()
+        .filter_map(|output| {
+            let (file, size) = open_file(&project_file(directory, output)?)?;
+            Some((output, file, size))
+        })
+        .collect();
+    let outputs = files
+        .iter()
+        .map(|(output, _, size)| FileHeader {
+            path: (*output).clone(),
+            size: *size,
+        })
+        .collect();
+    let mut stream = stream.lock().unwrap_or_else(PoisonError::into_inner);
+    write_line(&mut *stream, &Response::Finished { exit_code, outputs })?;
+    for (output, file, size) in files {
+        send_file(&mut *stream, file, size, output)?;
+    }
+    stream.flush()
+}
+
+/// Runs recipes for coordinators that connect to `address`, until `mak` is stopped.
+pub(crate) fn serve(address: &str) -> ! {
+    let token = token();
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(error) => {
+            eprintln!("Could not listen on `{}`: {}", address, error);
+            exit(1);
+        }
+    };
+    eprintln!("👷 Serving as a worker on {}", address);
+    for stream in listener.incoming().flatten() {
+        let token = token.clone();
+        std::thread::spawn(move || {
+            let peer = stream
+                .peer_addr()
+                .map(|peer| peer.to_string())
+                .unwrap_or_default();
+            if let Err(error) = handle_connection(stream, &token) {
+                eprintln!("👷 Error for {}: {}", peer, error);
+            }
+        });
+    }
+    exit(1)
+}
+
+fn handle_connection(stream: TcpStream, token: &str) -> Result<(), String> {
+    let mut reader = BufReader::new(stream.try_clone().map_err(|error| error.to_string())?);
+    let mut line = String::new();
+    (&mut reader)
+        .take(MAX_REQUEST_SIZE)
+        .read_line(&mut line)
+        .map_err(|error| error.to_string())?;
+    if !line.ends_with('\n') {
+        return Err("the request is incomplete or too long".to_owned());
+    }
+    let request: Request = serde_json::from_str(&line).map_err(|error| error.to_string())?;
+    let stream = Arc::new(Mutex::new(stream));
+    if !tokens_match(&request.token, token) {
+        let _ = send(
+            &stream,
+            &Response::Stderr(format!(
+                "mak worker: wrong `{}`",
+                TOKEN_ENVIRONMENT_VARIABLE
+            )),
+        );
+        let _ = send(
+            &stream,
+            &Response::Finished {
+                exit_code: None,
+                outputs: vec![],
+            },
+        );
+        return Err("wrong token".to_owned());
+    }
+    std::fs::create_dir_all(WORK_DIRECTORY).map_err(|error| error.to_string())?;
+    let Some(directory) = project_file(Path::new(WORK_DIRECTORY), &request.project) else {
+        return Err(format!("invalid project name `{}`", request.project));
+    };
+    std::fs::create_dir_all(&directory).map_err(|error| error.to_string())?;
+    send(&stream, &Response::Accepted).map_err(|error| error.to_string())?;
+    for input in &request.inputs {
+        let Some(path) = project_file(&directory, &input.path) else {
+            return Err(format!("invalid input `{}`", input.path));
+        };
+        receive_file(&mut reader, &path, input.size)?;
+    }
+    let Some((program, args)) = request.command.split_first() else {
+        return Err("empty command".to_owned());
+    };
+    eprintln!("👷 Running {}", request.command.join(" "));
+
+    let mut child = match processes::spawn_recipe(
+        Command::new(program)
+            .args(args)
+            .current_dir(&directory)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped()),
+        program,
+    ) {
+        Ok(child) => child,
+        Err(error) => {
+            let _ = send(
+                &stream,
+                &Response::Stderr(format!(
+                    "mak worker: could not run `{}`: {}",
+                    program, error
+                )),
+            );
+            let _ = send_finished(&stream, None, &directory, &[]);
+            return Ok(());
+        }
+    };
+    let pid = child.id();
+    let forward = |reader: Box<dyn std::io::Read + Send>, response: fn(String) -> Response| {
+        let stream = stream.clone();
+        std::thread::spawn(move || {
+            for line in BufReader::new(reader).lines() {
+                let Ok(line) = line else { break };
+                // The coordinator is gone, so the recipe is stopped.
+                if send(&stream, &response(line)).is_err() {
+                    processes::stop_process_group(pid, true);
+                    break;
+                }
+            }
+        })
+    };
+    let stdout_thread = forward(
+        Box::new(child.stdout.take().expect("Could not get stdout.")),
+        Response::Stdout,
+    );
+    let stderr_thread = forward(
+        Box::new(child.stderr.take().expect("Could not get stderr.")),
+        Response::Stderr,
+    );
+    // Notices a coordinator that goes away while the recipe doesn't print anything.
+    let watched_stream = stream
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .try_clone()
+        .map_err(|error| error.to_string())?;
+    let finished = Arc::new(AtomicBool::new(false));
+    let watcher_finished = finished.clone();
+    std::thread::spawn(move || {
+        let mut byte = [0u8];
+        if let Ok(0) = std::io::Read::read(&mut &watched_stream, &mut byte) {
+            if !watcher_finished.load(Ordering::SeqCst) {
+                processes::stop_process_group(pid, true);
+            }
+        }
+    });
+    let status = child.wait().map_err(|error| error.to_string())?;
+    finished.store(true, Ordering::SeqCst);
+    processes::recipe_finished(pid);
+    let _ = stdout_thread.join();
+    let _ = stderr_thread.join();
+
+    let outputs = match status.success() {
+        true => &request.outputs[..],
+        false => &[],
+    };
+    send_finished(&stream, status.code(), &directory, outputs).map_err(|error| error.to_string())
+}
+
+/// Runs the job in `job_file` on the worker at `address`: prints its output, writes its outputs, and exits with its
+/// exit code.
+pub(crate) fn run_client(address: &str, job_file: &str) -> ! {
+    let job: Job = std::fs::read_to_string(job_file)
+        .map_err(|error| error.to_string())
+        .and_then(|json| serde_json::from_str(&json).map_err(|error| error.to_string()))
+        .unwrap_or_else(|error| {
+            eprintln!("mak: invalid job: {}", error);
+            exit(1)
+        });
+    let (inputs, files): (Vec<FileHeader>, Vec<File>) = job
+        .inputs
+        .iter()
+        .map(|path| match open_file(Path::new(path)) {
+            Some((file, size)) => (
+                FileHeader {
+                    path: path.clone(),
+                    size,
+                },
+                file,
+            ),
+            None => {
+                eprintln!("mak: could not read `{}`", path);
+                exit(1)
+            }
+        })
+        .unzip();
+    let stream = TcpStream::connect(address).unwrap_or_else(|error| {
+        eprintln!(
+            "mak: could not connect to the worker `{}`: {}",
+            address, error
+        );
+        exit(1)
+    });
+    let sizes: Vec<u64> = inputs.iter().map(|input| input.size).collect();
+    let request = Request {
+        token: token(),
+        project: job.project,
+        command: job.command,
+        inputs,
+        outputs: job.outputs.clone(),
+    };
+    let sent = write_line(&mut &stream, &request);
+    if let Err(error) = sent {
+        eprintln!("mak: could not send the job to `{}`: {}", address, error);
+        exit(1)
+    }
+    let mut files = Some(files.into_iter().zip(&job.inputs).zip(sizes));
+    let mut reader = BufReader::new(&stream);
+    loop {
+        let mut line = String::new();
+        let Ok(response) = reader
+            .read_line(&mut line)
+            .map_err(|error| error.to_string())
+            .and_then(|_| serde_json::from_str(&line).map_err(|error| error.to_string()))
+        else {
+            break;
+        };
+        match response {
+            Response::Accepted => {
+                let mut writer = std::io::BufWriter::new(&stream);
+                let sent = files
+                    .take()
+                    .into_iter()
+                    .flatten()
+                    .try_for_each(|((file, path), size)| send_file(&mut writer, file, size, path))
+                    .and_then(|()| writer.flush());
+                if let Err(error) = sent {
+                    eprintln!("mak: could not send the inputs to `{}`: {}", address, error);
+                    exit(1)
+                }
+            }
+            Response::Stdout(line) => println!("{}", line),
+            Response::Stderr(line) => eprintln!("{}", line),
+            Response::Finished { exit_code, outputs } => {
+                for output in outputs {
+                    // Only the targets of the recipe, so that a worker can't change other files of the project.
+                    if !job.outputs.contains(&output.path) {
+                        eprintln!(
+                            "mak: the worker `{}` sent `{}`, which is not a target of the recipe",
+                            address, output.path
+                        );
+                        exit(1)
+                    }
+                    let result = match project_file(Path::new("."), &output.path) {
+                        Some(path) => receive_file(&mut reader, &path, output.size),
+                        None => std::io::copy(
+                            &mut (&mut reader).take(output.size),
+                            &mut std::io::sink(),
+                        )
+                        .map(|_| ())
+                        .map_err(|error| error.to_string()),
+                    };
+                    if let Err(error) = result {
+                        eprintln!("mak: could not write `{}`: {}", output.path, error);
+                        exit(1)
+                    }
+                }
+                exit(exit_code.unwrap_or(1))
+            }
+        }
+    }
+    eprintln!("mak: lost the connection to the worker `{}`", address);
+    exit(1)
+}
@@ -0,0 +1,302 @@
+// A small YAML reader for the block-style subset that configuration files like `Taskfile.yml` use:
+// mappings, sequences, (flow) `[…]`/`{…}` collections, quoted and plain scalars, and `|`/`>` block scalars.
+// Anchors, tags, and multi-document files are not supported. All scalars are read as strings.
+
+use serde_json::{Map, Value};
+
+struct Line<'a> {
+    indent: usize,
+    // The line without indentation or a trailing comment.
+    content: String,
+    raw: &'a str,
+}
+
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '#') if previous == ' ' || previous == '\t' => return text[..i].trim_end(),
+            _ => {}
+        }
+        previous = c;
+    }
+    text.trim_end()
+}
+
+/// Parses `text` into a JSON value (so that it can be read like JSON).
+pub(crate) fn parse_yaml(text: &str) -> Result<Value, String> {
+    let lines: Vec<Line> = text
+        .lines()
+        .map(|raw| {
+            let trimmed = raw.trim_start_matches(' ');
+            Line {
+                indent: raw.len() - trimmed.len(),
+                content: strip_comment(trimmed).to_owned(),
+                raw,
+            }
+        })
+        .collect();
+    let mut parser = Parser { lines, index: 0 };
+    parser.skip_blank_lines();
+    if parser.index >= parser.lines.len() {
+        return Ok(Value::Null);
+    }
+    let indent = parser.lines[parser.index].indent;
+    let value = parser.parse_block(indent)?;
+    parser.skip_blank_lines();
+    if let Some(line) = parser.lines.get(parser.index) {
+        return Err(format!(
+            "Unexpected indentation on line {}: {}",
+            parser.index + 1,
+            line.raw
+        ));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    lines: Vec<Line<'a>>,
+    index: usize,
+}
+
+impl Parser<'_> {
+    fn skip_blank_lines(&mut self) {
+        while self.index < self.lines.len()
+            && (self.lines[self.index].content.is_empty()
+                || self.lines[self.index].content == "---")
+        {
+            self.index += 1;
+        }
+    }
+
+    fn current_indent(&mut self) -> Option<usize> {
+        self.skip_blank_lines();
+        self.lines.get(self.index).map(|line| line.indent)
+    }
+
+    fn parse_block(&mut self, indent: usize) -> Result<Value, String> {
+        if is_sequence_item(&self.lines[self.index].content) {
+            self.parse_sequence(indent)
+        } else {
+            self.parse_mapping(indent)
+        }
+    }
+
+    fn parse_sequence(&mut self, indent: usize) -> Result<Value, String> {
+        let mut items = vec![];
+        while self.current_indent() == Some(indent)
+            && is_sequence_item(&self.lines[self.index].content)
+        {
+            let line = &mut self.lines[self.index];
+            let item = line.content[1..].trim_start().to_owned();
+            if item.is_empty() {
+                self.index += 1;
+                items.push(self.parse_nested_block(indent)?);
+            } else if is_block_scalar_indicator(&item) {
+                self.index += 1;
+                items.push(self.parse_block_scalar(indent, &item));
+            } else if split_key(&item).is_some() {
+                // `- key: value` starts a mapping, which continues on the lines indented like `key`.
+                line.indent = indent + line.content.len() - item.len();
+                line.content = item;
+                let item_indent = line.indent;
+                items.push(self.parse_mapping(item_indent)?);
+            } else {
+                self.index += 1;
+                items.push(parse_flow_value(&item)?);
+            }
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn parse_mapping(&mut self, indent: usize) -> Result<Value, String> {
+        let mut map = Map::new();
+        while self.current_indent() == Some(indent) {
+            let content = self.lines[self.index].content.clone();
+            if is_sequence_item(&content) {
+                break;
+            }
+            let Some((key, value)) = split_key(&content) else {
+                return Err(format!(
+                    "Expected `key: value` on line {}: {}",
+                    self.index + 1,
+                    self.lines[self.index].raw
+                ));
+            };
+            self.index += 1;
+            let value = match value {
+                "" => {
+                    // Sequences may be indented at the same level as their key.
+                    if self.current_indent() == Some(indent)
+                        && is_sequence_item(&self.lines[self.index].content)
+                    {
+                        self.parse_sequence(indent)?
+                    } else {
+                        self.parse_nested_block(indent)?
+                    }
+                }
+                value if is_block_scalar_indicator(value) => self.parse_block_scalar(indent, value),
+                value => parse_flow_value(value)?,
+            };
+            map.insert(unquote(key), value);
+        }
+        Ok(Value::Object(map))
+    }
+
+    fn parse_nested_block(&mut self, parent_indent: usize) -> Result<Value, String> {
+        match self.current_indent() {
+            Some(indent) if indent > parent_indent => self.parse_block(indent),
+            _ => Ok(Value::Null),
+        }
+    }
+
+    // Block scalars keep their lines verbatim (including `#`), minus the common indentation.
+    fn parse_block_scalar(&mut self, parent_indent: usize, indicator: &str) -> Value {
+        let mut lines: Vec<&str> = vec![];
+        let mut block_indent = None;
+        while let Some(line) = self.lines.get(self.index) {
+            if line.raw.trim().is_empty() {
+                lines.push("");
+                self.index += 1;
+                continue;
+            }
+            if line.indent <= parent_indent {
+                break;
+            }
+            let block_indent = *block_indent.get_or_insert(line.indent);
+            lines.push(
+                line.raw
+                    .get(block_indent..)
+                    .unwrap_or(line.raw.trim_start()),
+            );
+            self.index += 1;
+        }
+        while lines.last() == Some(&"") {
+            lines.pop();
+        }
+        let mut text = if indicator.starts_with('>') {
+            lines.join(" ")
+        } else {
+            lines.join("\n")
+        };
+        if !indicator.ends_with('-') {
+            text.push('\n');
+        }
+        Value::String(text)
+    }
+}
+
+fn is_sequence_item(content: &str) -> bool {
+    content == "-" || content.starts_with("- ")
+}
+
+fn is_block_scalar_indicator(content: &str) -> bool {
+    matches!(content, "|" | "|-" | "|+" | ">" | ">-" | ">+")
+}
+
+// Splits `key: value` (or `key:`), ignoring colons inside quotes or flow collections.
+fn split_key(content: &str) -> Option<(&str, &str)> {
+    if content.starts_with(['[', '{']) {
+        return None;
+    }
+    let mut quote = None;
+    for (i, c) in content.char_indices() {
+        match (quote, c) {
+            (None, '\'' | '"') if i == 0 => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, ':') => {
+                let rest = &content[i + 1..];
+                if rest.is_empty() || rest.starts_with([' ', '\t']) {
+                    return Some((content[..i].trim_end(), rest.trim()));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn unquote(text: &str) -> String {
+    let text = text.trim();
+    if text.len() >= 2 && text.starts_with('\'') && text.ends_with('\'') {
+        return text[1..text.len() - 1].replace("''", "'");
+    }
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        let mut unescaped = String::new();
+        let mut chars = text[1..text.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                unescaped.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n') => unescaped.push('\n'),
+                Some('t') => unescaped.push('\t'),
+                Some(c) => unescaped.push(c),
+                None => unescaped.push('\\'),
+            }
+        }
+        return unescaped;
+    }
+    text.to_owned()
+}
+
+// Splits the inside of a flow collection at top-level commas.
+fn split_flow_items(text: &str) -> Vec<&str> {
+    let mut items = vec![];
+    let mut nesting = 0;
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (None, '\'' | '"') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '[' | '{') => nesting += 1,
+            (None, ']' | '}') => nesting -= 1,
+            (None, ',') if nesting == 0 => {
+                items.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(text[start..].trim());
+    items.retain(|item| !item.is_empty());
+    items
+}
+
+fn parse_flow_value(text: &str) -> Result<Value, String> {
+    let text = text.trim();
+    if let Some(inner) = text.strip_prefix('[') {
+        let Some(inner) = inner.strip_suffix(']') else {
+            return Err(format!("Unterminated `[`: {}", text));
+        };
+        return split_flow_items(inner)
+            .into_iter()
+            .map(parse_flow_value)
+            .collect::<Result<Vec<Value>, String>>()
+            .map(Value::Array);
+    }
+    if let Some(inner) = text.strip_prefix('{') {
+        let Some(inner) = inner.strip_suffix('}') else {
+            return Err(format!("Unterminated `{{`: {}", text));
+        };
+        let mut map = Map::new();
+        for item in split_flow_items(inner) {
+            let (key, value) = split_key(item).unwrap_or((item, ""));
+            let value = match value {
+                "" => Value::Null,
+                value => parse_flow_value(value)?,
+            };
+            map.insert(unquote(key), value);
+        }
+        return Ok(Value::Object(map));
+    }
+    Ok(match text {
+        "~" | "null" => Value::Null,
+        text => Value::String(unquote(text)),
+    })
+}